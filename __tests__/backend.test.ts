@@ -1,24 +1,33 @@
-import { EnvironmentCreationResult, InitializedGatewayValue, RegisteredDeviceIndex, RegisteredDeviceValue, RegisteredGatewayValue, RejectedAccessKey, RejectedAccessKeyReason, UpdateValue } from "../src/declarations/omnia_backend/omnia_backend.did";
+import { EnvironmentCreationResult, InitializedGatewayValue, PageRequest, RegisteredDeviceIndex, RegisteredDeviceValue, RegisteredGatewayValue, RejectedAccessKey, RejectedAccessKeyReason, UpdateValue } from "../src/declarations/omnia_backend/omnia_backend.did";
 import {
   application1,
   application1Data,
   application1Ledger,
   applicationApi,
+  databaseApi,
   gateway1,
   gateway1Data,
+  gateway2,
   manager1,
   manager1Data,
   manager2,
   manager2Data,
 } from "./utils/actors";
-import { mintTokensForAccount } from "./utils/cli";
-import { DEVICE_AFFORDANCES, DEVICE_AFFORDANCE_VALUE_TUPLE, DEVICE_PAIRING_PAYLOAD, ENVIRONMENT_NAME, GATEWAY1_NAME, LONG_TEST_TIMEOUT, OMNIA_PROXY_HOST } from "./utils/constants";
+import { getAccessKeyId } from "./utils/accessKey";
+import { mintTokensForAccount, runGatewayPayouts } from "./utils/cli";
+import { DEVICE_AFFORDANCE_VALUE_TUPLE, DEVICE_THING_DESCRIPTION, DEVICE_PAIRING_PAYLOAD, ENVIRONMENT_NAME, GATEWAY1_NAME, LONG_TEST_TIMEOUT, OMNIA_PROXY_HOST } from "./utils/constants";
 import { getAccountIdentifierFromPrincipal } from "./utils/identity";
 import { APPLICATION_PLACEHOLDER_CANISTER_ID, LEDGER_CANISTER_ID, OMNIA_BACKEND_CANISTER_ID } from "./utils/omniaApi/canisterEnv";
-import { PREFIXES, parseSparqlQueryResult, sparqlClient } from "./utils/sparql-client";
+import { parseSparqlQueryResult, sparqlClient } from "./utils/sparql-client";
 import { Principal } from "@dfinity/principal";
+import { Cbor, Certificate, HashTree, lookup_path, reconstruct } from "@dfinity/agent";
+import { createHash } from "crypto";
+import fetch from "node-fetch";
+import { omniaBackendCarnisterUrl } from "./utils/omniaApi/http";
 import { SignatureReply } from "../src/declarations/application_placeholder/application_placeholder.did";
 
+const FIRST_PAGE: PageRequest = { cursor: [], limit: [] };
+
 let environmentUid: string;
 let deviceUid: string;
 
@@ -105,7 +114,10 @@ describe("Gateway", () => {
       },
       manager2Data.remoteIp,
     );
-    expect(registerGatewayResult.error).toBeTruthy();
+    // the gateway has been initialized in a different network
+    expect(registerGatewayResult.error).toMatchObject({
+      NetworkMismatch: { ip: manager2Data.remoteIp },
+    });
     expect(registerGatewayResult.data).toBeNull();
   });
 
@@ -133,7 +145,6 @@ describe("Gateway", () => {
     expect(registerGatewayResult.data).toMatchObject<RegisteredGatewayValue>({
       env_uid: environmentUid,
       gateway_name: GATEWAY1_NAME,
-      gat_registered_device_uids: [],
       gateway_ip: manager1Data.remoteIp,
       // since the Gateway is proxied, the gateway_url is the proxy's host name
       gateway_url: `https://${OMNIA_PROXY_HOST}`,
@@ -141,13 +152,30 @@ describe("Gateway", () => {
     });
   });
 
+  it("getRegisteredGateways: anyone can list the Gateways in the environment, filtered by name", async () => {
+    const manager1Actor = await manager1.getActor();
+    const registeredGatewaysResult = await manager1.parseResult(
+      manager1Actor.getRegisteredGateways(environmentUid, FIRST_PAGE, [GATEWAY1_NAME.slice(0, 3)])
+    );
+    expect(registeredGatewaysResult.error).toBeNull();
+    expect(registeredGatewaysResult.data!.items).toHaveLength(1);
+    expect(registeredGatewaysResult.data!.items[0].gateway_name).toEqual(GATEWAY1_NAME);
+    expect(registeredGatewaysResult.data!.next_cursor).toEqual([]);
+
+    const filteredGatewaysResult = await manager1.parseResult(
+      manager1Actor.getRegisteredGateways(environmentUid, FIRST_PAGE, [`not-${GATEWAY1_NAME}`])
+    );
+    expect(filteredGatewaysResult.error).toBeNull();
+    expect(filteredGatewaysResult.data).toEqual({ items: [], next_cursor: [] });
+  });
+
   it("getRegisteredDevices: Gateway can retrieve the list of registered devices, empty", async () => {
     const gateway1Actor = await gateway1.getActor();
     const registeredDevicesResult = await gateway1.parseResult(
-      gateway1Actor.getRegisteredDevices()
+      gateway1Actor.getRegisteredDevices(FIRST_PAGE)
     );
     expect(registeredDevicesResult.error).toBeNull();
-    expect(registeredDevicesResult.data).toEqual([]);
+    expect(registeredDevicesResult.data).toEqual({ items: [], next_cursor: [] });
   });
 
   it("getGatewayUpdates: Gateway can poll for updates, empty", async () => {
@@ -200,7 +228,7 @@ describe("Gateway", () => {
       async (nonce) => {
         return gateway1Actor.registerDevice(
           nonce,
-          DEVICE_AFFORDANCES,
+          JSON.stringify(DEVICE_THING_DESCRIPTION),
         );
       },
       gateway1Data.remoteIp,
@@ -225,12 +253,13 @@ describe("Gateway", () => {
   it("getRegisteredDevices: Gateway can retrieve the list of registered devices, device present", async () => {
     const gateway1Actor = await gateway1.getActor();
     const registeredDevicesResult = await gateway1.parseResult(
-      gateway1Actor.getRegisteredDevices()
+      gateway1Actor.getRegisteredDevices(FIRST_PAGE)
     );
     expect(registeredDevicesResult.error).toBeNull();
-    expect(registeredDevicesResult.data).toEqual([
-      deviceUid,
-    ]);
+    expect(registeredDevicesResult.data).toEqual({
+      items: [deviceUid],
+      next_cursor: [],
+    });
   });
 });
 
@@ -246,7 +275,7 @@ describe("Application", () => {
   it("Application can retrieve the devices in the environment", async () => {
     // first, we try a query with a non-existent affordance
     const failingQuery = await sparqlClient.query.select(
-      `${PREFIXES}
+      `
       SELECT ?device WHERE {
         urn:uuid:non-existing-environment bot:hasElement ?device .
       }
@@ -271,7 +300,7 @@ describe("Application", () => {
     });
 
     const response = await sparqlClient.query.select(
-      `${PREFIXES}
+      `
       SELECT ?device WHERE {
         urn:uuid:${environmentUid} bot:hasElement ?device .
       }
@@ -301,7 +330,7 @@ describe("Application", () => {
     });
   });
 
-  const deviceAffordancesSparqlQuery = `${PREFIXES}
+  const deviceAffordancesSparqlQuery = `
     SELECT ?device ?headerName ?headerValue WHERE {
       ?device ${DEVICE_AFFORDANCE_VALUE_TUPLE[0]} ${DEVICE_AFFORDANCE_VALUE_TUPLE[1]} .
       ?device omnia:requiresHeader ?header .
@@ -356,7 +385,7 @@ describe("Application", () => {
   it("Application can retrieve the devices by affordances", async () => {
     // first, we try a query with a non-existent affordance
     const failingQuery = await sparqlClient.query.select(
-      `${PREFIXES}
+      `
       SELECT ?device WHERE {
         ?device td:hasPropertyAffordance saref:NonExistingState .
       }
@@ -407,6 +436,256 @@ describe("Application", () => {
     expect(parseSparqlQueryResult(executeRdfQuery.data as Uint8Array)).toMatchObject(getExpectedDeviceAffordancesObject());
   });
 
+  it("Application can retrieve the devices by events and the subscription protocols of their gateways", async () => {
+    const response = await sparqlClient.query.select(
+      `
+      SELECT ?device ?gateway ?protocol WHERE {
+        ?device td:hasEventAffordance saref:Motion ;
+                omnia:hasGateway ?gateway ;
+                omnia:offersSubscriptionProtocol ?protocol .
+      }
+      `,
+      {
+        operation: "postDirect",
+      }
+    );
+
+    expect(response.status).toEqual(200);
+    expect(await response.json()).toMatchObject({
+      head: {
+        vars: [
+          "device",
+          "gateway",
+          "protocol",
+        ],
+      },
+      results: {
+        bindings: [
+          {
+            device: {
+              type: "uri",
+              value: `https://${OMNIA_PROXY_HOST}/${deviceUid}`,
+            },
+            gateway: {
+              type: "uri",
+              value: `urn:principal:${(await gateway1Data.identity).getPrincipal().toText()}`,
+            },
+            protocol: {
+              type: "uri",
+              value: "http://rdf.omnia-iot.com#ServerSentEvents",
+            },
+          },
+        ],
+      },
+    });
+  });
+
+  it("getThingDescription: Application can retrieve the Thing Description of the device", async () => {
+    const application1Actor = await application1.getActor();
+    const thingDescriptionResult = await application1.parseResult(
+      application1Actor.getThingDescription(deviceUid)
+    );
+    expect(thingDescriptionResult.error).toBeNull();
+    expect(JSON.parse(thingDescriptionResult.data as string)).toEqual({
+      ...DEVICE_THING_DESCRIPTION,
+      id: `https://${OMNIA_PROXY_HOST}/${deviceUid}`,
+      base: `https://${OMNIA_PROXY_HOST}/${deviceUid}/`,
+    });
+
+    const missingThingDescriptionResult = await application1.parseResult(
+      application1Actor.getThingDescription("non-existing-device")
+    );
+    expect(missingThingDescriptionResult.error).toMatchObject({
+      NotFound: { entity: "ThingDescription" },
+    });
+    expect(missingThingDescriptionResult.data).toBeNull();
+  });
+
+  it("Application can dump the graph of the device", async () => {
+    const deviceUrl = `https://${OMNIA_PROXY_HOST}/${deviceUid}`;
+    const response = await fetch(
+      `${omniaBackendCarnisterUrl("/rdf/dump")}&graph=${encodeURIComponent(deviceUrl)}`
+    );
+
+    expect(response.status).toEqual(200);
+    expect(response.headers.get("content-type")).toEqual("application/n-quads");
+    const lines = (await response.text()).trim().split("\n");
+    expect(lines.length).toBeGreaterThan(0);
+    lines.forEach((line) => expect(line.endsWith(`<${deviceUrl}> .`)).toBe(true));
+    expect(lines).toContainEqual(
+      `<${deviceUrl}> <http://rdf.omnia-iot.com#hasUid> "${deviceUid}" <${deviceUrl}> .`
+    );
+  });
+
+  it("Application can query the devices with any operation of the SPARQL 1.1 Protocol", async () => {
+    const query = `
+      SELECT ?device WHERE {
+        urn:uuid:${environmentUid} bot:hasElement ?device .
+      }
+      `;
+
+    for (const operation of ["get", "postUrlencoded", "postDirect"] as const) {
+      const response = await sparqlClient.query.select(query, { operation });
+
+      expect(response.status).toEqual(200);
+      expect(response.headers.get("content-type")).toEqual("application/sparql-results+json");
+      expect((await response.json()).results.bindings).toEqual([
+        {
+          device: {
+            type: "uri",
+            value: `https://${OMNIA_PROXY_HOST}/${deviceUid}`,
+          },
+        },
+      ]);
+    }
+
+    const preflightResponse = await fetch(omniaBackendCarnisterUrl("/sparql/query"), {
+      method: "OPTIONS",
+    });
+    expect(preflightResponse.status).toEqual(204);
+    expect(preflightResponse.headers.get("access-control-allow-methods")).toEqual("GET, POST, OPTIONS");
+  });
+
+  it("Application can read the service description of the SPARQL endpoint", async () => {
+    for (const path of ["/sparql/query", "/.well-known/void"]) {
+      const response = await fetch(omniaBackendCarnisterUrl(path));
+
+      expect(response.status).toEqual(200);
+      expect(response.headers.get("content-type")).toEqual("text/turtle");
+      const serviceDescription = await response.text();
+      expect(serviceDescription).toContain("a sd:Service");
+      expect(serviceDescription).toContain("void:class <https://saref.etsi.org/core/Device> ;");
+      expect(serviceDescription).toContain("omnia:affordancePartition");
+    }
+  });
+
+  it("Application can query without declaring the prefixes and get compact IRIs", async () => {
+    const prefixesResponse = await fetch(omniaBackendCarnisterUrl("/sparql/prefixes"));
+    expect(prefixesResponse.status).toEqual(200);
+    const prefixMap = await prefixesResponse.json();
+    expect(prefixMap).toMatchObject({
+      bot: "https://w3id.org/bot#",
+      saref: "https://saref.etsi.org/core/",
+      urn: "urn:",
+    });
+
+    const application1Actor = await application1.getActor();
+    const prefixMapResult = await application1.parseResult(application1Actor.getPrefixMap());
+    expect(prefixMapResult.error).toBeNull();
+    expect(Object.fromEntries(prefixMapResult.data as [string, string][])).toEqual(prefixMap);
+
+    const query = `
+      SELECT ?environment ?predicate WHERE {
+        ?environment ?predicate <https://${OMNIA_PROXY_HOST}/${deviceUid}> .
+      }
+      `;
+    const response = await fetch(
+      omniaBackendCarnisterUrl(`/sparql/query?query=${encodeURIComponent(query)}&compact-iris=true`)
+    );
+    expect(response.status).toEqual(200);
+    expect((await response.json()).results.bindings).toEqual([
+      {
+        environment: {
+          type: "uri",
+          value: `urn:uuid:${environmentUid}`,
+        },
+        predicate: {
+          type: "uri",
+          value: "bot:hasElement",
+        },
+      },
+    ]);
+  });
+
+  it("Application can verify the devices of the environment", async () => {
+    const application1Actor = await application1.getActor();
+    const certifiedDevicesResult = await application1.parseResult(
+      application1Actor.getCertifiedEnvironmentDevices(environmentUid)
+    );
+    expect(certifiedDevicesResult.error).toBeNull();
+
+    const { quads, certificate, tree } = certifiedDevicesResult.data!;
+    const deviceUrl = `https://${OMNIA_PROXY_HOST}/${deviceUid}`;
+    expect(quads).toContainEqual(
+      `<urn:uuid:${environmentUid}> <https://w3id.org/bot#hasElement> <${deviceUrl}> .`
+    );
+
+    const canisterId = Principal.fromText(OMNIA_BACKEND_CANISTER_ID);
+    const verifiedCertificate = await Certificate.create({
+      certificate: new Uint8Array(certificate).buffer,
+      rootKey: application1.getAgent().rootKey,
+      canisterId,
+    });
+    const certifiedData = verifiedCertificate.lookup(["canister", canisterId.toUint8Array(), "certified_data"]);
+    const hashTree = Cbor.decode<HashTree>(new Uint8Array(tree));
+    expect(Buffer.from(certifiedData!)).toEqual(Buffer.from(await reconstruct(hashTree)));
+
+    for (const quad of quads) {
+      const quadHash = createHash("sha256").update(quad).digest();
+      expect(lookup_path(["quads", quadHash], hashTree)).toBeDefined();
+    }
+  });
+
+  it("updateDeviceDescription: only the Gateway of the device can update its description", async () => {
+    const updatedThingDescription = {
+      ...DEVICE_THING_DESCRIPTION,
+      properties: {
+        ...DEVICE_THING_DESCRIPTION.properties,
+        power: {
+          "@type": "saref:Power",
+          type: "number",
+          readOnly: true,
+          forms: [{ href: "properties/power" }],
+        },
+      },
+    };
+
+    const manager1Actor = await manager1.getActor();
+    const unauthorizedUpdateResult = await manager1.parseResult(
+      manager1Actor.updateDeviceDescription(deviceUid, JSON.stringify(updatedThingDescription))
+    );
+    expect(unauthorizedUpdateResult.error).toHaveProperty("Unauthorized");
+
+    const gateway1Actor = await gateway1.getActor();
+    const updateResult = await gateway1.parseResult(
+      gateway1Actor.updateDeviceDescription(deviceUid, JSON.stringify(updatedThingDescription))
+    );
+    expect(updateResult.error).toBeNull();
+
+    const thingDescriptionResult = await gateway1.parseResult(
+      gateway1Actor.getThingDescription(deviceUid)
+    );
+    expect(JSON.parse(thingDescriptionResult.data as string)).toMatchObject({
+      properties: updatedThingDescription.properties,
+    });
+
+    const response = await sparqlClient.query.select(
+      `
+      SELECT ?updatedAt WHERE {
+        <https://${OMNIA_PROXY_HOST}/${deviceUid}> td:hasPropertyAffordance saref:Power ;
+                                                  omnia:descriptionUpdatedAt ?updatedAt .
+      }
+      `,
+      {
+        operation: "postDirect",
+      }
+    );
+
+    expect(response.status).toEqual(200);
+    expect(await response.json()).toMatchObject({
+      results: {
+        bindings: [
+          {
+            updatedAt: {
+              type: "literal",
+              datatype: "http://www.w3.org/2001/XMLSchema#dateTime",
+            },
+          },
+        ],
+      },
+    });
+  });
+
   it("Application can obtain an access key", async () => {
     const applicationPlaceholderActor = applicationApi.getActor();
 
@@ -419,6 +698,8 @@ describe("Application", () => {
 
     expect(accessKey.error).toBeNull();
     expect(accessKey.data).toBeTruthy();
+    // prefix, 32 random bytes and 4 bytes of checksum, hex encoded
+    expect(accessKey.data).toMatch(/^omnia_sk_[0-9a-f]{72}$/);
 
     applicationAccessKey = accessKey.data!;
   });
@@ -439,6 +720,50 @@ describe("Application", () => {
 
   // here we assume the Application sends a request to the Gateway
 
+  it("Gateway cannot report signed requests if it is not registered", async () => {
+    // gateway2 has never been registered in any environment
+    const gateway2Actor = await gateway2.getActor();
+    const reportAccessKeyResult = await gateway2.parseResult(
+      gateway2Actor.reportSignedRequests(
+        [
+          {
+            signature_hex: applicationSignedAccessKey.signature_hex,
+            unique_access_key: applicationSignedAccessKey.unique_access_key,
+            requester_canister_id: Principal.from(APPLICATION_PLACEHOLDER_CANISTER_ID),
+            device_uid: deviceUid,
+          },
+        ]
+      )
+    );
+
+    expect(reportAccessKeyResult.error).toHaveProperty("Unauthorized");
+    expect(reportAccessKeyResult.data).toBeNull();
+  });
+
+  it("Gateway cannot report signed requests for devices not registered on it", async () => {
+    const gateway1Actor = await gateway1.getActor();
+    const reportAccessKeyResult = await gateway1.parseResult(
+      gateway1Actor.reportSignedRequests(
+        [
+          {
+            signature_hex: applicationSignedAccessKey.signature_hex,
+            unique_access_key: applicationSignedAccessKey.unique_access_key,
+            requester_canister_id: Principal.from(APPLICATION_PLACEHOLDER_CANISTER_ID),
+            device_uid: "non-existing-device-uid",
+          },
+        ]
+      )
+    );
+
+    expect(reportAccessKeyResult.error).toBeNull();
+    expect(reportAccessKeyResult.data).toMatchObject<RejectedAccessKey[]>([
+      {
+        key: applicationSignedAccessKey.unique_access_key.key,
+        reason: { InvalidDevice: null },
+      },
+    ]);
+  });
+
   it("Gateway can verify the Application access key", async () => {
     const gateway1Actor = await gateway1.getActor();
     const reportAccessKeyResult = await gateway1.parseResult(
@@ -448,6 +773,7 @@ describe("Application", () => {
             signature_hex: applicationSignedAccessKey.signature_hex,
             unique_access_key: applicationSignedAccessKey.unique_access_key,
             requester_canister_id: Principal.from(APPLICATION_PLACEHOLDER_CANISTER_ID),
+            device_uid: deviceUid,
           },
         ]
       )
@@ -466,6 +792,7 @@ describe("Application", () => {
             signature_hex: applicationSignedAccessKey.signature_hex.slice(0, -5) + "00000",
             unique_access_key: applicationSignedAccessKey.unique_access_key,
             requester_canister_id: Principal.from(APPLICATION_PLACEHOLDER_CANISTER_ID),
+            device_uid: deviceUid,
           },
         ]
       )
@@ -489,6 +816,7 @@ describe("Application", () => {
               nonce: applicationSignedAccessKey.unique_access_key.nonce + BigInt(1),
             },
             requester_canister_id: Principal.from(APPLICATION_PLACEHOLDER_CANISTER_ID),
+            device_uid: deviceUid,
           },
         ]
       )
@@ -510,6 +838,7 @@ describe("Application", () => {
             unique_access_key: applicationSignedAccessKey.unique_access_key,
             // just use a different canister id
             requester_canister_id: Principal.from(OMNIA_BACKEND_CANISTER_ID),
+            device_uid: deviceUid,
           },
         ]
       )
@@ -536,6 +865,7 @@ describe("Application", () => {
             signature_hex: applicationSignedAccessKey.signature_hex,
             unique_access_key: applicationSignedAccessKey.unique_access_key,
             requester_canister_id: Principal.from(APPLICATION_PLACEHOLDER_CANISTER_ID),
+            device_uid: deviceUid,
           },
         ]
       )
@@ -575,6 +905,7 @@ describe("Application", () => {
           signature_hex: k.signature_hex,
           unique_access_key: k.unique_access_key,
           requester_canister_id: Principal.from(APPLICATION_PLACEHOLDER_CANISTER_ID),
+          device_uid: deviceUid,
         }))
       )
     );
@@ -624,6 +955,7 @@ describe("Application", () => {
             signature_hex: signedAccessKey.data!.signature_hex,
             unique_access_key: signedAccessKey.data!.unique_access_key,
             requester_canister_id: Principal.from(APPLICATION_PLACEHOLDER_CANISTER_ID),
+            device_uid: deviceUid,
           },
         ]
       )
@@ -632,4 +964,259 @@ describe("Application", () => {
     expect(reportAccessKeyResult.error).toBeNull();
     expect(reportAccessKeyResult.data).toMatchObject([]);
   });
+
+  it("Application can list its access keys, empty", async () => {
+    // access keys are owned by the application_placeholder canister, not by this identity
+    const application1Actor = await application1.getActor();
+    const accessKeysResult = await application1.parseResult(
+      application1Actor.listMyAccessKeys(FIRST_PAGE)
+    );
+
+    expect(accessKeysResult.error).toBeNull();
+    expect(accessKeysResult.data).toEqual({ items: [], next_cursor: [] });
+  });
+
+  it("Application can list its access keys, page by page", async () => {
+    // the access keys are owned by the application_placeholder canister, which lists them
+    const applicationPlaceholderActor = applicationApi.getActor();
+    const firstPageResult = await applicationApi.parseResult(
+      applicationPlaceholderActor.list_my_access_keys(
+        Principal.from(OMNIA_BACKEND_CANISTER_ID),
+        { cursor: [], limit: [BigInt(1)] },
+      )
+    );
+
+    expect(firstPageResult.error).toBeNull();
+    expect(firstPageResult.data!.items).toHaveLength(1);
+    expect(firstPageResult.data!.next_cursor).toHaveLength(1);
+
+    const lastPageResult = await applicationApi.parseResult(
+      applicationPlaceholderActor.list_my_access_keys(
+        Principal.from(OMNIA_BACKEND_CANISTER_ID),
+        { cursor: firstPageResult.data!.next_cursor, limit: [BigInt(1)] },
+      )
+    );
+
+    expect(lastPageResult.error).toBeNull();
+    expect(lastPageResult.data!.next_cursor).toEqual([]);
+
+    // the first access key has been spent up to the limit, the second one only once
+    const ownedAccessKeys = [...firstPageResult.data!.items, ...lastPageResult.data!.items];
+    expect(ownedAccessKeys).toHaveLength(2);
+    for (const ownedAccessKey of ownedAccessKeys) {
+      expect(ownedAccessKey.created_at).toHaveLength(1);
+      expect(ownedAccessKey.counter + ownedAccessKey.remaining_requests).toEqual(10);
+    }
+    expect(ownedAccessKeys.find((k) => k.access_key_uid === getAccessKeyId(applicationAccessKey))).toMatchObject({
+      counter: 10,
+      remaining_requests: 0,
+    });
+    expect(ownedAccessKeys.map((k) => k.remaining_requests).sort()).toEqual([0, 9]);
+  });
+
+  it("Only the owner can retrieve the usage of an access key", async () => {
+    // the access key is owned by the application_placeholder canister
+    const application1Actor = await application1.getActor();
+    const accessKeyUsageResult = await application1.parseResult(
+      application1Actor.getAccessKeyUsage(getAccessKeyId(applicationAccessKey), FIRST_PAGE)
+    );
+
+    expect(accessKeyUsageResult.error).toHaveProperty("Unauthorized");
+    expect(accessKeyUsageResult.data).toBeNull();
+
+    // the secret is not an identifier of the access key
+    const secretUsageResult = await application1.parseResult(
+      application1Actor.getAccessKeyUsage(applicationAccessKey, FIRST_PAGE)
+    );
+
+    expect(secretUsageResult.error).toMatchObject({
+      NotFound: { entity: "AccessKey" },
+    });
+  });
+
+  it("Application can retrieve the usage of its access key, page by page", async () => {
+    // the access key is owned by the application_placeholder canister, which retrieves its usage
+    const applicationPlaceholderActor = applicationApi.getActor();
+    const accessKeyId = getAccessKeyId(applicationAccessKey);
+
+    const firstPageResult = await applicationApi.parseResult(
+      applicationPlaceholderActor.get_access_key_usage(
+        Principal.from(OMNIA_BACKEND_CANISTER_ID),
+        accessKeyId,
+        { cursor: [], limit: [BigInt(6)] },
+      )
+    );
+
+    expect(firstPageResult.error).toBeNull();
+    // the access key has been spent up to the limit, always on the same device
+    expect(firstPageResult.data!.total_requests).toEqual(BigInt(10));
+    expect(firstPageResult.data!.requests_per_device).toEqual([[deviceUid, BigInt(10)]]);
+    expect(firstPageResult.data!.history).toHaveLength(6);
+    expect(firstPageResult.data!.next_cursor).toHaveLength(1);
+    for (const [usageIndex, usageValue] of firstPageResult.data!.history) {
+      expect(usageIndex.access_key_uid).toEqual(accessKeyId);
+      expect(usageValue.device_uid).toEqual(deviceUid);
+      expect(usageValue.gateway_principal_id).toEqual(gateway1Data.identity.getPrincipal().toText());
+    }
+
+    const lastPageResult = await applicationApi.parseResult(
+      applicationPlaceholderActor.get_access_key_usage(
+        Principal.from(OMNIA_BACKEND_CANISTER_ID),
+        accessKeyId,
+        { cursor: firstPageResult.data!.next_cursor, limit: [BigInt(6)] },
+      )
+    );
+
+    expect(lastPageResult.error).toBeNull();
+    expect(lastPageResult.data!.history).toHaveLength(4);
+    expect(lastPageResult.data!.next_cursor).toEqual([]);
+    // the pages don't overlap
+    const nonces = [...firstPageResult.data!.history, ...lastPageResult.data!.history]
+      .map(([usageIndex]) => usageIndex.nonce);
+    expect(new Set(nonces).size).toEqual(10);
+
+    // an empty page would never advance
+    const emptyPageResult = await applicationApi.parseResult(
+      applicationPlaceholderActor.get_access_key_usage(
+        Principal.from(OMNIA_BACKEND_CANISTER_ID),
+        accessKeyId,
+        { cursor: [], limit: [BigInt(0)] },
+      )
+    );
+
+    expect(emptyPageResult.error).toHaveProperty("InvalidArgument");
+
+    // the cursor must identify a spend
+    const invalidCursorResult = await applicationApi.parseResult(
+      applicationPlaceholderActor.get_access_key_usage(
+        Principal.from(OMNIA_BACKEND_CANISTER_ID),
+        accessKeyId,
+        { cursor: ["not a cursor"], limit: [BigInt(6)] },
+      )
+    );
+
+    expect(invalidCursorResult.error).toHaveProperty("InvalidArgument");
+  });
+
+  it("Gateway can retrieve its payouts", async () => {
+    const gateway1Actor = await gateway1.getActor();
+    const payoutsResult = await gateway1.parseResult(
+      gateway1Actor.getGatewayPayouts(FIRST_PAGE)
+    );
+
+    expect(payoutsResult.error).toBeNull();
+    // payouts run periodically, so the Gateway hasn't been paid yet
+    expect(payoutsResult.data).toEqual({ items: [], next_cursor: [] });
+  });
+
+  it("Controllers can run the payouts, which pay the Gateway once", async () => {
+    const gateway1Actor = await gateway1.getActor();
+    const gatewayPrincipal = gateway1Data.identity.getPrincipal();
+    const balanceBefore = await application1Ledger.balance({ owner: gatewayPrincipal, certified: false });
+
+    await runGatewayPayouts();
+
+    const payoutsResult = await gateway1.parseResult(
+      gateway1Actor.getGatewayPayouts(FIRST_PAGE)
+    );
+
+    expect(payoutsResult.error).toBeNull();
+    expect(payoutsResult.data!.items).toHaveLength(1);
+    const [payoutIndex, payoutValue] = payoutsResult.data!.items[0];
+    expect(payoutIndex.gateway_principal_id).toEqual(gatewayPrincipal.toText());
+    expect(payoutValue.paid_requests).toBeGreaterThan(BigInt(0));
+    expect(
+      await application1Ledger.balance({ owner: gatewayPrincipal, certified: false })
+    ).toEqual(balanceBefore + payoutValue.amount_e8s);
+
+    // the payout has been recorded, so running the payouts again doesn't pay the same requests twice
+    await runGatewayPayouts();
+
+    const secondPayoutsResult = await gateway1.parseResult(
+      gateway1Actor.getGatewayPayouts(FIRST_PAGE)
+    );
+
+    expect(secondPayoutsResult.data).toEqual(payoutsResult.data);
+    expect(
+      await application1Ledger.balance({ owner: gatewayPrincipal, certified: false })
+    ).toEqual(balanceBefore + payoutValue.amount_e8s);
+  });
+
+  it("Only controllers can run the payouts", async () => {
+    const gateway1Actor = await gateway1.getActor();
+    const runPayoutsResult = await gateway1.parseResult(
+      gateway1Actor.runGatewayPayouts()
+    );
+
+    expect(runPayoutsResult.error).toHaveProperty("Unauthorized");
+  });
+
+  it("Only controllers can check the consistency", async () => {
+    const manager1Actor = await manager1.getActor();
+    const consistencyResult = await manager1.parseResult(
+      manager1Actor.checkConsistency(false)
+    );
+
+    expect(consistencyResult.error).toHaveProperty("Unauthorized");
+    expect(consistencyResult.data).toBeNull();
+  });
+
+  it("Only controllers can export and import the database snapshot", async () => {
+    const databaseActor = databaseApi.getActor();
+    const exportResult = await databaseApi.parseResult(
+      databaseActor.exportSnapshot([])
+    );
+    expect(exportResult.error).toHaveProperty("Unauthorized");
+    expect(exportResult.data).toBeNull();
+
+    const importResult = await databaseApi.parseResult(
+      databaseActor.importSnapshot({
+        format_version: 1,
+        map: "environments",
+        entries: [],
+        checksum: [],
+        next_cursor: [],
+      })
+    );
+    expect(importResult.error).toHaveProperty("Unauthorized");
+  });
+
+  it("Only controllers can reconcile the devices", async () => {
+    const manager1Actor = await manager1.getActor();
+    const reconciliationResult = await manager1.parseResult(
+      manager1Actor.reconcileDevices(true)
+    );
+
+    expect(reconciliationResult.error).toHaveProperty("Unauthorized");
+    expect(reconciliationResult.data).toBeNull();
+  });
+
+  it("Only controllers can set the SPARQL query limits", async () => {
+    const manager1Actor = await manager1.getActor();
+    const limits = await manager1Actor.getSparqlQueryLimits();
+    expect(limits.max_rows).toBeGreaterThan(BigInt(0));
+
+    const setLimitsResult = await manager1.parseResult(
+      manager1Actor.setSparqlQueryLimits({ ...limits, max_rows: BigInt(1) })
+    );
+    expect(setLimitsResult.error).toHaveProperty("Unauthorized");
+    expect(await manager1Actor.getSparqlQueryLimits()).toEqual(limits);
+  });
+
+  it("Only controllers can register vocabularies", async () => {
+    const manager1Actor = await manager1.getActor();
+    const registerVocabularyResult = await manager1.parseResult(
+      manager1Actor.registerVocabulary("ex", "https://example.com/vocab#", [])
+    );
+
+    expect(registerVocabularyResult.error).toHaveProperty("Unauthorized");
+
+    const vocabulariesResult = await manager1.parseResult(
+      manager1Actor.getVocabularies()
+    );
+    expect(vocabulariesResult.error).toBeNull();
+    expect(vocabulariesResult.data).toContainEqual(
+      expect.objectContaining({ prefix: "saref", is_custom: false })
+    );
+  });
 });
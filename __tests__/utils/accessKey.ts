@@ -0,0 +1,18 @@
+import { createHash } from "crypto";
+
+const ACCESS_KEY_ID_PREFIX = "omnia_ak_";
+const ACCESS_KEY_ID_BYTES = 16;
+const CHECKSUM_BYTES = 4;
+
+const sha256 = (data: string) => createHash("sha256").update(data).digest();
+
+/**
+ * Derives the public identifier of an access key from its secret,
+ * like `omnia_utils::access_key::get_access_key_id` does.
+ */
+export const getAccessKeyId = (accessKeySecret: string): string => {
+  const body = sha256(accessKeySecret).subarray(0, ACCESS_KEY_ID_BYTES).toString("hex");
+  const checksum = sha256(ACCESS_KEY_ID_PREFIX + body).subarray(0, CHECKSUM_BYTES).toString("hex");
+
+  return ACCESS_KEY_ID_PREFIX + body + checksum;
+};
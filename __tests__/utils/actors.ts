@@ -4,6 +4,7 @@ import { identityFromSeed } from "./identity";
 import { OmniaApi } from "./omniaApi";
 import { LEDGER_CANISTER_ID } from "./omniaApi/canisterEnv";
 import { ApplicationApi } from "./application";
+import { DatabaseApi } from "./database";
 
 // These seed phrases are completely INSECURE. DO NOT use them for any purpose other than local testing.
 
@@ -75,3 +76,7 @@ export const application1Ledger = IcrcLedgerCanister.create({
   agent: application1.getAgent(),
 });
 export const applicationApi = new ApplicationApi();
+
+/// Database
+// called with the anonymous identity, which is not a controller
+export const databaseApi = new DatabaseApi();
@@ -17,3 +17,16 @@ export const mintTokensForAccount = async (account: AccountIdentifier, icpAmount
 
   return stdout;
 };
+
+export const runGatewayPayouts = async () => {
+  // this command assumes the default identity is a controller of the Backend, which is the case when running locally
+  const { stdout, stderr } = await execAsync(
+    `dfx canister call omnia_backend runGatewayPayouts`,
+  );
+
+  if (stderr) {
+    throw new Error(stderr);
+  }
+
+  return stdout;
+};
@@ -1,5 +1,3 @@
-import { DeviceAffordances } from "../../src/declarations/omnia_backend/omnia_backend.did";
-
 // The public IPv4 address of the Omnia Proxy, which forwards requests from and to Gateways.
 export const OMNIA_PROXY_IPV4 = "3.70.56.192";
 // The host under which the Omnia Proxy is reachable.
@@ -17,15 +15,35 @@ export const DEVICE1_NAME = "test_device1";
 export const DEVICE2_NAME = "test_device2";
 export const TOTAL_DEVICES_IN_ENV = 2;
 export const DEVICE_PAIRING_PAYLOAD = "test_device_pairing_payload";
-// mapped from the Thing Description of the device and must be SAREF verbs
-// backend accepts both saref:... and the verb itself
-export const DEVICE_AFFORDANCES: DeviceAffordances = {
-  properties: [
-    "saref:OnOffState",
-  ],
-  actions: [
-    "OnCommand",
-  ],
+// the affordances are mapped to the SAREF classes in their @type
+export const DEVICE_THING_DESCRIPTION = {
+  "@context": "https://www.w3.org/2022/wot/td/v1.1",
+  title: "Lamp",
+  securityDefinitions: {
+    nosec_sc: { scheme: "nosec" },
+  },
+  security: "nosec_sc",
+  properties: {
+    status: {
+      "@type": "saref:OnOffState",
+      type: "boolean",
+      readOnly: true,
+      forms: [{ href: "properties/status" }],
+    },
+  },
+  actions: {
+    toggle: {
+      "@type": "saref:OnCommand",
+      forms: [{ href: "actions/toggle" }],
+    },
+  },
+  events: {
+    motionDetected: {
+      "@type": "saref:Motion",
+      data: { type: "boolean" },
+      forms: [{ href: "events/motion", subprotocol: "sse" }],
+    },
+  },
 };
 export const DEVICE_AFFORDANCE_VALUE_TUPLE: [string, string] = [
   "td:hasPropertyAffordance",
@@ -0,0 +1,43 @@
+import { Actor, ActorSubclass, HttpAgent } from "@dfinity/agent";
+import { _SERVICE } from "../../src/declarations/database/database.did";
+// @ts-ignore
+import { idlFactory } from "../../src/declarations/database/database.did.js";
+import { DATABASE_CANISTER_ID } from "./omniaApi/canisterEnv";
+import { GenericResult, resultParser } from "./omniaApi/resultParser";
+
+export const createActor = () => {
+  // the anonymous identity is not a controller of the database
+  const agent = new HttpAgent({
+    host: "http://127.0.0.1:4943",
+  });
+
+  // Fetch root key for certificate validation during development
+  if (process.env.DFX_NETWORK !== "ic") {
+    agent.fetchRootKey().catch((err) => {
+      console.warn(
+        "Unable to fetch root key. Check to ensure that your local replica is running"
+      );
+      console.error(err);
+    });
+  }
+
+  // Creates an actor with using the candid interface and the HttpAgent
+  return Actor.createActor(idlFactory, {
+    agent,
+    canisterId: DATABASE_CANISTER_ID,
+  }) as ActorSubclass<_SERVICE>;
+};
+
+export class DatabaseApi {
+  private _actor: ActorSubclass<_SERVICE> | undefined;
+
+  getActor() {
+    return this._actor || createActor();
+  }
+
+  async parseResult<T>(
+    result: Promise<GenericResult<T>>,
+  ) {
+    return resultParser<T>(await result);
+  }
+};
@@ -1,8 +1,10 @@
-export type GenericResult<T> = { 'Ok': T } | { 'Err': string };
+import { GenericError } from "../../../src/declarations/omnia_backend/omnia_backend.did";
+
+export type GenericResult<T> = { 'Ok': T } | { 'Err': GenericError };
 
 export type ParsedResult<T> = {
   data: Inner<T> | null;
-  error: string | null;
+  error: GenericError | null;
 };
 
 type Inner<T> = T extends { 'Ok': infer S } ? S : null;
@@ -0,0 +1,43 @@
+#!/bin/bash
+
+# usage:
+#   ./scripts/snapshot.sh export <file> [--network <network>]
+#   ./scripts/snapshot.sh import <file> [--network <network>]
+# the current dfx identity must be a controller of the database canister
+
+set -e
+
+COMMAND=$1
+SNAPSHOT_FILE=$2
+shift 2
+DFX_ARGS="$@"
+
+if [ "$COMMAND" = "export" ]; then
+  if [ -e "$SNAPSHOT_FILE" ]; then
+    echo "$SNAPSHOT_FILE already exists"
+    exit 1
+  fi
+
+  # the database is exported in chunks, each one returning the cursor of the next one
+  ARGUMENT=$(cargo run -q --bin snapshot start)
+  while [ "$ARGUMENT" != "" ]; do
+    ARGUMENT=$(dfx canister call database exportSnapshot "$ARGUMENT" --type raw --output raw $DFX_ARGS | cargo run -q --bin snapshot append "$SNAPSHOT_FILE")
+  done
+
+  cargo run -q --bin snapshot verify "$SNAPSHOT_FILE"
+elif [ "$COMMAND" = "import" ]; then
+  cargo run -q --bin snapshot verify "$SNAPSHOT_FILE"
+
+  # chunks may be larger than the maximum size of a command line argument, so they're passed as files
+  CHUNKS_DIR=$(mktemp -d)
+  trap "rm -rf $CHUNKS_DIR" EXIT
+  cargo run -q --bin snapshot split "$SNAPSHOT_FILE" "$CHUNKS_DIR"
+
+  for chunk in "$CHUNKS_DIR"/chunk-*.hex; do
+    echo "Importing $(basename $chunk)..."
+    dfx canister call database importSnapshot --argument-file "$chunk" --type raw $DFX_ARGS
+  done
+else
+  echo "Unknown command $COMMAND, expected export or import"
+  exit 1
+fi
@@ -1,5 +1,5 @@
 use candid::candid_method;
-use ic_cdk::{api::management_canister::provisional::CanisterId};
+use ic_cdk::api::management_canister::provisional::CanisterId;
 use ic_cdk_macros::update;
 use omnia_core_sdk::InitParams;
 use omnia_core_sdk::{access_key::AccessKeyUID, signature::SignatureReply};
@@ -8,7 +8,11 @@ use omnia_core_sdk::{
     init_client,
 };
 
-use omnia_types::errors::GenericResult;
+use omnia_types::{
+    access_key::{AccessKeyUsageResult, OwnedAccessKey},
+    errors::{GenericError, GenericResult},
+    pagination::{Page, PageRequest},
+};
 
 #[update]
 #[candid_method(update)]
@@ -22,13 +26,54 @@ async fn get_access_key(
         omnia_canister_id: Some(omnia_canister_id),
         ledger_canister_id: Some(ledger_canister_id),
     });
-    request_access_key().await
+    // the SDK returns plain error messages
+    request_access_key().await.map_err(GenericError::internal)
 }
 
 #[update]
 #[candid_method(update)]
 async fn sign_access_key(access_key: AccessKeyUID) -> GenericResult<SignatureReply> {
-    generate_signed_unique_access_key(access_key).await
+    generate_signed_unique_access_key(access_key)
+        .await
+        .map_err(GenericError::internal)
+}
+
+#[update]
+#[candid_method(update)]
+async fn list_my_access_keys(
+    omnia_canister_id: CanisterId,
+    page_request: PageRequest,
+) -> GenericResult<Page<OwnedAccessKey>> {
+    // the access keys are owned by this canister, which is the caller of the Backend
+    let (owned_access_keys_result,): (GenericResult<Page<OwnedAccessKey>>,) =
+        ic_cdk::call(omnia_canister_id, "listMyAccessKeys", (page_request,))
+            .await
+            .map_err(|e| {
+                GenericError::inter_canister_call_failed(omnia_canister_id, "listMyAccessKeys", e)
+            })?;
+
+    owned_access_keys_result
+}
+
+#[update]
+#[candid_method(update)]
+async fn get_access_key_usage(
+    omnia_canister_id: CanisterId,
+    access_key_uid: AccessKeyUID,
+    page_request: PageRequest,
+) -> AccessKeyUsageResult {
+    // the access keys are owned by this canister, so only this canister can retrieve their usage
+    let (access_key_usage_result,): (AccessKeyUsageResult,) = ic_cdk::call(
+        omnia_canister_id,
+        "getAccessKeyUsage",
+        (access_key_uid, page_request),
+    )
+    .await
+    .map_err(|e| {
+        GenericError::inter_canister_call_failed(omnia_canister_id, "getAccessKeyUsage", e)
+    })?;
+
+    access_key_usage_result
 }
 
 #[cfg(test)]
@@ -38,7 +83,9 @@ mod tests {
     use std::env;
 
     use omnia_core_sdk::{access_key::AccessKeyUID, signature::SignatureReply};
+    use omnia_types::access_key::*;
     use omnia_types::errors::*;
+    use omnia_types::pagination::*;
 
     #[test]
     fn generate_candid_interface() {
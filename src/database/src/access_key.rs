@@ -1,23 +1,55 @@
-use candid::candid_method;
-use ic_cdk::print;
-use ic_cdk_macros::update;
-use omnia_core_sdk::access_key::UniqueAccessKey;
+use std::{collections::BTreeMap, ops::Bound};
+
+use candid::{candid_method, Principal};
+use ic_cdk::{
+    api::{management_canister::main::raw_rand, time},
+    print,
+};
+use ic_cdk_macros::{query, update};
+use omnia_core_sdk::access_key::AccessKeyUID;
 use omnia_types::{
     access_key::{
-        AccessKeyCreationArgs, AccessKeyCreationResult, AccessKeyIndex, AccessKeyValue,
-        RejectedAccessKey, RejectedAccessKeyReason,
+        AccessKeyCreationArgs, AccessKeyCreationResult, AccessKeyIndex, AccessKeyOwnerIndex,
+        AccessKeySecretIndex, AccessKeySecretValue, AccessKeyUsage, AccessKeyUsageIndex,
+        AccessKeyUsageResult, AccessKeyUsageStatsValue, AccessKeyUsageValue, AccessKeyValue,
+        OwnedAccessKey, RejectedAccessKey, RejectedAccessKeyReason, ServedRequest,
+    },
+    device::RegisteredDeviceIndex,
+    errors::{GenericError, GenericResult},
+    gateway::{GatewayLedgerIndex, GatewayPrincipalId, RegisteredGatewayIndex},
+    pagination::{Page, PageCursor, PageRequest},
+};
+use omnia_utils::{
+    access_key::{
+        generate_access_key_secret, get_access_key_id, hash_access_key_secret,
+        is_valid_access_key_id,
+    },
+    constants::{
+        ACCESS_KEY_REQUESTS_LIMIT, ACCESS_KEY_USAGE_DEVICES_LIMIT, ACCESS_KEY_USAGE_PAGE_SIZE_LIMIT,
     },
-    errors::GenericResult,
 };
-use omnia_utils::constants::ACCESS_KEY_REQUESTS_LIMIT;
-use uuid::Uuid;
 
-use crate::{utils::caller_is_omnia_backend, STATE};
+use crate::{
+    migrations::are_migrations_completed,
+    snapshot::no_snapshot_import_in_progress,
+    utils::{caller_is_omnia_backend, get_page_limit},
+    STATE,
+};
 
 #[update]
 #[candid_method(update)]
-fn create_new_access_key(args: AccessKeyCreationArgs) -> AccessKeyCreationResult {
+async fn create_new_access_key(args: AccessKeyCreationArgs) -> AccessKeyCreationResult {
     caller_is_omnia_backend();
+    no_snapshot_import_in_progress();
+
+    // the secret has 256 bits of entropy, taken from the management canister
+    let (random_bytes,) = raw_rand().await.map_err(|e| {
+        GenericError::inter_canister_call_failed(Principal::management_canister(), "raw_rand", e)
+    })?;
+    let random_bytes = random_bytes
+        .try_into()
+        .map_err(|_| GenericError::internal("Randomness must be 32 bytes long"))?;
+    let access_key_secret = generate_access_key_secret(random_bytes);
 
     STATE.with(|state| {
         print(format!("Requested new access key, args: {:?}", args));
@@ -28,65 +60,121 @@ fn create_new_access_key(args: AccessKeyCreationArgs) -> AccessKeyCreationResult
             .valid_access_keys
             .transaction_hash_exists(args.transaction_hash)
         {
-            return Err(String::from(
-                "Access key with the same transaction hash already exists",
-            ));
+            return Err(GenericError::AlreadyExists {
+                entity: String::from("AccessKey"),
+                id: format!("transaction hash {:?}", args.transaction_hash),
+            });
         }
 
-        // create new access key
-        // TODO: generate an access key that is not a UUIDv4
-        let access_key_uid = Uuid::new_v4().simple().to_string();
+        // create new access key, storing only the hash of its secret
+        let secret_hash = hash_access_key_secret(&access_key_secret);
+        let access_key_uid = get_access_key_id(&secret_hash);
 
         print(format!("Creating new access key: {:?}", access_key_uid));
 
+        state.borrow_mut().access_key_secrets.create(
+            AccessKeySecretIndex { secret_hash },
+            AccessKeySecretValue {
+                access_key_uid: access_key_uid.clone(),
+            },
+        )?;
+
         let access_key_index = AccessKeyIndex {
             access_key_uid: access_key_uid.clone(),
         };
 
         let access_key_value =
-            AccessKeyValue::new(access_key_uid, args.owner, args.transaction_hash);
+            AccessKeyValue::new(access_key_uid, args.owner, args.transaction_hash, time());
 
+        // the access key is indexed by owner as well, so that the owner can list its access keys
         state
             .borrow_mut()
             .valid_access_keys
             .create(access_key_index, access_key_value.clone())?;
 
-        Ok(access_key_value)
+        Ok((access_key_value, access_key_secret))
     })
 }
 
 #[update]
 #[candid_method(update)]
 fn spend_requests_for_keys(
-    unique_access_keys: Vec<UniqueAccessKey>,
+    gateway_principal_id: GatewayPrincipalId,
+    served_requests: Vec<ServedRequest>,
 ) -> GenericResult<Vec<RejectedAccessKey>> {
     caller_is_omnia_backend();
+    no_snapshot_import_in_progress();
 
     STATE.with(|state| {
         let mut state = state.borrow_mut();
 
+        // only registered gateways can report the requests they served
+        let registered_gateway_index = RegisteredGatewayIndex {
+            principal_id: gateway_principal_id.clone(),
+        };
+        state.registered_gateways.read(&registered_gateway_index)?;
+
         let mut rejected_access_keys: Vec<RejectedAccessKey> = vec![];
+        // the served requests are all validated before writing, so that an error doesn't leave the batch partially spent
+        let mut spent_access_keys: BTreeMap<AccessKeyIndex, AccessKeyValue> = BTreeMap::new();
+        let mut access_key_usages: Vec<(AccessKeyUsageIndex, AccessKeyUsageValue)> = vec![];
 
-        for unique_access_key in unique_access_keys {
-            let access_key_index = AccessKeyIndex {
-                access_key_uid: unique_access_key.get_key(),
+        for served_request in served_requests {
+            let unique_access_key = served_request.unique_access_key;
+            // the requester signs the secret of the access key, rejections report it back as it was received
+            let access_key_secret = unique_access_key.get_key();
+
+            // the request must have been served by a device registered on the reporting gateway
+            let registered_device_index = RegisteredDeviceIndex {
+                device_uid: served_request.device_uid,
             };
+            let registered_device_value =
+                match state.registered_devices.read(&registered_device_index) {
+                    Ok(registered_device_value)
+                        if registered_device_value.gateway_principal_id == gateway_principal_id =>
+                    {
+                        registered_device_value
+                    }
+                    _ => {
+                        rejected_access_keys.push(RejectedAccessKey {
+                            key: access_key_secret,
+                            reason: RejectedAccessKeyReason::InvalidDevice,
+                        });
+                        continue;
+                    }
+                };
 
-            let access_key_value = state.valid_access_keys.read(&access_key_index);
+            let access_key_secret_index = AccessKeySecretIndex {
+                secret_hash: hash_access_key_secret(&access_key_secret),
+            };
+            let access_key_value = state
+                .access_key_secrets
+                .read(&access_key_secret_index)
+                .and_then(|access_key_secret_value| {
+                    let access_key_index = AccessKeyIndex {
+                        access_key_uid: access_key_secret_value.access_key_uid,
+                    };
+                    // the access key may have already been spent by the previous requests of the batch
+                    let access_key_value = match spent_access_keys.get(&access_key_index) {
+                        Some(spent_access_key_value) => spent_access_key_value.clone(),
+                        None => state.valid_access_keys.read(&access_key_index)?,
+                    };
+                    Ok((access_key_index, access_key_value))
+                });
 
             if access_key_value.is_err() {
                 rejected_access_keys.push(RejectedAccessKey {
-                    key: access_key_index.access_key_uid.clone(),
+                    key: access_key_secret,
                     reason: RejectedAccessKeyReason::InvalidAccessKey,
                 });
                 continue;
             }
 
-            let mut access_key_value = access_key_value.unwrap().clone();
+            let (access_key_index, mut access_key_value) = access_key_value.unwrap();
 
             if access_key_value.get_requests_count() >= ACCESS_KEY_REQUESTS_LIMIT {
                 rejected_access_keys.push(RejectedAccessKey {
-                    key: access_key_index.access_key_uid.clone(),
+                    key: access_key_secret,
                     reason: RejectedAccessKeyReason::RequestsLimitReached,
                 });
                 continue;
@@ -96,7 +184,7 @@ fn spend_requests_for_keys(
 
             if access_key_value.is_used_nonce(nonce) {
                 rejected_access_keys.push(RejectedAccessKey {
-                    key: access_key_index.access_key_uid.clone(),
+                    key: access_key_secret,
                     reason: RejectedAccessKeyReason::NonceAlreadyUsed,
                 });
                 continue;
@@ -104,11 +192,304 @@ fn spend_requests_for_keys(
 
             access_key_value.spend_nonce(nonce);
 
+            // keep track of when and where the access key has been spent
+            access_key_usages.push((
+                AccessKeyUsageIndex {
+                    access_key_uid: access_key_index.access_key_uid.clone(),
+                    timestamp: time(),
+                    nonce,
+                },
+                AccessKeyUsageValue {
+                    gateway_principal_id: gateway_principal_id.clone(),
+                    device_uid: registered_device_index.device_uid,
+                    device_url: registered_device_value.device_url,
+                },
+            ));
+            spent_access_keys.insert(access_key_index, access_key_value);
+        }
+
+        // the access keys exist and their nonces are new, so the writes cannot fail
+        for (access_key_index, access_key_value) in spent_access_keys {
             state
                 .valid_access_keys
-                .update(access_key_index, access_key_value.clone())?;
+                .update(access_key_index, access_key_value)
+                .expect("spent access key should exist");
+        }
+        let spent_requests_count = access_key_usages.len() as u64;
+        let mut access_key_usage_stats: BTreeMap<AccessKeyIndex, AccessKeyUsageStatsValue> =
+            BTreeMap::new();
+        for (access_key_usage_index, access_key_usage_value) in access_key_usages {
+            let access_key_index = AccessKeyIndex {
+                access_key_uid: access_key_usage_index.access_key_uid.clone(),
+            };
+            access_key_usage_stats
+                .entry(access_key_index.clone())
+                .or_insert_with(|| {
+                    state
+                        .access_key_usage_stats
+                        .read(&access_key_index)
+                        .unwrap_or_default()
+                })
+                .add_spend(
+                    access_key_usage_index.timestamp,
+                    &access_key_usage_value.device_uid,
+                    ACCESS_KEY_USAGE_DEVICES_LIMIT as usize,
+                );
+
+            state
+                .access_key_usages
+                .create(access_key_usage_index, access_key_usage_value)
+                .expect("access key usage should not exist, because its nonce is new");
+        }
+        for (access_key_index, access_key_usage_stats_value) in access_key_usage_stats {
+            state
+                .access_key_usage_stats
+                .upsert(access_key_index, access_key_usage_stats_value);
+        }
+
+        // keep track of the requests served by the gateway
+        if spent_requests_count > 0 {
+            let gateway_ledger_index = GatewayLedgerIndex {
+                gateway_principal_id: gateway_principal_id.clone(),
+            };
+            let gateway_ledger_value = state.gateway_ledgers.insert_served_requests_in_ledger(
+                gateway_ledger_index,
+                spent_requests_count,
+                time(),
+            )?;
+
+            print(format!(
+                "Gateway {:?} served {} new requests, ledger: {:?}",
+                gateway_principal_id, spent_requests_count, gateway_ledger_value
+            ));
         }
 
         Ok(rejected_access_keys)
     })
 }
+
+#[query]
+#[candid_method(query)]
+fn get_access_keys_by_owner(owner: Principal, page_request: PageRequest) -> Page<OwnedAccessKey> {
+    caller_is_omnia_backend();
+
+    STATE.with(|state| {
+        let state = state.borrow();
+
+        // only the index entries of the owner are scanned, once the legacy access keys are indexed
+        let (access_keys, next_cursor) = state.valid_access_keys.get_secondary_page(
+            &state.valid_access_keys.indexes().by_owner,
+            &AccessKeyOwnerIndex { owner },
+            page_request
+                .cursor
+                .clone()
+                .map(|access_key_uid| AccessKeyIndex { access_key_uid }),
+            get_page_limit(&page_request),
+            are_migrations_completed(),
+            |_, _| true,
+        );
+
+        let items = access_keys
+            .into_iter()
+            .map(|(_, access_key_value)| OwnedAccessKey {
+                remaining_requests: ACCESS_KEY_REQUESTS_LIMIT
+                    .saturating_sub(access_key_value.get_requests_count()),
+                counter: access_key_value.get_requests_count(),
+                created_at: access_key_value.created_at,
+                access_key_uid: access_key_value.key,
+            })
+            .collect();
+
+        Page {
+            items,
+            next_cursor: next_cursor.map(|access_key_index| access_key_index.access_key_uid),
+        }
+    })
+}
+
+/// Replaces the identifiers of the access keys created before secrets were introduced,
+/// which are the secrets themselves, with the identifiers derived from the hash of the secrets.
+///
+/// Only the `limit` access keys after the cursor are scanned, so that the migration can run in batches.
+/// If more access keys may follow, the index of the last scanned one is returned, to be used as the next cursor
+pub fn migrate_legacy_access_keys_batch(
+    cursor: Option<AccessKeyIndex>,
+    limit: usize,
+) -> Option<AccessKeyIndex> {
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+
+        // the migrated access keys get new identifiers, which are skipped if they're scanned again
+        let (access_keys, next_cursor) = state.valid_access_keys.get_page(
+            (Bound::Unbounded, Bound::Unbounded),
+            cursor,
+            limit,
+            |_, _| true,
+        );
+
+        for (legacy_access_key_index, legacy_access_key_value) in access_keys {
+            if is_valid_access_key_id(&legacy_access_key_index.access_key_uid) {
+                continue;
+            }
+
+            let legacy_access_key_uid = legacy_access_key_index.access_key_uid.clone();
+            let secret_hash = hash_access_key_secret(&legacy_access_key_uid);
+            let access_key_uid = get_access_key_id(&secret_hash);
+
+            print(format!(
+                "Migrating legacy access key to: {:?}",
+                access_key_uid
+            ));
+
+            state
+                .valid_access_keys
+                .delete(&legacy_access_key_index)
+                .expect("legacy access key should exist");
+            state
+                .valid_access_keys
+                .create(
+                    AccessKeyIndex {
+                        access_key_uid: access_key_uid.clone(),
+                    },
+                    AccessKeyValue {
+                        key: access_key_uid.clone(),
+                        ..legacy_access_key_value
+                    },
+                )
+                .expect("previous entry should not exist");
+            state
+                .access_key_secrets
+                .create(
+                    AccessKeySecretIndex { secret_hash },
+                    AccessKeySecretValue {
+                        access_key_uid: access_key_uid.clone(),
+                    },
+                )
+                .expect("previous entry should not exist");
+
+            // the usage history references the access key by its identifier
+            for (legacy_access_key_usage_index, access_key_usage_value) in state
+                .access_key_usages
+                .get_access_key_usages(&legacy_access_key_uid)
+            {
+                state
+                    .access_key_usages
+                    .delete(&legacy_access_key_usage_index)
+                    .expect("legacy access key usage should exist");
+                state
+                    .access_key_usages
+                    .create(
+                        AccessKeyUsageIndex {
+                            access_key_uid: access_key_uid.clone(),
+                            ..legacy_access_key_usage_index
+                        },
+                        access_key_usage_value,
+                    )
+                    .expect("previous entry should not exist");
+            }
+            if let Ok(access_key_usage_stats_value) =
+                state.access_key_usage_stats.delete(&AccessKeyIndex {
+                    access_key_uid: legacy_access_key_uid.clone(),
+                })
+            {
+                state.access_key_usage_stats.upsert(
+                    AccessKeyIndex {
+                        access_key_uid: access_key_uid.clone(),
+                    },
+                    access_key_usage_stats_value,
+                );
+            }
+        }
+
+        next_cursor
+    })
+}
+
+/// The cursor of the usage history identifies the last spend of the page within the spends of the access key
+fn get_access_key_usage_cursor(access_key_usage_index: &AccessKeyUsageIndex) -> PageCursor {
+    format!(
+        "{}:{}",
+        access_key_usage_index.timestamp, access_key_usage_index.nonce
+    )
+}
+
+fn parse_access_key_usage_cursor(
+    access_key_uid: &AccessKeyUID,
+    cursor: &str,
+) -> GenericResult<AccessKeyUsageIndex> {
+    let invalid_cursor = || GenericError::invalid_argument(format!("Invalid cursor {:?}", cursor));
+
+    let (timestamp, nonce) = cursor.split_once(':').ok_or_else(invalid_cursor)?;
+    Ok(AccessKeyUsageIndex {
+        access_key_uid: access_key_uid.clone(),
+        timestamp: timestamp.parse().map_err(|_| invalid_cursor())?,
+        nonce: nonce.parse().map_err(|_| invalid_cursor())?,
+    })
+}
+
+#[query]
+#[candid_method(query)]
+fn get_access_key_usage(
+    owner: Principal,
+    access_key_uid: AccessKeyUID,
+    page_request: PageRequest,
+) -> AccessKeyUsageResult {
+    caller_is_omnia_backend();
+
+    // an empty page would never advance the cursor
+    if page_request.limit == Some(0) {
+        return Err(GenericError::invalid_argument(
+            "The limit of the history page must be greater than 0",
+        ));
+    }
+
+    STATE.with(|state| {
+        let state = state.borrow();
+
+        // only the owner of the access key can see how it has been used
+        let access_key_index = AccessKeyIndex {
+            access_key_uid: access_key_uid.clone(),
+        };
+        let access_key_value = state.valid_access_keys.read(&access_key_index)?;
+        if access_key_value.owner != owner {
+            return Err(GenericError::Unauthorized {
+                caller: owner,
+                reason: format!("Access key {:?} is not owned by the caller", access_key_uid),
+            });
+        }
+
+        let cursor = page_request
+            .cursor
+            .as_deref()
+            .map(|cursor| parse_access_key_usage_cursor(&access_key_uid, cursor))
+            .transpose()?;
+
+        // aggregates are kept up to date by the spends, so that the whole history is not scanned
+        let access_key_usage_stats_value = state
+            .access_key_usage_stats
+            .read(&access_key_index)
+            .unwrap_or_default();
+
+        // the spends of the access key are contiguous, so the page is read right after the cursor
+        let (history, next_cursor) = state.access_key_usages.get_prefix_page(
+            AccessKeyUsageIndex {
+                access_key_uid: access_key_uid.clone(),
+                ..Default::default()
+            },
+            |access_key_usage_index| access_key_usage_index.access_key_uid == access_key_uid,
+            cursor,
+            page_request
+                .limit
+                .unwrap_or(ACCESS_KEY_USAGE_PAGE_SIZE_LIMIT)
+                .min(ACCESS_KEY_USAGE_PAGE_SIZE_LIMIT) as usize,
+        );
+
+        Ok(AccessKeyUsage {
+            history,
+            next_cursor: next_cursor.as_ref().map(get_access_key_usage_cursor),
+            total_requests: access_key_usage_stats_value.total_requests,
+            requests_per_day: access_key_usage_stats_value.requests_per_day,
+            requests_per_device: access_key_usage_stats_value.requests_per_device,
+        })
+    })
+}
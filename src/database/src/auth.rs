@@ -1,4 +1,4 @@
-use crate::STATE;
+use crate::{snapshot::no_snapshot_import_in_progress, STATE};
 use candid::candid_method;
 use ic_cdk::print;
 use ic_cdk_macros::update;
@@ -7,6 +7,8 @@ use omnia_types::http::{IpChallengeIndex, IpChallengeNonce, IpChallengeValue};
 #[update]
 #[candid_method(update)]
 async fn init_nonce_to_ip(nonce: IpChallengeNonce, ip_challenge_value: IpChallengeValue) {
+    no_snapshot_import_in_progress();
+
     print(format!(
         "Initialized requester info: {:?} for nonce: {:?} ",
         ip_challenge_value, nonce
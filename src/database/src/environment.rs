@@ -3,20 +3,21 @@ use ic_cdk::print;
 use ic_cdk_macros::{query, update};
 use omnia_types::{
     device::{
-        RegisteredDeviceIndex, RegisteredDeviceResult, RegisteredDeviceValue,
+        DeviceUid, RegisteredDeviceIndex, RegisteredDeviceResult, RegisteredDeviceValue,
         RegisteredDevicesUidsResult,
     },
     environment::{
         EnvironmentCreationInput, EnvironmentCreationResult, EnvironmentIndex, EnvironmentUID,
-        EnvironmentUidIndex, EnvironmentUidValue, EnvironmentValue,
+        EnvironmentUidIndex, EnvironmentValue,
     },
-    errors::GenericResult,
+    errors::{GenericError, GenericResult},
     gateway::{
         GatewayPrincipalId, GatewayRegistrationInput, InitializedGatewayIndex,
         InitializedGatewayValue, MultipleRegisteredGatewayResult, RegisteredGatewayIndex,
         RegisteredGatewayResult, RegisteredGatewayValue,
     },
     http::IpChallengeNonce,
+    pagination::{Page, PageRequest},
     updates::{
         PairingInfo, PairingPayload, UpdateIndex, UpdateValue, UpdateValueOption, UpdateValueResult,
     },
@@ -24,9 +25,15 @@ use omnia_types::{
 };
 use omnia_utils::net::{get_device_url, get_gateway_url};
 use std::collections::BTreeMap;
+use std::ops::Bound;
 use uuid::Uuid;
 
-use crate::{utils::caller_is_omnia_backend, STATE};
+use crate::{
+    migrations::are_migrations_completed,
+    snapshot::no_snapshot_import_in_progress,
+    utils::{caller_is_omnia_backend, get_page_limit},
+    STATE,
+};
 
 #[query]
 #[candid_method(query)]
@@ -54,6 +61,7 @@ async fn init_gateway_by_ip(
     gateway_principal_id: GatewayPrincipalId,
 ) -> GenericResult<GatewayPrincipalId> {
     caller_is_omnia_backend();
+    no_snapshot_import_in_progress();
 
     STATE.with(|state| {
         // validate IP challenge
@@ -99,6 +107,7 @@ async fn get_initialized_gateways_by_ip(
     nonce: IpChallengeNonce,
 ) -> GenericResult<Vec<InitializedGatewayValue>> {
     caller_is_omnia_backend();
+    no_snapshot_import_in_progress();
 
     STATE.with(|state| {
         // validate IP challenge
@@ -129,6 +138,7 @@ async fn create_new_environment(
     environment_creation_input: EnvironmentCreationInput,
 ) -> GenericResult<EnvironmentCreationResult> {
     caller_is_omnia_backend();
+    no_snapshot_import_in_progress();
 
     let environment_uid = Uuid::new_v4().hyphenated().to_string();
 
@@ -146,7 +156,6 @@ async fn create_new_environment(
             env_name: environment_creation_input.env_name.clone(),
             env_ip: None,
             env_users_principals_ids: BTreeMap::default(),
-            env_gateways_principals_ids: BTreeMap::default(),
             env_manager_principal_id: environment_manager_principal_id.clone(),
         };
         state
@@ -187,6 +196,7 @@ fn register_gateway_in_environment(
     gateway_registration_input: GatewayRegistrationInput,
 ) -> RegisteredGatewayResult {
     caller_is_omnia_backend();
+    no_snapshot_import_in_progress();
 
     STATE.with(|state| {
         // validate IP challenge
@@ -195,6 +205,27 @@ fn register_gateway_in_environment(
             .ip_challenges
             .validate_ip_challenge_by_nonce(nonce)?;
 
+        // the UID of the environment is retrieved from the IP of its gateways when a User registers in an environment,
+        // so the gateways of a network must all be in the same environment
+        let environment_uid_index = EnvironmentUidIndex {
+            ip: ip_challenge_value.requester_ip.clone(),
+        };
+        if let Ok(environment_uid) = state
+            .borrow()
+            .registered_gateways
+            .get_environment_uid_by_ip(environment_uid_index, are_migrations_completed())
+        {
+            if environment_uid != gateway_registration_input.env_uid {
+                return Err(GenericError::NetworkMismatch {
+                    ip: ip_challenge_value.requester_ip,
+                    reason: format!(
+                        "The network already belongs to the environment {:?}",
+                        environment_uid
+                    ),
+                });
+            }
+        }
+
         // remove initialized gateways
         // we only get the initialized gateway value if the registration request (from the managaer) comes from the same network of the initialized gateway
         let initialized_gateway_index = InitializedGatewayIndex {
@@ -203,19 +234,16 @@ fn register_gateway_in_environment(
         let initialized_gateway_value = state
             .borrow_mut()
             .initialized_gateways
-            .delete(&initialized_gateway_index)?;
-        // register mapping IP to Environment UID in order to be able to retrive the UID of the environment from the IP when a User registers in an environment
-        let environment_uid_index = EnvironmentUidIndex {
-            ip: ip_challenge_value.requester_ip.clone(),
-        };
-        let environment_uid_value = EnvironmentUidValue {
-            env_uid: gateway_registration_input.env_uid.clone(),
-        };
-        state
-            .borrow_mut()
-            .environment_uids
-            .create(environment_uid_index, environment_uid_value)
-            .expect("previous entry should not exist");
+            .delete(&initialized_gateway_index)
+            .map_err(|e| match e {
+                GenericError::NotFound { .. } => GenericError::NetworkMismatch {
+                    ip: ip_challenge_value.requester_ip.clone(),
+                    reason: String::from(
+                        "Cannot register a gateway from a different network of the gateway",
+                    ),
+                },
+                e => e,
+            })?;
 
         // created registered gateway
         print(format!(
@@ -233,25 +261,19 @@ fn register_gateway_in_environment(
                 initialized_gateway_value.proxied_gateway_uid.is_some(), // true if gateway is proxied (determined during intialization)
             ),
             proxied_gateway_uid: initialized_gateway_value.proxied_gateway_uid,
-            env_uid: gateway_registration_input.env_uid.clone(),
-            gat_registered_device_uids: BTreeMap::default(),
+            env_uid: gateway_registration_input.env_uid,
         };
-        state.borrow_mut().registered_gateways.create(
-            registered_gateway_index.clone(),
-            registered_gateway_value.clone(),
-        )?;
-
-        // add principal ID of registered Gateway to Environment
+        // check if environment exists
         let environment_index = EnvironmentIndex {
-            environment_uid: gateway_registration_input.env_uid,
+            environment_uid: registered_gateway_value.env_uid.clone(),
         };
+        state.borrow().environments.read(&environment_index)?;
+
+        // the registered gateway is indexed by environment as well
         state
             .borrow_mut()
-            .environments
-            .insert_gateway_principal_id_in_env(
-                environment_index,
-                registered_gateway_index.principal_id,
-            )?;
+            .registered_gateways
+            .create(registered_gateway_index, registered_gateway_value.clone())?;
 
         Ok(registered_gateway_value)
     })
@@ -261,37 +283,42 @@ fn register_gateway_in_environment(
 #[candid_method(update)]
 fn get_registered_gateways_in_environment(
     environment_uid: EnvironmentUID,
+    page_request: PageRequest,
+    gateway_name_prefix: Option<String>,
 ) -> MultipleRegisteredGatewayResult {
     caller_is_omnia_backend();
 
     STATE.with(|state| {
-        // get principal IDs of gateways registered in environment
-        let environment_index = EnvironmentIndex { environment_uid };
-        let environment_value = state.borrow().environments.read(&environment_index)?;
-        let gateway_principal_ids: Vec<GatewayPrincipalId> =
-            environment_value.env_gateways_principals_ids.iter().fold(
-                vec![],
-                |mut gateway_principal_ids, (gateway_principal_id, _)| {
-                    gateway_principal_ids.push(gateway_principal_id.clone());
-                    gateway_principal_ids
-                },
-            );
+        let state = state.borrow();
 
-        // get registered gateways by principal ID
-        let mut registered_gateways: Vec<RegisteredGatewayValue> = vec![];
-        for gateway_principal_id in gateway_principal_ids {
-            let registered_gateway_index = RegisteredGatewayIndex {
-                principal_id: gateway_principal_id,
-            };
-            let registered_gateway_value = state
-                .borrow()
-                .registered_gateways
-                .read(&registered_gateway_index)?
-                .clone();
-            registered_gateways.push(registered_gateway_value.clone());
-        }
-        print(format!("Registered gateways: {:?}", registered_gateways));
-        Ok(registered_gateways)
+        // check if environment exists
+        let environment_index = EnvironmentIndex { environment_uid };
+        state.environments.read(&environment_index)?;
+
+        let (registered_gateways, next_cursor) = state.registered_gateways.get_secondary_page(
+            &state.registered_gateways.indexes().by_environment,
+            &environment_index,
+            page_request
+                .cursor
+                .clone()
+                .map(|principal_id| RegisteredGatewayIndex { principal_id }),
+            get_page_limit(&page_request),
+            are_migrations_completed(),
+            |_, registered_gateway_value| {
+                gateway_name_prefix.as_ref().map_or(true, |prefix| {
+                    registered_gateway_value.gateway_name.starts_with(prefix)
+                })
+            },
+        );
+
+        Ok(Page {
+            items: registered_gateways
+                .into_iter()
+                .map(|(_, registered_gateway_value)| registered_gateway_value)
+                .collect(),
+            next_cursor: next_cursor
+                .map(|registered_gateway_index| registered_gateway_index.principal_id),
+        })
     })
 }
 
@@ -299,6 +326,7 @@ fn get_registered_gateways_in_environment(
 #[candid_method(update)]
 fn get_gateway_updates_by_principal(gateway_principal_id: GatewayPrincipalId) -> UpdateValueOption {
     caller_is_omnia_backend();
+    no_snapshot_import_in_progress();
 
     STATE.with(|state| {
         // get updates for gateway
@@ -330,6 +358,7 @@ fn pair_new_device_on_gateway(
     pairing_payload: PairingPayload,
 ) -> UpdateValueResult {
     caller_is_omnia_backend();
+    no_snapshot_import_in_progress();
 
     STATE.with(|state| {
         // validate IP challenge
@@ -374,9 +403,12 @@ fn pair_new_device_on_gateway(
             ));
             return Ok(update_value);
         }
-        Err(String::from(
-            "Cannot commission devices from a different network of the gateway",
-        ))
+        Err(GenericError::NetworkMismatch {
+            ip: ip_challenge_value.requester_ip,
+            reason: String::from(
+                "Cannot commission devices from a different network of the gateway",
+            ),
+        })
     })
 }
 
@@ -387,6 +419,7 @@ async fn register_device_on_gateway(
     gateway_principal_id: GatewayPrincipalId,
 ) -> RegisteredDeviceResult {
     caller_is_omnia_backend();
+    no_snapshot_import_in_progress();
 
     let device_uid = Uuid::new_v4().hyphenated().to_string();
 
@@ -433,12 +466,7 @@ async fn register_device_on_gateway(
                 ),
             };
 
-            // register device in gateway
-            state
-                .borrow_mut()
-                .registered_gateways
-                .insert_device_uid_in_gateway(registered_gateway_index, device_uid.clone())?;
-
+            // the registered device is indexed by gateway and by environment as well
             state.borrow_mut().registered_devices.create(
                 registered_device_index.clone(),
                 registered_device_value.clone(),
@@ -450,9 +478,10 @@ async fn register_device_on_gateway(
 
             return Ok((registered_device_index, registered_device_value));
         }
-        Err(String::from(
-            "Cannot register device from a different network of the gateway",
-        ))
+        Err(GenericError::NetworkMismatch {
+            ip: ip_challenge_value.requester_ip,
+            reason: String::from("Cannot register device from a different network of the gateway"),
+        })
     })
 }
 
@@ -460,24 +489,155 @@ async fn register_device_on_gateway(
 #[candid_method(update)]
 async fn get_registered_devices_on_gateway(
     gateway_principal_id: GatewayPrincipalId,
+    page_request: PageRequest,
 ) -> RegisteredDevicesUidsResult {
     caller_is_omnia_backend();
 
     STATE.with(|state| {
+        let state = state.borrow();
+
         // check if gateway is already registered
         let registered_gateway_index = RegisteredGatewayIndex {
             principal_id: gateway_principal_id.clone(),
         };
-        let registered_gateway_value = state
-            .borrow()
-            .registered_gateways
-            .read(&registered_gateway_index)?;
+        state.registered_gateways.read(&registered_gateway_index)?;
 
         // return registered devices
-        Ok(registered_gateway_value
-            .gat_registered_device_uids
-            .keys()
-            .cloned()
-            .collect())
+        let (registered_devices, next_cursor) = state.registered_devices.get_secondary_page(
+            &state.registered_devices.indexes().by_gateway,
+            &registered_gateway_index,
+            page_request
+                .cursor
+                .clone()
+                .map(|device_uid| RegisteredDeviceIndex { device_uid }),
+            get_page_limit(&page_request),
+            are_migrations_completed(),
+            |_, _| true,
+        );
+
+        Ok(Page {
+            items: registered_devices
+                .into_iter()
+                .map(|(registered_device_index, _)| registered_device_index.device_uid)
+                .collect(),
+            next_cursor: next_cursor
+                .map(|registered_device_index| registered_device_index.device_uid),
+        })
+    })
+}
+
+#[update]
+#[candid_method(update)]
+/// Compensates [register_device_on_gateway] when the Backend fails to describe the device in the RDF database
+fn unregister_device_on_gateway(
+    gateway_principal_id: GatewayPrincipalId,
+    device_uid: DeviceUid,
+) -> GenericResult<()> {
+    caller_is_omnia_backend();
+    no_snapshot_import_in_progress();
+
+    STATE.with(|state| {
+        let registered_device_index = RegisteredDeviceIndex {
+            device_uid: device_uid.clone(),
+        };
+        let registered_device_value = state
+            .borrow()
+            .registered_devices
+            .read(&registered_device_index)?;
+
+        if registered_device_value.gateway_principal_id != gateway_principal_id {
+            return Err(GenericError::invalid_argument(format!(
+                "Device with UID {:?} is not registered on gateway {:?}",
+                device_uid, gateway_principal_id
+            )));
+        }
+
+        state
+            .borrow_mut()
+            .registered_devices
+            .delete(&registered_device_index)?;
+        print(format!(
+            "Gateway {:?} unregistered device with UID {:?}",
+            gateway_principal_id, device_uid
+        ));
+
+        Ok(())
+    })
+}
+
+#[query]
+#[candid_method(query)]
+fn get_registered_device(device_uid: DeviceUid) -> RegisteredDeviceResult {
+    caller_is_omnia_backend();
+
+    STATE.with(|state| {
+        let registered_device_index = RegisteredDeviceIndex { device_uid };
+        let registered_device_value = state
+            .borrow()
+            .registered_devices
+            .read(&registered_device_index)?;
+
+        Ok((registered_device_index, registered_device_value))
+    })
+}
+
+#[query]
+#[candid_method(query)]
+fn get_registered_devices(
+    page_request: PageRequest,
+    environment_uid: EnvironmentUID,
+) -> Page<(RegisteredDeviceIndex, RegisteredDeviceValue)> {
+    caller_is_omnia_backend();
+
+    STATE.with(|state| {
+        let state = state.borrow();
+        let registered_devices = &state.registered_devices;
+        let cursor = page_request
+            .cursor
+            .clone()
+            .map(|device_uid| RegisteredDeviceIndex { device_uid });
+
+        // the devices are always listed by environment, so that only the index entries of the environment are scanned
+        // once the devices stored before the index was declared are indexed
+        let (registered_devices, next_cursor) = registered_devices.get_secondary_page(
+            &registered_devices.indexes().by_environment,
+            &EnvironmentIndex { environment_uid },
+            cursor,
+            get_page_limit(&page_request),
+            are_migrations_completed(),
+            |_, _| true,
+        );
+
+        Page {
+            items: registered_devices,
+            next_cursor: next_cursor
+                .map(|registered_device_index| registered_device_index.device_uid),
+        }
+    })
+}
+
+#[query]
+#[candid_method(query)]
+fn get_environment_uids(page_request: PageRequest) -> Page<EnvironmentUID> {
+    caller_is_omnia_backend();
+
+    STATE.with(|state| {
+        let (environments, next_cursor) = state.borrow().environments.get_page(
+            (Bound::Unbounded, Bound::Unbounded),
+            page_request
+                .cursor
+                .clone()
+                .map(|environment_uid| EnvironmentIndex { environment_uid }),
+            get_page_limit(&page_request),
+            |_, _| true,
+        );
+
+        Page {
+            items: environments
+                .into_iter()
+                .map(|(environment_index, _)| environment_index.environment_uid)
+                .collect(),
+            next_cursor: next_cursor.map(|environment_index| environment_index.environment_uid),
+        }
     })
 }
@@ -2,15 +2,18 @@ use candid::{candid_method, Principal};
 use ic_cdk_macros::{init, post_upgrade};
 use ic_stable_structures::memory_manager::{MemoryId, MemoryManager};
 use ic_stable_structures::DefaultMemoryImpl;
+use migrations::start_migrations;
 use omnia_core_sdk::random::init_rng;
-use omnia_types::access_key::{AccessKeyIndex, AccessKeyValue};
-use omnia_types::device::{RegisteredDeviceIndex, RegisteredDeviceValue};
-use omnia_types::environment::{
-    EnvironmentIndex, EnvironmentUidIndex, EnvironmentUidValue, EnvironmentValue,
+use omnia_types::access_key::{
+    AccessKeyIndex, AccessKeyIndexes, AccessKeySecretIndex, AccessKeySecretValue,
+    AccessKeyUsageIndex, AccessKeyUsageStatsValue, AccessKeyUsageValue, AccessKeyValue,
 };
+use omnia_types::device::{RegisteredDeviceIndex, RegisteredDeviceIndexes, RegisteredDeviceValue};
+use omnia_types::environment::{EnvironmentIndex, EnvironmentValue};
 use omnia_types::gateway::{
+    GatewayLedgerIndex, GatewayLedgerValue, GatewayPayoutIndex, GatewayPayoutValue,
     InitializedGatewayIndex, InitializedGatewayValue, RegisteredGatewayIndex,
-    RegisteredGatewayValue,
+    RegisteredGatewayIndexes, RegisteredGatewayValue,
 };
 use omnia_types::http::{IpChallengeIndex, IpChallengeValue};
 use omnia_types::updates::{UpdateIndex, UpdateValue};
@@ -22,19 +25,37 @@ use utils::update_omnia_backend_principal;
 mod access_key;
 mod auth;
 mod environment;
+mod migrations;
+mod payouts;
+mod snapshot;
+mod stored_maps;
 mod utils;
 mod virtual_persona;
 
+/// The maps are stored in the memories from 0 to 13, their indexes in the memories from 14 to 18,
+/// the progress of the migrations in the memory 19 and the progress of the snapshot import in the memory 20.
+/// The maps stored by the previous versions of the canister with the layout of ic-stable-structures 0.5
+/// are loaded in place and migrated to the current layout when they're initialized.
+/// The memory 2 stored the environments by IP, which are now found through an index of the registered gateways:
+/// a migration zeroes it once the index is complete, but its pages stay allocated, since the memory manager cannot release them
 struct State {
     pub virtual_personas: CrudMap<VirtualPersonaIndex, VirtualPersonaValue>,
     pub environments: CrudMap<EnvironmentIndex, EnvironmentValue>,
-    pub environment_uids: CrudMap<EnvironmentUidIndex, EnvironmentUidValue>,
-    pub registered_gateways: CrudMap<RegisteredGatewayIndex, RegisteredGatewayValue>,
+    pub registered_gateways:
+        CrudMap<RegisteredGatewayIndex, RegisteredGatewayValue, RegisteredGatewayIndexes>,
     pub ip_challenges: CrudMap<IpChallengeIndex, IpChallengeValue>,
     pub initialized_gateways: CrudMap<InitializedGatewayIndex, InitializedGatewayValue>,
     pub updates: CrudMap<UpdateIndex, UpdateValue>,
-    pub registered_devices: CrudMap<RegisteredDeviceIndex, RegisteredDeviceValue>,
-    pub valid_access_keys: CrudMap<AccessKeyIndex, AccessKeyValue>,
+    pub registered_devices:
+        CrudMap<RegisteredDeviceIndex, RegisteredDeviceValue, RegisteredDeviceIndexes>,
+    pub valid_access_keys: CrudMap<AccessKeyIndex, AccessKeyValue, AccessKeyIndexes>,
+    pub gateway_ledgers: CrudMap<GatewayLedgerIndex, GatewayLedgerValue>,
+    pub gateway_payouts: CrudMap<GatewayPayoutIndex, GatewayPayoutValue>,
+    pub access_key_usages: CrudMap<AccessKeyUsageIndex, AccessKeyUsageValue>,
+    /// aggregates of the access key usages, by access key
+    pub access_key_usage_stats: CrudMap<AccessKeyIndex, AccessKeyUsageStatsValue>,
+    /// hashes of the access key secrets, which are never stored
+    pub access_key_secrets: CrudMap<AccessKeySecretIndex, AccessKeySecretValue>,
 }
 
 impl State {
@@ -46,11 +67,12 @@ impl State {
             environments: CrudMap::default(
                 MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(1))),
             ),
-            environment_uids: CrudMap::default(
-                MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(2))),
-            ),
-            registered_gateways: CrudMap::default(
+            registered_gateways: CrudMap::with_indexes(
                 MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(3))),
+                RegisteredGatewayIndexes::init(
+                    MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(14))),
+                    MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(17))),
+                ),
             ),
             ip_challenges: CrudMap::default(
                 MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(4))),
@@ -59,11 +81,31 @@ impl State {
                 MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(5))),
             ),
             updates: CrudMap::default(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(6)))),
-            registered_devices: CrudMap::default(
+            registered_devices: CrudMap::with_indexes(
                 MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(7))),
+                RegisteredDeviceIndexes::init(
+                    MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(15))),
+                    MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(16))),
+                ),
             ),
-            valid_access_keys: CrudMap::default(
+            valid_access_keys: CrudMap::with_indexes(
                 MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(8))),
+                AccessKeyIndexes::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(18)))),
+            ),
+            gateway_ledgers: CrudMap::default(
+                MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(9))),
+            ),
+            gateway_payouts: CrudMap::default(
+                MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(10))),
+            ),
+            access_key_usages: CrudMap::default(
+                MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(11))),
+            ),
+            access_key_usage_stats: CrudMap::default(
+                MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(12))),
+            ),
+            access_key_secrets: CrudMap::default(
+                MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(13))),
             ),
         }
     }
@@ -99,6 +141,10 @@ fn post_upgrade(
     init_rng();
 
     update_omnia_backend_principal(omnia_backend_canister_principal_id);
+
+    // legacy access keys, indexes of the existing records and records stored with a previous version of their schema
+    // are migrated in batches
+    start_migrations();
 }
 
 #[cfg(test)]
@@ -107,13 +153,15 @@ mod tests {
     use std::env;
 
     use super::*;
-    use omnia_core_sdk::access_key::UniqueAccessKey;
+    use omnia_core_sdk::access_key::AccessKeyUID;
     use omnia_types::access_key::*;
     use omnia_types::device::*;
     use omnia_types::environment::*;
     use omnia_types::errors::*;
     use omnia_types::gateway::*;
     use omnia_types::http::*;
+    use omnia_types::pagination::*;
+    use omnia_types::snapshot::*;
     use omnia_types::updates::*;
     use omnia_types::virtual_persona::*;
 
@@ -127,4 +175,81 @@ mod tests {
         export_service!();
         write(did_path, __export_service()).expect("Write failed.");
     }
+
+    /// Record encoded like the records stored by the previous versions of the canister
+    struct LegacyRecord(Vec<u8>);
+
+    impl ic_stable_structures_v0_5::Storable for LegacyRecord {
+        fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+            std::borrow::Cow::Borrowed(&self.0)
+        }
+
+        fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+            Self(bytes.into_owned())
+        }
+    }
+
+    impl ic_stable_structures_v0_5::BoundedStorable for LegacyRecord {
+        const MAX_SIZE: u32 = 1000;
+        const IS_FIXED_SIZE: bool = false;
+    }
+
+    #[test]
+    fn test_legacy_map_is_loaded_in_place() {
+        use candid::Encode;
+        use ic_stable_structures::Memory;
+
+        let virtual_persona_index = VirtualPersonaIndex {
+            principal_id: String::from("virtual_persona"),
+        };
+        let virtual_persona_value = VirtualPersonaValue {
+            virtual_persona_principal_id: String::from("virtual_persona"),
+            virtual_persona_ip: String::from("10.10.10.10"),
+            user_env_uid: Some(String::from("environment")),
+            manager_env_uid: None,
+        };
+
+        // the virtual personas were stored in the memory 0 with the layout of ic-stable-structures 0.5
+        let legacy_memory = ic_stable_structures_v0_5::VectorMemory::default();
+        let mut legacy_map: ic_stable_structures_v0_5::StableBTreeMap<
+            LegacyRecord,
+            LegacyRecord,
+            _,
+        > = ic_stable_structures_v0_5::StableBTreeMap::init(legacy_memory.clone());
+        legacy_map.insert(
+            LegacyRecord(Encode!(&virtual_persona_index).unwrap()),
+            LegacyRecord(Encode!(&virtual_persona_value).unwrap()),
+        );
+        let legacy_bytes = legacy_memory.borrow().clone();
+        let memory = MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(0)));
+        memory.grow(legacy_bytes.len() as u64 / 65536);
+        memory.write(0, &legacy_bytes);
+
+        let mut state = State::default();
+        let stored_virtual_persona_value =
+            state.virtual_personas.read(&virtual_persona_index).unwrap();
+        assert_eq!(
+            stored_virtual_persona_value.user_env_uid,
+            virtual_persona_value.user_env_uid
+        );
+
+        // once loaded, the map stores records larger than the bound of the previous layout
+        let large_virtual_persona_value = VirtualPersonaValue {
+            virtual_persona_ip: "a".repeat(1000),
+            ..virtual_persona_value
+        };
+        state
+            .virtual_personas
+            .upsert(virtual_persona_index.clone(), large_virtual_persona_value);
+        assert_eq!(
+            state
+                .virtual_personas
+                .read(&virtual_persona_index)
+                .unwrap()
+                .virtual_persona_ip
+                .len(),
+            1000
+        );
+        assert_eq!(state.virtual_personas.len(), 1);
+    }
 }
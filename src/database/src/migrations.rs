@@ -0,0 +1,448 @@
+use candid::{candid_method, CandidType, Decode, Deserialize, Encode};
+use ic_cdk::print;
+use ic_cdk_macros::query;
+use ic_stable_structures::{memory_manager::MemoryId, storable::Bound, StableCell, Storable};
+use omnia_types::Memory;
+use omnia_utils::constants::MIGRATION_BATCH_SIZE;
+use std::{borrow::Cow, cell::RefCell, time::Duration};
+
+use crate::{
+    access_key::migrate_legacy_access_keys_batch,
+    stored_maps::{INDEXED_MAPS, STORED_MAPS},
+    utils::caller_is_omnia_backend,
+    MEMORY_MANAGER, STATE,
+};
+
+/// Migration run in batches after an upgrade, see [start_migrations]
+enum Migration {
+    /// replaces the identifiers of the access keys created before secrets were introduced
+    LegacyAccessKeys,
+    /// adds to the secondary indexes of the stored map the records stored before the indexes were declared
+    Index(&'static str),
+    /// zeroes the memory with the environments by IP stored by the previous versions of the canister
+    LegacyEnvironmentsByIp,
+    /// rewrites the records of the stored map with the current version of their schema,
+    /// so that the previous versions can eventually be dropped
+    StoredMap(&'static str),
+}
+
+impl Migration {
+    /// Identifies the migration in the persisted [MigrationProgress], regardless of its position in [get_migrations]
+    fn name(&self) -> String {
+        match self {
+            Self::LegacyAccessKeys => String::from("legacy_access_keys"),
+            Self::Index(name) => format!("index:{}", name),
+            Self::LegacyEnvironmentsByIp => String::from("legacy_environments_by_ip"),
+            Self::StoredMap(name) => format!("rewrite:{}", name),
+        }
+    }
+
+    /// Migrates a batch of entries after the cursor, returning the next cursor if more entries may follow
+    fn run_batch(&self, cursor: Option<Vec<u8>>) -> Option<Vec<u8>> {
+        match self {
+            Self::LegacyAccessKeys => {
+                migrate_legacy_access_keys_batch(cursor.map(decode), MIGRATION_BATCH_SIZE)
+                    .map(|next_cursor| next_cursor.to_bytes().into_owned())
+            }
+            Self::Index(name) => STATE.with(|state| {
+                state
+                    .borrow_mut()
+                    .get_stored_map(name)
+                    .expect("stored map should exist")
+                    .index_batch(cursor, MIGRATION_BATCH_SIZE)
+            }),
+            Self::LegacyEnvironmentsByIp => {
+                clear_legacy_environments_by_ip_batch(cursor.map(decode))
+                    .map(|next_cursor| next_cursor.to_bytes().into_owned())
+            }
+            Self::StoredMap(name) => STATE.with(|state| {
+                state
+                    .borrow_mut()
+                    .get_stored_map(name)
+                    .expect("stored map should exist")
+                    .rewrite_batch(cursor, MIGRATION_BATCH_SIZE)
+            }),
+        }
+    }
+}
+
+/// Version of the migrations of [get_migrations], which must be increased whenever a release changes
+/// the migrations, or the schemas and the indexes of the maps they handle
+const MIGRATIONS_VERSION: u32 = 1;
+
+/// The migrations in the order they run: the access keys are migrated before being indexed,
+/// the legacy environments by IP are cleared once the registered gateways are indexed by IP
+/// and the indexes are completed before the maps are rewritten
+fn get_migrations() -> Vec<Migration> {
+    [Migration::LegacyAccessKeys]
+        .into_iter()
+        .chain(INDEXED_MAPS.iter().copied().map(Migration::Index))
+        .chain([Migration::LegacyEnvironmentsByIp])
+        .chain(STORED_MAPS.iter().copied().map(Migration::StoredMap))
+        .collect()
+}
+
+/// Memory in which the previous versions of the canister stored the environments by IP
+const LEGACY_ENVIRONMENTS_BY_IP_MEMORY_ID: u8 = 2;
+/// Number of pages of the legacy memory zeroed by each migration batch
+const CLEARED_PAGES_PER_BATCH: u64 = 16;
+const WASM_PAGE_SIZE: u64 = 65_536;
+
+/// Zeroes the pages of the memory with the legacy environments by IP that follow the cursor, returning the next page if more pages follow,
+/// so that the IPs are not kept after the registered gateways are indexed by IP
+fn clear_legacy_environments_by_ip_batch(cursor: Option<u64>) -> Option<u64> {
+    use ic_stable_structures::Memory;
+
+    let memory = MEMORY_MANAGER.with(|m| {
+        m.borrow()
+            .get(MemoryId::new(LEGACY_ENVIRONMENTS_BY_IP_MEMORY_ID))
+    });
+    let start = cursor.unwrap_or(0);
+    let end = memory.size().min(start + CLEARED_PAGES_PER_BATCH);
+    if start < end {
+        memory.write(
+            start * WASM_PAGE_SIZE,
+            &vec![0; ((end - start) * WASM_PAGE_SIZE) as usize],
+        );
+    }
+
+    (end < memory.size()).then_some(end)
+}
+
+fn decode<T: Storable>(bytes: Vec<u8>) -> T {
+    T::from_bytes(Cow::Owned(bytes))
+}
+
+/// Progress of the migrations, persisted so that an upgrade during the migrations doesn't scan the migrated entries again
+#[derive(Clone, Debug, Default, CandidType, Deserialize, PartialEq)]
+struct MigrationProgress {
+    /// [MIGRATIONS_VERSION] of the release that started the migrations, None if written before the migrations were versioned
+    version: Option<u32>,
+    /// name of the running migration, None once all the migrations have been completed
+    migration: Option<String>,
+    cursor: Option<Vec<u8>>,
+}
+
+impl Storable for MigrationProgress {
+    const BOUND: Bound = Bound::Unbounded;
+
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+thread_local! {
+    /* stable */ static MIGRATION_PROGRESS: RefCell<StableCell<MigrationProgress, Memory>> = RefCell::new(
+        StableCell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(19))),
+            MigrationProgress::default(),
+        )
+        .expect("migration progress should be initialized"),
+    );
+}
+
+fn get_migration_progress() -> MigrationProgress {
+    MIGRATION_PROGRESS.with(|progress| progress.borrow().get().clone())
+}
+
+fn set_migration_progress(migration_progress: MigrationProgress) {
+    MIGRATION_PROGRESS.with(|progress| {
+        progress
+            .borrow_mut()
+            .set(migration_progress)
+            .expect("migration progress should be stored")
+    });
+}
+
+/// Returns the progress to start the migrations from after an upgrade.
+/// A migration interrupted by an upgrade to a release with the same [MIGRATIONS_VERSION] is resumed from its cursor.
+/// Otherwise the migrations start again from the first one, because the upgrade may have changed the schemas or the indexes
+/// handled by the migrations that already ran
+fn get_starting_progress(
+    migrations: &[Migration],
+    previous_progress: MigrationProgress,
+) -> MigrationProgress {
+    let is_interrupted = previous_progress.version == Some(MIGRATIONS_VERSION)
+        && previous_progress.migration.as_ref().map_or(false, |name| {
+            migrations.iter().any(|migration| &migration.name() == name)
+        });
+    match is_interrupted {
+        true => previous_progress,
+        false => MigrationProgress {
+            version: Some(MIGRATIONS_VERSION),
+            migration: migrations.first().map(Migration::name),
+            cursor: None,
+        },
+    }
+}
+
+/// Runs a batch of the running migration and returns the progress after the batch
+fn run_migration_batch(migrations: &[Migration], progress: MigrationProgress) -> MigrationProgress {
+    let position = match progress.migration.as_ref().and_then(|name| {
+        migrations
+            .iter()
+            .position(|migration| &migration.name() == name)
+    }) {
+        Some(position) => position,
+        None => return MigrationProgress::default(),
+    };
+
+    match migrations[position].run_batch(progress.cursor) {
+        Some(next_cursor) => MigrationProgress {
+            version: progress.version,
+            migration: progress.migration,
+            cursor: Some(next_cursor),
+        },
+        None => MigrationProgress {
+            version: progress.version,
+            migration: migrations.get(position + 1).map(Migration::name),
+            cursor: None,
+        },
+    }
+}
+
+/// Every batch runs in its own message, so that migrating large maps doesn't exceed the instruction limit
+fn schedule_migration_batch() {
+    ic_cdk_timers::set_timer(Duration::ZERO, || {
+        let progress = run_migration_batch(&get_migrations(), get_migration_progress());
+        let is_completed = progress.migration.is_none();
+        set_migration_progress(progress);
+
+        match is_completed {
+            true => print("Migrations completed"),
+            false => schedule_migration_batch(),
+        }
+    });
+}
+
+/// Whether the migrations started by the last upgrade are completed, which is always the case for a newly installed canister
+pub fn are_migrations_completed() -> bool {
+    get_migration_progress().migration.is_none()
+}
+
+#[query]
+#[candid_method(query)]
+/// Until the migrations are completed, the records stored before their indexes were declared are not in the indexes yet:
+/// the listings that use the indexes scan the whole maps instead, and the callers that compare the records with other data should wait
+fn migrations_completed() -> bool {
+    caller_is_omnia_backend();
+
+    are_migrations_completed()
+}
+
+/// Starts the migrations of the records stored by the previous versions of the canister.
+/// Until they're completed (see [are_migrations_completed]), the legacy access keys are not found by their secrets
+/// and the records stored before their indexes were declared are found only by scanning the whole maps
+pub fn start_migrations() {
+    set_migration_progress(get_starting_progress(
+        &get_migrations(),
+        get_migration_progress(),
+    ));
+    schedule_migration_batch();
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use omnia_types::access_key::{AccessKeySecretIndex, AccessKeySecretValue};
+
+    use super::*;
+
+    fn run_migrations(mut progress: MigrationProgress) -> usize {
+        let migrations = get_migrations();
+        let mut batches = 0;
+        while progress.migration.is_some() {
+            progress = run_migration_batch(&migrations, progress);
+            batches += 1;
+        }
+        batches
+    }
+
+    #[test]
+    fn test_migrations_registry() {
+        let migrations = get_migrations();
+        let names: BTreeSet<String> = migrations.iter().map(Migration::name).collect();
+
+        assert_eq!(names.len(), migrations.len());
+        assert_eq!(migrations.len(), 2 + INDEXED_MAPS.len() + STORED_MAPS.len());
+        assert!(matches!(migrations[0], Migration::LegacyAccessKeys));
+        STATE.with(|state| {
+            let mut state = state.borrow_mut();
+            for name in INDEXED_MAPS.iter().chain(STORED_MAPS.iter()) {
+                assert!(
+                    state.get_stored_map(name).is_some(),
+                    "{} should exist",
+                    name
+                );
+            }
+        });
+    }
+
+    #[test]
+    fn test_migrations_run_in_batches() {
+        let migrations = get_migrations();
+        STATE.with(|state| {
+            let mut state = state.borrow_mut();
+            for index in 0..MIGRATION_BATCH_SIZE + 1 {
+                let mut secret_hash = [0; 32];
+                secret_hash[..8].copy_from_slice(&(index as u64).to_be_bytes());
+                state
+                    .access_key_secrets
+                    .create(
+                        AccessKeySecretIndex { secret_hash },
+                        AccessKeySecretValue {
+                            access_key_uid: index.to_string(),
+                        },
+                    )
+                    .unwrap();
+            }
+        });
+
+        // the access key secrets take two batches, the other maps are empty
+        assert_eq!(
+            run_migrations(get_starting_progress(
+                &migrations,
+                MigrationProgress::default()
+            )),
+            migrations.len() + 1
+        );
+    }
+
+    #[test]
+    fn test_clear_legacy_environments_by_ip() {
+        use ic_stable_structures::Memory;
+
+        let memory = MEMORY_MANAGER.with(|m| {
+            m.borrow()
+                .get(MemoryId::new(LEGACY_ENVIRONMENTS_BY_IP_MEMORY_ID))
+        });
+        let pages = CLEARED_PAGES_PER_BATCH + 1;
+        memory.grow(pages);
+        memory.write(0, &[1; 8]);
+        memory.write(pages * WASM_PAGE_SIZE - 8, &[1; 8]);
+
+        let mut batches = 0;
+        let mut cursor = None;
+        loop {
+            batches += 1;
+            match clear_legacy_environments_by_ip_batch(cursor) {
+                Some(next_cursor) => cursor = Some(next_cursor),
+                None => break,
+            }
+        }
+        assert_eq!(batches, 2);
+
+        let mut bytes = vec![1; (pages * WASM_PAGE_SIZE) as usize];
+        memory.read(0, &mut bytes);
+        assert!(bytes.iter().all(|byte| *byte == 0));
+    }
+
+    #[test]
+    fn test_migrations_resume_after_upgrade() {
+        let migrations = get_migrations();
+        let interrupted_progress = MigrationProgress {
+            version: Some(MIGRATIONS_VERSION),
+            migration: migrations.last().map(Migration::name),
+            cursor: Some(vec![0]),
+        };
+        assert_eq!(
+            get_starting_progress(&migrations, interrupted_progress.clone()),
+            interrupted_progress
+        );
+
+        // completed migrations and migrations that don't exist anymore start again
+        let first_progress = MigrationProgress {
+            version: Some(MIGRATIONS_VERSION),
+            migration: migrations.first().map(Migration::name),
+            cursor: None,
+        };
+        assert_eq!(
+            get_starting_progress(&migrations, MigrationProgress::default()),
+            first_progress
+        );
+        assert_eq!(
+            get_starting_progress(
+                &migrations,
+                MigrationProgress {
+                    version: Some(MIGRATIONS_VERSION),
+                    migration: Some(String::from("removed")),
+                    cursor: Some(vec![0]),
+                }
+            ),
+            first_progress
+        );
+    }
+
+    #[test]
+    fn test_migrations_restart_after_upgrade_to_new_version() {
+        let migrations = get_migrations();
+        // the earlier migrations may handle schemas or indexes changed by the new release, so they run again
+        for version in [
+            None,
+            Some(MIGRATIONS_VERSION - 1),
+            Some(MIGRATIONS_VERSION + 1),
+        ] {
+            let interrupted_progress = MigrationProgress {
+                version,
+                migration: migrations.last().map(Migration::name),
+                cursor: Some(vec![0]),
+            };
+            assert_eq!(
+                get_starting_progress(&migrations, interrupted_progress),
+                MigrationProgress {
+                    version: Some(MIGRATIONS_VERSION),
+                    migration: migrations.first().map(Migration::name),
+                    cursor: None,
+                }
+            );
+        }
+
+        // the version is kept along the batches, so that a later upgrade resumes them
+        let progress = run_migration_batch(
+            &migrations,
+            get_starting_progress(&migrations, MigrationProgress::default()),
+        );
+        assert_eq!(progress.version, Some(MIGRATIONS_VERSION));
+        assert_eq!(
+            get_starting_progress(&migrations, progress.clone()),
+            progress
+        );
+    }
+
+    #[test]
+    fn test_migrations_completed() {
+        let migrations = get_migrations();
+        assert!(are_migrations_completed());
+
+        set_migration_progress(get_starting_progress(
+            &migrations,
+            MigrationProgress::default(),
+        ));
+        assert!(!are_migrations_completed());
+
+        let mut progress = get_migration_progress();
+        while progress.migration.is_some() {
+            progress = run_migration_batch(&migrations, progress);
+            set_migration_progress(progress.clone());
+        }
+        assert!(are_migrations_completed());
+    }
+
+    #[test]
+    fn test_migration_progress_is_persisted() {
+        let progress = MigrationProgress {
+            version: Some(MIGRATIONS_VERSION),
+            migration: Some(Migration::Index("registered_devices").name()),
+            cursor: Some(vec![1, 2, 3]),
+        };
+        set_migration_progress(progress.clone());
+
+        assert_eq!(get_migration_progress(), progress);
+        assert_eq!(MigrationProgress::from_bytes(progress.to_bytes()), progress);
+    }
+}
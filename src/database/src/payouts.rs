@@ -0,0 +1,268 @@
+use candid::candid_method;
+use ic_cdk::print;
+use ic_cdk_macros::{query, update};
+use omnia_types::{
+    errors::{GenericError, GenericResult},
+    gateway::{
+        GatewayLedgerIndex, GatewayLedgerValue, GatewayPayoutIndex, GatewayPayoutValue,
+        GatewayPayoutsResult, GatewayPrincipalId, PendingGatewayPayout, RegisteredGatewayIndex,
+    },
+    pagination::{Page, PageRequest},
+};
+use std::ops::Bound;
+
+use crate::{
+    snapshot::no_snapshot_import_in_progress,
+    utils::{caller_is_omnia_backend, get_page_limit},
+    State, STATE,
+};
+
+#[query]
+#[candid_method(query)]
+fn get_unpaid_gateway_ledgers(
+    page_request: PageRequest,
+) -> Page<(GatewayLedgerIndex, GatewayLedgerValue)> {
+    caller_is_omnia_backend();
+
+    STATE.with(|state| {
+        let (gateway_ledgers, next_cursor) = state.borrow().gateway_ledgers.get_page(
+            (Bound::Unbounded, Bound::Unbounded),
+            page_request
+                .cursor
+                .clone()
+                .map(|gateway_principal_id| GatewayLedgerIndex {
+                    gateway_principal_id,
+                }),
+            get_page_limit(&page_request),
+            |_, gateway_ledger_value| gateway_ledger_value.get_unpaid_requests() > 0,
+        );
+
+        Page {
+            items: gateway_ledgers,
+            next_cursor: next_cursor
+                .map(|gateway_ledger_index| gateway_ledger_index.gateway_principal_id),
+        }
+    })
+}
+
+#[update]
+#[candid_method(update)]
+fn start_gateway_payout(
+    gateway_ledger_index: GatewayLedgerIndex,
+    pending_payout: PendingGatewayPayout,
+) -> GenericResult<GatewayLedgerValue> {
+    caller_is_omnia_backend();
+    no_snapshot_import_in_progress();
+
+    STATE.with(|state| {
+        // reserve the requests before the transfer, so that they are not paid by another payout
+        state
+            .borrow_mut()
+            .gateway_ledgers
+            .insert_pending_payout_in_ledger(gateway_ledger_index, pending_payout)
+    })
+}
+
+#[update]
+#[candid_method(update)]
+fn cancel_gateway_payout(
+    gateway_ledger_index: GatewayLedgerIndex,
+    pending_payout: PendingGatewayPayout,
+) -> GenericResult<GatewayLedgerValue> {
+    caller_is_omnia_backend();
+    no_snapshot_import_in_progress();
+
+    STATE.with(|state| {
+        // the ledger rejected the transfer, so the requests can be paid by the next payout
+        state
+            .borrow_mut()
+            .gateway_ledgers
+            .remove_pending_payout_from_ledger(gateway_ledger_index, &pending_payout)
+    })
+}
+
+#[update]
+#[candid_method(update)]
+fn record_gateway_payout(
+    gateway_payout_index: GatewayPayoutIndex,
+    gateway_payout_value: GatewayPayoutValue,
+) -> GenericResult<GatewayLedgerValue> {
+    caller_is_omnia_backend();
+    no_snapshot_import_in_progress();
+
+    STATE.with(|state| {
+        insert_gateway_payout(
+            &mut state.borrow_mut(),
+            gateway_payout_index,
+            gateway_payout_value,
+        )
+    })
+}
+
+/// Both the gateway ledger and the history of the payouts are written, or neither of them
+fn insert_gateway_payout(
+    state: &mut State,
+    gateway_payout_index: GatewayPayoutIndex,
+    gateway_payout_value: GatewayPayoutValue,
+) -> GenericResult<GatewayLedgerValue> {
+    // the payouts are checked first, because the gateway ledger is written before the payout is added
+    if state.gateway_payouts.contains(&gateway_payout_index) {
+        return Err(GenericError::already_exists(&gateway_payout_index));
+    }
+
+    // mark the requests of the pending payout as paid in the gateway ledger
+    let gateway_ledger_index = GatewayLedgerIndex {
+        gateway_principal_id: gateway_payout_index.gateway_principal_id.clone(),
+    };
+    let gateway_ledger_value = state
+        .gateway_ledgers
+        .insert_paid_requests_in_ledger(gateway_ledger_index, gateway_payout_value.paid_requests)?;
+
+    // add the payout to the history
+    state
+        .gateway_payouts
+        .create(gateway_payout_index.clone(), gateway_payout_value.clone())
+        .expect("payout should not exist");
+
+    print(format!(
+        "Recorded payout {:?} for gateway {:?}",
+        gateway_payout_value, gateway_payout_index.gateway_principal_id
+    ));
+
+    Ok(gateway_ledger_value)
+}
+
+#[query]
+#[candid_method(query)]
+fn get_gateway_payouts(
+    gateway_principal_id: GatewayPrincipalId,
+    page_request: PageRequest,
+) -> GatewayPayoutsResult {
+    caller_is_omnia_backend();
+
+    STATE.with(|state| {
+        // check if gateway is already registered
+        let registered_gateway_index = RegisteredGatewayIndex {
+            principal_id: gateway_principal_id.clone(),
+        };
+        state
+            .borrow()
+            .registered_gateways
+            .read(&registered_gateway_index)?;
+
+        // the cursor is the block index of the last payout
+        let cursor = page_request
+            .cursor
+            .as_ref()
+            .map(|cursor| {
+                cursor.parse::<u64>().map_err(|e| {
+                    GenericError::invalid_argument(format!("Invalid cursor {:?}: {}", cursor, e))
+                })
+            })
+            .transpose()?;
+
+        let (gateway_payouts, next_cursor) = state.borrow().gateway_payouts.get_page(
+            (
+                Bound::Included(GatewayPayoutIndex {
+                    gateway_principal_id: gateway_principal_id.clone(),
+                    block_index: u64::MIN,
+                }),
+                Bound::Included(GatewayPayoutIndex {
+                    gateway_principal_id: gateway_principal_id.clone(),
+                    block_index: u64::MAX,
+                }),
+            ),
+            cursor.map(|block_index| GatewayPayoutIndex {
+                gateway_principal_id: gateway_principal_id.clone(),
+                block_index,
+            }),
+            get_page_limit(&page_request),
+            |_, _| true,
+        );
+
+        Ok(Page {
+            items: gateway_payouts,
+            next_cursor: next_cursor
+                .map(|gateway_payout_index| gateway_payout_index.block_index.to_string()),
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get_pending_ledger() -> (GatewayLedgerIndex, GatewayLedgerValue) {
+        (
+            GatewayLedgerIndex {
+                gateway_principal_id: String::from("gateway"),
+            },
+            GatewayLedgerValue {
+                served_requests: 100,
+                paid_requests: 0,
+                last_served_at: 0,
+                pending_payout: Some(PendingGatewayPayout {
+                    paid_requests: 100,
+                    amount_e8s: 1000,
+                    memo: 0,
+                    created_at_time: 42,
+                }),
+            },
+        )
+    }
+
+    #[test]
+    fn test_insert_gateway_payout() {
+        let (gateway_ledger_index, gateway_ledger_value) = get_pending_ledger();
+        let gateway_payout_index = GatewayPayoutIndex {
+            gateway_principal_id: String::from("gateway"),
+            block_index: 7,
+        };
+        let gateway_payout_value = GatewayPayoutValue {
+            paid_requests: 100,
+            amount_e8s: 1000,
+            timestamp: 42,
+        };
+
+        STATE.with(|state| {
+            let mut state = state.borrow_mut();
+            state
+                .gateway_ledgers
+                .create(gateway_ledger_index.clone(), gateway_ledger_value.clone())
+                .unwrap();
+            // a payout recorded with the same block, e.g. by a controller
+            state
+                .gateway_payouts
+                .create(gateway_payout_index.clone(), gateway_payout_value.clone())
+                .unwrap();
+
+            // the requests are not marked as paid, so that the payout can still be resolved
+            assert!(matches!(
+                insert_gateway_payout(
+                    &mut state,
+                    gateway_payout_index.clone(),
+                    gateway_payout_value.clone()
+                ),
+                Err(GenericError::AlreadyExists { .. })
+            ));
+            let unchanged_gateway_ledger_value =
+                state.gateway_ledgers.read(&gateway_ledger_index).unwrap();
+            assert_eq!(unchanged_gateway_ledger_value.paid_requests, 0);
+            assert_eq!(
+                unchanged_gateway_ledger_value.pending_payout,
+                gateway_ledger_value.pending_payout
+            );
+
+            state.gateway_payouts.delete(&gateway_payout_index).unwrap();
+            let paid_gateway_ledger_value = insert_gateway_payout(
+                &mut state,
+                gateway_payout_index.clone(),
+                gateway_payout_value.clone(),
+            )
+            .unwrap();
+            assert_eq!(paid_gateway_ledger_value.paid_requests, 100);
+            assert_eq!(paid_gateway_ledger_value.pending_payout, None);
+            assert!(state.gateway_payouts.contains(&gateway_payout_index));
+        });
+    }
+}
@@ -0,0 +1,338 @@
+use std::{borrow::Cow, cell::RefCell};
+
+use candid::{candid_method, CandidType, Decode, Deserialize, Encode};
+use ic_cdk::trap;
+use ic_cdk_macros::update;
+use ic_stable_structures::{memory_manager::MemoryId, storable::Bound, StableCell, Storable};
+use omnia_types::{
+    errors::{GenericError, GenericResult},
+    snapshot::{SnapshotChunk, SnapshotChunkResult, SnapshotCursor},
+    Memory,
+};
+use omnia_utils::constants::SNAPSHOT_CHUNK_MAX_BYTES;
+
+use crate::{stored_maps::STORED_MAPS, utils::caller_is_controller, MEMORY_MANAGER, STATE};
+
+/// Progress of the snapshot import, persisted so that an upgrade during the import doesn't accept the chunks out of order
+#[derive(Clone, Debug, Default, CandidType, Deserialize, PartialEq)]
+struct SnapshotImport {
+    /// cursor returned by the last imported chunk, None if no import is in progress
+    next_cursor: Option<SnapshotCursor>,
+}
+
+impl Storable for SnapshotImport {
+    const BOUND: Bound = Bound::Unbounded;
+
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+thread_local! {
+    /* stable */ static SNAPSHOT_IMPORT: RefCell<StableCell<SnapshotImport, Memory>> = RefCell::new(
+        StableCell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(20))),
+            SnapshotImport::default(),
+        )
+        .expect("snapshot import should be initialized"),
+    );
+}
+
+fn get_snapshot_import() -> SnapshotImport {
+    SNAPSHOT_IMPORT.with(|snapshot_import| snapshot_import.borrow().get().clone())
+}
+
+fn set_snapshot_import(snapshot_import: SnapshotImport) {
+    SNAPSHOT_IMPORT.with(|cell| {
+        cell.borrow_mut()
+            .set(snapshot_import)
+            .expect("snapshot import should be stored")
+    });
+}
+
+/// Must be called by the methods that write the maps on behalf of omnia_backend,
+/// so that their records are not mixed with the ones of a snapshot being imported
+pub fn no_snapshot_import_in_progress() {
+    if get_snapshot_import().next_cursor.is_some() {
+        trap("a snapshot is being imported in the database")
+    }
+}
+
+fn get_first_cursor() -> SnapshotCursor {
+    SnapshotCursor {
+        map: STORED_MAPS[0].to_string(),
+        index: None,
+        previous_checksum: vec![],
+    }
+}
+
+#[update(name = "exportSnapshot")]
+#[candid_method(update, rename = "exportSnapshot")]
+/// Only controllers can export the snapshot of the database.
+/// The snapshot is exported one chunk at a time, starting from the first map when no cursor is provided
+/// and then from the cursor of the previous chunk, until a chunk without cursor is returned.
+/// Each chunk is read in a different call, so the snapshot is not consistent at a single point in time
+/// if the database is written during the export: the callers of the database should be stopped before exporting
+fn export_snapshot(cursor: Option<SnapshotCursor>) -> SnapshotChunkResult {
+    caller_is_controller()?;
+
+    export_snapshot_chunk(cursor)
+}
+
+fn export_snapshot_chunk(cursor: Option<SnapshotCursor>) -> SnapshotChunkResult {
+    let cursor = cursor.unwrap_or_else(get_first_cursor);
+
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let stored_map = state
+            .get_stored_map(&cursor.map)
+            .ok_or_else(|| unknown_map(&cursor.map))?;
+
+        let (entries, next_index) =
+            stored_map.export_batch(cursor.index.clone(), SNAPSHOT_CHUNK_MAX_BYTES)?;
+
+        // the previous checksum of the next cursor is set by the chunk
+        let next_cursor = match next_index {
+            Some(next_index) => Some(SnapshotCursor {
+                map: cursor.map.clone(),
+                index: Some(next_index),
+                previous_checksum: vec![],
+            }),
+            // continue from the first entry of the next map, if any
+            None => STORED_MAPS
+                .iter()
+                .skip_while(|map| **map != cursor.map)
+                .nth(1)
+                .map(|map| SnapshotCursor {
+                    map: map.to_string(),
+                    index: None,
+                    previous_checksum: vec![],
+                }),
+        };
+
+        Ok(SnapshotChunk::new(cursor, entries, next_cursor))
+    })
+}
+
+#[update(name = "importSnapshot")]
+#[candid_method(update, rename = "importSnapshot")]
+/// Only controllers can import a snapshot, one chunk at a time in the order the chunks have been exported.
+/// The import must start in an empty database, so that the imported entries are not mixed with existing ones,
+/// and is completed by the last chunk of the snapshot. Until then, the methods that write the database on behalf of omnia_backend trap
+fn import_snapshot(chunk: SnapshotChunk) -> GenericResult<()> {
+    caller_is_controller()?;
+
+    import_snapshot_chunk(chunk)
+}
+
+fn import_snapshot_chunk(chunk: SnapshotChunk) -> GenericResult<()> {
+    let expected_cursor = match get_snapshot_import().next_cursor {
+        Some(next_cursor) => next_cursor,
+        None => {
+            if !STATE.with(|state| state.borrow_mut().is_empty()) {
+                return Err(GenericError::invalid_argument(
+                    "A snapshot can only be imported in an empty database",
+                ));
+            }
+            get_first_cursor()
+        }
+    };
+
+    if chunk.map != expected_cursor.map || chunk.start_index != expected_cursor.index {
+        return Err(GenericError::invalid_argument(format!(
+            "Expected the snapshot chunk of map {} starting after index {:?}, got the one of map {} starting after index {:?}",
+            expected_cursor.map, expected_cursor.index, chunk.map, chunk.start_index
+        )));
+    }
+    chunk.verify(&expected_cursor.previous_checksum)?;
+
+    STATE.with(|state| {
+        state
+            .borrow_mut()
+            .get_stored_map(&chunk.map)
+            .ok_or_else(|| unknown_map(&chunk.map))?
+            .import_entries(chunk.entries)
+    })?;
+
+    set_snapshot_import(SnapshotImport {
+        next_cursor: chunk.next_cursor,
+    });
+    Ok(())
+}
+
+fn unknown_map(map: &str) -> GenericError {
+    GenericError::invalid_argument(format!("Unknown map {:?}", map))
+}
+
+#[cfg(test)]
+mod tests {
+    use ic_stable_structures::{memory_manager::MemoryManager, DefaultMemoryImpl};
+    use omnia_types::{
+        access_key::{AccessKeySecretIndex, AccessKeySecretValue},
+        virtual_persona::{VirtualPersonaIndex, VirtualPersonaValue},
+    };
+
+    use super::*;
+    use crate::{State, MEMORY_MANAGER};
+
+    fn fill_database() {
+        STATE.with(|state| {
+            let mut state = state.borrow_mut();
+            state
+                .virtual_personas
+                .create(
+                    VirtualPersonaIndex {
+                        principal_id: String::from("virtual_persona"),
+                    },
+                    VirtualPersonaValue {
+                        virtual_persona_principal_id: String::from("virtual_persona"),
+                        virtual_persona_ip: String::from("10.10.10.10"),
+                        user_env_uid: None,
+                        manager_env_uid: None,
+                    },
+                )
+                .unwrap();
+            for index in 0..3 {
+                state
+                    .access_key_secrets
+                    .create(
+                        AccessKeySecretIndex {
+                            secret_hash: [index; 32],
+                        },
+                        AccessKeySecretValue {
+                            access_key_uid: index.to_string(),
+                        },
+                    )
+                    .unwrap();
+            }
+        });
+    }
+
+    /// Replaces the database with an empty one, as if the canister had been installed again
+    fn reset_database() {
+        MEMORY_MANAGER
+            .with(|m| *m.borrow_mut() = MemoryManager::init(DefaultMemoryImpl::default()));
+        STATE.with(|state| *state.borrow_mut() = State::default());
+        set_snapshot_import(SnapshotImport::default());
+    }
+
+    fn export_all_chunks() -> Vec<SnapshotChunk> {
+        let mut chunks: Vec<SnapshotChunk> = vec![];
+        let mut cursor = None;
+        loop {
+            let chunk = export_snapshot_chunk(cursor).unwrap();
+            cursor = chunk.next_cursor.clone();
+            chunks.push(chunk);
+            if cursor.is_none() {
+                return chunks;
+            }
+        }
+    }
+
+    #[test]
+    fn test_export_cursor_advances_across_maps() {
+        fill_database();
+
+        let chunks = export_all_chunks();
+        assert_eq!(
+            chunks
+                .iter()
+                .map(|chunk| chunk.map.as_str())
+                .collect::<Vec<_>>(),
+            STORED_MAPS.to_vec()
+        );
+        assert_eq!(chunks[0].entries.len(), 1);
+        assert_eq!(chunks[STORED_MAPS.len() - 1].entries.len(), 3);
+
+        // the entries of a map are split in chunks of at most the maximum size, with at least one entry each
+        STATE.with(|state| {
+            let mut state = state.borrow_mut();
+            let access_key_secrets = state.get_stored_map("access_key_secrets").unwrap();
+            let (entries, next_index) = access_key_secrets.export_batch(None, 1).unwrap();
+            assert_eq!(entries.len(), 1);
+            let (entries, next_index) = access_key_secrets.export_batch(next_index, 1).unwrap();
+            assert_eq!(entries.len(), 1);
+            let (entries, next_index) = access_key_secrets.export_batch(next_index, 1).unwrap();
+            assert_eq!(entries.len(), 1);
+            assert_eq!(next_index, None);
+        });
+    }
+
+    #[test]
+    fn test_snapshot_roundtrip() {
+        fill_database();
+        let chunks = export_all_chunks();
+
+        reset_database();
+        for chunk in chunks.iter().cloned() {
+            assert_eq!(import_snapshot_chunk(chunk), Ok(()));
+        }
+
+        assert_eq!(export_all_chunks(), chunks);
+        // the import is completed by the last chunk
+        assert_eq!(get_snapshot_import(), SnapshotImport::default());
+        assert!(import_snapshot_chunk(chunks[0].clone()).is_err());
+    }
+
+    #[test]
+    fn test_import_chunks_in_order() {
+        fill_database();
+        let chunks = export_all_chunks();
+
+        reset_database();
+        // the import starts from the first chunk
+        assert!(import_snapshot_chunk(chunks[1].clone()).is_err());
+        assert_eq!(import_snapshot_chunk(chunks[0].clone()), Ok(()));
+        assert_eq!(
+            get_snapshot_import().next_cursor,
+            chunks[0].next_cursor.clone()
+        );
+
+        // chunks can't be skipped or imported twice
+        assert!(matches!(
+            import_snapshot_chunk(chunks[2].clone()),
+            Err(GenericError::InvalidArgument { .. })
+        ));
+        assert!(import_snapshot_chunk(chunks[0].clone()).is_err());
+
+        // a chunk with the expected position must be chained to the previous one
+        let mut altered_chunk = chunks[1].clone();
+        altered_chunk.entries.clear();
+        assert!(import_snapshot_chunk(altered_chunk).is_err());
+
+        for chunk in chunks.iter().skip(1).cloned() {
+            assert_eq!(import_snapshot_chunk(chunk), Ok(()));
+        }
+        assert_eq!(export_all_chunks(), chunks);
+    }
+
+    #[test]
+    fn test_import_in_non_empty_database() {
+        fill_database();
+        let chunks = export_all_chunks();
+
+        assert!(matches!(
+            import_snapshot_chunk(chunks[0].clone()),
+            Err(GenericError::InvalidArgument { .. })
+        ));
+    }
+
+    #[test]
+    fn test_import_undecodable_entries() {
+        let chunk = SnapshotChunk::new(
+            get_first_cursor(),
+            vec![(vec![1, 2, 3], vec![4, 5, 6])],
+            None,
+        );
+
+        assert!(matches!(
+            import_snapshot_chunk(chunk),
+            Err(GenericError::InvalidArgument { .. })
+        ));
+        assert!(STATE.with(|state| state.borrow_mut().is_empty()));
+    }
+}
@@ -0,0 +1,166 @@
+use candid::{CandidType, Decode};
+use ic_stable_structures::Storable;
+use omnia_types::{
+    errors::{GenericError, GenericResult},
+    index::Indexes,
+    versioning::{try_decode_versioned, Versioned},
+    CrudMap,
+};
+use serde::de::DeserializeOwned;
+use std::{borrow::Cow, fmt::Debug, ops::Bound};
+
+use crate::State;
+
+/// Names of the maps of the [State], in the order they're migrated and exported
+pub const STORED_MAPS: [&str; 13] = [
+    "virtual_personas",
+    "environments",
+    "registered_gateways",
+    "ip_challenges",
+    "initialized_gateways",
+    "updates",
+    "registered_devices",
+    "valid_access_keys",
+    "gateway_ledgers",
+    "gateway_payouts",
+    "access_key_usages",
+    "access_key_usage_stats",
+    "access_key_secrets",
+];
+
+/// Names of the maps of the [State] with secondary indexes
+pub const INDEXED_MAPS: [&str; 3] = [
+    "registered_gateways",
+    "registered_devices",
+    "valid_access_keys",
+];
+
+/// Stored map whose entries are read and written encoded, regardless of the types of its indexes and values
+pub trait StoredMap {
+    /// See [CrudMap::rewrite_batch]
+    fn rewrite_batch(&mut self, cursor: Option<Vec<u8>>, limit: usize) -> Option<Vec<u8>>;
+
+    /// See [CrudMap::index_batch]
+    fn index_batch(&mut self, cursor: Option<Vec<u8>>, limit: usize) -> Option<Vec<u8>>;
+
+    /// Returns the encoded entries after the cursor, up to `max_bytes` bytes but at least one entry.
+    /// If more entries may follow, the encoded index of the last returned one is returned as well
+    fn export_batch(
+        &self,
+        cursor: Option<Vec<u8>>,
+        max_bytes: usize,
+    ) -> GenericResult<(Vec<(Vec<u8>, Vec<u8>)>, Option<Vec<u8>>)>;
+
+    /// Creates or replaces the encoded entries, none of them if any entry can't be decoded
+    fn import_entries(&mut self, entries: Vec<(Vec<u8>, Vec<u8>)>) -> GenericResult<()>;
+
+    fn is_empty(&self) -> bool;
+}
+
+impl<I, V, X> StoredMap for CrudMap<I, V, X>
+where
+    I: Ord + Debug + Storable + Clone + CandidType + DeserializeOwned,
+    V: Versioned + Storable + Clone,
+    X: Indexes<I, V>,
+{
+    fn rewrite_batch(&mut self, cursor: Option<Vec<u8>>, limit: usize) -> Option<Vec<u8>> {
+        CrudMap::rewrite_batch(self, cursor.map(decode::<I>), limit)
+            .map(|next_cursor| next_cursor.to_bytes().into_owned())
+    }
+
+    fn index_batch(&mut self, cursor: Option<Vec<u8>>, limit: usize) -> Option<Vec<u8>> {
+        CrudMap::index_batch(self, cursor.map(decode::<I>), limit)
+            .map(|next_cursor| next_cursor.to_bytes().into_owned())
+    }
+
+    fn export_batch(
+        &self,
+        cursor: Option<Vec<u8>>,
+        max_bytes: usize,
+    ) -> GenericResult<(Vec<(Vec<u8>, Vec<u8>)>, Option<Vec<u8>>)> {
+        let start = match cursor {
+            Some(cursor) => Bound::Excluded(try_decode_index::<I>(&cursor)?),
+            None => Bound::Unbounded,
+        };
+
+        let mut entries: Vec<(Vec<u8>, Vec<u8>)> = vec![];
+        let mut size = 0;
+        for (index, value) in self.range((start, Bound::Unbounded)) {
+            let entry = (index.to_bytes().into_owned(), value.to_bytes().into_owned());
+            let entry_size = entry.0.len() + entry.1.len();
+            if !entries.is_empty() && size + entry_size > max_bytes {
+                let next_cursor = entries.last().map(|(index, _)| index.clone());
+                return Ok((entries, next_cursor));
+            }
+            size += entry_size;
+            entries.push(entry);
+        }
+
+        Ok((entries, None))
+    }
+
+    fn import_entries(&mut self, entries: Vec<(Vec<u8>, Vec<u8>)>) -> GenericResult<()> {
+        let entries = entries
+            .into_iter()
+            .map(|(index, value)| {
+                let value =
+                    try_decode_versioned::<V>(&value).map_err(GenericError::invalid_argument)?;
+                Ok((try_decode_index::<I>(&index)?, value))
+            })
+            .collect::<GenericResult<Vec<(I, V)>>>()?;
+
+        for (index, value) in entries {
+            self.upsert(index, value);
+        }
+        Ok(())
+    }
+
+    fn is_empty(&self) -> bool {
+        CrudMap::is_empty(self)
+    }
+}
+
+/// Decodes an index that doesn't come from stable memory, which is encoded with Candid like all the indexes of the [State]
+fn try_decode_index<I: CandidType + DeserializeOwned>(bytes: &[u8]) -> GenericResult<I> {
+    Decode!(bytes, I).map_err(|e| {
+        GenericError::invalid_argument(format!(
+            "Cannot decode {}: {}",
+            std::any::type_name::<I>(),
+            e
+        ))
+    })
+}
+
+fn decode<T: Storable>(bytes: Vec<u8>) -> T {
+    T::from_bytes(Cow::Owned(bytes))
+}
+
+impl State {
+    /// Whether all the stored maps are empty
+    pub fn is_empty(&mut self) -> bool {
+        STORED_MAPS.iter().all(|name| {
+            self.get_stored_map(name)
+                .expect("stored map should exist")
+                .is_empty()
+        })
+    }
+
+    pub fn get_stored_map(&mut self, name: &str) -> Option<&mut dyn StoredMap> {
+        match name {
+            "virtual_personas" => Some(&mut self.virtual_personas),
+            "environments" => Some(&mut self.environments),
+            "registered_gateways" => Some(&mut self.registered_gateways),
+            "ip_challenges" => Some(&mut self.ip_challenges),
+            "initialized_gateways" => Some(&mut self.initialized_gateways),
+            "updates" => Some(&mut self.updates),
+            "registered_devices" => Some(&mut self.registered_devices),
+            "valid_access_keys" => Some(&mut self.valid_access_keys),
+            "gateway_ledgers" => Some(&mut self.gateway_ledgers),
+            "gateway_payouts" => Some(&mut self.gateway_payouts),
+            "access_key_usages" => Some(&mut self.access_key_usages),
+            "access_key_usage_stats" => Some(&mut self.access_key_usage_stats),
+            "access_key_secrets" => Some(&mut self.access_key_secrets),
+            _ => None,
+        }
+    }
+}
@@ -1,5 +1,10 @@
 use candid::Principal;
-use ic_cdk::{caller, print, trap};
+use ic_cdk::{api::is_controller, caller, print, trap};
+use omnia_types::{
+    errors::{GenericError, GenericResult},
+    pagination::PageRequest,
+};
+use omnia_utils::constants::PAGE_SIZE_LIMIT;
 
 use crate::OMNIA_BACKEND_PRINCIPAL;
 
@@ -17,6 +22,18 @@ pub fn caller_is_omnia_backend() {
     })
 }
 
+/// Only controllers can call the administrative methods
+pub fn caller_is_controller() -> GenericResult<()> {
+    let caller = caller();
+    if !is_controller(&caller) {
+        return Err(GenericError::Unauthorized {
+            caller,
+            reason: String::from("Only controllers can call this method"),
+        });
+    }
+    Ok(())
+}
+
 pub fn update_omnia_backend_principal(omnia_backend_canister_principal_id: String) {
     print(format!(
         "Omnia Backend canister Principal ID: {:?}",
@@ -29,3 +46,11 @@ pub fn update_omnia_backend_principal(omnia_backend_canister_principal_id: Strin
         *state.borrow_mut() = Some(remote_principal);
     });
 }
+
+/// The number of items to return in the requested page, between 1 and [PAGE_SIZE_LIMIT]
+pub fn get_page_limit(page_request: &PageRequest) -> usize {
+    page_request
+        .limit
+        .unwrap_or(PAGE_SIZE_LIMIT)
+        .clamp(1, PAGE_SIZE_LIMIT) as usize
+}
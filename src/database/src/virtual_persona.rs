@@ -9,6 +9,8 @@ use omnia_types::{
     virtual_persona::{VirtualPersonaPrincipalId, VirtualPersonaValue},
 };
 
+use crate::migrations::are_migrations_completed;
+use crate::snapshot::no_snapshot_import_in_progress;
 use crate::utils::caller_is_omnia_backend;
 use crate::STATE;
 
@@ -19,6 +21,7 @@ fn set_user_in_environment(
     nonce: IpChallengeNonce,
 ) -> EnvironmentInfoResult {
     caller_is_omnia_backend();
+    no_snapshot_import_in_progress();
 
     STATE.with(|state| {
         // validate IP challenge
@@ -33,8 +36,8 @@ fn set_user_in_environment(
         };
         let environment_uid = state
             .borrow()
-            .environment_uids
-            .get_environment_uid_by_ip(environment_uid_index)?;
+            .registered_gateways
+            .get_environment_uid_by_ip(environment_uid_index, are_migrations_completed())?;
         let environment_index = EnvironmentIndex {
             environment_uid: environment_uid.clone(),
         };
@@ -76,6 +79,7 @@ fn reset_user_from_environment(
     nonce: IpChallengeNonce,
 ) -> EnvironmentInfoResult {
     caller_is_omnia_backend();
+    no_snapshot_import_in_progress();
 
     STATE.with(|state| {
         // validate IP challenge
@@ -90,8 +94,8 @@ fn reset_user_from_environment(
         };
         let environment_uid = state
             .borrow()
-            .environment_uids
-            .get_environment_uid_by_ip(environment_uid_index)?;
+            .registered_gateways
+            .get_environment_uid_by_ip(environment_uid_index, are_migrations_completed())?;
         let environment_index = EnvironmentIndex {
             environment_uid: environment_uid.clone(),
         };
@@ -130,6 +134,7 @@ fn get_virtual_persona(
     virtual_persona_principal_id: VirtualPersonaPrincipalId,
 ) -> VirtualPersonaValueResult {
     caller_is_omnia_backend();
+    no_snapshot_import_in_progress();
 
     STATE.with(|state| {
         // validate IP challenge
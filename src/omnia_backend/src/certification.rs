@@ -0,0 +1,858 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use candid::candid_method;
+use ic_cdk::{api::data_certificate, print};
+use ic_cdk_macros::query;
+use ic_certified_map::{
+    fork, fork_hash, labeled, labeled_hash, AsHashTree, Hash, HashTree, RbTree,
+};
+use ic_oxigraph::model::{Quad, Subject, Term};
+use omnia_types::{
+    certification::{CertifiedQuads, CertifiedQuadsResult},
+    device::DeviceUrl,
+    environment::EnvironmentUID,
+    errors::{GenericError, GenericResult},
+    http::{
+        HttpRequest, HttpResponse, IC_CERTIFICATE_EXPRESSION_HEADER_KEY, IC_CERTIFICATE_HEADER_KEY,
+    },
+};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    http_endpoint::{handle_sparql_query, percent_encode},
+    rdf::{
+        get_device_environment_quad, get_device_node, get_environment_devices_quads,
+        get_environment_devices_query, get_environment_quad, get_stored_device_quads, URN_PREFIX,
+    },
+    utils::set_certified_data,
+    CERTIFIED_ENVIRONMENTS, CERTIFIED_QUADS, CERTIFIED_RESPONSES, RDF_DB,
+};
+
+/// Label of the tree that maps the UIDs of the environments to the hashes of their devices
+const ENVIRONMENTS_LABEL: &[u8] = b"environments";
+/// Label of the tree with the certified HTTP responses, as defined by the [HTTP Gateway Protocol](https://internetcomputer.org/docs/current/references/http-gateway-protocol-spec#response-verification)
+const HTTP_EXPR_LABEL: &[u8] = b"http_expr";
+/// Label of the tree with the hashes of the quads
+const QUADS_LABEL: &[u8] = b"quads";
+/// The segments of the exact path `/sparql/query` in the tree of the certified HTTP responses
+const SPARQL_QUERY_PATH: [&str; 3] = ["sparql", "query", "<$>"];
+/// The parameters of the SPARQL endpoint, so that a certified response cannot be returned for a request that passes other ones
+const SPARQL_QUERY_CERTIFIED_PARAMETERS: [&str; 5] = [
+    "query",
+    "default-graph-uri",
+    "named-graph-uri",
+    "continuation",
+    "compact-iris",
+];
+/// Certifies the method and the parameters of the request, along with the status, all the headers and the body of the response
+const SPARQL_QUERY_CERTIFICATE_EXPRESSION: &str = r#"default_certification(ValidationArgs{certification:Certification{request_certification:RequestCertification{certified_request_headers:[],certified_query_parameters:["query","default-graph-uri","named-graph-uri","continuation","compact-iris"]},response_certification:ResponseCertification{response_header_exclusions:ResponseHeaderList{headers:[]}}}})"#;
+/// CBOR tag 55799, which marks the encoded trees as CBOR
+const CBOR_SELF_DESCRIBE_TAG: &[u8] = &[0xd9, 0xd9, 0xf7];
+
+/// The certified responses to the queries of the SPARQL endpoint, which are the leaves of the `http_expr` tree
+#[derive(Default)]
+pub struct CertifiedResponses {
+    /// the hashes of the certified responses, by the hash of their request
+    responses: RbTree<Hash, RbTree<Hash, Vec<u8>>>,
+    /// the hashes of the request and of the response certified for the devices of each environment, which are replaced when its devices change
+    environment_responses: BTreeMap<EnvironmentUID, (Hash, Hash)>,
+}
+
+impl CertifiedResponses {
+    fn insert(&mut self, env_uid: &EnvironmentUID, request_hash: Hash, response_hash: Hash) {
+        self.remove(env_uid);
+
+        let mut responses = RbTree::new();
+        responses.insert(response_hash, vec![]);
+        self.responses.insert(request_hash, responses);
+        self.environment_responses
+            .insert(env_uid.clone(), (request_hash, response_hash));
+    }
+
+    fn remove(&mut self, env_uid: &EnvironmentUID) {
+        if let Some((request_hash, _)) = self.environment_responses.remove(env_uid) {
+            self.responses.delete(&request_hash);
+        }
+    }
+
+    fn contains(&self, request_hash: &Hash, response_hash: &Hash) -> bool {
+        self.responses
+            .get(request_hash)
+            .and_then(|responses| responses.get(response_hash))
+            .is_some()
+    }
+
+    /// The root hash of the `http_expr` tree, whose only path is the one of the SPARQL endpoint
+    fn root_hash(&self) -> Hash {
+        let expression_hash = get_hash(SPARQL_QUERY_CERTIFICATE_EXPRESSION.as_bytes());
+        let responses_hash = labeled_hash(&expression_hash, &self.responses.root_hash());
+
+        SPARQL_QUERY_PATH
+            .into_iter()
+            .rev()
+            .fold(responses_hash, |hash, segment| {
+                labeled_hash(segment.as_bytes(), &hash)
+            })
+    }
+
+    /// The witness of the response, or of its absence, along the path of the SPARQL endpoint
+    fn witness<'a>(
+        &'a self,
+        expression_hash: &'a Hash,
+        request_hash: &Hash,
+        response_hash: &Hash,
+    ) -> HashTree<'a> {
+        let responses_witness = self
+            .responses
+            .nested_witness(request_hash, |responses| responses.witness(response_hash));
+
+        SPARQL_QUERY_PATH.into_iter().rev().fold(
+            labeled(expression_hash, responses_witness),
+            |witness, segment| labeled(segment.as_bytes(), witness),
+        )
+    }
+}
+
+fn get_hash(data: &[u8]) -> Hash {
+    Sha256::digest(data).into()
+}
+
+/// SHA-256 of the quad in N-Quads, without the line terminator
+fn get_quad_hash(quad: &Quad) -> Hash {
+    get_hash(format!("{} .", quad).as_bytes())
+}
+
+/// SHA-256 of the sorted hashes of the quads returned by [get_environment_devices_quads], so that none of them can be omitted
+fn get_environment_devices_hash(quads: &[Quad]) -> Hash {
+    let mut quad_hashes: Vec<Hash> = quads.iter().map(get_quad_hash).collect();
+    quad_hashes.sort_unstable();
+
+    get_hash(&quad_hashes.concat())
+}
+
+/// The UID of the environment if the quad is one of the quads returned by [get_environment_devices_quads]
+fn get_quad_environment_uid(quad: &Quad) -> Option<EnvironmentUID> {
+    let env_uid = match &quad.subject {
+        Subject::NamedNode(node) => node
+            .as_str()
+            .strip_prefix(&format!("{}uuid:", URN_PREFIX))?
+            .to_string(),
+        _ => return None,
+    };
+
+    let is_environment_devices_quad = match &quad.object {
+        Term::NamedNode(device_node) => {
+            *quad == get_device_environment_quad(&env_uid, device_node.clone())
+                || *quad == get_environment_quad(&env_uid)
+        }
+        _ => false,
+    };
+
+    is_environment_devices_quad.then_some(env_uid)
+}
+
+/// The root hash of the tree with the labeled subtrees, which is the certified data of the canister
+fn get_root_hash() -> Hash {
+    let environments_hash = CERTIFIED_ENVIRONMENTS
+        .with(|certified_environments| certified_environments.borrow().root_hash());
+    let http_expr_hash =
+        CERTIFIED_RESPONSES.with(|certified_responses| certified_responses.borrow().root_hash());
+    let quads_hash = CERTIFIED_QUADS.with(|certified_quads| certified_quads.borrow().root_hash());
+
+    fork_hash(
+        &fork_hash(
+            &labeled_hash(ENVIRONMENTS_LABEL, &environments_hash),
+            &labeled_hash(HTTP_EXPR_LABEL, &http_expr_hash),
+        ),
+        &labeled_hash(QUADS_LABEL, &quads_hash),
+    )
+}
+
+/// The labeled witness of the subtree, or the hash of the whole subtree if it's not revealed
+fn reveal<'a>(label: &'a [u8], witness: Option<HashTree<'a>>, root_hash: Hash) -> HashTree<'a> {
+    match witness {
+        Some(witness) => labeled(label, witness),
+        None => HashTree::Pruned(labeled_hash(label, &root_hash)),
+    }
+}
+
+/// The tree whose root hash is the certified data, in which only the witnesses passed are revealed, encoded in self-described CBOR
+fn get_certified_tree(
+    environments_witness: Option<HashTree>,
+    http_expr_witness: Option<HashTree>,
+    quads_witness: Option<HashTree>,
+) -> GenericResult<Vec<u8>> {
+    let environments_tree = CERTIFIED_ENVIRONMENTS.with(|certified_environments| {
+        reveal(
+            ENVIRONMENTS_LABEL,
+            environments_witness,
+            certified_environments.borrow().root_hash(),
+        )
+    });
+    let http_expr_tree = CERTIFIED_RESPONSES.with(|certified_responses| {
+        reveal(
+            HTTP_EXPR_LABEL,
+            http_expr_witness,
+            certified_responses.borrow().root_hash(),
+        )
+    });
+    let quads_tree = CERTIFIED_QUADS.with(|certified_quads| {
+        reveal(
+            QUADS_LABEL,
+            quads_witness,
+            certified_quads.borrow().root_hash(),
+        )
+    });
+
+    serialize_cbor(&fork(fork(environments_tree, http_expr_tree), quads_tree))
+}
+
+fn update_certified_data() {
+    set_certified_data(&get_root_hash());
+}
+
+/// Certifies the devices of the environment and the response to the query that selects them, or removes them if the environment doesn't exist
+fn update_certified_environment(env_uid: &EnvironmentUID) {
+    let quads = match get_environment_devices_quads(env_uid) {
+        Ok(quads) => Some(quads),
+        Err(GenericError::NotFound { .. }) => None,
+        Err(e) => {
+            print(format!(
+                "Error certifying devices of environment {}: {}",
+                env_uid, e
+            ));
+            None
+        }
+    };
+
+    CERTIFIED_ENVIRONMENTS.with(|certified_environments| {
+        let mut certified_environments = certified_environments.borrow_mut();
+        match &quads {
+            Some(quads) => {
+                certified_environments.insert(env_uid.clone(), get_environment_devices_hash(quads))
+            }
+            None => certified_environments.delete(env_uid.as_bytes()),
+        }
+    });
+
+    let certified_response = quads
+        .and_then(|_| get_environment_devices_response(env_uid))
+        .map(|(request, response)| (get_request_hash(&request), get_response_hash(&response)));
+    CERTIFIED_RESPONSES.with(|certified_responses| {
+        let mut certified_responses = certified_responses.borrow_mut();
+        match certified_response {
+            Some((request_hash, response_hash)) => {
+                certified_responses.insert(env_uid, request_hash, response_hash)
+            }
+            None => certified_responses.remove(env_uid),
+        }
+    });
+}
+
+/// The request that runs the query of the devices of the environment with `GET`, encoded like `encodeURIComponent` does,
+/// along with its response, which can be certified only if it's complete
+fn get_environment_devices_response(
+    env_uid: &EnvironmentUID,
+) -> Option<(HttpRequest, HttpResponse)> {
+    let request = HttpRequest {
+        method: String::from("GET"),
+        url: format!(
+            "/sparql/query?query={}",
+            percent_encode(&get_environment_devices_query(env_uid))
+        ),
+        headers: vec![],
+        body: None,
+        upgrade: None,
+    };
+
+    match handle_sparql_query(request.clone(), false) {
+        Ok(response) if response.status_code == 200 && response.streaming_strategy.is_none() => {
+            Some((request, response))
+        }
+        Ok(_) => None,
+        Err(e) => {
+            print(format!(
+                "Error certifying query of devices of environment {}: {}",
+                env_uid, e
+            ));
+            None
+        }
+    }
+}
+
+/// Certifies the responses to the queries of the devices of all the environments again, since they depend on the limits of the queries
+pub fn certify_environment_devices_responses() {
+    let env_uids: Vec<EnvironmentUID> = CERTIFIED_ENVIRONMENTS.with(|certified_environments| {
+        certified_environments
+            .borrow()
+            .iter()
+            .map(|(env_uid, _)| env_uid.clone())
+            .collect()
+    });
+    for env_uid in env_uids.iter() {
+        update_certified_environment(env_uid);
+    }
+    update_certified_data();
+}
+
+/// Collects the environments whose devices are changed by the quads of a write, so that each of them is certified once, along with the certified data,
+/// when the batch is dropped at the end of the write, even if the write fails halfway
+#[derive(Default)]
+pub struct CertificationBatch {
+    env_uids: BTreeSet<EnvironmentUID>,
+}
+
+impl CertificationBatch {
+    pub fn insert_quad(&mut self, quad: &Quad) {
+        CERTIFIED_QUADS.with(|certified_quads| {
+            certified_quads
+                .borrow_mut()
+                .insert(get_quad_hash(quad), vec![])
+        });
+        self.env_uids.extend(get_quad_environment_uid(quad));
+    }
+
+    pub fn remove_quad(&mut self, quad: &Quad) {
+        CERTIFIED_QUADS
+            .with(|certified_quads| certified_quads.borrow_mut().delete(&get_quad_hash(quad)));
+        self.env_uids.extend(get_quad_environment_uid(quad));
+    }
+}
+
+impl Drop for CertificationBatch {
+    fn drop(&mut self) {
+        for env_uid in self.env_uids.iter() {
+            update_certified_environment(env_uid);
+        }
+        update_certified_data();
+    }
+}
+
+/// Certifies all the quads of the RDF database and the devices of its environments, which must be called whenever the database is loaded
+pub fn init_certified_data() {
+    let mut env_uids = BTreeSet::new();
+    RDF_DB.with(|store| {
+        CERTIFIED_QUADS.with(|certified_quads| {
+            let mut certified_quads = certified_quads.borrow_mut();
+            *certified_quads = RbTree::new();
+
+            for quad in store.borrow().iter() {
+                let quad = quad.expect("failed to read RDF dataset");
+                certified_quads.insert(get_quad_hash(&quad), vec![]);
+                env_uids.extend(get_quad_environment_uid(&quad));
+            }
+        })
+    });
+
+    CERTIFIED_ENVIRONMENTS.with(|certified_environments| {
+        *certified_environments.borrow_mut() = RbTree::new();
+    });
+    CERTIFIED_RESPONSES.with(|certified_responses| {
+        *certified_responses.borrow_mut() = CertifiedResponses::default();
+    });
+    for env_uid in env_uids.iter() {
+        update_certified_environment(env_uid);
+    }
+    update_certified_data();
+}
+
+/// Merges the witnesses of the same tree, so that the merged one reveals what any of them reveals
+fn merge_hash_trees<'a>(lhs: HashTree<'a>, rhs: HashTree<'a>) -> HashTree<'a> {
+    match (lhs, rhs) {
+        (HashTree::Pruned(_), rhs) => rhs,
+        (lhs, HashTree::Pruned(_)) => lhs,
+        (HashTree::Fork(lhs), HashTree::Fork(rhs)) => {
+            let (lhs_left, lhs_right) = *lhs;
+            let (rhs_left, rhs_right) = *rhs;
+            fork(
+                merge_hash_trees(lhs_left, rhs_left),
+                merge_hash_trees(lhs_right, rhs_right),
+            )
+        }
+        (HashTree::Labeled(label, lhs), HashTree::Labeled(_, rhs)) => {
+            labeled(label, merge_hash_trees(*lhs, *rhs))
+        }
+        // the other nodes are the same in both witnesses
+        (lhs, _) => lhs,
+    }
+}
+
+fn get_certificate() -> GenericResult<Vec<u8>> {
+    data_certificate()
+        .ok_or_else(|| GenericError::internal("The certificate is available only in query calls"))
+}
+
+/// The quads along with the witness that they're in the certified quads, which is available only in query calls
+fn get_certified_quads(quads: Vec<Quad>) -> CertifiedQuadsResult {
+    let certificate = get_certificate()?;
+
+    let tree = CERTIFIED_QUADS.with(|certified_quads| {
+        let certified_quads = certified_quads.borrow();
+        let witness = quads
+            .iter()
+            .map(|quad| certified_quads.witness(&get_quad_hash(quad)))
+            .reduce(merge_hash_trees)
+            .unwrap_or_else(|| HashTree::Pruned(certified_quads.root_hash()));
+
+        get_certified_tree(None, None, Some(witness))
+    })?;
+
+    Ok(CertifiedQuads {
+        quads: quads.iter().map(|quad| format!("{} .", quad)).collect(),
+        certificate,
+        tree,
+    })
+}
+
+/// Encodes the value in self-described CBOR, like the trees of the certified variables of the Internet Computer
+fn serialize_cbor(value: &impl serde::Serialize) -> GenericResult<Vec<u8>> {
+    let mut buffer = CBOR_SELF_DESCRIBE_TAG.to_vec();
+    ciborium::ser::into_writer(value, &mut buffer)
+        .map_err(|e| GenericError::internal(format!("Error serializing to CBOR: {}", e)))?;
+
+    Ok(buffer)
+}
+
+/// SHA-256 of the map, computed as defined by the [representation-independent hash](https://internetcomputer.org/docs/current/references/ic-interface-spec#hash-of-map)
+/// of its keys and of the hashes of its values
+fn get_representation_independent_hash(map: &[(String, Hash)]) -> Hash {
+    let mut hashes: Vec<(Hash, Hash)> = map
+        .iter()
+        .map(|(key, value_hash)| (get_hash(key.as_bytes()), *value_hash))
+        .collect();
+    hashes.sort_unstable();
+
+    let mut hasher = Sha256::new();
+    for (key_hash, value_hash) in hashes.iter() {
+        hasher.update(key_hash);
+        hasher.update(value_hash);
+    }
+    hasher.finalize().into()
+}
+
+/// SHA-256 of the number encoded in unsigned LEB128
+fn get_number_hash(mut number: u64) -> Hash {
+    let mut bytes = vec![];
+    loop {
+        let byte = (number & 0x7f) as u8;
+        number >>= 7;
+        if number == 0 {
+            bytes.push(byte);
+            break;
+        }
+        bytes.push(byte | 0x80);
+    }
+    get_hash(&bytes)
+}
+
+/// Hash of the method and of the certified query parameters of the request, as they're passed in the URL, along with its body
+fn get_request_hash(request: &HttpRequest) -> Hash {
+    let mut certified_headers = vec![(
+        String::from(":ic-cert-method"),
+        get_hash(request.method.as_bytes()),
+    )];
+    if let Some((_, query_string)) = request.url.split_once('?') {
+        let certified_query_string = query_string
+            .split('&')
+            .filter(|param| {
+                let name = param.split('=').next().unwrap_or_default();
+                SPARQL_QUERY_CERTIFIED_PARAMETERS
+                    .iter()
+                    .any(|certified_name| certified_name.eq_ignore_ascii_case(name))
+            })
+            .collect::<Vec<&str>>()
+            .join("&");
+        certified_headers.push((
+            String::from(":ic-cert-query"),
+            get_hash(certified_query_string.as_bytes()),
+        ));
+    }
+
+    get_hash(
+        &[
+            get_representation_independent_hash(&certified_headers),
+            get_hash(request.body.as_deref().unwrap_or_default()),
+        ]
+        .concat(),
+    )
+}
+
+/// Hash of the status, of the headers and of the body of the response, with the certificate expression that the response carries
+fn get_response_hash(response: &HttpResponse) -> Hash {
+    let mut certified_headers: Vec<(String, Hash)> = response
+        .headers
+        .iter()
+        .filter(|(name, _)| {
+            !name.eq_ignore_ascii_case(IC_CERTIFICATE_HEADER_KEY)
+                && !name.eq_ignore_ascii_case(IC_CERTIFICATE_EXPRESSION_HEADER_KEY)
+        })
+        .map(|(name, value)| (name.to_ascii_lowercase(), get_hash(value.as_bytes())))
+        .collect();
+    certified_headers.extend([
+        (
+            IC_CERTIFICATE_EXPRESSION_HEADER_KEY.to_ascii_lowercase(),
+            get_hash(SPARQL_QUERY_CERTIFICATE_EXPRESSION.as_bytes()),
+        ),
+        (
+            String::from(":ic-cert-status"),
+            get_number_hash(response.status_code as u64),
+        ),
+    ]);
+
+    get_hash(
+        &[
+            get_representation_independent_hash(&certified_headers),
+            get_hash(&response.body),
+        ]
+        .concat(),
+    )
+}
+
+/// Adds the `IC-Certificate` header to the response of a query call of the SPARQL endpoint if it's certified,
+/// so that the HTTP gateways can verify it. The other responses are returned as they are
+pub fn certify_sparql_query_response(request: &HttpRequest, response: &mut HttpResponse) {
+    let request_hash = get_request_hash(request);
+    let response_hash = get_response_hash(response);
+    let is_certified = CERTIFIED_RESPONSES.with(|certified_responses| {
+        certified_responses
+            .borrow()
+            .contains(&request_hash, &response_hash)
+    });
+    if !is_certified {
+        return;
+    }
+
+    match get_certificate_header(&request_hash, &response_hash) {
+        Ok(certificate_header) => response.headers.extend([
+            (
+                String::from(IC_CERTIFICATE_EXPRESSION_HEADER_KEY),
+                String::from(SPARQL_QUERY_CERTIFICATE_EXPRESSION),
+            ),
+            (String::from(IC_CERTIFICATE_HEADER_KEY), certificate_header),
+        ]),
+        Err(e) => print(format!("Error certifying response: {}", e)),
+    }
+}
+
+/// The `IC-Certificate` header, version 2, with the witness of the response along the path of the SPARQL endpoint
+fn get_certificate_header(request_hash: &Hash, response_hash: &Hash) -> GenericResult<String> {
+    let certificate = get_certificate()?;
+    let expression_hash = get_hash(SPARQL_QUERY_CERTIFICATE_EXPRESSION.as_bytes());
+    let tree = CERTIFIED_RESPONSES.with(|certified_responses| {
+        let certified_responses = certified_responses.borrow();
+        get_certified_tree(
+            None,
+            Some(certified_responses.witness(&expression_hash, request_hash, response_hash)),
+            None,
+        )
+    })?;
+    let expr_path: Vec<&str> = [std::str::from_utf8(HTTP_EXPR_LABEL).unwrap()]
+        .into_iter()
+        .chain(SPARQL_QUERY_PATH)
+        .collect();
+
+    Ok(format!(
+        "certificate=:{}:, tree=:{}:, expr_path=:{}:, version=2",
+        BASE64.encode(certificate),
+        BASE64.encode(tree),
+        BASE64.encode(serialize_cbor(&expr_path)?)
+    ))
+}
+
+#[query(name = "getCertifiedQuadsRoot")]
+#[candid_method(query, rename = "getCertifiedQuadsRoot")]
+/// No quads are returned: the tree prunes the whole tree of the quads, whose root hash is certified
+fn get_certified_quads_root() -> CertifiedQuadsResult {
+    get_certified_quads(vec![])
+}
+
+#[query(name = "getCertifiedEnvironmentDevices")]
+#[candid_method(query, rename = "getCertifiedEnvironmentDevices")]
+/// The quad that declares the environment and the ones that relate the devices to it,
+/// with the witness of the hash of all of them under the UID of the environment
+fn get_certified_environment_devices(env_uid: EnvironmentUID) -> CertifiedQuadsResult {
+    let quads = get_environment_devices_quads(&env_uid)?;
+    let certificate = get_certificate()?;
+
+    let tree = CERTIFIED_ENVIRONMENTS.with(|certified_environments| {
+        get_certified_tree(
+            Some(certified_environments.borrow().witness(env_uid.as_bytes())),
+            None,
+            None,
+        )
+    })?;
+
+    Ok(CertifiedQuads {
+        quads: quads.iter().map(|quad| format!("{} .", quad)).collect(),
+        certificate,
+        tree,
+    })
+}
+
+#[query(name = "getCertifiedDeviceDescription")]
+#[candid_method(query, rename = "getCertifiedDeviceDescription")]
+/// The quads that describe the device and its graph with the Thing Description
+fn get_certified_device_description(device_url: DeviceUrl) -> CertifiedQuadsResult {
+    let device_quads = get_stored_device_quads(&get_device_node(&device_url)?)?;
+    if device_quads.is_empty() {
+        return Err(GenericError::NotFound {
+            entity: String::from("Device"),
+            id: device_url,
+        });
+    }
+
+    get_certified_quads(device_quads)
+}
+
+#[cfg(test)]
+mod tests {
+    use ic_oxigraph::model::{Literal, NamedNode};
+
+    use super::*;
+    use crate::rdf::{insert_quads, remove_device_quads, replace_quads};
+
+    const ENV_UID: &str = "9a2e6f3c-3a1b-4c43-9a7c-5f3c0b6e1d2a";
+
+    /// The labels revealed by the tree, which are the hashes of the quads in the witnesses of the certified quads
+    fn get_labels(tree: &HashTree) -> Vec<Vec<u8>> {
+        match tree {
+            HashTree::Fork(forked_trees) => {
+                [get_labels(&forked_trees.0), get_labels(&forked_trees.1)].concat()
+            }
+            HashTree::Labeled(label, labeled_tree) => {
+                [vec![label.to_vec()], get_labels(labeled_tree)].concat()
+            }
+            _ => vec![],
+        }
+    }
+
+    /// The root hash of the certified data of the RDF database, computed from scratch
+    fn get_rdf_db_root_hash() -> Hash {
+        let certified_root_hash = get_root_hash();
+        init_certified_data();
+        let root_hash = get_root_hash();
+        assert_eq!(root_hash, certified_root_hash);
+        root_hash
+    }
+
+    /// The hash of the devices of the environment revealed by the witness of the certified environments
+    fn get_certified_environment_devices_hash() -> Option<Hash> {
+        CERTIFIED_ENVIRONMENTS.with(|certified_environments| {
+            let certified_environments = certified_environments.borrow();
+            let witness = certified_environments.witness(ENV_UID.as_bytes());
+            assert_eq!(witness.reconstruct(), certified_environments.root_hash());
+
+            fn find_leaf(tree: &HashTree) -> Option<Hash> {
+                match tree {
+                    HashTree::Fork(forked_trees) => {
+                        find_leaf(&forked_trees.0).or_else(|| find_leaf(&forked_trees.1))
+                    }
+                    HashTree::Labeled(label, labeled_tree) if *label == ENV_UID.as_bytes() => {
+                        match labeled_tree.as_ref() {
+                            HashTree::Leaf(leaf) => Some(leaf.as_ref().try_into().unwrap()),
+                            _ => None,
+                        }
+                    }
+                    _ => None,
+                }
+            }
+            find_leaf(&witness)
+        })
+    }
+
+    fn get_device_quads(device_url: &str) -> Vec<Quad> {
+        let device_node = NamedNode::new(device_url).unwrap();
+        vec![
+            get_device_environment_quad(&String::from(ENV_UID), device_node.clone()),
+            Quad::new(
+                device_node.clone(),
+                NamedNode::new("https://www.w3.org/2019/wot/td#title").unwrap(),
+                Literal::new_simple_literal(device_url),
+                device_node,
+            ),
+        ]
+    }
+
+    #[test]
+    fn test_certified_quads_follow_rdf_db() {
+        let empty_root_hash = get_rdf_db_root_hash();
+
+        let device_quads = get_device_quads("https://proxy.example.com/device-1");
+        insert_quads(&[get_environment_quad(&String::from(ENV_UID))]).unwrap();
+        insert_quads(&device_quads).unwrap();
+        let root_hash = get_rdf_db_root_hash();
+        assert_ne!(root_hash, empty_root_hash);
+
+        // inserting the same quads again doesn't change the root hash
+        insert_quads(&device_quads).unwrap();
+        assert_eq!(get_rdf_db_root_hash(), root_hash);
+
+        replace_quads(
+            &device_quads,
+            &get_device_quads("https://proxy.example.com/device-2"),
+        )
+        .unwrap();
+        get_rdf_db_root_hash();
+
+        remove_device_quads(&NamedNode::new("https://proxy.example.com/device-2").unwrap())
+            .unwrap();
+        assert_ne!(get_rdf_db_root_hash(), empty_root_hash);
+    }
+
+    #[test]
+    fn test_merged_witness() {
+        insert_quads(&[get_environment_quad(&String::from(ENV_UID))]).unwrap();
+        for i in 0..10 {
+            insert_quads(&get_device_quads(&format!(
+                "https://proxy.example.com/device-{}",
+                i
+            )))
+            .unwrap();
+        }
+        let quads = get_environment_devices_quads(&String::from(ENV_UID)).unwrap();
+        assert_eq!(quads.len(), 11);
+
+        CERTIFIED_QUADS.with(|certified_quads| {
+            let certified_quads = certified_quads.borrow();
+            let witness = quads
+                .iter()
+                .map(|quad| certified_quads.witness(&get_quad_hash(quad)))
+                .reduce(merge_hash_trees)
+                .unwrap();
+
+            assert_eq!(witness.reconstruct(), certified_quads.root_hash());
+            let labels = get_labels(&witness);
+            for quad in quads.iter() {
+                assert!(labels.contains(&get_quad_hash(quad).to_vec()));
+            }
+            // the witness doesn't reveal the quads that describe the devices
+            assert_eq!(labels.len(), quads.len());
+        });
+    }
+
+    #[test]
+    fn test_certified_environment_devices() {
+        assert_eq!(get_certified_environment_devices_hash(), None);
+
+        insert_quads(&[get_environment_quad(&String::from(ENV_UID))]).unwrap();
+        for i in 0..3 {
+            insert_quads(&get_device_quads(&format!(
+                "https://proxy.example.com/device-{}",
+                i
+            )))
+            .unwrap();
+        }
+        let quads = get_environment_devices_quads(&String::from(ENV_UID)).unwrap();
+        assert_eq!(quads.len(), 4);
+        assert_eq!(
+            get_certified_environment_devices_hash(),
+            Some(get_environment_devices_hash(&quads))
+        );
+
+        // the hash doesn't match the devices if one of them is omitted
+        assert_ne!(
+            get_certified_environment_devices_hash(),
+            Some(get_environment_devices_hash(&quads[..3]))
+        );
+
+        remove_device_quads(&NamedNode::new("https://proxy.example.com/device-0").unwrap())
+            .unwrap();
+        let quads = get_environment_devices_quads(&String::from(ENV_UID)).unwrap();
+        assert_eq!(quads.len(), 3);
+        assert_eq!(
+            get_certified_environment_devices_hash(),
+            Some(get_environment_devices_hash(&quads))
+        );
+        get_rdf_db_root_hash();
+    }
+
+    #[test]
+    fn test_certified_environment_devices_batch() {
+        // the environment is certified once, when all the quads of the write are in the database
+        let mut quads = vec![get_environment_quad(&String::from(ENV_UID))];
+        for i in 0..3 {
+            quads.extend(get_device_quads(&format!(
+                "https://proxy.example.com/device-{}",
+                i
+            )));
+        }
+        insert_quads(&quads).unwrap();
+        let environment_quads = get_environment_devices_quads(&String::from(ENV_UID)).unwrap();
+        assert_eq!(environment_quads.len(), 4);
+        assert_eq!(
+            get_certified_environment_devices_hash(),
+            Some(get_environment_devices_hash(&environment_quads))
+        );
+
+        // only the environment and the first device are kept
+        replace_quads(&quads, &quads[..3]).unwrap();
+        let environment_quads = get_environment_devices_quads(&String::from(ENV_UID)).unwrap();
+        assert_eq!(environment_quads.len(), 2);
+        assert_eq!(
+            get_certified_environment_devices_hash(),
+            Some(get_environment_devices_hash(&environment_quads))
+        );
+        get_rdf_db_root_hash();
+    }
+
+    #[test]
+    fn test_certified_sparql_query_response() {
+        let env_uid = String::from(ENV_UID);
+        insert_quads(&[get_environment_quad(&env_uid)]).unwrap();
+        insert_quads(&get_device_quads("https://proxy.example.com/device-1")).unwrap();
+
+        let (request, response) = get_environment_devices_response(&env_uid).unwrap();
+        assert!(String::from_utf8(response.body.clone())
+            .unwrap()
+            .contains("https://proxy.example.com/device-1"));
+        let request_hash = get_request_hash(&request);
+        let response_hash = get_response_hash(&response);
+
+        CERTIFIED_RESPONSES.with(|certified_responses| {
+            let certified_responses = certified_responses.borrow();
+            assert!(certified_responses.contains(&request_hash, &response_hash));
+
+            let expression_hash = get_hash(SPARQL_QUERY_CERTIFICATE_EXPRESSION.as_bytes());
+            let witness =
+                certified_responses.witness(&expression_hash, &request_hash, &response_hash);
+            assert_eq!(witness.reconstruct(), certified_responses.root_hash());
+        });
+
+        // the parameters that are not passed to the SPARQL endpoint are not certified
+        let mut other_request = request.clone();
+        other_request.url.push_str("&other=1");
+        assert_eq!(get_request_hash(&other_request), request_hash);
+        other_request.url.push_str("&compact-iris=true");
+        assert_ne!(get_request_hash(&other_request), request_hash);
+
+        // the responses that are not certified are returned as they are
+        let mut other_response = response.clone();
+        certify_sparql_query_response(&other_request, &mut other_response);
+        assert_eq!(other_response, response);
+
+        // the certified response changes along with the devices of the environment
+        insert_quads(&get_device_quads("https://proxy.example.com/device-2")).unwrap();
+        CERTIFIED_RESPONSES.with(|certified_responses| {
+            assert!(!certified_responses
+                .borrow()
+                .contains(&request_hash, &response_hash));
+        });
+        get_rdf_db_root_hash();
+    }
+
+    #[test]
+    fn test_representation_independent_hash() {
+        let map = [
+            (String::from("name"), get_hash(b"foo")),
+            (String::from("message"), get_hash(b"Hello World!")),
+            (String::from("answer"), get_number_hash(42)),
+        ];
+
+        assert_eq!(
+            hex::encode(get_representation_independent_hash(&map)),
+            "b0c6f9191e37dceafdfc47fbfc7e9cc95f21c7b985c2f7ba5855015c2a8f13ac"
+        );
+        assert_eq!(get_number_hash(300), get_hash(&[0xac, 0x02]));
+    }
+}
@@ -0,0 +1,693 @@
+use std::future::Future;
+
+use crate::utils::get_database_principal;
+use candid::{
+    decode_args, encode_args,
+    utils::{ArgumentDecoder, ArgumentEncoder},
+    Principal,
+};
+use ic_cdk::api::call::{call_raw, CallResult};
+use omnia_core_sdk::access_key::AccessKeyUID;
+use omnia_types::{
+    access_key::{
+        AccessKeyCreationArgs, AccessKeyCreationResult, AccessKeyUsageResult, OwnedAccessKey,
+        RejectedAccessKey, ServedRequest,
+    },
+    device::{
+        DeviceUid, RegisteredDeviceIndex, RegisteredDeviceResult, RegisteredDeviceValue,
+        RegisteredDevicesUidsResult,
+    },
+    environment::{
+        EnvironmentCreationInput, EnvironmentCreationResult, EnvironmentInfoResult, EnvironmentUID,
+    },
+    errors::{CallRejectionCode, GenericError, GenericResult},
+    gateway::{
+        GatewayLedgerIndex, GatewayLedgerValue, GatewayPayoutIndex, GatewayPayoutValue,
+        GatewayPayoutsResult, GatewayPrincipalId, GatewayRegistrationInput,
+        InitializedGatewayValue, MultipleRegisteredGatewayResult, PendingGatewayPayout,
+        RegisteredGatewayResult,
+    },
+    http::{IpChallengeNonce, IpChallengeValue},
+    pagination::{Page, PageRequest},
+    updates::{PairingPayload, UpdateValueOption, UpdateValueResult},
+    virtual_persona::{VirtualPersonaPrincipalId, VirtualPersonaValueResult},
+};
+use omnia_utils::constants::INTER_CANISTER_CALL_MAX_RETRIES;
+
+/// Abstracts the raw inter-canister calls, so that the database client can be tested against a mock canister
+pub trait CanisterCaller {
+    async fn call_raw(
+        &self,
+        canister_id: Principal,
+        method: &str,
+        args: &[u8],
+    ) -> CallResult<Vec<u8>>;
+}
+
+/// Performs the calls through the system API
+pub struct IcCaller;
+
+impl CanisterCaller for IcCaller {
+    async fn call_raw(
+        &self,
+        canister_id: Principal,
+        method: &str,
+        args: &[u8],
+    ) -> CallResult<Vec<u8>> {
+        call_raw(canister_id, method, args, 0).await
+    }
+}
+
+/// Typed client of the database canister.
+///
+/// Rejected calls are returned as [GenericError::InterCanisterCallFailed] instead of trapping.
+/// Calls rejected with [CallRejectionCode::SysTransient] have not been executed by the database:
+/// the idempotent ones are retried by the client, the others are returned so that the callers can retry them later (see [is_transient_error]).
+pub struct DatabaseClient<C: CanisterCaller = IcCaller> {
+    caller: C,
+    database_principal: Principal,
+}
+
+/// Whether the call was rejected before being executed by the database, because it was temporarily unavailable
+pub fn is_transient_error(error: &GenericError) -> bool {
+    matches!(
+        error,
+        GenericError::InterCanisterCallFailed {
+            rejection_code: CallRejectionCode::SysTransient,
+            ..
+        }
+    )
+}
+
+fn encode_call_args<A: ArgumentEncoder>(method: &str, args: A) -> GenericResult<Vec<u8>> {
+    encode_args(args).map_err(|e| {
+        GenericError::internal(format!("Error encoding arguments of {}: {}", method, e))
+    })
+}
+
+/// Returns the client of the database canister, whose principal is passed as init argument
+pub fn get_database_client() -> DatabaseClient {
+    DatabaseClient::new(IcCaller, get_database_principal())
+}
+
+impl<C: CanisterCaller> DatabaseClient<C> {
+    pub fn new(caller: C, database_principal: Principal) -> Self {
+        Self {
+            caller,
+            database_principal,
+        }
+    }
+
+    /// Retries the calls rejected with [CallRejectionCode::SysTransient] up to [INTER_CANISTER_CALL_MAX_RETRIES] times,
+    /// so it's used only for the methods that have the same effect when executed more than once
+    async fn call<A: ArgumentEncoder, R: for<'a> ArgumentDecoder<'a>>(
+        &self,
+        method: &str,
+        args: A,
+    ) -> GenericResult<R> {
+        let args = encode_call_args(method, args)?;
+
+        let mut retries = 0;
+        loop {
+            match self.call_encoded(method, &args).await {
+                Err(e) if is_transient_error(&e) && retries < INTER_CANISTER_CALL_MAX_RETRIES => {
+                    retries += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Performs the call a single time, for the methods that consume a nonce, create entities or move funds:
+    /// the transient errors are returned to the callers, which decide whether to retry
+    async fn call_once<A: ArgumentEncoder, R: for<'a> ArgumentDecoder<'a>>(
+        &self,
+        method: &str,
+        args: A,
+    ) -> GenericResult<R> {
+        let args = encode_call_args(method, args)?;
+        self.call_encoded(method, &args).await
+    }
+
+    async fn call_encoded<R: for<'a> ArgumentDecoder<'a>>(
+        &self,
+        method: &str,
+        args: &[u8],
+    ) -> GenericResult<R> {
+        self.caller
+            .call_raw(self.database_principal, method, args)
+            .await
+            .map_err(|e| {
+                GenericError::inter_canister_call_failed(self.database_principal, method, e)
+            })
+            .and_then(|reply| {
+                decode_args(&reply).map_err(|e| {
+                    GenericError::internal(format!("Error decoding reply of {}: {}", method, e))
+                })
+            })
+    }
+
+    /// Until the migrations are completed, the database answers the listings by scanning its maps instead of their indexes
+    pub async fn migrations_completed(&self) -> GenericResult<bool> {
+        self.call("migrations_completed", ())
+            .await
+            .map(|(completed,)| completed)
+    }
+
+    pub async fn init_nonce_to_ip(
+        &self,
+        nonce: IpChallengeNonce,
+        ip_challenge_value: IpChallengeValue,
+    ) -> GenericResult<()> {
+        self.call_once("init_nonce_to_ip", (nonce, ip_challenge_value))
+            .await
+    }
+
+    pub async fn check_if_virtual_persona_exists(
+        &self,
+        virtual_persona_principal_id: VirtualPersonaPrincipalId,
+    ) -> GenericResult<bool> {
+        self.call(
+            "check_if_virtual_persona_exists",
+            (virtual_persona_principal_id,),
+        )
+        .await
+        .map(|(exists,)| exists)
+    }
+
+    pub async fn get_virtual_persona(
+        &self,
+        nonce: IpChallengeNonce,
+        virtual_persona_principal_id: VirtualPersonaPrincipalId,
+    ) -> VirtualPersonaValueResult {
+        self.call_once("get_virtual_persona", (nonce, virtual_persona_principal_id))
+            .await
+            .and_then(|(res,)| res)
+    }
+
+    pub async fn set_user_in_environment(
+        &self,
+        virtual_persona_principal_id: VirtualPersonaPrincipalId,
+        nonce: IpChallengeNonce,
+    ) -> EnvironmentInfoResult {
+        self.call_once(
+            "set_user_in_environment",
+            (virtual_persona_principal_id, nonce),
+        )
+        .await
+        .and_then(|(res,)| res)
+    }
+
+    pub async fn reset_user_from_environment(
+        &self,
+        virtual_persona_principal_id: VirtualPersonaPrincipalId,
+        nonce: IpChallengeNonce,
+    ) -> EnvironmentInfoResult {
+        self.call_once(
+            "reset_user_from_environment",
+            (virtual_persona_principal_id, nonce),
+        )
+        .await
+        .and_then(|(res,)| res)
+    }
+
+    pub async fn create_new_environment(
+        &self,
+        environment_manager_principal_id: VirtualPersonaPrincipalId,
+        environment_creation_input: EnvironmentCreationInput,
+    ) -> GenericResult<EnvironmentCreationResult> {
+        self.call_once(
+            "create_new_environment",
+            (environment_manager_principal_id, environment_creation_input),
+        )
+        .await
+        .and_then(|(res,)| res)
+    }
+
+    pub async fn is_gateway_registered(
+        &self,
+        gateway_principal_id: GatewayPrincipalId,
+    ) -> GenericResult<bool> {
+        self.call("is_gateway_registered", (gateway_principal_id,))
+            .await
+            .map(|(is_registered,)| is_registered)
+    }
+
+    pub async fn init_gateway_by_ip(
+        &self,
+        nonce: IpChallengeNonce,
+        gateway_principal_id: GatewayPrincipalId,
+    ) -> GenericResult<GatewayPrincipalId> {
+        self.call_once("init_gateway_by_ip", (nonce, gateway_principal_id))
+            .await
+            .and_then(|(res,)| res)
+    }
+
+    pub async fn get_initialized_gateways_by_ip(
+        &self,
+        nonce: IpChallengeNonce,
+    ) -> GenericResult<Vec<InitializedGatewayValue>> {
+        self.call_once("get_initialized_gateways_by_ip", (nonce,))
+            .await
+            .and_then(|(res,)| res)
+    }
+
+    pub async fn register_gateway_in_environment(
+        &self,
+        nonce: IpChallengeNonce,
+        environment_manager_principal_id: VirtualPersonaPrincipalId,
+        gateway_registration_input: GatewayRegistrationInput,
+    ) -> RegisteredGatewayResult {
+        self.call_once(
+            "register_gateway_in_environment",
+            (
+                nonce,
+                environment_manager_principal_id,
+                gateway_registration_input,
+            ),
+        )
+        .await
+        .and_then(|(res,)| res)
+    }
+
+    pub async fn get_registered_gateways_in_environment(
+        &self,
+        environment_uid: EnvironmentUID,
+        page_request: PageRequest,
+        gateway_name_prefix: Option<String>,
+    ) -> MultipleRegisteredGatewayResult {
+        self.call(
+            "get_registered_gateways_in_environment",
+            (environment_uid, page_request, gateway_name_prefix),
+        )
+        .await
+        .and_then(|(res,)| res)
+    }
+
+    pub async fn get_gateway_updates_by_principal(
+        &self,
+        gateway_principal_id: GatewayPrincipalId,
+    ) -> GenericResult<UpdateValueOption> {
+        self.call_once("get_gateway_updates_by_principal", (gateway_principal_id,))
+            .await
+            .map(|(update_value,)| update_value)
+    }
+
+    pub async fn pair_new_device_on_gateway(
+        &self,
+        nonce: IpChallengeNonce,
+        manager_principal_id: VirtualPersonaPrincipalId,
+        gateway_principal_id: GatewayPrincipalId,
+        pairing_payload: PairingPayload,
+    ) -> UpdateValueResult {
+        self.call_once(
+            "pair_new_device_on_gateway",
+            (
+                nonce,
+                manager_principal_id,
+                gateway_principal_id,
+                pairing_payload,
+            ),
+        )
+        .await
+        .and_then(|(res,)| res)
+    }
+
+    pub async fn register_device_on_gateway(
+        &self,
+        nonce: IpChallengeNonce,
+        gateway_principal_id: GatewayPrincipalId,
+    ) -> RegisteredDeviceResult {
+        self.call_once("register_device_on_gateway", (nonce, gateway_principal_id))
+            .await
+            .and_then(|(res,)| res)
+    }
+
+    pub async fn get_registered_devices_on_gateway(
+        &self,
+        gateway_principal_id: GatewayPrincipalId,
+        page_request: PageRequest,
+    ) -> RegisteredDevicesUidsResult {
+        self.call(
+            "get_registered_devices_on_gateway",
+            (gateway_principal_id, page_request),
+        )
+        .await
+        .and_then(|(res,)| res)
+    }
+
+    pub async fn unregister_device_on_gateway(
+        &self,
+        gateway_principal_id: GatewayPrincipalId,
+        device_uid: DeviceUid,
+    ) -> GenericResult<()> {
+        self.call_once(
+            "unregister_device_on_gateway",
+            (gateway_principal_id, device_uid),
+        )
+        .await
+        .and_then(|(res,)| res)
+    }
+
+    pub async fn get_registered_device(&self, device_uid: DeviceUid) -> RegisteredDeviceResult {
+        self.call("get_registered_device", (device_uid,))
+            .await
+            .and_then(|(res,)| res)
+    }
+
+    pub async fn get_environment_uids(
+        &self,
+        page_request: PageRequest,
+    ) -> GenericResult<Page<EnvironmentUID>> {
+        self.call("get_environment_uids", (page_request,))
+            .await
+            .map(|(environment_uids,)| environment_uids)
+    }
+
+    pub async fn get_registered_devices(
+        &self,
+        page_request: PageRequest,
+        environment_uid: EnvironmentUID,
+    ) -> GenericResult<Page<(RegisteredDeviceIndex, RegisteredDeviceValue)>> {
+        self.call("get_registered_devices", (page_request, environment_uid))
+            .await
+            .map(|(registered_devices,)| registered_devices)
+    }
+
+    pub async fn create_new_access_key(
+        &self,
+        access_key_creation_args: AccessKeyCreationArgs,
+    ) -> AccessKeyCreationResult {
+        self.call_once("create_new_access_key", (access_key_creation_args,))
+            .await
+            .and_then(|(res,)| res)
+    }
+
+    pub async fn get_access_keys_by_owner(
+        &self,
+        owner: Principal,
+        page_request: PageRequest,
+    ) -> GenericResult<Page<OwnedAccessKey>> {
+        self.call("get_access_keys_by_owner", (owner, page_request))
+            .await
+            .map(|(owned_access_keys,)| owned_access_keys)
+    }
+
+    pub async fn get_access_key_usage(
+        &self,
+        owner: Principal,
+        access_key_uid: AccessKeyUID,
+        page_request: PageRequest,
+    ) -> AccessKeyUsageResult {
+        self.call(
+            "get_access_key_usage",
+            (owner, access_key_uid, page_request),
+        )
+        .await
+        .and_then(|(res,)| res)
+    }
+
+    pub async fn spend_requests_for_keys(
+        &self,
+        gateway_principal_id: GatewayPrincipalId,
+        served_requests: Vec<ServedRequest>,
+    ) -> GenericResult<Vec<RejectedAccessKey>> {
+        self.call_once(
+            "spend_requests_for_keys",
+            (gateway_principal_id, served_requests),
+        )
+        .await
+        .and_then(|(res,)| res)
+    }
+
+    pub async fn get_unpaid_gateway_ledgers(
+        &self,
+        page_request: PageRequest,
+    ) -> GenericResult<Page<(GatewayLedgerIndex, GatewayLedgerValue)>> {
+        self.call("get_unpaid_gateway_ledgers", (page_request,))
+            .await
+            .map(|(gateway_ledgers,)| gateway_ledgers)
+    }
+
+    pub async fn start_gateway_payout(
+        &self,
+        gateway_ledger_index: GatewayLedgerIndex,
+        pending_payout: PendingGatewayPayout,
+    ) -> GenericResult<GatewayLedgerValue> {
+        self.call_once(
+            "start_gateway_payout",
+            (gateway_ledger_index, pending_payout),
+        )
+        .await
+        .and_then(|(res,)| res)
+    }
+
+    pub async fn cancel_gateway_payout(
+        &self,
+        gateway_ledger_index: GatewayLedgerIndex,
+        pending_payout: PendingGatewayPayout,
+    ) -> GenericResult<GatewayLedgerValue> {
+        self.call_once(
+            "cancel_gateway_payout",
+            (gateway_ledger_index, pending_payout),
+        )
+        .await
+        .and_then(|(res,)| res)
+    }
+
+    pub async fn record_gateway_payout(
+        &self,
+        gateway_payout_index: GatewayPayoutIndex,
+        gateway_payout_value: GatewayPayoutValue,
+    ) -> GenericResult<GatewayLedgerValue> {
+        self.call_once(
+            "record_gateway_payout",
+            (gateway_payout_index, gateway_payout_value),
+        )
+        .await
+        .and_then(|(res,)| res)
+    }
+
+    pub async fn get_gateway_payouts(
+        &self,
+        gateway_principal_id: GatewayPrincipalId,
+        page_request: PageRequest,
+    ) -> GatewayPayoutsResult {
+        self.call("get_gateway_payouts", (gateway_principal_id, page_request))
+            .await
+            .and_then(|(res,)| res)
+    }
+}
+
+/// Requests the pages one after the other and collects all their items, for the lists that the Backend needs entirely
+pub async fn collect_pages<T, F: Future<Output = GenericResult<Page<T>>>>(
+    get_page: impl Fn(PageRequest) -> F,
+) -> GenericResult<Vec<T>> {
+    let mut items = vec![];
+    let mut page_request = PageRequest::default();
+
+    loop {
+        let page = get_page(page_request).await?;
+        items.extend(page.items);
+
+        match page.next_cursor {
+            Some(cursor) => {
+                page_request = PageRequest {
+                    cursor: Some(cursor),
+                    limit: None,
+                }
+            }
+            None => return Ok(items),
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::{
+        cell::RefCell,
+        collections::VecDeque,
+        future::Future,
+        pin::pin,
+        task::{Context, Poll, Waker},
+    };
+
+    use candid::CandidType;
+    use ic_cdk::api::call::RejectionCode;
+
+    use super::*;
+
+    /// The mocks never await, so futures complete on the first poll
+    pub fn block_on<F: Future>(future: F) -> F::Output {
+        match pin!(future).poll(&mut Context::from_waker(Waker::noop())) {
+            Poll::Ready(output) => output,
+            Poll::Pending => panic!("future is not ready"),
+        }
+    }
+
+    pub const DATABASE_PRINCIPAL_ID: &str = "rrkah-fqaaa-aaaaa-aaaaq-cai";
+
+    /// Mock canister that returns the queued replies, in order, and records the called methods
+    #[derive(Default)]
+    pub struct MockCaller {
+        replies: RefCell<VecDeque<CallResult<Vec<u8>>>>,
+        pub calls: RefCell<Vec<String>>,
+    }
+
+    impl MockCaller {
+        pub fn reply<R: CandidType>(self, reply: R) -> Self {
+            self.replies
+                .borrow_mut()
+                .push_back(Ok(encode_args((reply,)).unwrap()));
+            self
+        }
+
+        pub fn reject(self, rejection_code: RejectionCode, message: &str) -> Self {
+            self.replies
+                .borrow_mut()
+                .push_back(Err((rejection_code, message.to_string())));
+            self
+        }
+
+        pub fn into_client(self) -> DatabaseClient<Self> {
+            DatabaseClient::new(self, Principal::from_text(DATABASE_PRINCIPAL_ID).unwrap())
+        }
+    }
+
+    pub fn called_methods(database: &DatabaseClient<MockCaller>) -> Vec<String> {
+        database.caller.calls.borrow().clone()
+    }
+
+    impl CanisterCaller for MockCaller {
+        async fn call_raw(
+            &self,
+            _canister_id: Principal,
+            method: &str,
+            _args: &[u8],
+        ) -> CallResult<Vec<u8>> {
+            self.calls.borrow_mut().push(method.to_string());
+            self.replies
+                .borrow_mut()
+                .pop_front()
+                .unwrap_or_else(|| panic!("unexpected call to {}", method))
+        }
+    }
+
+    #[test]
+    fn test_call_retries_transient_errors() {
+        let database = MockCaller::default()
+            .reject(RejectionCode::SysTransient, "queue full")
+            .reject(RejectionCode::SysTransient, "queue full")
+            .reply(true)
+            .into_client();
+
+        assert_eq!(
+            block_on(database.is_gateway_registered(String::from("gateway"))),
+            Ok(true)
+        );
+        assert_eq!(database.caller.calls.borrow().len(), 3);
+    }
+
+    #[test]
+    fn test_call_returns_transient_errors_after_retries() {
+        let mut caller = MockCaller::default();
+        for _ in 0..=INTER_CANISTER_CALL_MAX_RETRIES {
+            caller = caller.reject(RejectionCode::SysTransient, "queue full");
+        }
+        let database = caller.into_client();
+
+        let result = block_on(database.is_gateway_registered(String::from("gateway")));
+        assert_eq!(
+            result,
+            Err(GenericError::InterCanisterCallFailed {
+                canister_id: Principal::from_text(DATABASE_PRINCIPAL_ID).unwrap(),
+                method: String::from("is_gateway_registered"),
+                rejection_code: CallRejectionCode::SysTransient,
+                message: String::from("queue full"),
+            })
+        );
+        assert!(is_transient_error(&result.unwrap_err()));
+        assert_eq!(
+            database.caller.calls.borrow().len(),
+            INTER_CANISTER_CALL_MAX_RETRIES as usize + 1
+        );
+    }
+
+    #[test]
+    fn test_call_once_returns_transient_errors() {
+        let database = MockCaller::default()
+            .reject(RejectionCode::SysTransient, "queue full")
+            .into_client();
+
+        // the nonce has not been used, the caller can register the gateway again with it
+        let result = block_on(database.register_gateway_in_environment(
+            String::from("nonce"),
+            String::from("manager"),
+            GatewayRegistrationInput {
+                env_uid: String::from("environment"),
+                gateway_name: String::from("gateway"),
+            },
+        ));
+        assert!(is_transient_error(&result.unwrap_err()));
+        assert_eq!(database.caller.calls.borrow().len(), 1);
+    }
+
+    #[test]
+    fn test_call_returns_other_errors() {
+        let database = MockCaller::default()
+            .reject(RejectionCode::CanisterError, "out of cycles")
+            .into_client();
+
+        let result = block_on(database.is_gateway_registered(String::from("gateway")));
+        assert!(matches!(
+            result,
+            Err(GenericError::InterCanisterCallFailed {
+                rejection_code: CallRejectionCode::CanisterError,
+                ..
+            })
+        ));
+        assert!(!is_transient_error(&result.unwrap_err()));
+        assert_eq!(database.caller.calls.borrow().len(), 1);
+    }
+
+    #[test]
+    fn test_call_flattens_database_errors() {
+        let database_error = GenericError::ChallengeExpired {
+            nonce: String::from("nonce"),
+        };
+        let database = MockCaller::default()
+            .reply::<GenericResult<GatewayPrincipalId>>(Err(database_error.clone()))
+            .into_client();
+
+        assert_eq!(
+            block_on(database.init_gateway_by_ip(String::from("nonce"), String::from("gateway"))),
+            Err(database_error)
+        );
+    }
+
+    #[test]
+    fn test_collect_pages() {
+        let database = MockCaller::default()
+            .reply(Page {
+                items: vec![String::from("environment1"), String::from("environment2")],
+                next_cursor: Some(String::from("environment2")),
+            })
+            .reply(Page {
+                items: vec![String::from("environment3")],
+                next_cursor: None,
+            })
+            .into_client();
+
+        assert_eq!(
+            block_on(collect_pages(
+                |page_request| database.get_environment_uids(page_request)
+            )),
+            Ok(vec![
+                String::from("environment1"),
+                String::from("environment2"),
+                String::from("environment3")
+            ])
+        );
+        assert_eq!(database.caller.calls.borrow().len(), 2);
+    }
+}
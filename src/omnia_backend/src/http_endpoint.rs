@@ -1,33 +1,573 @@
 use std::collections::BTreeMap;
 
-use crate::{rdf::execute_sparql_query, utils::get_database_principal};
+use crate::{
+    certification::certify_sparql_query_response,
+    database_client::get_database_client,
+    prefixes::get_prefix_map,
+    rdf::{dump_quads, execute_sparql_query_from},
+    service_description::get_service_description,
+    streaming::{get_sparql_results_chunks, get_streaming_secret, stream_chunks},
+};
 use candid::candid_method;
-use omnia_types::http::{
-    HttpRequest, HttpResponse, IpChallengeValue, ParsedHttpRequestBody,
-    ACCESS_CONTROL_ALLOW_ORIGIN_HEADER_KEY, CONNECTION_HEADER_KEY, CONTENT_TYPE_HEADER_KEY,
+use omnia_types::{
+    errors::{GenericError, GenericResult},
+    http::{
+        HttpRequest, HttpResponse, IpChallengeValue, ParsedHttpRequestBody, ACCEPT_HEADER_KEY,
+        ACCESS_CONTROL_ALLOW_HEADERS_HEADER_KEY, ACCESS_CONTROL_ALLOW_METHODS_HEADER_KEY,
+        ACCESS_CONTROL_ALLOW_ORIGIN_HEADER_KEY, ACCESS_CONTROL_EXPOSE_HEADERS_HEADER_KEY,
+        ACCESS_CONTROL_MAX_AGE_HEADER_KEY, ALLOW_HEADER_KEY, CONNECTION_HEADER_KEY,
+        CONTENT_TYPE_HEADER_KEY, SPARQL_CONTINUATION_HEADER_KEY,
+    },
+    sparql::{SparqlContinuationToken, SparqlDataset},
 };
 use omnia_utils::net::is_proxy_ip;
 
-use ic_cdk::api::{call::call, time};
+use ic_cdk::api::time;
 use ic_cdk_macros::{query, update};
-use serde_json::from_slice;
+use ic_oxigraph::{io::GraphFormat, model::NamedNode};
+use serde_json::{from_slice, to_vec};
+
+const SPARQL_QUERY_MEDIA_TYPE: &str = "application/sparql-query";
+const FORM_URLENCODED_MEDIA_TYPE: &str = "application/x-www-form-urlencoded";
+const TURTLE_MEDIA_TYPE: &str = "text/turtle";
+const N_QUADS_MEDIA_TYPE: &str = "application/n-quads";
+/// The formats of the graphs returned by the `CONSTRUCT` and `DESCRIBE` queries, starting from the default one
+const GRAPH_FORMATS: [GraphFormat; 2] = [GraphFormat::Turtle, GraphFormat::NTriples];
+/// How long the browsers can cache the response to the CORS preflight requests
+const CORS_PREFLIGHT_MAX_AGE_SECONDS: u64 = 86_400;
+
+/// The percent-decoded parameters of a query string, or of a form-urlencoded body, in the order they're passed
+fn parse_params(query_string: &str) -> Vec<(String, String)> {
+    query_string
+        .split('&')
+        .filter(|param| !param.is_empty())
+        .map(|param| match param.split_once('=') {
+            Some((name, value)) => (percent_decode(name), percent_decode(value)),
+            None => (percent_decode(param), String::new()),
+        })
+        .collect()
+}
+
+fn get_url_params(url: &str) -> Vec<(String, String)> {
+    url.split_once('?')
+        .map(|(_, query_string)| parse_params(query_string))
+        .unwrap_or_default()
+}
+
+/// The percent-decoded value of the parameter in the query string of the URL, if present
+fn get_url_param(url: &str, name: &str) -> Option<String> {
+    get_url_params(url)
+        .into_iter()
+        .find_map(|(param_name, value)| (param_name == name).then_some(value))
+}
+
+/// The media type of the body, without its parameters (like `charset`)
+fn get_media_type(req: &HttpRequest) -> Option<String> {
+    req.headers
+        .iter()
+        .find(|(header, _)| header.eq_ignore_ascii_case(CONTENT_TYPE_HEADER_KEY))
+        .map(|(_, value)| {
+            let media_type = value.split(';').next().unwrap_or_default();
+            media_type.trim().to_ascii_lowercase()
+        })
+}
+
+/// The graph format with the highest quality in the `Accept` header, where the most specific media range that matches a format sets its quality.
+/// The default format is returned if the header is missing or if it doesn't accept any format
+fn get_accepted_graph_format(req: &HttpRequest) -> GraphFormat {
+    let accept = match req
+        .headers
+        .iter()
+        .find(|(header, _)| header.eq_ignore_ascii_case(ACCEPT_HEADER_KEY))
+    {
+        Some((_, accept)) => accept.to_ascii_lowercase(),
+        None => return GRAPH_FORMATS[0],
+    };
+    let media_ranges: Vec<(&str, f32)> = accept
+        .split(',')
+        .map(|media_range| {
+            let mut params = media_range.split(';');
+            let range = params.next().unwrap_or_default().trim();
+            let quality = params
+                .find_map(|param| param.trim().strip_prefix("q="))
+                .and_then(|quality| quality.trim().parse().ok())
+                .unwrap_or(1.0);
+            (range, quality)
+        })
+        .collect();
+
+    let get_quality = |graph_format: GraphFormat| {
+        let media_type = graph_format.media_type();
+        let type_range = media_type.split('/').next().unwrap_or_default();
+        media_ranges
+            .iter()
+            .filter_map(|(range, quality)| {
+                let specificity = match range.split_once('/') {
+                    _ if *range == media_type => 2,
+                    Some((range_type, "*")) if range_type == type_range => 1,
+                    Some(("*", "*")) => 0,
+                    _ => return None,
+                };
+                Some((specificity, *quality))
+            })
+            .max_by_key(|(specificity, _)| *specificity)
+            .map_or(0.0, |(_, quality)| quality)
+    };
+
+    let mut accepted_format: Option<(GraphFormat, f32)> = None;
+    for graph_format in GRAPH_FORMATS {
+        let quality = get_quality(graph_format);
+        // on equal qualities, the format that comes first wins
+        if quality > accepted_format.map_or(0.0, |(_, accepted_quality)| accepted_quality) {
+            accepted_format = Some((graph_format, quality));
+        }
+    }
+    accepted_format.map_or(GRAPH_FORMATS[0], |(graph_format, _)| graph_format)
+}
+
+fn get_body_text(req: &HttpRequest) -> GenericResult<String> {
+    req.body
+        .clone()
+        .map(String::from_utf8)
+        .transpose()
+        .map_err(|_| GenericError::invalid_argument("The body must be UTF-8 text"))
+        .map(Option::unwrap_or_default)
+}
+
+/// Decodes the `%XX` sequences and the `+` of a query string value, leaving the invalid sequences as they are
+fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' if i + 2 < bytes.len()
+                && bytes[i + 1].is_ascii_hexdigit()
+                && bytes[i + 2].is_ascii_hexdigit() =>
+            {
+                let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).unwrap();
+                decoded.push(u8::from_str_radix(hex, 16).unwrap());
+                i += 2;
+            }
+            b'+' => decoded.push(b' '),
+            byte => decoded.push(byte),
+        }
+        i += 1;
+    }
+
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// Encodes all the characters of the value but the unreserved ones, like `encodeURIComponent` does for the characters of a SPARQL query
+pub fn percent_encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                char::from(byte).to_string()
+            }
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+fn get_error_response(e: GenericError) -> HttpResponse {
+    HttpResponse {
+        status_code: match e {
+            GenericError::InvalidArgument { .. } | GenericError::LimitExceeded { .. } => 400,
+            _ => 500,
+        },
+        headers: vec![
+            (
+                String::from(CONTENT_TYPE_HEADER_KEY),
+                String::from("text/plain"),
+            ),
+            (
+                String::from(ACCESS_CONTROL_ALLOW_ORIGIN_HEADER_KEY),
+                String::from("*"),
+            ),
+        ],
+        body: format!("Error: {}", e).into(),
+        streaming_strategy: None,
+        upgrade: None,
+    }
+}
+
+/// The parameters of the query, passed as described by the [SPARQL 1.1 Protocol](https://www.w3.org/TR/sparql11-protocol/#query-operation):
+/// in the URL with `GET`, in the body with a form-urlencoded `POST`, or in the URL with a `POST` whose body is the query.
+/// Returns None if the media type of the body is not supported
+fn get_sparql_query_params(req: &HttpRequest) -> GenericResult<Option<Vec<(String, String)>>> {
+    let url_params = get_url_params(&req.url);
+
+    match (req.method.as_str(), get_media_type(req).as_deref()) {
+        ("GET", _) => Ok(Some(url_params)),
+        ("POST", Some(FORM_URLENCODED_MEDIA_TYPE)) => Ok(Some(
+            [url_params, parse_params(&get_body_text(req)?)].concat(),
+        )),
+        // the query was sent as the raw body before the SPARQL 1.1 Protocol was supported, so the media type is optional
+        ("POST", Some(SPARQL_QUERY_MEDIA_TYPE) | None) => Ok(Some(
+            [
+                vec![(String::from("query"), get_body_text(req)?)],
+                url_params,
+            ]
+            .concat(),
+        )),
+        _ => Ok(None),
+    }
+}
+
+/// The solutions that don't fit in a single response are evaluated at once by an update call and kept by the canister, which streams them.
+/// Until the streaming secret is generated, the client requests the next pages with a continuation token instead, which evaluates the query again from the end of the previous page
+pub fn handle_sparql_query(req: HttpRequest, is_update: bool) -> GenericResult<HttpResponse> {
+    let params = match get_sparql_query_params(&req)? {
+        Some(params) => params,
+        None => {
+            return Ok(HttpResponse {
+                status_code: 415,
+                headers: vec![
+                    (
+                        String::from(CONTENT_TYPE_HEADER_KEY),
+                        String::from("text/plain"),
+                    ),
+                    (
+                        String::from(ACCESS_CONTROL_ALLOW_ORIGIN_HEADER_KEY),
+                        String::from("*"),
+                    ),
+                ],
+                body: format!(
+                    "Unsupported Media Type: the query must be sent as {} or {}",
+                    SPARQL_QUERY_MEDIA_TYPE, FORM_URLENCODED_MEDIA_TYPE
+                )
+                .into(),
+                streaming_strategy: None,
+                upgrade: None,
+            })
+        }
+    };
+
+    let mut queries = vec![];
+    let mut dataset = SparqlDataset::default();
+    let mut continuation_token = None;
+    let mut compact_iris = false;
+    for (name, value) in params {
+        match name.as_str() {
+            "query" => queries.push(value),
+            "default-graph-uri" => dataset.default_graph_uris.push(value),
+            "named-graph-uri" => dataset.named_graph_uris.push(value),
+            "continuation" => continuation_token = Some(value),
+            "compact-iris" => compact_iris = value == "true",
+            _ => (),
+        }
+    }
+    // the endpoint describes itself when it's dereferenced without a query
+    if req.method == "GET" && queries.is_empty() {
+        return handle_service_description();
+    }
+    let query = match <[String; 1]>::try_from(queries) {
+        Ok([query]) => query,
+        Err(queries) => {
+            return Err(GenericError::invalid_argument(format!(
+                "Exactly one query must be passed, got: {}",
+                queries.len()
+            )))
+        }
+    };
+
+    // the token carries the number of results returned by the previous pages, which are skipped when the query is evaluated again
+    let continuation_offset = continuation_token
+        .map(|token| SparqlContinuationToken::decode(&token, &query, &dataset))
+        .transpose()?
+        .map(|continuation_token| continuation_token.offset);
+    // the pages requested with a continuation token are paginated as the first one was
+    let streaming_secret = match continuation_offset {
+        Some(_) => None,
+        None => get_streaming_secret(),
+    };
+    let offset = continuation_offset.unwrap_or(0);
+
+    // query calls only evaluate the first page, which is enough to know whether the results fit in a single response
+    let query_pages = execute_sparql_query_from(
+        &query,
+        &dataset,
+        compact_iris,
+        get_accepted_graph_format(&req),
+        usize::try_from(offset).unwrap_or(usize::MAX),
+        is_update && streaming_secret.is_some(),
+    )?;
+    let media_type = query_pages.media_type();
+    let mut response = get_sparql_results_response(query_pages.pages[0].clone(), media_type);
+    if query_pages.exceeded_limit.is_none() {
+        return Ok(response);
+    }
+
+    let secret = match streaming_secret {
+        Some(secret) => secret,
+        None => {
+            add_continuation_token(
+                &mut response,
+                SparqlContinuationToken::new(
+                    &query,
+                    &dataset,
+                    offset + query_pages.first_page_rows,
+                ),
+            );
+            return Ok(response);
+        }
+    };
+    // the pages that didn't fit in this response are kept by the canister, which requires an update call to evaluate them
+    if !is_update {
+        return Ok(get_upgrade_response());
+    }
+    // the pages of a graph are documents that can be concatenated, so they're already the chunks of a single body
+    let mut chunks = match query_pages.graph_format {
+        Some(_) => query_pages.pages,
+        None => get_sparql_results_chunks(query_pages.pages)?,
+    };
+    let next_chunks = chunks.split_off(1);
+    response.body = chunks.remove(0);
+    match stream_chunks(&secret, next_chunks, media_type) {
+        Some(streaming_strategy) => response.streaming_strategy = Some(streaming_strategy),
+        None => return Ok(get_results_cache_full_response()),
+    }
+
+    Ok(response)
+}
+
+fn get_sparql_results_response(results: Vec<u8>, media_type: &str) -> HttpResponse {
+    HttpResponse {
+        status_code: 200,
+        headers: vec![
+            (
+                String::from(CONTENT_TYPE_HEADER_KEY),
+                String::from(media_type),
+            ),
+            (
+                String::from(ACCESS_CONTROL_ALLOW_ORIGIN_HEADER_KEY),
+                String::from("*"),
+            ),
+        ],
+        body: results,
+        streaming_strategy: None,
+        upgrade: None,
+    }
+}
+
+/// The next page of the results can be requested with the continuation token
+fn add_continuation_token(
+    response: &mut HttpResponse,
+    continuation_token: SparqlContinuationToken,
+) {
+    response.headers.extend([
+        (
+            String::from(SPARQL_CONTINUATION_HEADER_KEY),
+            continuation_token.encode(),
+        ),
+        (
+            String::from(ACCESS_CONTROL_EXPOSE_HEADERS_HEADER_KEY),
+            String::from(SPARQL_CONTINUATION_HEADER_KEY),
+        ),
+    ]);
+}
+
+/// The bodies that don't fit in a single response cannot be kept until the ones being downloaded expire
+fn get_results_cache_full_response() -> HttpResponse {
+    get_service_unavailable_response("too many results are being downloaded, retry later")
+}
+
+fn get_service_unavailable_response(reason: &str) -> HttpResponse {
+    HttpResponse {
+        status_code: 503,
+        headers: vec![
+            (
+                String::from(CONTENT_TYPE_HEADER_KEY),
+                String::from("text/plain"),
+            ),
+            (
+                String::from(ACCESS_CONTROL_ALLOW_ORIGIN_HEADER_KEY),
+                String::from("*"),
+            ),
+        ],
+        body: format!("Service Unavailable: {}", reason).into(),
+        streaming_strategy: None,
+        upgrade: None,
+    }
+}
+
+/// This response is directed to the boundary node so that it can upgrade the initial query request "http_request" to an update request "http_request_update"
+fn get_upgrade_response() -> HttpResponse {
+    HttpResponse {
+        status_code: 101, // this is the HTTP status code to request an Upgrade of the protocol (it's anyway ignored by the Boundary node)
+        headers: vec![
+            // this header is optional and we use it just to explain which protocol we are upgrading to
+            (
+                String::from(CONNECTION_HEADER_KEY),
+                String::from("IC_http_update_request"),
+            ),
+        ],
+        body: "".into(),
+        streaming_strategy: None,
+        upgrade: Some(true),
+    }
+}
+
+/// The service description of the SPARQL endpoint, with the VoID description of its dataset
+fn handle_service_description() -> GenericResult<HttpResponse> {
+    Ok(HttpResponse {
+        status_code: 200,
+        headers: vec![
+            (
+                String::from(CONTENT_TYPE_HEADER_KEY),
+                String::from(TURTLE_MEDIA_TYPE),
+            ),
+            (
+                String::from(ACCESS_CONTROL_ALLOW_ORIGIN_HEADER_KEY),
+                String::from("*"),
+            ),
+        ],
+        body: get_service_description()?.into(),
+        streaming_strategy: None,
+        upgrade: None,
+    })
+}
+
+/// The prefixes that the SPARQL queries can use without declaring them, as a JSON object that maps them to their namespaces
+fn handle_prefix_map() -> GenericResult<HttpResponse> {
+    let prefix_map: BTreeMap<String, String> = get_prefix_map()?.into_iter().collect();
+
+    Ok(HttpResponse {
+        status_code: 200,
+        headers: vec![
+            (
+                String::from(CONTENT_TYPE_HEADER_KEY),
+                String::from("application/json"),
+            ),
+            (
+                String::from(ACCESS_CONTROL_ALLOW_ORIGIN_HEADER_KEY),
+                String::from("*"),
+            ),
+        ],
+        body: to_vec(&prefix_map)
+            .map_err(|e| GenericError::internal(format!("Error serializing prefix map: {}", e)))?,
+        streaming_strategy: None,
+        upgrade: None,
+    })
+}
+
+/// Dumps the named graph passed in the `graph` parameter, or the whole dataset, in N-Quads.
+/// The dumps that don't fit in a single response are evaluated at once by an update call and streamed
+fn handle_rdf_dump(req: HttpRequest, is_update: bool) -> GenericResult<HttpResponse> {
+    let graph = get_url_param(&req.url, "graph")
+        .map(|graph| {
+            NamedNode::new(&graph).map_err(|e| {
+                GenericError::invalid_argument(format!("Invalid graph {}: {}", graph, e))
+            })
+        })
+        .transpose()?;
+    // query calls only dump the first chunk, which is enough to know whether the dump fits in a single response
+    let (mut chunks, has_next_chunk) = dump_quads(graph.as_ref(), is_update)?;
+
+    let mut response = HttpResponse {
+        status_code: 200,
+        headers: vec![
+            (
+                String::from(CONTENT_TYPE_HEADER_KEY),
+                String::from(N_QUADS_MEDIA_TYPE),
+            ),
+            (
+                String::from(ACCESS_CONTROL_ALLOW_ORIGIN_HEADER_KEY),
+                String::from("*"),
+            ),
+        ],
+        body: vec![],
+        streaming_strategy: None,
+        upgrade: None,
+    };
+    if !has_next_chunk && chunks.len() == 1 {
+        response.body = chunks.remove(0);
+        return Ok(response);
+    }
+
+    if !is_update {
+        return Ok(get_upgrade_response());
+    }
+    // the dump cannot be paginated, so the client has to retry once the streaming secret is generated
+    let secret = match get_streaming_secret() {
+        Some(secret) => secret,
+        None => {
+            return Ok(get_service_unavailable_response(
+                "the dump cannot be streamed yet",
+            ))
+        }
+    };
+    let next_chunks = chunks.split_off(1);
+    response.body = chunks.remove(0);
+    match stream_chunks(&secret, next_chunks, N_QUADS_MEDIA_TYPE) {
+        Some(streaming_strategy) => response.streaming_strategy = Some(streaming_strategy),
+        None => return Ok(get_results_cache_full_response()),
+    }
+
+    Ok(response)
+}
+
+/// Lets the browsers send the requests of the SPARQL 1.1 Protocol from any origin
+fn get_cors_preflight_response() -> HttpResponse {
+    HttpResponse {
+        status_code: 204,
+        headers: vec![
+            (
+                String::from(ACCESS_CONTROL_ALLOW_ORIGIN_HEADER_KEY),
+                String::from("*"),
+            ),
+            (
+                String::from(ACCESS_CONTROL_ALLOW_METHODS_HEADER_KEY),
+                String::from("GET, POST, OPTIONS"),
+            ),
+            (
+                String::from(ACCESS_CONTROL_ALLOW_HEADERS_HEADER_KEY),
+                String::from("Accept, Content-Type"),
+            ),
+            (
+                String::from(ACCESS_CONTROL_MAX_AGE_HEADER_KEY),
+                CORS_PREFLIGHT_MAX_AGE_SECONDS.to_string(),
+            ),
+        ],
+        body: vec![],
+        streaming_strategy: None,
+        upgrade: None,
+    }
+}
 
 #[query]
 #[candid_method(query)]
 fn http_request(req: HttpRequest) -> HttpResponse {
-    // only allow POST method
-    if req.method != "POST" {
+    if req.method == "OPTIONS" {
+        return get_cors_preflight_response();
+    }
+
+    // only allow POST method, and GET for the SPARQL queries and prefixes, the dumps and the VoID description
+    let is_get_allowed = req.url.starts_with("/sparql/query")
+        || req.url.starts_with("/sparql/prefixes")
+        || req.url.starts_with("/rdf/dump")
+        || req.url.starts_with("/.well-known/void");
+    if req.method != "POST" && !(req.method == "GET" && is_get_allowed) {
         return HttpResponse {
             status_code: 405,
             headers: vec![
                 (
                     String::from(CONTENT_TYPE_HEADER_KEY),
-                    String::from("plain/text"),
+                    String::from("text/plain"),
                 ),
                 (
                     String::from(ACCESS_CONTROL_ALLOW_ORIGIN_HEADER_KEY),
                     String::from("*"),
                 ),
+                (
+                    String::from(ALLOW_HEADER_KEY),
+                    String::from(match is_get_allowed {
+                        true => "GET, POST, OPTIONS",
+                        false => "POST, OPTIONS",
+                    }),
+                ),
             ],
             body: "Method Not Allowed".into(),
             streaming_strategy: None,
@@ -36,56 +576,21 @@ fn http_request(req: HttpRequest) -> HttpResponse {
     }
 
     if req.url.starts_with("/sparql/query") {
-        let parsed_body = String::from_utf8(req.body.unwrap()).unwrap();
-        return match execute_sparql_query(parsed_body) {
-            Ok(query_result) => HttpResponse {
-                status_code: 200,
-                headers: vec![
-                    (
-                        String::from(CONTENT_TYPE_HEADER_KEY),
-                        String::from("application/json"),
-                    ),
-                    (
-                        String::from(ACCESS_CONTROL_ALLOW_ORIGIN_HEADER_KEY),
-                        String::from("*"),
-                    ),
-                ],
-                body: query_result,
-                streaming_strategy: None,
-                upgrade: None,
-            },
-            Err(e) => HttpResponse {
-                status_code: 500,
-                headers: vec![
-                    (
-                        String::from(CONTENT_TYPE_HEADER_KEY),
-                        String::from("plain/text"),
-                    ),
-                    (
-                        String::from(ACCESS_CONTROL_ALLOW_ORIGIN_HEADER_KEY),
-                        String::from("*"),
-                    ),
-                ],
-                body: format!("Error: {:?}", e).into(),
-                streaming_strategy: None,
-                upgrade: None,
-            },
-        };
+        let mut response =
+            handle_sparql_query(req.clone(), false).unwrap_or_else(get_error_response);
+        // only the complete responses to the queries of the devices of the environments are certified
+        if req.method == "GET" && response.status_code == 200 {
+            certify_sparql_query_response(&req, &mut response);
+        }
+        return response;
+    } else if req.url.starts_with("/sparql/prefixes") {
+        return handle_prefix_map().unwrap_or_else(get_error_response);
+    } else if req.url.starts_with("/rdf/dump") {
+        return handle_rdf_dump(req, false).unwrap_or_else(get_error_response);
+    } else if req.url.starts_with("/.well-known/void") {
+        return handle_service_description().unwrap_or_else(get_error_response);
     } else if req.url.starts_with("/ip-challenge") {
-        // this response is directed to the boundary node so that it can upgrade the initial query request "http_request" to an upgrade request "http_request_upgrade"
-        return HttpResponse {
-            status_code: 101, // this is the HTTP status code to request an Upgrade of the protocol (it's anyway ignored by the Boundary node)
-            headers: vec![
-                // this header is optional and we use it just to explain which protocol we are upgrading to
-                (
-                    String::from(CONNECTION_HEADER_KEY),
-                    String::from("IC_http_update_request"),
-                ),
-            ],
-            body: "".into(),
-            streaming_strategy: None,
-            upgrade: Some(true),
-        };
+        return get_upgrade_response();
     }
 
     HttpResponse {
@@ -94,7 +599,7 @@ fn http_request(req: HttpRequest) -> HttpResponse {
             // this header is optional and we use it just to explain which protocol we are upgrading to
             (
                 String::from(CONTENT_TYPE_HEADER_KEY),
-                String::from("plain/text"),
+                String::from("text/plain"),
             ),
             (
                 String::from(ACCESS_CONTROL_ALLOW_ORIGIN_HEADER_KEY),
@@ -110,6 +615,13 @@ fn http_request(req: HttpRequest) -> HttpResponse {
 #[update]
 #[candid_method(update)]
 async fn http_request_update(req: HttpRequest) -> HttpResponse {
+    // the SPARQL queries and the dumps are upgraded when they don't fit in a single response
+    if req.url.starts_with("/sparql/query") {
+        return handle_sparql_query(req, true).unwrap_or_else(get_error_response);
+    } else if req.url.starts_with("/rdf/dump") {
+        return handle_rdf_dump(req, true).unwrap_or_else(get_error_response);
+    }
+
     // we check if the request has valid headers, otherwise we return an error
     // in order to have valid headers, the request must have the following headers:
     // - "x-forwarded-for" (mandatory): it contains the list of IP addresses of the proxies that the HTTP message went through.
@@ -134,7 +646,7 @@ async fn http_request_update(req: HttpRequest) -> HttpResponse {
             headers: vec![
                 (
                     String::from(CONTENT_TYPE_HEADER_KEY),
-                    String::from("plain/text"),
+                    String::from("text/plain"),
                 ),
                 (
                     String::from(ACCESS_CONTROL_ALLOW_ORIGIN_HEADER_KEY),
@@ -168,7 +680,7 @@ async fn http_request_update(req: HttpRequest) -> HttpResponse {
                         headers: vec![
                             (
                                 String::from(CONTENT_TYPE_HEADER_KEY),
-                                String::from("plain/text"),
+                                String::from("text/plain"),
                             ),
                             (
                                 String::from(ACCESS_CONTROL_ALLOW_ORIGIN_HEADER_KEY),
@@ -188,7 +700,7 @@ async fn http_request_update(req: HttpRequest) -> HttpResponse {
                         headers: vec![
                             (
                                 String::from(CONTENT_TYPE_HEADER_KEY),
-                                String::from("plain/text"),
+                                String::from("text/plain"),
                             ),
                             (
                                 String::from(ACCESS_CONTROL_ALLOW_ORIGIN_HEADER_KEY),
@@ -217,7 +729,7 @@ async fn http_request_update(req: HttpRequest) -> HttpResponse {
                 headers: vec![
                     (
                         String::from(CONTENT_TYPE_HEADER_KEY),
-                        String::from("plain/text"),
+                        String::from("text/plain"),
                     ),
                     (
                         String::from(ACCESS_CONTROL_ALLOW_ORIGIN_HEADER_KEY),
@@ -240,13 +752,27 @@ async fn http_request_update(req: HttpRequest) -> HttpResponse {
         timestamp: time(),
     };
 
-    ((),) = call(
-        get_database_principal(),
-        "init_nonce_to_ip",
-        (parsed_body.nonce.to_string(), Box::new(requester_info)),
-    )
-    .await
-    .unwrap();
+    if let Err(e) = get_database_client()
+        .init_nonce_to_ip(parsed_body.nonce.to_string(), requester_info)
+        .await
+    {
+        return HttpResponse {
+            status_code: 500,
+            headers: vec![
+                (
+                    String::from(CONTENT_TYPE_HEADER_KEY),
+                    String::from("text/plain"),
+                ),
+                (
+                    String::from(ACCESS_CONTROL_ALLOW_ORIGIN_HEADER_KEY),
+                    String::from("*"),
+                ),
+            ],
+            body: format!("Error: {}", e).into(),
+            streaming_strategy: None,
+            upgrade: None,
+        };
+    }
 
     // this is the response that the client actually get, even if the client called "http_requst"
     HttpResponse {
@@ -254,7 +780,7 @@ async fn http_request_update(req: HttpRequest) -> HttpResponse {
         headers: vec![
             (
                 String::from(CONTENT_TYPE_HEADER_KEY),
-                String::from("plain/text"),
+                String::from("text/plain"),
             ),
             (
                 String::from(ACCESS_CONTROL_ALLOW_ORIGIN_HEADER_KEY),
@@ -266,3 +792,234 @@ async fn http_request_update(req: HttpRequest) -> HttpResponse {
         upgrade: None,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SPARQL_RESULTS_JSON_MEDIA_TYPE: &str = "application/sparql-results+json";
+    const QUERY: &str = "SELECT ?s WHERE { ?s ?p ?o } LIMIT 1";
+    const ENCODED_QUERY: &str = "SELECT+%3Fs+WHERE+%7B+%3Fs+%3Fp+%3Fo+%7D+LIMIT+1";
+
+    fn get_request(method: &str, url: &str, content_type: Option<&str>, body: &str) -> HttpRequest {
+        HttpRequest {
+            method: String::from(method),
+            url: String::from(url),
+            headers: content_type
+                .map(|content_type| {
+                    vec![(String::from("Content-Type"), String::from(content_type))]
+                })
+                .unwrap_or_default(),
+            body: Some(body.into()),
+            upgrade: None,
+        }
+    }
+
+    fn get_header<'a>(response: &'a HttpResponse, name: &str) -> Option<&'a str> {
+        response
+            .headers
+            .iter()
+            .find(|(header, _)| header == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    #[test]
+    fn test_sparql_query_protocol() {
+        let requests = [
+            get_request(
+                "GET",
+                &format!("/sparql/query?query={}", ENCODED_QUERY),
+                None,
+                "",
+            ),
+            get_request(
+                "POST",
+                "/sparql/query",
+                Some("application/x-www-form-urlencoded"),
+                &format!("query={}", ENCODED_QUERY),
+            ),
+            get_request(
+                "POST",
+                "/sparql/query",
+                Some("application/sparql-query; charset=utf-8"),
+                QUERY,
+            ),
+            get_request("POST", "/sparql/query", None, QUERY),
+        ];
+
+        for request in requests {
+            let response = http_request(request);
+            assert_eq!(response.status_code, 200);
+            assert_eq!(
+                get_header(&response, CONTENT_TYPE_HEADER_KEY),
+                Some(SPARQL_RESULTS_JSON_MEDIA_TYPE)
+            );
+        }
+    }
+
+    #[test]
+    fn test_sparql_query_forms() {
+        let response = http_request(get_request(
+            "POST",
+            "/sparql/query",
+            Some("application/sparql-query"),
+            "ASK { ?s ?p ?o }",
+        ));
+        assert_eq!(response.status_code, 200);
+        assert_eq!(
+            get_header(&response, CONTENT_TYPE_HEADER_KEY),
+            Some(SPARQL_RESULTS_JSON_MEDIA_TYPE)
+        );
+        assert!(String::from_utf8(response.body)
+            .unwrap()
+            .contains("\"boolean\":"));
+
+        for (accept, media_type) in [
+            (None, TURTLE_MEDIA_TYPE),
+            (Some("application/n-triples"), "application/n-triples"),
+            (
+                Some("text/turtle;q=0.5, application/n-triples"),
+                "application/n-triples",
+            ),
+            (Some("application/json"), TURTLE_MEDIA_TYPE),
+        ] {
+            let mut request = get_request(
+                "POST",
+                "/sparql/query",
+                Some("application/sparql-query"),
+                "CONSTRUCT { ?s ?p ?o } WHERE { ?s ?p ?o }",
+            );
+            if let Some(accept) = accept {
+                request
+                    .headers
+                    .push((String::from(ACCEPT_HEADER_KEY), String::from(accept)));
+            }
+            let response = http_request(request);
+
+            assert_eq!(response.status_code, 200);
+            assert_eq!(
+                get_header(&response, CONTENT_TYPE_HEADER_KEY),
+                Some(media_type)
+            );
+        }
+    }
+
+    #[test]
+    fn test_accepted_graph_format() {
+        let get_format = |accept: &str| {
+            let mut request = get_request("GET", "/sparql/query", None, "");
+            request
+                .headers
+                .push((String::from("accept"), String::from(accept)));
+            get_accepted_graph_format(&request)
+        };
+
+        assert_eq!(get_format("*/*"), GraphFormat::Turtle);
+        assert_eq!(get_format("application/*"), GraphFormat::NTriples);
+        assert_eq!(
+            get_format("application/n-triples;q=0.9, text/turtle;q=0.8"),
+            GraphFormat::NTriples
+        );
+        // the exact media type overrides the quality of the wildcard
+        assert_eq!(get_format("text/turtle;q=0, */*"), GraphFormat::NTriples);
+        assert_eq!(get_format("text/html"), GraphFormat::Turtle);
+    }
+
+    #[test]
+    fn test_sparql_query_invalid_requests() {
+        let response = http_request(get_request(
+            "POST",
+            "/sparql/query",
+            Some("application/json"),
+            QUERY,
+        ));
+        assert_eq!(response.status_code, 415);
+        assert_eq!(
+            get_header(&response, CONTENT_TYPE_HEADER_KEY),
+            Some("text/plain")
+        );
+
+        // the query is passed twice
+        let response = http_request(get_request(
+            "POST",
+            &format!("/sparql/query?query={}", ENCODED_QUERY),
+            Some("application/sparql-query"),
+            QUERY,
+        ));
+        assert_eq!(response.status_code, 400);
+
+        let response = http_request(get_request("PUT", "/sparql/query", None, QUERY));
+        assert_eq!(response.status_code, 405);
+        assert_eq!(
+            get_header(&response, ALLOW_HEADER_KEY),
+            Some("GET, POST, OPTIONS")
+        );
+    }
+
+    #[test]
+    fn test_service_description() {
+        for url in ["/sparql/query", "/.well-known/void"] {
+            let response = http_request(get_request("GET", url, None, ""));
+
+            assert_eq!(response.status_code, 200);
+            assert_eq!(
+                get_header(&response, CONTENT_TYPE_HEADER_KEY),
+                Some(TURTLE_MEDIA_TYPE)
+            );
+        }
+    }
+
+    #[test]
+    fn test_prefix_map() {
+        let response = http_request(get_request("GET", "/sparql/prefixes", None, ""));
+
+        assert_eq!(response.status_code, 200);
+        let prefix_map: BTreeMap<String, String> = from_slice(&response.body).unwrap();
+        assert_eq!(
+            prefix_map.get("saref").map(String::as_str),
+            Some("https://saref.etsi.org/core/")
+        );
+        assert!(prefix_map.contains_key("omnia"));
+    }
+
+    #[test]
+    fn test_cors_preflight() {
+        let response = http_request(get_request("OPTIONS", "/sparql/query", None, ""));
+
+        assert_eq!(response.status_code, 204);
+        assert_eq!(
+            get_header(&response, ACCESS_CONTROL_ALLOW_ORIGIN_HEADER_KEY),
+            Some("*")
+        );
+        assert_eq!(
+            get_header(&response, ACCESS_CONTROL_ALLOW_METHODS_HEADER_KEY),
+            Some("GET, POST, OPTIONS")
+        );
+    }
+
+    #[test]
+    fn test_parse_params() {
+        assert_eq!(
+            get_url_params(
+                "/sparql/query?query=ASK+%7B%7D&default-graph-uri=https%3A%2F%2Fexample.com%2Fa&default-graph-uri=b&flag"
+            ),
+            vec![
+                (String::from("query"), String::from("ASK {}")),
+                (
+                    String::from("default-graph-uri"),
+                    String::from("https://example.com/a")
+                ),
+                (String::from("default-graph-uri"), String::from("b")),
+                (String::from("flag"), String::new()),
+            ]
+        );
+        assert!(get_url_params("/sparql/query").is_empty());
+
+        let encoded_query = percent_encode(QUERY);
+        assert_eq!(
+            encoded_query,
+            "SELECT%20%3Fs%20WHERE%20%7B%20%3Fs%20%3Fp%20%3Fo%20%7D%20LIMIT%201"
+        );
+        assert_eq!(percent_decode(&encoded_query), QUERY);
+    }
+}
@@ -1,18 +1,41 @@
+mod certification;
+mod database_client;
 mod http_endpoint;
 mod manager;
+mod payouts;
+mod prefixes;
 mod rdf;
+mod reconciliation;
+mod results_cache;
+mod service_description;
+mod streaming;
+mod thing_description;
 mod user;
 mod utils;
+mod vocabulary;
 
 use candid::{candid_method, CandidType, Deserialize, Principal};
+use certification::{init_certified_data, CertifiedResponses};
 use ic_cdk::api::stable::{StableReader, StableWriter};
 use ic_cdk::print;
 use ic_cdk_macros::{init, post_upgrade, pre_upgrade};
-use ic_oxigraph::io::GraphFormat;
-use ic_oxigraph::model::GraphNameRef;
+use ic_certified_map::{Hash, RbTree};
+use ic_oxigraph::io::DatasetFormat;
 use ic_oxigraph::store::Store;
 use omnia_core_sdk::random::{init_rng, RNG_REF_CELL};
+use omnia_types::sparql::SparqlQueryLimits;
+use omnia_utils::constants::{
+    GATEWAY_REVENUE_SHARE_PERCENTAGE, SPARQL_QUERY_MAX_INSTRUCTIONS,
+    SPARQL_QUERY_MAX_RESPONSE_BYTES, SPARQL_QUERY_MAX_ROWS,
+};
+use payouts::start_gateway_payouts_timer;
+use reconciliation::start_device_reconciliation_timer;
+use results_cache::ResultsCache;
+use serde::Serialize;
+use service_description::{init_dataset_statistics, DatasetStatistics};
 use std::cell::RefCell;
+use std::io::{Read, Write};
+use streaming::init_streaming_secret;
 use utils::{update_backend_principal, update_database_principal, update_ledger_principal};
 
 #[derive(Default, CandidType, Deserialize)]
@@ -20,6 +43,17 @@ struct State {
     pub backend_principal: Option<Principal>,
     pub database_principal: Option<Principal>,
     pub ledger_principal: Option<Principal>,
+    /// percentage of the Access Key revenue paid to Gateway operators, kept across upgrades
+    pub gateway_revenue_share_percentage: u8,
+    pub payouts_in_progress: bool,
+    /// devices that are registered in the database but not yet described in the RDF database
+    pub device_registrations_in_progress: u32,
+    pub device_registrations_started: u64,
+    pub reconciliation_in_progress: bool,
+    /// caps on the SPARQL queries, kept across upgrades
+    pub sparql_query_limits: SparqlQueryLimits,
+    /// key that signs the streaming callback tokens, regenerated on upgrade
+    pub streaming_secret: Option<Vec<u8>>,
 }
 
 impl State {
@@ -28,6 +62,73 @@ impl State {
             backend_principal: None,
             database_principal: None,
             ledger_principal: None,
+            gateway_revenue_share_percentage: GATEWAY_REVENUE_SHARE_PERCENTAGE,
+            payouts_in_progress: false,
+            device_registrations_in_progress: 0,
+            device_registrations_started: 0,
+            reconciliation_in_progress: false,
+            sparql_query_limits: get_default_sparql_query_limits(),
+            streaming_secret: None,
+        }
+    }
+}
+
+fn get_default_sparql_query_limits() -> SparqlQueryLimits {
+    SparqlQueryLimits {
+        max_rows: SPARQL_QUERY_MAX_ROWS,
+        max_response_bytes: SPARQL_QUERY_MAX_RESPONSE_BYTES,
+        max_instructions: SPARQL_QUERY_MAX_INSTRUCTIONS,
+    }
+}
+
+/// Written before the [StableState] in stable memory, to tell it apart from the dataset written by the previous versions
+const STABLE_STATE_MAGIC: &[u8; 4] = b"OMST";
+/// Version of the [StableState] encoding, written after the [STABLE_STATE_MAGIC]
+const STABLE_STATE_VERSION: u8 = 1;
+
+/// Data written to stable memory before an upgrade and restored after it
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct StableState {
+    /// the whole dataset in N-Quads, because the Thing Descriptions are stored in the graphs of the devices.
+    /// It's encoded as a byte string, so that it's decoded in a single buffer
+    #[serde(with = "serde_bytes")]
+    rdf_dataset: Vec<u8>,
+    gateway_revenue_share_percentage: u8,
+    sparql_query_limits: SparqlQueryLimits,
+}
+
+impl StableState {
+    /// Writes the state after its version header
+    fn write(&self, mut writer: impl Write) {
+        writer
+            .write_all(STABLE_STATE_MAGIC)
+            .and_then(|_| writer.write_all(&[STABLE_STATE_VERSION]))
+            .expect("failed to encode state header");
+        ciborium::ser::into_writer(self, writer).expect("failed to encode state")
+    }
+
+    /// Reads the data written by [StableState::write].
+    /// Canisters upgraded from a version that only stored the RDF dataset, as a CBOR array of bytes, get the default settings
+    fn read(mut reader: impl Read) -> Self {
+        let mut header = [0; STABLE_STATE_MAGIC.len() + 1];
+        reader
+            .read_exact(&mut header)
+            .expect("failed to read state header");
+
+        if header.starts_with(STABLE_STATE_MAGIC) {
+            let version = header[STABLE_STATE_MAGIC.len()];
+            if version != STABLE_STATE_VERSION {
+                panic!("unsupported state version: {}", version);
+            }
+            return ciborium::de::from_reader(reader).expect("failed to decode state");
+        }
+
+        // the array is decoded straight into the bytes of the dataset
+        Self {
+            rdf_dataset: ciborium::de::from_reader((&header[..]).chain(reader))
+                .expect("failed to decode RDF dataset"),
+            gateway_revenue_share_percentage: GATEWAY_REVENUE_SHARE_PERCENTAGE,
+            sparql_query_limits: get_default_sparql_query_limits(),
         }
     }
 }
@@ -35,6 +136,16 @@ impl State {
 thread_local! {
     /* flexible */ static STATE: RefCell<State>  = RefCell::new(State::default());
     /* stable */ static RDF_DB: RefCell<Store>  = RefCell::new(Store::new().unwrap());
+    /// hashes of the quads of the RDF database, rebuilt from the database on upgrade
+    /* flexible */ static CERTIFIED_QUADS: RefCell<RbTree<Hash, Vec<u8>>> = RefCell::new(RbTree::new());
+    /// hashes of the devices of each environment, rebuilt from the database on upgrade
+    /* flexible */ static CERTIFIED_ENVIRONMENTS: RefCell<RbTree<String, Hash>> = RefCell::new(RbTree::new());
+    /// hashes of the responses to the queries of the devices of each environment, rebuilt from the database on upgrade
+    /* flexible */ static CERTIFIED_RESPONSES: RefCell<CertifiedResponses> = RefCell::new(CertifiedResponses::default());
+    /// counts of the quads of the RDF database, recounted from the database on upgrade
+    /* flexible */ static DATASET_STATISTICS: RefCell<DatasetStatistics> = RefCell::new(DatasetStatistics::default());
+    /// pages of the SPARQL query results and of the dumps that don't fit in a single response, which expire shortly after they're evaluated
+    /* flexible */ static RESULTS_CACHE: RefCell<ResultsCache> = RefCell::new(ResultsCache::default());
 }
 
 // to deploy this canister with the database principal id as init argument, use
@@ -58,24 +169,35 @@ fn init(
     update_backend_principal(omnia_backend_canister_principal_id);
     update_database_principal(database_canister_principal_id);
     update_ledger_principal(ledger_canister_principal_id);
+
+    init_certified_data();
+    init_dataset_statistics();
+
+    start_gateway_payouts_timer();
+    start_device_reconciliation_timer();
+    init_streaming_secret();
 }
 
 #[pre_upgrade]
 fn pre_upgrade() {
+    let mut rdf_dataset = Vec::new();
     RDF_DB.with(|store| {
-        let mut buffer = Vec::new();
         store
             .borrow()
-            .dump_graph(
-                &mut buffer,
-                GraphFormat::NTriples,
-                GraphNameRef::DefaultGraph,
-            )
-            .expect("failed to dump RDF graph");
+            .dump_dataset(&mut rdf_dataset, DatasetFormat::NQuads)
+            .expect("failed to dump RDF dataset");
+    });
 
-        ciborium::ser::into_writer(buffer.as_slice(), StableWriter::default())
-            .expect("failed to encode state")
+    let stable_state = STATE.with(|state| {
+        let state = state.borrow();
+        StableState {
+            rdf_dataset,
+            gateway_revenue_share_percentage: state.gateway_revenue_share_percentage,
+            sparql_query_limits: state.sparql_query_limits,
+        }
     });
+
+    stable_state.write(StableWriter::default());
 }
 
 #[post_upgrade]
@@ -95,25 +217,35 @@ fn post_upgrade(
     update_backend_principal(omnia_backend_canister_principal_id);
     update_database_principal(database_canister_principal_id);
 
-    RDF_DB.with(|cell| {
-        let deserialized: Vec<u8> =
-            ciborium::de::from_reader(StableReader::default()).expect("failed to decode state");
+    let stable_state = StableState::read(StableReader::default());
 
+    RDF_DB.with(|cell| {
         let store = Store::new().unwrap();
-        // loading the graph can probably be optimized
+        // loading the dataset can probably be optimized.
+        // Dumps of the default graph only, in N-Triples, are valid N-Quads as well
         store
-            .load_graph(
-                deserialized.as_slice(),
-                GraphFormat::NTriples,
-                GraphNameRef::DefaultGraph,
+            .load_dataset(
+                stable_state.rdf_dataset.as_slice(),
+                DatasetFormat::NQuads,
                 None,
             )
             .unwrap();
 
         *cell.borrow_mut() = store;
     });
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        state.gateway_revenue_share_percentage = stable_state.gateway_revenue_share_percentage;
+        state.sparql_query_limits = stable_state.sparql_query_limits;
+    });
+    init_certified_data();
+    init_dataset_statistics();
 
     update_ledger_principal(ledger_canister_principal_id);
+
+    start_gateway_payouts_timer();
+    start_device_reconciliation_timer();
+    init_streaming_secret();
 }
 
 #[cfg(test)]
@@ -124,13 +256,50 @@ mod tests {
     use ic_ledger_types::*;
     use omnia_core_sdk::access_key::AccessKeyUID;
     use omnia_types::access_key::*;
+    use omnia_types::certification::*;
+    use omnia_types::consistency::*;
     use omnia_types::device::*;
     use omnia_types::environment::*;
     use omnia_types::errors::*;
     use omnia_types::gateway::*;
     use omnia_types::http::*;
+    use omnia_types::pagination::*;
+    use omnia_types::sparql::*;
     use omnia_types::updates::*;
     use omnia_types::virtual_persona::*;
+    use omnia_types::vocabulary::*;
+
+    #[test]
+    fn test_stable_state() {
+        let stable_state = super::StableState {
+            rdf_dataset:
+                b"<https://example.com/s> <https://example.com/p> <https://example.com/o> .\n"
+                    .to_vec(),
+            gateway_revenue_share_percentage: 30,
+            sparql_query_limits: SparqlQueryLimits {
+                max_rows: 10,
+                max_response_bytes: 1_000,
+                max_instructions: 1_000_000,
+            },
+        };
+        let mut stable_memory = vec![];
+        stable_state.write(&mut stable_memory);
+        assert!(stable_memory.starts_with(super::STABLE_STATE_MAGIC));
+        assert_eq!(
+            super::StableState::read(stable_memory.as_slice()),
+            stable_state
+        );
+
+        // the previous versions only wrote the dataset, as an array of bytes
+        let mut stable_memory = vec![];
+        ciborium::ser::into_writer(&stable_state.rdf_dataset, &mut stable_memory).unwrap();
+        let legacy_state = super::StableState::read(stable_memory.as_slice());
+        assert_eq!(legacy_state.rdf_dataset, stable_state.rdf_dataset);
+        assert_eq!(
+            legacy_state.sparql_query_limits,
+            super::get_default_sparql_query_limits()
+        );
+    }
 
     #[test]
     fn generate_candid_interface() {
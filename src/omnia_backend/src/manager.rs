@@ -1,36 +1,43 @@
-use candid::candid_method;
+use candid::{candid_method, Principal};
 use ic_cdk::{
-    api::{call::call, caller},
-    print, trap,
+    api::{caller, time},
+    print,
 };
 use ic_cdk_macros::{query, update};
 use ic_ledger_types::{BlockIndex, Operation, Tokens};
-use ic_oxigraph::model::{vocab, GraphName, Literal, NamedNode, Quad};
-use omnia_core_sdk::access_key::{AccessKeyUID, UniqueAccessKey, ACCESS_KEY_PRICE};
+use ic_oxigraph::model::Quad;
+use omnia_core_sdk::access_key::{AccessKeyUID, ACCESS_KEY_PRICE};
 use omnia_types::{
     access_key::{
-        AccessKeyCreationArgs, AccessKeyCreationResult, RejectedAccessKey, RejectedAccessKeyReason,
-        SignedRequest,
+        AccessKeyCreationArgs, AccessKeySecret, AccessKeyUsageResult, OwnedAccessKey,
+        RejectedAccessKey, RejectedAccessKeyReason, ServedRequest, SignedRequest,
+    },
+    device::{
+        DeviceUid, RegisteredDeviceIndex, RegisteredDeviceResult, RegisteredDeviceValue,
+        RegisteredDevicesUidsResult,
     },
-    device::{DeviceAffordances, RegisteredDeviceResult, RegisteredDevicesUidsResult},
     environment::{EnvironmentCreationInput, EnvironmentCreationResult, EnvironmentUID},
-    errors::GenericResult,
+    errors::{GenericError, GenericResult},
     gateway::{
         GatewayPrincipalId, GatewayRegistrationInput, InitializedGatewayValue,
         MultipleRegisteredGatewayResult, RegisteredGatewayResult,
     },
     http::IpChallengeNonce,
+    pagination::{Page, PageRequest},
     updates::{PairingPayload, UpdateValueOption, UpdateValueResult},
     virtual_persona::VirtualPersonaPrincipalId,
 };
 use omnia_utils::ic::{get_transaction_hash, principal_to_account};
 
 use crate::{
-    rdf::{BotNode, HttpNode, OmniaNode, SarefNode, TdNode, UrnNode},
-    utils::{
-        get_backend_principal, get_database_principal, is_valid_signature, query_ledger_block,
+    database_client::{get_database_client, CanisterCaller, DatabaseClient},
+    rdf::{
+        get_description_updated_at_quad, get_device_node, get_device_quads, get_environment_quad,
+        get_stored_device_quads, insert_quads, replace_quads,
     },
-    RDF_DB,
+    reconciliation::DeviceRegistrationGuard,
+    thing_description::ThingDescription,
+    utils::{get_backend_principal, is_valid_signature, query_ledger_block},
 };
 
 #[update(name = "createEnvironment")]
@@ -38,27 +45,30 @@ use crate::{
 async fn create_environment(
     environment_creation_input: EnvironmentCreationInput,
 ) -> GenericResult<EnvironmentCreationResult> {
-    let environment_manager_principal_id = caller().to_string();
-
-    let (virtual_persona_exists,): (bool,) = call(
-        get_database_principal(),
-        "check_if_virtual_persona_exists",
-        (environment_manager_principal_id.clone(),),
+    create_environment_with(
+        &get_database_client(),
+        caller().to_string(),
+        environment_creation_input,
     )
     .await
-    .unwrap();
+}
+
+async fn create_environment_with<C: CanisterCaller>(
+    database: &DatabaseClient<C>,
+    environment_manager_principal_id: VirtualPersonaPrincipalId,
+    environment_creation_input: EnvironmentCreationInput,
+) -> GenericResult<EnvironmentCreationResult> {
+    let virtual_persona_exists = database
+        .check_if_virtual_persona_exists(environment_manager_principal_id.clone())
+        .await?;
     match virtual_persona_exists {
         true => {
-            let (environment_creation_result,): (GenericResult<EnvironmentCreationResult>,) = call(
-                get_database_principal(),
-                "create_new_environment",
-                (
+            let environment_creation_result = database
+                .create_new_environment(
                     environment_manager_principal_id,
-                    Box::new(environment_creation_input),
-                ),
-            )
-            .await
-            .unwrap();
+                    environment_creation_input,
+                )
+                .await;
 
             print(format!(
                 "Created new environment: {:?}",
@@ -68,26 +78,18 @@ async fn create_environment(
             // register the environment in the RDF database
             match environment_creation_result {
                 Ok(result) => {
-                    let quad = Quad::new(
-                        UrnNode::new_uuid(&result.env_uid),
-                        vocab::rdf::TYPE,
-                        BotNode::from("Zone"),
-                        GraphName::DefaultGraph,
-                    );
-
-                    RDF_DB.with(|rdf_db| match rdf_db.borrow().insert(&quad) {
-                        Ok(_) => Ok(result),
-                        Err(err) => Err(format!("Error inserting quad: {:?}", err)),
-                    })
+                    let quad = get_environment_quad(&result.env_uid);
+
+                    insert_quads(&[quad]).map(|_| result)
                 }
                 Err(err) => Err(err),
             }
         }
         false => {
-            let err = format!(
-                "Virtual persona with principal id: {:?} does not exist",
-                environment_manager_principal_id
-            );
+            let err = GenericError::NotFound {
+                entity: String::from("VirtualPersona"),
+                id: environment_manager_principal_id,
+            };
 
             println!("{}", err);
             Err(err)
@@ -98,31 +100,26 @@ async fn create_environment(
 #[update(name = "initGateway")]
 #[candid_method(update, rename = "initGateway")]
 async fn init_gateway(nonce: IpChallengeNonce) -> GenericResult<GatewayPrincipalId> {
-    let gateway_principal_id = caller().to_string();
+    init_gateway_with(&get_database_client(), caller().to_string(), nonce).await
+}
 
-    let is_registered = call::<(GatewayPrincipalId,), (bool,)>(
-        get_database_principal(),
-        "is_gateway_registered",
-        (gateway_principal_id.clone(),),
-    )
-    .await
-    .unwrap()
-    .0;
+async fn init_gateway_with<C: CanisterCaller>(
+    database: &DatabaseClient<C>,
+    gateway_principal_id: GatewayPrincipalId,
+    nonce: IpChallengeNonce,
+) -> GenericResult<GatewayPrincipalId> {
+    let is_registered = database
+        .is_gateway_registered(gateway_principal_id.clone())
+        .await?;
 
     if !is_registered {
         print(format!(
             "Gateway with principal ID: {:?} is not yet registered",
             gateway_principal_id
         ));
-        let principal_id =
-            call::<(IpChallengeNonce, GatewayPrincipalId), (GenericResult<GatewayPrincipalId>,)>(
-                get_database_principal(),
-                "init_gateway_by_ip",
-                (nonce, gateway_principal_id),
-            )
-            .await
-            .unwrap()
-            .0?;
+        let principal_id = database
+            .init_gateway_by_ip(nonce, gateway_principal_id)
+            .await?;
 
         return Ok(principal_id);
     }
@@ -134,79 +131,56 @@ async fn init_gateway(nonce: IpChallengeNonce) -> GenericResult<GatewayPrincipal
 async fn get_initialized_gateways(
     nonce: IpChallengeNonce,
 ) -> GenericResult<Vec<InitializedGatewayValue>> {
-    let initialized_gateway_principals_result: GenericResult<Vec<InitializedGatewayValue>> =
-        match call(
-            get_database_principal(),
-            "get_initialized_gateways_by_ip",
-            (nonce,),
-        )
-        .await
-        .unwrap()
-        {
-            (Ok(initialized_gateway_principals),) => {
-                print(format!(
-                    "Initialized gateways in the local network have principals {:?}",
-                    initialized_gateway_principals
-                ));
-                Ok(initialized_gateway_principals)
-            }
-            (Err(e),) => Err(e),
-        };
-    initialized_gateway_principals_result
+    let initialized_gateway_principals = get_database_client()
+        .get_initialized_gateways_by_ip(nonce)
+        .await?;
+
+    print(format!(
+        "Initialized gateways in the local network have principals {:?}",
+        initialized_gateway_principals
+    ));
+    Ok(initialized_gateway_principals)
 }
 
 #[update(name = "registerGateway")]
 #[candid_method(update, rename = "registerGateway")]
+/// If the database is temporarily unavailable, the transient error is returned: the IP challenge has not been used yet,
+/// so the registration can be retried with the same nonce
 async fn register_gateway(
     nonce: IpChallengeNonce,
     gateway_registration_input: GatewayRegistrationInput,
 ) -> RegisteredGatewayResult {
-    let environment_manager_principal = caller();
-
-    let (gateway_registration_result,): (RegisteredGatewayResult,) = call(
-        get_database_principal(),
-        "register_gateway_in_environment",
-        (
-            nonce,
-            environment_manager_principal.to_string(),
-            Box::new(gateway_registration_input),
-        ),
-    )
-    .await
-    .unwrap();
-
-    gateway_registration_result
+    get_database_client()
+        .register_gateway_in_environment(nonce, caller().to_string(), gateway_registration_input)
+        .await
 }
 
 #[update(name = "getRegisteredGateways")]
 #[candid_method(update, rename = "getRegisteredGateways")]
+/// Gateways can be filtered by the prefix of their name
 async fn get_registered_gateways(
     environment_uid: EnvironmentUID,
+    page_request: PageRequest,
+    gateway_name_prefix: Option<String>,
 ) -> MultipleRegisteredGatewayResult {
-    let (res,): (MultipleRegisteredGatewayResult,) = call(
-        get_database_principal(),
-        "get_registered_gateways_in_environment",
-        (environment_uid.clone(),),
-    )
-    .await
-    .unwrap();
-
-    res
+    get_database_client()
+        .get_registered_gateways_in_environment(environment_uid, page_request, gateway_name_prefix)
+        .await
 }
 
 #[update(name = "getGatewayUpdates")]
 #[candid_method(update, rename = "getGatewayUpdates")]
+/// Gateways poll this method, so if the database can't be reached no updates are returned and the Gateway will get them at the next poll
 async fn get_gateway_updates() -> UpdateValueOption {
     let gateway_principal_id = caller().to_string();
 
-    call::<(GatewayPrincipalId,), (UpdateValueOption,)>(
-        get_database_principal(),
-        "get_gateway_updates_by_principal",
-        (gateway_principal_id,),
-    )
-    .await
-    .unwrap()
-    .0
+    get_database_client()
+        .get_gateway_updates_by_principal(gateway_principal_id)
+        .await
+        .unwrap_or_else(|e| {
+            print(format!("Error getting gateway updates: {}", e));
+            None
+        })
 }
 
 #[update(name = "pairNewDevice")]
@@ -218,160 +192,166 @@ async fn pair_new_device(
 ) -> UpdateValueResult {
     let manager_principal_id = caller().to_string();
 
-    call::<
-        (
-            IpChallengeNonce,
-            VirtualPersonaPrincipalId,
-            GatewayPrincipalId,
-            PairingPayload,
-        ),
-        (UpdateValueResult,),
-    >(
-        get_database_principal(),
-        "pair_new_device_on_gateway",
-        (
+    get_database_client()
+        .pair_new_device_on_gateway(
             nonce,
             manager_principal_id,
             gateway_principal_id,
             pairing_payload,
-        ),
-    )
-    .await
-    .unwrap()
-    .0
+        )
+        .await
 }
 
 #[update(name = "registerDevice")]
 #[candid_method(update, rename = "registerDevice")]
+/// The Thing Description of the device is a JSON-LD document, whose forms are relative to the device
 async fn register_device(
     nonce: IpChallengeNonce,
-    affordances: DeviceAffordances,
+    thing_description: String,
 ) -> RegisteredDeviceResult {
     let gateway_principal_id = caller().to_string();
 
-    let registered_device =
-        call::<(IpChallengeNonce, GatewayPrincipalId), (RegisteredDeviceResult,)>(
-            get_database_principal(),
-            "register_device_on_gateway",
-            (nonce, gateway_principal_id),
-        )
-        .await
-        .unwrap()
-        .0?;
-
-    let device_url = registered_device.clone().1.device_url;
+    let _registration = DeviceRegistrationGuard::start();
+    register_device_with(
+        &get_database_client(),
+        gateway_principal_id,
+        nonce,
+        thing_description,
+        time(),
+    )
+    .await
+}
 
-    let device_node = NamedNode::new(device_url.clone()).map_err(|err| {
-        format!(
-            "Error while creating device node for device with URL: {:?} {:?}",
-            device_url, err
-        )
-    })?;
+/// Registers the device in the database and then describes it in the RDF database.
+/// If the description fails, the device is unregistered from the database, so that it exists in both or in none of them.
+async fn register_device_with<C: CanisterCaller>(
+    database: &DatabaseClient<C>,
+    gateway_principal_id: GatewayPrincipalId,
+    nonce: IpChallengeNonce,
+    thing_description: String,
+    timestamp: u64,
+) -> RegisteredDeviceResult {
+    // the Thing Description is validated before registering the device, so that invalid ones don't need to be compensated
+    let thing_description = ThingDescription::parse(&thing_description)?;
+
+    let (registered_device_index, registered_device_value) = database
+        .register_device_on_gateway(nonce, gateway_principal_id.clone())
+        .await?;
+
+    if let Err(e) = get_device_description_quads(
+        &registered_device_index,
+        &registered_device_value,
+        &thing_description,
+        timestamp,
+    )
+    .and_then(|quads| insert_quads(&quads))
+    {
+        if let Err(compensation_error) = database
+            .unregister_device_on_gateway(
+                gateway_principal_id,
+                registered_device_index.device_uid.clone(),
+            )
+            .await
+        {
+            print(format!(
+                "Error unregistering device with UID {:?}, it will be removed by the reconciliation: {}",
+                registered_device_index.device_uid, compensation_error
+            ));
+        }
 
-    let mut quads: Vec<Quad> = vec![
-        // device declaration
-        Quad::new(
-            device_node.clone(),
-            vocab::rdf::TYPE,
-            SarefNode::from("Device"),
-            GraphName::DefaultGraph,
-        ),
-        // device - environment relation
-        Quad::new(
-            UrnNode::new_uuid(&registered_device.1.env_uid),
-            BotNode::from("hasElement"),
-            device_node.clone(),
-            GraphName::DefaultGraph,
-        ),
-    ];
-
-    // device required HTTP headers
-    // TODO: define better names for HTTP headers
-    let required_headers = registered_device.clone().1.required_headers;
-    if required_headers.is_some() {
-        required_headers.unwrap().iter().enumerate().for_each(
-            |(i, (header_name, header_value))| {
-                let header_node = OmniaNode::from(&format!("HTTPHeader{}", i));
-
-                quads.extend_from_slice(&[
-                    Quad::new(
-                        header_node.clone(),
-                        vocab::rdf::TYPE,
-                        HttpNode::from("RequestHeader"),
-                        GraphName::DefaultGraph,
-                    ),
-                    Quad::new(
-                        header_node.clone(),
-                        HttpNode::from("fieldName"),
-                        Literal::new_simple_literal(header_name),
-                        GraphName::DefaultGraph,
-                    ),
-                    Quad::new(
-                        header_node.clone(),
-                        HttpNode::from("fieldValue"),
-                        Literal::new_simple_literal(header_value),
-                        GraphName::DefaultGraph,
-                    ),
-                    Quad::new(
-                        device_node.clone(),
-                        OmniaNode::from("requiresHeader"),
-                        header_node,
-                        GraphName::DefaultGraph,
-                    ),
-                ]);
-            },
-        );
+        return Err(e);
     }
 
-    quads.extend(affordances.properties.iter().map(|affordance| {
-        Quad::new(
-            device_node.clone(),
-            TdNode::from("hasPropertyAffordance"),
-            SarefNode::from_prefixed(affordance),
-            GraphName::DefaultGraph,
-        )
-    }));
+    Ok((registered_device_index, registered_device_value))
+}
 
-    quads.extend(affordances.actions.iter().map(|affordance| {
-        Quad::new(
-            device_node.clone(),
-            TdNode::from("hasActionAffordance"),
-            SarefNode::from_prefixed(affordance),
-            GraphName::DefaultGraph,
-        )
-    }));
+#[update(name = "updateDeviceDescription")]
+#[candid_method(update, rename = "updateDeviceDescription")]
+/// Replaces the Thing Description of a device registered by the calling gateway, for example after a firmware update added new affordances.
+/// The device keeps its UID and URL
+async fn update_device_description(
+    device_uid: DeviceUid,
+    thing_description: String,
+) -> GenericResult<()> {
+    update_device_description_with(
+        &get_database_client(),
+        caller(),
+        device_uid,
+        thing_description,
+        time(),
+    )
+    .await
+}
 
-    // TODO: handle outcall errors. For example we may want to retry or remove the registered device
-    quads.iter().for_each(|quad| {
-        RDF_DB.with(|rdf_db| match rdf_db.borrow().insert(quad) {
-            Ok(_) => (),
-            Err(e) => {
-                trap(&format!("Error inserting quad: {}", e));
-            }
+/// Only the quads that changed are written, so that the device is never missing from the RDF database while its description is updated
+async fn update_device_description_with<C: CanisterCaller>(
+    database: &DatabaseClient<C>,
+    gateway_principal: Principal,
+    device_uid: DeviceUid,
+    thing_description: String,
+    timestamp: u64,
+) -> GenericResult<()> {
+    let thing_description = ThingDescription::parse(&thing_description)?;
+
+    let (registered_device_index, registered_device_value) =
+        database.get_registered_device(device_uid).await?;
+    if registered_device_value.gateway_principal_id != gateway_principal.to_string() {
+        return Err(GenericError::Unauthorized {
+            caller: gateway_principal,
+            reason: String::from("Only the gateway of the device can update its description"),
         });
-    });
+    }
 
-    Ok(registered_device)
+    let device_node = get_device_node(&registered_device_value.device_url)?;
+    replace_quads(
+        &get_stored_device_quads(&device_node)?,
+        &get_device_description_quads(
+            &registered_device_index,
+            &registered_device_value,
+            &thing_description,
+            timestamp,
+        )?,
+    )?;
+    print(format!(
+        "Gateway {:?} updated the description of device with UID {:?}",
+        registered_device_value.gateway_principal_id, registered_device_index.device_uid
+    ));
+
+    Ok(())
+}
+
+/// The affordances are related to their SAREF classes in the default graph, and the whole Thing Description is described in the graph of the device
+fn get_device_description_quads(
+    registered_device_index: &RegisteredDeviceIndex,
+    registered_device_value: &RegisteredDeviceValue,
+    thing_description: &ThingDescription,
+    timestamp: u64,
+) -> GenericResult<Vec<Quad>> {
+    let device_node = get_device_node(&registered_device_value.device_url)?;
+
+    let mut quads = get_device_quads(
+        registered_device_value,
+        thing_description.get_affordance_nodes(),
+    )?;
+    quads.extend(thing_description.get_quads(&registered_device_index.device_uid, &device_node)?);
+    quads.push(get_description_updated_at_quad(device_node, timestamp));
+
+    Ok(quads)
 }
 
 #[update(name = "getRegisteredDevices")]
 #[candid_method(update, rename = "getRegisteredDevices")]
-async fn get_registered_devices() -> RegisteredDevicesUidsResult {
+async fn get_registered_devices(page_request: PageRequest) -> RegisteredDevicesUidsResult {
     let gateway_principal_id = caller().to_string();
 
-    call::<(GatewayPrincipalId,), (RegisteredDevicesUidsResult,)>(
-        get_database_principal(),
-        "get_registered_devices_on_gateway",
-        (gateway_principal_id,),
-    )
-    .await
-    .unwrap()
-    .0
+    get_database_client()
+        .get_registered_devices_on_gateway(gateway_principal_id, page_request)
+        .await
 }
 
 #[update(name = "obtainAccessKey")]
 #[candid_method(update, rename = "obtainAccessKey")]
-async fn obtain_access_key(block_index: BlockIndex) -> GenericResult<AccessKeyUID> {
+async fn obtain_access_key(block_index: BlockIndex) -> GenericResult<AccessKeySecret> {
     let caller_principal = caller();
 
     let ledger_block = query_ledger_block(block_index).await?;
@@ -388,41 +368,76 @@ async fn obtain_access_key(block_index: BlockIndex) -> GenericResult<AccessKeyUI
 
             // check if the caller of this method is the same principal that paid for the access key
             if from != caller_account {
-                return Err(String::from("Caller account does not match the sender"));
+                return Err(GenericError::PaymentInvalid {
+                    block_index,
+                    reason: String::from("Caller account does not match the sender"),
+                });
             }
             // check if the receiver of the transfer was the Omnia Backend canister
             if to != backend_account {
-                return Err(String::from(
-                    "Receiver does not match the Omnia Backend account",
-                ));
+                return Err(GenericError::PaymentInvalid {
+                    block_index,
+                    reason: String::from("Receiver does not match the Omnia Backend account"),
+                });
             }
             // check if the amount of the transfer is correct
             if amount != ACCESS_KEY_PRICE {
-                return Err(String::from(
-                    "Transferred amount does not match the price of the access key",
-                ));
+                return Err(GenericError::PaymentInvalid {
+                    block_index,
+                    reason: String::from(
+                        "Transferred amount does not match the price of the access key",
+                    ),
+                });
             }
 
-            let access_key_value = call::<(AccessKeyCreationArgs,), (AccessKeyCreationResult,)>(
-                get_database_principal(),
-                "create_new_access_key",
-                (AccessKeyCreationArgs {
+            let (access_key_value, access_key_secret) = get_database_client()
+                .create_new_access_key(AccessKeyCreationArgs {
                     owner: caller_principal,
                     transaction_hash: get_transaction_hash(block.transaction),
-                },),
-            )
-            .await
-            .unwrap()
-            .0?;
+                })
+                .await?;
 
             print(format!("Access key value: {:?}", access_key_value));
 
-            return Ok(access_key_value.get_key());
+            // the secret is not stored, so this is the only time it's returned
+            return Ok(access_key_secret);
         }
 
-        return Err(String::from("Block does not contain a transfer operation"));
+        return Err(GenericError::PaymentInvalid {
+            block_index,
+            reason: String::from("Block does not contain a transfer operation"),
+        });
     }
-    Err(String::from("No block found"))
+    Err(GenericError::PaymentInvalid {
+        block_index,
+        reason: String::from("No block found"),
+    })
+}
+
+#[update(name = "listMyAccessKeys")]
+#[candid_method(update, rename = "listMyAccessKeys")]
+/// Returns the access keys owned by the caller, along with the number of requests left for each one
+async fn list_my_access_keys(page_request: PageRequest) -> GenericResult<Page<OwnedAccessKey>> {
+    let owner = caller();
+
+    get_database_client()
+        .get_access_keys_by_owner(owner, page_request)
+        .await
+}
+
+#[update(name = "getAccessKeyUsage")]
+#[candid_method(update, rename = "getAccessKeyUsage")]
+/// Returns the paginated history of the spends of the access key, along with aggregated statistics.
+/// Only the owner of the access key can call it
+async fn get_access_key_usage(
+    access_key_uid: AccessKeyUID,
+    page_request: PageRequest,
+) -> AccessKeyUsageResult {
+    let owner = caller();
+
+    get_database_client()
+        .get_access_key_usage(owner, access_key_uid, page_request)
+        .await
 }
 
 #[update(name = "reportSignedRequests")]
@@ -430,12 +445,31 @@ async fn obtain_access_key(block_index: BlockIndex) -> GenericResult<AccessKeyUI
 async fn report_signed_requests(
     signed_requests: Vec<SignedRequest>,
 ) -> GenericResult<Vec<RejectedAccessKey>> {
+    let gateway_principal_id = caller().to_string();
+    let database = get_database_client();
+
+    // only registered gateways can report signed requests
+    let is_registered = database
+        .is_gateway_registered(gateway_principal_id.clone())
+        .await?;
+
+    if !is_registered {
+        let err = GenericError::Unauthorized {
+            caller: caller(),
+            reason: String::from("Only registered gateways can report signed requests"),
+        };
+
+        print(err.to_string());
+        return Err(err);
+    }
+
     print(format!(
-        "Reporting {} signed requests...",
+        "Gateway {:?} is reporting {} signed requests...",
+        gateway_principal_id,
         signed_requests.len()
     ));
 
-    let mut unique_access_keys_to_spend: Vec<UniqueAccessKey> = vec![];
+    let mut served_requests_to_spend: Vec<ServedRequest> = vec![];
     let mut rejected_access_keys: Vec<RejectedAccessKey> = vec![];
 
     // check if the signature of the signed request is valid
@@ -448,7 +482,10 @@ async fn report_signed_requests(
         .await
         {
             Ok(true) => {
-                unique_access_keys_to_spend.push(signed_request.get_unique_access_key());
+                served_requests_to_spend.push(ServedRequest {
+                    unique_access_key: signed_request.get_unique_access_key(),
+                    device_uid: signed_request.get_device_uid(),
+                });
             }
             Ok(false) => {
                 rejected_access_keys.push(RejectedAccessKey {
@@ -459,21 +496,16 @@ async fn report_signed_requests(
             Err(e) => {
                 rejected_access_keys.push(RejectedAccessKey {
                     key: signed_request.get_unique_access_key().get_key(),
-                    reason: RejectedAccessKeyReason::SignatureVerificationError(e),
+                    reason: RejectedAccessKeyReason::SignatureVerificationError(e.to_string()),
                 });
             }
         }
     }
 
-    // spend the unique access keys and get the rejected ones
-    let rejected_keys = call::<(Vec<UniqueAccessKey>,), (GenericResult<Vec<RejectedAccessKey>>,)>(
-        get_database_principal(),
-        "spend_requests_for_keys",
-        (unique_access_keys_to_spend,),
-    )
-    .await
-    .unwrap()
-    .0?;
+    // spend the unique access keys on behalf of the gateway and get the rejected ones
+    let rejected_keys = database
+        .spend_requests_for_keys(gateway_principal_id, served_requests_to_spend)
+        .await?;
 
     rejected_access_keys.extend(rejected_keys);
 
@@ -497,3 +529,230 @@ fn get_access_key_price() -> Tokens {
 fn get_access_key_price_as_update() -> Tokens {
     ACCESS_KEY_PRICE
 }
+
+#[cfg(test)]
+mod tests {
+    use ic_cdk::api::call::RejectionCode;
+
+    use ic_oxigraph::model::{vocab, GraphName, Literal, NamedNode};
+
+    use super::*;
+    use crate::{
+        database_client::tests::{block_on, called_methods, MockCaller},
+        rdf::{contains_quad, get_device_environment_quad, SarefNode, TdNode},
+    };
+
+    const GATEWAY_PRINCIPAL_ID: &str =
+        "xri4h-7eqgu-ad7eb-n3yik-avr5c-tjs6r-3e2yb-cigc2-mdswc-olkvi-3ae";
+    // 2023-11-14T22:13:20Z
+    const TIMESTAMP: u64 = 1_700_000_000_000_000_000;
+
+    fn get_thing_description(property_type: &str) -> String {
+        serde_json::json!({
+            "@context": "https://www.w3.org/2022/wot/td/v1.1",
+            "title": "Lamp",
+            "securityDefinitions": { "nosec_sc": { "scheme": "nosec" } },
+            "security": "nosec_sc",
+            "properties": {
+                "status": {
+                    "@type": property_type,
+                    "type": "boolean",
+                    "forms": [{ "href": "properties/status" }]
+                }
+            }
+        })
+        .to_string()
+    }
+
+    fn get_registered_device(device_url: &str) -> RegisteredDeviceResult {
+        Ok((
+            RegisteredDeviceIndex {
+                device_uid: String::from("device"),
+            },
+            RegisteredDeviceValue {
+                gateway_principal_id: GATEWAY_PRINCIPAL_ID.to_string(),
+                env_uid: String::from("environment"),
+                device_url: device_url.to_string(),
+                required_headers: None,
+            },
+        ))
+    }
+
+    fn get_affordance_quad(device_node: &NamedNode, name: &str) -> Quad {
+        Quad::new(
+            device_node.clone(),
+            TdNode::from("hasPropertyAffordance"),
+            SarefNode::from(name),
+            GraphName::DefaultGraph,
+        )
+    }
+
+    #[test]
+    fn test_init_gateway_already_registered() {
+        let database = MockCaller::default().reply(true).into_client();
+
+        assert_eq!(
+            block_on(init_gateway_with(
+                &database,
+                GATEWAY_PRINCIPAL_ID.to_string(),
+                String::from("nonce")
+            )),
+            Ok(GATEWAY_PRINCIPAL_ID.to_string())
+        );
+    }
+
+    #[test]
+    fn test_init_gateway_database_unreachable() {
+        let database = MockCaller::default()
+            .reject(RejectionCode::DestinationInvalid, "canister not found")
+            .into_client();
+
+        assert!(matches!(
+            block_on(init_gateway_with(
+                &database,
+                GATEWAY_PRINCIPAL_ID.to_string(),
+                String::from("nonce")
+            )),
+            Err(GenericError::InterCanisterCallFailed { .. })
+        ));
+    }
+
+    #[test]
+    fn test_create_environment_without_virtual_persona() {
+        let database = MockCaller::default().reply(false).into_client();
+
+        assert_eq!(
+            block_on(create_environment_with(
+                &database,
+                GATEWAY_PRINCIPAL_ID.to_string(),
+                EnvironmentCreationInput {
+                    env_name: String::from("Environment"),
+                }
+            )),
+            Err(GenericError::NotFound {
+                entity: String::from("VirtualPersona"),
+                id: GATEWAY_PRINCIPAL_ID.to_string(),
+            })
+        );
+    }
+
+    #[test]
+    fn test_register_device_invalid_thing_description() {
+        let database = MockCaller::default().into_client();
+
+        assert!(matches!(
+            block_on(register_device_with(
+                &database,
+                GATEWAY_PRINCIPAL_ID.to_string(),
+                String::from("nonce"),
+                get_thing_description("saref:On Off State"),
+                TIMESTAMP,
+            )),
+            Err(GenericError::InvalidArgument { .. })
+        ));
+        assert!(called_methods(&database).is_empty());
+    }
+
+    #[test]
+    fn test_register_device_compensated() {
+        let database = MockCaller::default()
+            .reply(get_registered_device("not a device url"))
+            .reply::<GenericResult<()>>(Ok(()))
+            .into_client();
+
+        assert!(matches!(
+            block_on(register_device_with(
+                &database,
+                GATEWAY_PRINCIPAL_ID.to_string(),
+                String::from("nonce"),
+                get_thing_description("saref:OnOffState"),
+                TIMESTAMP,
+            )),
+            Err(GenericError::Internal { .. })
+        ));
+        assert_eq!(
+            called_methods(&database),
+            vec!["register_device_on_gateway", "unregister_device_on_gateway"]
+        );
+    }
+
+    #[test]
+    fn test_update_device_description() {
+        let device_url = "https://proxy.example.com/updated-device";
+        let device_node = NamedNode::new(device_url).unwrap();
+        let database = MockCaller::default()
+            .reply(get_registered_device(device_url))
+            .reply(get_registered_device(device_url))
+            .into_client();
+
+        block_on(register_device_with(
+            &database,
+            GATEWAY_PRINCIPAL_ID.to_string(),
+            String::from("nonce"),
+            get_thing_description("saref:OnOffState"),
+            0,
+        ))
+        .unwrap();
+        assert_eq!(
+            block_on(update_device_description_with(
+                &database,
+                Principal::from_text(GATEWAY_PRINCIPAL_ID).unwrap(),
+                String::from("device"),
+                get_thing_description("saref:Temperature"),
+                TIMESTAMP,
+            )),
+            Ok(())
+        );
+
+        assert!(!contains_quad(&get_affordance_quad(&device_node, "OnOffState")).unwrap());
+        assert!(contains_quad(&get_affordance_quad(&device_node, "Temperature")).unwrap());
+        assert!(contains_quad(&get_description_updated_at_quad(
+            device_node.clone(),
+            TIMESTAMP
+        ))
+        .unwrap());
+        assert!(!contains_quad(&get_description_updated_at_quad(device_node.clone(), 0)).unwrap());
+        // the device is still in its environment
+        assert!(contains_quad(&get_device_environment_quad(
+            &String::from("environment"),
+            device_node
+        ))
+        .unwrap());
+    }
+
+    #[test]
+    fn test_update_device_description_of_another_gateway() {
+        let database = MockCaller::default()
+            .reply(get_registered_device(
+                "https://proxy.example.com/other-gateway-device",
+            ))
+            .into_client();
+
+        assert!(matches!(
+            block_on(update_device_description_with(
+                &database,
+                Principal::anonymous(),
+                String::from("device"),
+                get_thing_description("saref:Temperature"),
+                TIMESTAMP,
+            )),
+            Err(GenericError::Unauthorized { .. })
+        ));
+    }
+
+    #[test]
+    fn test_description_updated_at() {
+        let device_node = NamedNode::new("https://proxy.example.com/device").unwrap();
+
+        for (timestamp, date_time) in [
+            (0, "1970-01-01T00:00:00Z"),
+            (TIMESTAMP, "2023-11-14T22:13:20Z"),
+            (951_827_696_000_000_000, "2000-02-29T12:34:56Z"),
+        ] {
+            assert_eq!(
+                get_description_updated_at_quad(device_node.clone(), timestamp).object,
+                Literal::new_typed_literal(date_time, vocab::xsd::DATE_TIME).into()
+            );
+        }
+    }
+}
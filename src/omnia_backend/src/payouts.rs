@@ -0,0 +1,710 @@
+use std::time::Duration;
+
+use candid::{candid_method, Principal};
+use ic_cdk::{
+    api::{call::CallResult, caller, time},
+    print,
+};
+use ic_cdk_macros::{query, update};
+use ic_ledger_types::{
+    transfer, BlockIndex, Memo, Timestamp, Tokens, TransferArgs, TransferError, TransferResult,
+    DEFAULT_FEE,
+};
+use omnia_core_sdk::access_key::ACCESS_KEY_PRICE;
+use omnia_types::{
+    errors::{GenericError, GenericResult},
+    gateway::{
+        GatewayLedgerIndex, GatewayLedgerValue, GatewayPayoutIndex, GatewayPayoutValue,
+        GatewayPayoutsResult, GatewayPrincipalId, PendingGatewayPayout,
+        PendingGatewayPayoutResolution,
+    },
+    pagination::PageRequest,
+};
+use omnia_utils::{
+    constants::{
+        ACCESS_KEY_REQUESTS_LIMIT, GATEWAY_PAYOUTS_INTERVAL_SECONDS, GATEWAY_PAYOUTS_RETRY_SECONDS,
+    },
+    ic::principal_to_account,
+};
+
+use crate::{
+    database_client::{collect_pages, get_database_client, CanisterCaller, DatabaseClient},
+    utils::{caller_is_controller, get_ledger_principal},
+    STATE,
+};
+
+/// Abstracts the ledger canister, so that payouts can be tested against a mock ledger
+pub trait LedgerClient {
+    async fn transfer(&self, transfer_args: TransferArgs) -> CallResult<TransferResult>;
+}
+
+/// The ICP ledger canister, whose principal is passed as init argument
+pub struct IcpLedger;
+
+impl LedgerClient for IcpLedger {
+    async fn transfer(&self, transfer_args: TransferArgs) -> CallResult<TransferResult> {
+        transfer(get_ledger_principal(), transfer_args).await
+    }
+}
+
+/// Computes the amount to transfer to the Gateway operator for the given number of served requests.
+///
+/// The transfer fee is paid by the operator, so `None` is returned if the share doesn't cover it.
+/// In this case, requests are accumulated and paid in the next payouts.
+pub fn compute_gateway_share(served_requests: u64, share_percentage: u8) -> Option<Tokens> {
+    let request_price_e8s = ACCESS_KEY_PRICE.e8s() / ACCESS_KEY_REQUESTS_LIMIT as u64;
+    let share_e8s = served_requests * request_price_e8s * share_percentage as u64 / 100;
+
+    if share_e8s <= DEFAULT_FEE.e8s() {
+        return None;
+    }
+
+    Some(Tokens::from_e8s(share_e8s - DEFAULT_FEE.e8s()))
+}
+
+/// Prepares the payout of the requests that have not been paid yet, if the share of the Gateway operator covers the fee.
+///
+/// The memo of the transfer is the number of requests paid before the payout, which is different for every payout of the Gateway
+pub fn prepare_gateway_payout(
+    gateway_ledger_value: &GatewayLedgerValue,
+    share_percentage: u8,
+    created_at_time: u64,
+) -> Option<PendingGatewayPayout> {
+    let unpaid_requests = gateway_ledger_value.get_unpaid_requests();
+
+    compute_gateway_share(unpaid_requests, share_percentage).map(|amount| PendingGatewayPayout {
+        paid_requests: unpaid_requests,
+        amount_e8s: amount.e8s(),
+        memo: gateway_ledger_value.paid_requests,
+        created_at_time,
+    })
+}
+
+/// Transfers the pending payout to the Gateway operator and returns the index of the block of the transfer.
+///
+/// A retry of a transfer that has already been executed has the same memo and creation time,
+/// so the ledger rejects it as a duplicate and the block of the first transfer is returned.
+/// The inner error is returned if the ledger rejected the transfer, which has not been executed then
+pub async fn transfer_gateway_payout<L: LedgerClient>(
+    ledger: &L,
+    gateway_ledger_index: &GatewayLedgerIndex,
+    pending_payout: &PendingGatewayPayout,
+) -> GenericResult<Result<BlockIndex, TransferError>> {
+    let gateway_principal = Principal::from_text(&gateway_ledger_index.gateway_principal_id)
+        .map_err(|e| {
+            GenericError::internal(format!(
+                "Invalid gateway principal ID: {:?} {:?}",
+                gateway_ledger_index.gateway_principal_id, e
+            ))
+        })?;
+
+    let transfer_args = TransferArgs {
+        memo: Memo(pending_payout.memo),
+        amount: Tokens::from_e8s(pending_payout.amount_e8s),
+        fee: DEFAULT_FEE,
+        from_subaccount: None,
+        to: principal_to_account(gateway_principal),
+        created_at_time: Some(Timestamp {
+            timestamp_nanos: pending_payout.created_at_time,
+        }),
+    };
+
+    match ledger.transfer(transfer_args).await {
+        Ok(Ok(block_index)) => Ok(Ok(block_index)),
+        Ok(Err(TransferError::TxDuplicate { duplicate_of })) => Ok(Ok(duplicate_of)),
+        // the ledger doesn't remember the transfers older than its deduplication window,
+        // so it cannot tell whether the previous transfer has been executed
+        Ok(Err(TransferError::TxTooOld { .. })) => Err(GenericError::internal(format!(
+            "Pending payout {:?} is too old to be retried, check the ledger and resolve it with resolveGatewayPayout",
+            pending_payout
+        ))),
+        Ok(Err(transfer_error)) => Ok(Err(transfer_error)),
+        // the transfer may have been executed before the rejection
+        Err(e) => Err(GenericError::inter_canister_call_failed(
+            get_ledger_principal(),
+            "transfer",
+            e,
+        )),
+    }
+}
+
+/// Transfers to the Gateway operator its share of the requests that have not been paid yet and records the payout.
+///
+/// The requests are reserved in the database before the transfer, so that they are never paid twice.
+/// If the payout could not be recorded, the pending payout is transferred again, which the ledger deduplicates
+pub async fn pay_gateway<L: LedgerClient, C: CanisterCaller>(
+    ledger: &L,
+    database: &DatabaseClient<C>,
+    gateway_ledger_index: GatewayLedgerIndex,
+    gateway_ledger_value: GatewayLedgerValue,
+    share_percentage: u8,
+    timestamp: u64,
+) -> GenericResult<Option<GatewayPayoutIndex>> {
+    let pending_payout = match gateway_ledger_value.pending_payout {
+        Some(pending_payout) => pending_payout,
+        None => {
+            let pending_payout =
+                match prepare_gateway_payout(&gateway_ledger_value, share_percentage, timestamp) {
+                    Some(pending_payout) => pending_payout,
+                    None => return Ok(None),
+                };
+
+            database
+                .start_gateway_payout(gateway_ledger_index.clone(), pending_payout.clone())
+                .await?;
+
+            pending_payout
+        }
+    };
+
+    let block_index =
+        match transfer_gateway_payout(ledger, &gateway_ledger_index, &pending_payout).await? {
+            Ok(block_index) => block_index,
+            Err(transfer_error) => {
+                // the transfer has not been executed, so the requests can be paid by the next payouts
+                database
+                    .cancel_gateway_payout(gateway_ledger_index, pending_payout)
+                    .await?;
+
+                return Err(GenericError::internal(format!(
+                    "Ledger transfer failed: {:?}",
+                    transfer_error
+                )));
+            }
+        };
+
+    record_pending_payout(database, gateway_ledger_index, pending_payout, block_index)
+        .await
+        .map(Some)
+}
+
+/// Records the pending payout as transferred in the ledger block with the given index
+async fn record_pending_payout<C: CanisterCaller>(
+    database: &DatabaseClient<C>,
+    gateway_ledger_index: GatewayLedgerIndex,
+    pending_payout: PendingGatewayPayout,
+    block_index: BlockIndex,
+) -> GenericResult<GatewayPayoutIndex> {
+    let gateway_payout_index = GatewayPayoutIndex {
+        gateway_principal_id: gateway_ledger_index.gateway_principal_id,
+        block_index,
+    };
+    database
+        .record_gateway_payout(
+            gateway_payout_index.clone(),
+            GatewayPayoutValue {
+                paid_requests: pending_payout.paid_requests,
+                amount_e8s: pending_payout.amount_e8s,
+                timestamp: pending_payout.created_at_time,
+            },
+        )
+        .await?;
+
+    Ok(gateway_payout_index)
+}
+
+/// Records or cancels the pending payout, according to the outcome of its transfer.
+/// The database checks that the gateway still has the pending payout, so a payout that has been resolved meanwhile is not resolved again
+pub async fn resolve_gateway_payout_with<C: CanisterCaller>(
+    database: &DatabaseClient<C>,
+    gateway_ledger_index: GatewayLedgerIndex,
+    pending_payout: PendingGatewayPayout,
+    resolution: PendingGatewayPayoutResolution,
+) -> GenericResult<()> {
+    match resolution {
+        PendingGatewayPayoutResolution::Transferred(block_index) => {
+            record_pending_payout(database, gateway_ledger_index, pending_payout, block_index)
+                .await?;
+        }
+        PendingGatewayPayoutResolution::NotTransferred => {
+            database
+                .cancel_gateway_payout(gateway_ledger_index, pending_payout)
+                .await?;
+        }
+    }
+    Ok(())
+}
+
+/// Pays all the Gateways with unpaid requests and returns whether some payouts failed
+pub async fn pay_gateways<L: LedgerClient, C: CanisterCaller>(
+    ledger: &L,
+    database: &DatabaseClient<C>,
+    share_percentage: u8,
+    timestamp: u64,
+) -> bool {
+    let gateway_ledgers =
+        match collect_pages(|page_request| database.get_unpaid_gateway_ledgers(page_request)).await
+        {
+            Ok(gateway_ledgers) => gateway_ledgers,
+            Err(e) => {
+                print(format!("Error getting unpaid gateway ledgers: {}", e));
+                return true;
+            }
+        };
+
+    print(format!(
+        "Paying {} gateways with a share of {}%...",
+        gateway_ledgers.len(),
+        share_percentage
+    ));
+
+    let mut has_failed = false;
+    for (gateway_ledger_index, gateway_ledger_value) in gateway_ledgers {
+        let gateway_principal_id = gateway_ledger_index.gateway_principal_id.clone();
+        match pay_gateway(
+            ledger,
+            database,
+            gateway_ledger_index,
+            gateway_ledger_value,
+            share_percentage,
+            timestamp,
+        )
+        .await
+        {
+            Ok(Some(gateway_payout_index)) => {
+                print(format!("Recorded payout {:?}", gateway_payout_index))
+            }
+            Ok(None) => (),
+            Err(e) => {
+                print(format!(
+                    "Error paying gateway {:?}: {}",
+                    gateway_principal_id, e
+                ));
+                has_failed = true;
+            }
+        }
+    }
+
+    has_failed
+}
+
+/// Flags the payouts as in progress until it's dropped, which happens even if the payouts trap after an await
+struct PayoutsGuard;
+
+impl PayoutsGuard {
+    /// Returns `None` if the payouts are already in progress
+    fn acquire() -> Option<Self> {
+        STATE.with(|state| {
+            let mut state = state.borrow_mut();
+            if state.payouts_in_progress {
+                return None;
+            }
+            state.payouts_in_progress = true;
+            Some(Self)
+        })
+    }
+}
+
+impl Drop for PayoutsGuard {
+    fn drop(&mut self) {
+        STATE.with(|state| state.borrow_mut().payouts_in_progress = false);
+    }
+}
+
+async fn run_gateway_payouts() {
+    // prevent concurrent payouts, which would reserve the same requests twice
+    let _guard = match PayoutsGuard::acquire() {
+        Some(guard) => guard,
+        None => {
+            print("Gateway payouts are already in progress, skipping...");
+            return;
+        }
+    };
+
+    let share_percentage = STATE.with(|state| state.borrow().gateway_revenue_share_percentage);
+
+    if pay_gateways(&IcpLedger, &get_database_client(), share_percentage, time()).await {
+        // the pending payouts must be retried before the ledger forgets their transfers
+        ic_cdk_timers::set_timer(Duration::from_secs(GATEWAY_PAYOUTS_RETRY_SECONDS), || {
+            ic_cdk::spawn(run_gateway_payouts())
+        });
+    }
+}
+
+pub fn start_gateway_payouts_timer() {
+    ic_cdk_timers::set_timer_interval(
+        Duration::from_secs(GATEWAY_PAYOUTS_INTERVAL_SECONDS),
+        || ic_cdk::spawn(run_gateway_payouts()),
+    );
+}
+
+#[update(name = "runGatewayPayouts")]
+#[candid_method(update, rename = "runGatewayPayouts")]
+/// Only controllers can run the payouts without waiting for the timer
+async fn run_gateway_payouts_now() -> GenericResult<()> {
+    caller_is_controller()?;
+
+    run_gateway_payouts().await;
+
+    Ok(())
+}
+
+#[update(name = "resolveGatewayPayout")]
+#[candid_method(update, rename = "resolveGatewayPayout")]
+/// Only controllers can resolve the pending payouts that are too old to be retried, because the ledger doesn't remember their transfers anymore.
+/// The controllers check on the ledger whether the transfer with the memo and the creation time of the pending payout has been executed
+async fn resolve_gateway_payout(
+    gateway_principal_id: GatewayPrincipalId,
+    pending_payout: PendingGatewayPayout,
+    resolution: PendingGatewayPayoutResolution,
+) -> GenericResult<()> {
+    caller_is_controller()?;
+
+    // the payouts would otherwise retry the transfer of the pending payout while it's being resolved
+    let _guard = PayoutsGuard::acquire()
+        .ok_or_else(|| GenericError::internal("Gateway payouts are in progress, retry later"))?;
+
+    resolve_gateway_payout_with(
+        &get_database_client(),
+        GatewayLedgerIndex {
+            gateway_principal_id,
+        },
+        pending_payout,
+        resolution,
+    )
+    .await
+}
+
+#[update(name = "getGatewayPayouts")]
+#[candid_method(update, rename = "getGatewayPayouts")]
+async fn get_gateway_payouts(page_request: PageRequest) -> GatewayPayoutsResult {
+    let gateway_principal_id = caller().to_string();
+
+    get_database_client()
+        .get_gateway_payouts(gateway_principal_id, page_request)
+        .await
+}
+
+#[query(name = "getGatewayRevenueShare")]
+#[candid_method(query, rename = "getGatewayRevenueShare")]
+fn get_gateway_revenue_share() -> u8 {
+    STATE.with(|state| state.borrow().gateway_revenue_share_percentage)
+}
+
+#[update(name = "setGatewayRevenueShare")]
+#[candid_method(update, rename = "setGatewayRevenueShare")]
+/// Only controllers can change the percentage of the Access Key revenue paid to Gateway operators
+fn set_gateway_revenue_share(share_percentage: u8) -> GenericResult<()> {
+    caller_is_controller()?;
+
+    if share_percentage > 100 {
+        return Err(GenericError::invalid_argument(format!(
+            "Revenue share must be a percentage, got: {}",
+            share_percentage
+        )));
+    }
+
+    STATE.with(|state| state.borrow_mut().gateway_revenue_share_percentage = share_percentage);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+
+    use ic_cdk::api::call::RejectionCode;
+    use omnia_types::pagination::Page;
+
+    use super::*;
+    use crate::database_client::tests::{block_on, called_methods, MockCaller};
+
+    /// Mock ledger canister that records the transfers, returns increasing block indexes
+    /// and rejects the transfers with the same memo and creation time as duplicates
+    #[derive(Default)]
+    struct MockLedger {
+        transfers: RefCell<Vec<TransferArgs>>,
+        error: Option<TransferError>,
+    }
+
+    impl LedgerClient for MockLedger {
+        async fn transfer(&self, transfer_args: TransferArgs) -> CallResult<TransferResult> {
+            if let Some(error) = &self.error {
+                return Ok(Err(error.clone()));
+            }
+
+            let mut transfers = self.transfers.borrow_mut();
+            if let Some(position) = transfers.iter().position(|transfer| {
+                transfer.memo == transfer_args.memo
+                    && transfer.created_at_time == transfer_args.created_at_time
+            }) {
+                return Ok(Err(TransferError::TxDuplicate {
+                    duplicate_of: position as BlockIndex + 1,
+                }));
+            }
+            transfers.push(transfer_args);
+            Ok(Ok(transfers.len() as BlockIndex))
+        }
+    }
+
+    const GATEWAY_PRINCIPAL_ID: &str =
+        "xri4h-7eqgu-ad7eb-n3yik-avr5c-tjs6r-3e2yb-cigc2-mdswc-olkvi-3ae";
+
+    fn gateway_ledger(
+        served_requests: u64,
+        paid_requests: u64,
+    ) -> (GatewayLedgerIndex, GatewayLedgerValue) {
+        (
+            GatewayLedgerIndex {
+                gateway_principal_id: GATEWAY_PRINCIPAL_ID.to_string(),
+            },
+            GatewayLedgerValue {
+                served_requests,
+                paid_requests,
+                last_served_at: 0,
+                pending_payout: None,
+            },
+        )
+    }
+
+    fn database_reply() -> GenericResult<GatewayLedgerValue> {
+        Ok(GatewayLedgerValue::default())
+    }
+
+    #[test]
+    fn test_compute_gateway_share() {
+        let request_price_e8s = ACCESS_KEY_PRICE.e8s() / ACCESS_KEY_REQUESTS_LIMIT as u64;
+
+        assert_eq!(
+            compute_gateway_share(100, 50),
+            Some(Tokens::from_e8s(
+                100 * request_price_e8s / 2 - DEFAULT_FEE.e8s()
+            ))
+        );
+        assert_eq!(compute_gateway_share(100, 0), None);
+        assert_eq!(compute_gateway_share(0, 100), None);
+    }
+
+    #[test]
+    fn test_prepare_gateway_payout() {
+        let (_, value) = gateway_ledger(150, 50);
+
+        assert_eq!(
+            prepare_gateway_payout(&value, 70, 42),
+            Some(PendingGatewayPayout {
+                paid_requests: 100,
+                amount_e8s: compute_gateway_share(100, 70).unwrap().e8s(),
+                memo: 50,
+                created_at_time: 42,
+            })
+        );
+        assert_eq!(prepare_gateway_payout(&value, 0, 42), None);
+    }
+
+    #[test]
+    fn test_pay_gateway() {
+        let ledger = MockLedger::default();
+        let database = MockCaller::default()
+            .reply(database_reply())
+            .reply(database_reply())
+            .into_client();
+        let (index, value) = gateway_ledger(150, 50);
+
+        let payout_index = block_on(pay_gateway(&ledger, &database, index, value, 70, 42))
+            .unwrap()
+            .expect("should pay the gateway");
+
+        assert_eq!(payout_index.gateway_principal_id, GATEWAY_PRINCIPAL_ID);
+        assert_eq!(payout_index.block_index, 1);
+        // the requests are reserved before the transfer
+        assert_eq!(
+            called_methods(&database),
+            vec!["start_gateway_payout", "record_gateway_payout"]
+        );
+
+        let transfers = ledger.transfers.borrow();
+        assert_eq!(transfers.len(), 1);
+        assert_eq!(
+            transfers[0].to,
+            principal_to_account(Principal::from_text(GATEWAY_PRINCIPAL_ID).unwrap())
+        );
+        assert_eq!(transfers[0].amount, compute_gateway_share(100, 70).unwrap());
+        assert_eq!(transfers[0].memo, Memo(50));
+        assert_eq!(
+            transfers[0].created_at_time,
+            Some(Timestamp {
+                timestamp_nanos: 42
+            })
+        );
+    }
+
+    #[test]
+    fn test_pay_gateway_nothing_to_pay() {
+        let ledger = MockLedger::default();
+        let database = MockCaller::default().into_client();
+        let (index, value) = gateway_ledger(50, 50);
+
+        assert!(
+            block_on(pay_gateway(&ledger, &database, index, value, 70, 42))
+                .unwrap()
+                .is_none()
+        );
+        assert!(ledger.transfers.borrow().is_empty());
+        assert!(called_methods(&database).is_empty());
+    }
+
+    #[test]
+    fn test_pay_gateway_record_failed() {
+        let ledger = MockLedger::default();
+        let database = MockCaller::default()
+            .reply(database_reply())
+            .reject(RejectionCode::CanisterError, "trapped")
+            .into_client();
+        let (index, value) = gateway_ledger(150, 50);
+
+        assert!(block_on(pay_gateway(
+            &ledger,
+            &database,
+            index.clone(),
+            value.clone(),
+            70,
+            42
+        ))
+        .is_err());
+
+        // the next payouts find the payout pending in the database, possibly with another share
+        let pending_value = GatewayLedgerValue {
+            pending_payout: prepare_gateway_payout(&value, 70, 42),
+            ..value
+        };
+        let database = MockCaller::default().reply(database_reply()).into_client();
+
+        let payout_index = block_on(pay_gateway(
+            &ledger,
+            &database,
+            index,
+            pending_value,
+            50,
+            1042,
+        ))
+        .unwrap()
+        .expect("should record the payout");
+
+        // the ledger rejects the retry as a duplicate, so the gateway is paid only once
+        assert_eq!(payout_index.block_index, 1);
+        assert_eq!(ledger.transfers.borrow().len(), 1);
+        assert_eq!(called_methods(&database), vec!["record_gateway_payout"]);
+    }
+
+    #[test]
+    fn test_pay_gateway_transfer_rejected() {
+        let ledger = MockLedger {
+            error: Some(TransferError::InsufficientFunds {
+                balance: Tokens::from_e8s(0),
+            }),
+            ..Default::default()
+        };
+        let database = MockCaller::default()
+            .reply(database_reply())
+            .reply(database_reply())
+            .into_client();
+        let (index, value) = gateway_ledger(100, 0);
+
+        assert!(block_on(pay_gateway(&ledger, &database, index, value, 70, 42)).is_err());
+        // the requests are released, so that the next payouts can pay them
+        assert_eq!(
+            called_methods(&database),
+            vec!["start_gateway_payout", "cancel_gateway_payout"]
+        );
+    }
+
+    #[test]
+    fn test_pay_gateway_transfer_too_old() {
+        let ledger = MockLedger {
+            error: Some(TransferError::TxTooOld {
+                allowed_window_nanos: 0,
+            }),
+            ..Default::default()
+        };
+        let database = MockCaller::default().into_client();
+        let (index, value) = gateway_ledger(150, 50);
+        let pending_value = GatewayLedgerValue {
+            pending_payout: prepare_gateway_payout(&value, 70, 42),
+            ..value
+        };
+
+        // the transfer may have been executed, so the payout stays pending until the controllers resolve it
+        assert!(block_on(pay_gateway(
+            &ledger,
+            &database,
+            index,
+            pending_value,
+            70,
+            1042
+        ))
+        .is_err());
+        assert!(called_methods(&database).is_empty());
+    }
+
+    #[test]
+    fn test_resolve_gateway_payout() {
+        let (index, value) = gateway_ledger(150, 50);
+        let pending_payout = prepare_gateway_payout(&value, 70, 42).unwrap();
+
+        let database = MockCaller::default().reply(database_reply()).into_client();
+        assert_eq!(
+            block_on(resolve_gateway_payout_with(
+                &database,
+                index.clone(),
+                pending_payout.clone(),
+                PendingGatewayPayoutResolution::Transferred(7),
+            )),
+            Ok(())
+        );
+        assert_eq!(called_methods(&database), vec!["record_gateway_payout"]);
+
+        let database = MockCaller::default().reply(database_reply()).into_client();
+        assert_eq!(
+            block_on(resolve_gateway_payout_with(
+                &database,
+                index.clone(),
+                pending_payout.clone(),
+                PendingGatewayPayoutResolution::NotTransferred,
+            )),
+            Ok(())
+        );
+        assert_eq!(called_methods(&database), vec!["cancel_gateway_payout"]);
+
+        // the database rejects the resolution of a payout that is not pending anymore
+        let not_pending_error = GenericError::invalid_argument("no pending payout");
+        let database = MockCaller::default()
+            .reply::<GenericResult<GatewayLedgerValue>>(Err(not_pending_error.clone()))
+            .into_client();
+        assert_eq!(
+            block_on(resolve_gateway_payout_with(
+                &database,
+                index,
+                pending_payout,
+                PendingGatewayPayoutResolution::NotTransferred,
+            )),
+            Err(not_pending_error)
+        );
+    }
+
+    #[test]
+    fn test_pay_gateways() {
+        let ledger = MockLedger::default();
+        let (index, value) = gateway_ledger(100, 0);
+        let database = MockCaller::default()
+            .reply(Page {
+                items: vec![(index, value)],
+                next_cursor: None,
+            })
+            .reply(database_reply())
+            .reject(RejectionCode::SysFatal, "out of cycles")
+            .into_client();
+
+        assert!(block_on(pay_gateways(&ledger, &database, 70, 42)));
+        assert_eq!(ledger.transfers.borrow().len(), 1);
+    }
+
+    #[test]
+    fn test_payouts_guard() {
+        let guard = PayoutsGuard::acquire().expect("payouts should not be in progress");
+        assert!(PayoutsGuard::acquire().is_none());
+
+        drop(guard);
+        assert!(!STATE.with(|state| state.borrow().payouts_in_progress));
+        assert!(PayoutsGuard::acquire().is_some());
+    }
+}
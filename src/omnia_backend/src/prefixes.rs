@@ -0,0 +1,193 @@
+use candid::candid_method;
+use ic_cdk_macros::query;
+use ic_oxigraph::model::{NamedNode, Term};
+use omnia_types::{errors::GenericResult, vocabulary::PrefixMapResult};
+
+use crate::{
+    rdf::{BOT_PREFIX, HTTP_PREFIX, OMNIA_PREFIX, RDFS_PREFIX, RDF_PREFIX, URN_PREFIX, XSD_PREFIX},
+    vocabulary::get_vocabularies,
+};
+
+/// Namespaces of the nodes written by the backend, which are not vocabularies of the Thing Descriptions
+/// but are needed to query the environments and the devices
+const QUERY_NAMESPACES: &[(&str, &str)] = &[
+    ("omnia", OMNIA_PREFIX),
+    ("rdf", RDF_PREFIX),
+    ("rdfs", RDFS_PREFIX),
+    ("xsd", XSD_PREFIX),
+    ("bot", BOT_PREFIX),
+    ("http", HTTP_PREFIX),
+    ("urn", URN_PREFIX),
+];
+
+/// Whether the prefix is reserved for one of the namespaces of the queries, so that no vocabulary can use it
+pub fn is_query_prefix(prefix: &str) -> bool {
+    QUERY_NAMESPACES
+        .iter()
+        .any(|(query_prefix, _)| *query_prefix == prefix)
+}
+
+/// The prefixes declared in every SPARQL query, along with their namespaces: the ones of the queries
+/// followed by the ones of the built-in and custom vocabularies
+pub fn get_prefix_map() -> GenericResult<Vec<(String, String)>> {
+    let mut prefix_map: Vec<(String, String)> = QUERY_NAMESPACES
+        .iter()
+        .map(|(prefix, namespace)| (prefix.to_string(), namespace.to_string()))
+        .collect();
+
+    for vocabulary in get_vocabularies()? {
+        if !prefix_map
+            .iter()
+            .any(|(prefix, _)| *prefix == vocabulary.prefix)
+        {
+            prefix_map.push((vocabulary.prefix, vocabulary.namespace));
+        }
+    }
+
+    Ok(prefix_map)
+}
+
+/// The `PREFIX` declarations of the prefix map on a single line, so that they can be prepended to a query
+/// without changing the lines of its errors. The query can still declare the same prefixes with other namespaces
+pub fn get_prefix_declarations(prefix_map: &[(String, String)]) -> String {
+    prefix_map
+        .iter()
+        .map(|(prefix, namespace)| format!("PREFIX {}: <{}> ", prefix, namespace))
+        .collect()
+}
+
+/// The IRI in compact form (like `saref:Device`), if it belongs to one of the namespaces of the prefix map
+/// and its local name doesn't contain `/`, `#` or `?`, which would make it look like a path of the namespace
+pub fn compact_iri(prefix_map: &[(String, String)], iri: &str) -> Option<String> {
+    let (prefix, namespace) = prefix_map
+        .iter()
+        .filter(|(_, namespace)| iri.starts_with(namespace.as_str()))
+        .max_by_key(|(_, namespace)| namespace.len())?;
+
+    let name = &iri[namespace.len()..];
+    if name.is_empty() || name.contains(|c| matches!(c, '/' | '#' | '?')) {
+        return None;
+    }
+
+    Some(format!("{}:{}", prefix, name))
+}
+
+/// Compacts the IRIs of the named nodes, leaving the other terms as they are.
+/// The compact IRIs are not valid absolute IRIs, so they're only meant to be serialized
+pub fn compact_term(prefix_map: &[(String, String)], term: &Term) -> Term {
+    match term {
+        Term::NamedNode(node) => match compact_iri(prefix_map, node.as_str()) {
+            Some(compact_iri) => Term::NamedNode(NamedNode::new_unchecked(compact_iri)),
+            None => term.clone(),
+        },
+        _ => term.clone(),
+    }
+}
+
+#[query(name = "getPrefixMap")]
+#[candid_method(query, rename = "getPrefixMap")]
+/// Returns the prefixes that the SPARQL queries can use without declaring them, along with their namespaces
+fn get_prefix_map_query() -> PrefixMapResult {
+    get_prefix_map()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vocabulary::insert_vocabulary;
+
+    #[test]
+    fn test_prefix_map() {
+        let prefix_map = get_prefix_map().unwrap();
+        assert_eq!(
+            prefix_map[0],
+            (String::from("omnia"), String::from(OMNIA_PREFIX))
+        );
+        assert!(prefix_map.contains(&(
+            String::from("saref"),
+            String::from("https://saref.etsi.org/core/")
+        )));
+
+        insert_vocabulary(
+            String::from("schema"),
+            String::from("https://schema.org/"),
+            None,
+        )
+        .unwrap();
+        assert_eq!(
+            get_prefix_map().unwrap().last(),
+            Some(&(String::from("schema"), String::from("https://schema.org/")))
+        );
+    }
+
+    #[test]
+    fn test_prefix_declarations() {
+        let declarations = get_prefix_declarations(&get_prefix_map().unwrap());
+        assert!(!declarations.contains('\n'));
+        assert!(declarations.starts_with("PREFIX omnia: <http://rdf.omnia-iot.com#> "));
+    }
+
+    #[test]
+    fn test_compact_iri() {
+        let prefix_map = get_prefix_map().unwrap();
+
+        assert_eq!(
+            compact_iri(&prefix_map, "https://saref.etsi.org/core/Device"),
+            Some(String::from("saref:Device"))
+        );
+        assert_eq!(
+            compact_iri(
+                &prefix_map,
+                "https://www.w3.org/2019/wot/td#hasPropertyAffordance"
+            ),
+            Some(String::from("td:hasPropertyAffordance"))
+        );
+        assert_eq!(
+            compact_iri(&prefix_map, "https://saref.etsi.org/saref4bldg/Building"),
+            Some(String::from("s4bldg:Building"))
+        );
+        assert_eq!(
+            compact_iri(&prefix_map, "urn:uuid:9a2e6f3c"),
+            Some(String::from("urn:uuid:9a2e6f3c"))
+        );
+        assert_eq!(
+            compact_iri(&prefix_map, "https://saref.etsi.org/core/"),
+            None
+        );
+        assert_eq!(
+            compact_iri(&prefix_map, "https://saref.etsi.org/core/v3.1.1/Device"),
+            None
+        );
+        assert_eq!(
+            compact_iri(&prefix_map, "https://proxy.example.com/device"),
+            None
+        );
+    }
+
+    #[test]
+    fn test_compact_iri_longest_namespace() {
+        let prefix_map = vec![
+            (String::from("ex"), String::from("https://example.com/")),
+            (
+                String::from("exdev"),
+                String::from("https://example.com/devices/"),
+            ),
+        ];
+
+        assert_eq!(
+            compact_iri(&prefix_map, "https://example.com/devices/lamp"),
+            Some(String::from("exdev:lamp"))
+        );
+        assert_eq!(
+            compact_iri(&prefix_map, "https://example.com/lamp"),
+            Some(String::from("ex:lamp"))
+        );
+        assert_eq!(
+            compact_term(
+                &prefix_map,
+                &Term::NamedNode(NamedNode::new("https://example.com/lamp").unwrap())
+            ),
+            Term::NamedNode(NamedNode::new_unchecked("ex:lamp"))
+        );
+    }
+}
@@ -1,12 +1,29 @@
 use candid::candid_method;
-use ic_cdk::api::trap;
+use ic_cdk::{api::trap, print};
 use ic_cdk_macros::{query, update};
-use ic_oxigraph::model::NamedNode;
-use ic_oxigraph::sparql::QueryResults;
-use omnia_types::errors::GenericResult;
+use ic_oxigraph::io::{GraphFormat, GraphSerializer};
+use ic_oxigraph::model::{
+    vocab, GraphName, GraphNameRef, Literal, NamedNode, NamedOrBlankNode, Quad, Subject, Term,
+    Triple,
+};
+use ic_oxigraph::sparql::{Query, QueryResults, QuerySolution, QueryTripleIter, Variable};
+use ic_oxigraph::store::{StorageError, Store};
+use omnia_types::device::{DeviceUrl, RegisteredDeviceValue};
+use omnia_types::environment::EnvironmentUID;
+use omnia_types::errors::{GenericError, GenericResult};
+use omnia_types::sparql::{SparqlDataset, SparqlQueryLimits};
+use omnia_utils::constants::SPARQL_QUERY_MAX_RESPONSE_BYTES_LIMIT;
 use sparesults::{QueryResultsFormat, QueryResultsSerializer};
+use spargebra::algebra::GraphPattern;
+use std::collections::{BTreeSet, HashSet};
 
-use crate::RDF_DB;
+use crate::{
+    certification::{certify_environment_devices_responses, CertificationBatch},
+    prefixes::{compact_term, get_prefix_declarations, get_prefix_map},
+    service_description::{count_inserted_quad, count_removed_quad},
+    utils::{caller_is_controller, get_instruction_counter},
+    RDF_DB, STATE,
+};
 
 // RDF available prefixes and nodes
 
@@ -32,16 +49,14 @@ impl SarefNode {
             Err(_) => trap("Error creating SarefNode"),
         }
     }
-
-    /// Expects a string like saref:OnCommand or OnCommand as input and returns the SAREF named node
-    pub fn from_prefixed(name: &str) -> NamedNode {
-        if let Some(stripped) = name.strip_prefix("saref:") {
-            return SarefNode::from(stripped);
-        }
-        SarefNode::from(name)
-    }
 }
 
+/// https://saref.etsi.org/saref4bldg/
+pub const S4BLDG_PREFIX: &str = "https://saref.etsi.org/saref4bldg/";
+
+/// https://saref.etsi.org/saref4ener/
+pub const S4ENER_PREFIX: &str = "https://saref.etsi.org/saref4ener/";
+
 /// https://w3id.org/bot#
 pub const BOT_PREFIX: &str = "https://w3id.org/bot#";
 pub struct BotNode;
@@ -78,6 +93,42 @@ impl TdNode {
     }
 }
 
+/// https://www.w3.org/2019/wot/hypermedia#
+pub const HCTL_PREFIX: &str = "https://www.w3.org/2019/wot/hypermedia#";
+pub struct HctlNode;
+impl HctlNode {
+    pub fn from(name: &str) -> NamedNode {
+        match NamedNode::new(format!("{}{}", HCTL_PREFIX, name)) {
+            Ok(node) => node,
+            Err(_) => trap("Error creating HctlNode"),
+        }
+    }
+}
+
+/// https://www.w3.org/2019/wot/json-schema#
+pub const JSON_SCHEMA_PREFIX: &str = "https://www.w3.org/2019/wot/json-schema#";
+pub struct JsonSchemaNode;
+impl JsonSchemaNode {
+    pub fn from(name: &str) -> NamedNode {
+        match NamedNode::new(format!("{}{}", JSON_SCHEMA_PREFIX, name)) {
+            Ok(node) => node,
+            Err(_) => trap("Error creating JsonSchemaNode"),
+        }
+    }
+}
+
+/// https://www.w3.org/2019/wot/security#
+pub const WOTSEC_PREFIX: &str = "https://www.w3.org/2019/wot/security#";
+pub struct WotSecNode;
+impl WotSecNode {
+    pub fn from(name: &str) -> NamedNode {
+        match NamedNode::new(format!("{}{}", WOTSEC_PREFIX, name)) {
+            Ok(node) => node,
+            Err(_) => trap("Error creating WotSecNode"),
+        }
+    }
+}
+
 /// urn:
 pub const URN_PREFIX: &str = "urn:";
 pub struct UrnNode;
@@ -89,47 +140,1535 @@ impl UrnNode {
             Err(_) => trap("Error creating UrnNode (uuid)"),
         }
     }
+
+    /// Creates a urn:principal: node, used for the Gateways
+    pub fn new_principal(principal_id: &str) -> NamedNode {
+        match NamedNode::new(format!("{}principal:{}", URN_PREFIX, principal_id)) {
+            Ok(node) => node,
+            Err(_) => trap("Error creating UrnNode (principal)"),
+        }
+    }
+}
+
+/// http://www.w3.org/1999/02/22-rdf-syntax-ns#
+pub const RDF_PREFIX: &str = "http://www.w3.org/1999/02/22-rdf-syntax-ns#";
+
+/// http://www.w3.org/2000/01/rdf-schema#
+pub const RDFS_PREFIX: &str = "http://www.w3.org/2000/01/rdf-schema#";
+
+/// http://www.w3.org/2001/XMLSchema#
+pub const XSD_PREFIX: &str = "http://www.w3.org/2001/XMLSchema#";
+
+pub fn get_device_node(device_url: &DeviceUrl) -> GenericResult<NamedNode> {
+    NamedNode::new(device_url).map_err(|err| {
+        GenericError::internal(format!(
+            "Error while creating device node for device with URL: {:?} {:?}",
+            device_url, err
+        ))
+    })
+}
+
+/// The quad that declares the environment as a `bot:Zone`
+pub fn get_environment_quad(env_uid: &EnvironmentUID) -> Quad {
+    Quad::new(
+        UrnNode::new_uuid(env_uid),
+        vocab::rdf::TYPE,
+        BotNode::from("Zone"),
+        GraphName::DefaultGraph,
+    )
+}
+
+/// The quad that relates the device to its environment
+pub fn get_device_environment_quad(env_uid: &EnvironmentUID, device_node: NamedNode) -> Quad {
+    Quad::new(
+        UrnNode::new_uuid(env_uid),
+        BotNode::from("hasElement"),
+        device_node,
+        GraphName::DefaultGraph,
+    )
+}
+
+/// The quads that relate the devices to their environment, which are found along with the quad that declares the environment
+pub fn get_environment_devices_quads(env_uid: &EnvironmentUID) -> GenericResult<Vec<Quad>> {
+    let environment_quad = get_environment_quad(env_uid);
+    if !contains_quad(&environment_quad)? {
+        return Err(GenericError::NotFound {
+            entity: String::from("Environment"),
+            id: env_uid.clone(),
+        });
+    }
+
+    RDF_DB.with(|store| {
+        store
+            .borrow()
+            .quads_for_pattern(
+                Some(environment_quad.subject.as_ref()),
+                Some(BotNode::from("hasElement").as_ref()),
+                None,
+                Some(GraphNameRef::DefaultGraph),
+            )
+            .collect::<Result<Vec<Quad>, _>>()
+            .map(|device_quads| [vec![environment_quad], device_quads].concat())
+            .map_err(|e| {
+                GenericError::internal(format!(
+                    "Error reading devices of environment {}: {}",
+                    env_uid, e
+                ))
+            })
+    })
+}
+
+/// The SPARQL query that selects the devices of the environment, whose response to `GET /sparql/query` is certified
+pub fn get_environment_devices_query(env_uid: &EnvironmentUID) -> String {
+    format!(
+        "SELECT ?device WHERE {{ {} {} ?device }}",
+        UrnNode::new_uuid(env_uid),
+        BotNode::from("hasElement")
+    )
+}
+
+/// The SAREF classes of the affordances of a device and the protocols that its gateway offers to subscribe to its events
+#[derive(Default)]
+pub struct DeviceAffordanceNodes {
+    pub properties: Vec<NamedNode>,
+    pub actions: Vec<NamedNode>,
+    pub events: Vec<NamedNode>,
+    pub subscription_protocols: Vec<NamedNode>,
+}
+
+/// The quads that describe the device in its environment, along with its affordances
+pub fn get_device_quads(
+    registered_device_value: &RegisteredDeviceValue,
+    affordance_nodes: DeviceAffordanceNodes,
+) -> GenericResult<Vec<Quad>> {
+    let device_node = get_device_node(&registered_device_value.device_url)?;
+
+    let mut quads: Vec<Quad> = vec![
+        // device declaration
+        Quad::new(
+            device_node.clone(),
+            vocab::rdf::TYPE,
+            SarefNode::from("Device"),
+            GraphName::DefaultGraph,
+        ),
+        // device - environment relation
+        get_device_environment_quad(&registered_device_value.env_uid, device_node.clone()),
+        // device - gateway relation
+        Quad::new(
+            device_node.clone(),
+            OmniaNode::from("hasGateway"),
+            UrnNode::new_principal(&registered_device_value.gateway_principal_id),
+            GraphName::DefaultGraph,
+        ),
+    ];
+
+    // device required HTTP headers
+    // TODO: define better names for HTTP headers
+    if let Some(required_headers) = &registered_device_value.required_headers {
+        required_headers
+            .iter()
+            .enumerate()
+            .for_each(|(i, (header_name, header_value))| {
+                let header_node = OmniaNode::from(&format!("HTTPHeader{}", i));
+
+                quads.extend_from_slice(&[
+                    Quad::new(
+                        header_node.clone(),
+                        vocab::rdf::TYPE,
+                        HttpNode::from("RequestHeader"),
+                        GraphName::DefaultGraph,
+                    ),
+                    Quad::new(
+                        header_node.clone(),
+                        HttpNode::from("fieldName"),
+                        Literal::new_simple_literal(header_name),
+                        GraphName::DefaultGraph,
+                    ),
+                    Quad::new(
+                        header_node.clone(),
+                        HttpNode::from("fieldValue"),
+                        Literal::new_simple_literal(header_value),
+                        GraphName::DefaultGraph,
+                    ),
+                    Quad::new(
+                        device_node.clone(),
+                        OmniaNode::from("requiresHeader"),
+                        header_node,
+                        GraphName::DefaultGraph,
+                    ),
+                ]);
+            });
+    }
+
+    for (predicate, nodes) in [
+        ("hasPropertyAffordance", affordance_nodes.properties),
+        ("hasActionAffordance", affordance_nodes.actions),
+        ("hasEventAffordance", affordance_nodes.events),
+    ] {
+        quads.extend(nodes.into_iter().map(|affordance| {
+            Quad::new(
+                device_node.clone(),
+                TdNode::from(predicate),
+                affordance,
+                GraphName::DefaultGraph,
+            )
+        }));
+    }
+
+    // the protocols are related to the device, so that they're removed along with it
+    quads.extend(affordance_nodes.subscription_protocols.into_iter().map(
+        |subscription_protocol| {
+            Quad::new(
+                device_node.clone(),
+                OmniaNode::from("offersSubscriptionProtocol"),
+                subscription_protocol,
+                GraphName::DefaultGraph,
+            )
+        },
+    ));
+
+    Ok(quads)
+}
+
+/// The quad that records when the description of the device was last changed, as an `xsd:dateTime`
+pub fn get_description_updated_at_quad(device_node: NamedNode, timestamp: u64) -> Quad {
+    let seconds = timestamp / 1_000_000_000;
+    let (days, seconds_of_day) = (seconds / 86_400, seconds % 86_400);
+
+    // converts the days since the Unix epoch to a date of the proleptic Gregorian calendar,
+    // see http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let days = days + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1_460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    let year = year_of_era + era * 400 + u64::from(month <= 2);
+
+    Quad::new(
+        device_node,
+        OmniaNode::from("descriptionUpdatedAt"),
+        Literal::new_typed_literal(
+            format!(
+                "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+                year,
+                month,
+                day,
+                seconds_of_day / 3_600,
+                seconds_of_day % 3_600 / 60,
+                seconds_of_day % 60
+            ),
+            vocab::xsd::DATE_TIME,
+        ),
+        GraphName::DefaultGraph,
+    )
+}
+
+/// The quads that currently describe the device: the ones in the default graph whose subject is the device and its graph with the Thing Description
+pub fn get_stored_device_quads(device_node: &NamedNode) -> GenericResult<Vec<Quad>> {
+    RDF_DB.with(|store| {
+        let rdf_db = store.borrow();
+
+        rdf_db
+            .quads_for_pattern(
+                Some(device_node.as_ref().into()),
+                None,
+                None,
+                Some(GraphNameRef::DefaultGraph),
+            )
+            .chain(rdf_db.quads_for_pattern(None, None, None, Some(device_node.as_ref().into())))
+            .collect::<Result<Vec<Quad>, _>>()
+            .map_err(|e| {
+                GenericError::internal(format!(
+                    "Error reading quads of device {}: {}",
+                    device_node, e
+                ))
+            })
+    })
+}
+
+/// Inserts the quad in the store, in the certified quads of the batch and in the dataset statistics, returning whether it wasn't already in the store
+pub fn insert_quad(
+    rdf_db: &Store,
+    quad: &Quad,
+    certification_batch: &mut CertificationBatch,
+) -> Result<bool, StorageError> {
+    let inserted = rdf_db.insert(quad)?;
+    if inserted {
+        certification_batch.insert_quad(quad);
+        count_inserted_quad(quad);
+    }
+    Ok(inserted)
+}
+
+/// Removes the quad from the store, from the certified quads of the batch and from the dataset statistics, returning whether it was in the store
+pub fn remove_quad(
+    rdf_db: &Store,
+    quad: &Quad,
+    certification_batch: &mut CertificationBatch,
+) -> Result<bool, StorageError> {
+    let removed = rdf_db.remove(quad)?;
+    if removed {
+        certification_batch.remove_quad(quad);
+        count_removed_quad(quad);
+    }
+    Ok(removed)
+}
+
+/// Replaces the old quads with the new ones, removing only the old quads that are not among the new ones and inserting only the new quads that are not among the old ones.
+/// Either the whole diff is applied or none of it: if a write fails, the changes made so far are reverted
+pub fn replace_quads(old_quads: &[Quad], new_quads: &[Quad]) -> GenericResult<()> {
+    let old_quads_set: HashSet<&Quad> = old_quads.iter().collect();
+    let new_quads_set: HashSet<&Quad> = new_quads.iter().collect();
+    let mut certification_batch = CertificationBatch::default();
+
+    RDF_DB.with(|store| {
+        let rdf_db = store.borrow();
+        let mut removed_quads: Vec<&Quad> = vec![];
+        let mut inserted_quads: Vec<&Quad> = vec![];
+
+        let result = old_quads
+            .iter()
+            .filter(|quad| !new_quads_set.contains(quad))
+            .try_for_each(
+                |quad| match remove_quad(&rdf_db, quad, &mut certification_batch) {
+                    Ok(_) => {
+                        removed_quads.push(quad);
+                        Ok(())
+                    }
+                    Err(e) => Err(GenericError::internal(format!(
+                        "Error removing quad {}: {}",
+                        quad, e
+                    ))),
+                },
+            )
+            .and_then(|_| {
+                new_quads
+                    .iter()
+                    .filter(|quad| !old_quads_set.contains(quad))
+                    .try_for_each(|quad| {
+                        match insert_quad(&rdf_db, quad, &mut certification_batch) {
+                            Ok(true) => {
+                                inserted_quads.push(quad);
+                                Ok(())
+                            }
+                            // quads that were already in the graph may be shared with other resources, so they are not reverted
+                            Ok(false) => Ok(()),
+                            Err(e) => Err(GenericError::internal(format!(
+                                "Error inserting quad {}: {}",
+                                quad, e
+                            ))),
+                        }
+                    })
+            });
+
+        if result.is_err() {
+            for quad in inserted_quads {
+                if let Err(e) = remove_quad(&rdf_db, quad, &mut certification_batch) {
+                    print(format!("Error removing quad {}: {}", quad, e));
+                }
+            }
+            for quad in removed_quads {
+                if let Err(e) = insert_quad(&rdf_db, quad, &mut certification_batch) {
+                    print(format!("Error inserting quad {}: {}", quad, e));
+                }
+            }
+        }
+
+        result
+    })
+}
+
+/// Inserts all the quads or none of them: if an insertion fails, the quads inserted so far are removed
+pub fn insert_quads(quads: &[Quad]) -> GenericResult<()> {
+    let mut certification_batch = CertificationBatch::default();
+
+    RDF_DB.with(|store| {
+        let rdf_db = store.borrow();
+        let mut inserted_quads: Vec<&Quad> = vec![];
+
+        for quad in quads {
+            match insert_quad(&rdf_db, quad, &mut certification_batch) {
+                Ok(true) => inserted_quads.push(quad),
+                // quads that were already in the graph may be shared with other resources, so they are not rolled back
+                Ok(false) => (),
+                Err(e) => {
+                    for inserted_quad in inserted_quads {
+                        if let Err(e) =
+                            remove_quad(&rdf_db, inserted_quad, &mut certification_batch)
+                        {
+                            print(format!("Error removing quad {}: {}", inserted_quad, e));
+                        }
+                    }
+
+                    return Err(GenericError::internal(format!(
+                        "Error inserting quad {}: {}",
+                        quad, e
+                    )));
+                }
+            }
+        }
+
+        Ok(())
+    })
+}
+
+/// Removes the quads that have the node as subject or object and returns how many were removed
+pub fn remove_node_quads(node: &NamedNode) -> GenericResult<usize> {
+    let mut certification_batch = CertificationBatch::default();
+
+    RDF_DB.with(|store| remove_node_quads_from(&store.borrow(), node, &mut certification_batch))
+}
+
+fn remove_node_quads_from(
+    rdf_db: &Store,
+    node: &NamedNode,
+    certification_batch: &mut CertificationBatch,
+) -> GenericResult<usize> {
+    let node_quads = rdf_db
+        .quads_for_pattern(Some(node.as_ref().into()), None, None, None)
+        .chain(rdf_db.quads_for_pattern(None, None, Some(node.as_ref().into()), None))
+        .collect::<Result<Vec<Quad>, _>>()
+        .map_err(|e| {
+            GenericError::internal(format!("Error reading quads of node {}: {}", node, e))
+        })?;
+
+    for quad in node_quads.iter() {
+        remove_quad(rdf_db, quad, certification_batch)
+            .map_err(|e| GenericError::internal(format!("Error removing quad {}: {}", quad, e)))?;
+    }
+
+    Ok(node_quads.len())
+}
+
+/// Removes the quads of the device, both the ones in the default graph and its graph with the Thing Description
+pub fn remove_device_quads(device_node: &NamedNode) -> GenericResult<()> {
+    let mut certification_batch = CertificationBatch::default();
+
+    RDF_DB.with(|store| {
+        let rdf_db = store.borrow();
+        remove_node_quads_from(&rdf_db, device_node, &mut certification_batch)?;
+
+        // the quads are removed one by one to remove them from the certified quads as well
+        let graph_quads = rdf_db
+            .quads_for_pattern(None, None, None, Some(device_node.as_ref().into()))
+            .collect::<Result<Vec<Quad>, _>>()
+            .map_err(|e| {
+                GenericError::internal(format!("Error reading graph {}: {}", device_node, e))
+            })?;
+        for quad in graph_quads.iter() {
+            remove_quad(&rdf_db, quad, &mut certification_batch).map_err(|e| {
+                GenericError::internal(format!("Error removing quad {}: {}", quad, e))
+            })?;
+        }
+
+        rdf_db
+            .remove_named_graph(device_node.as_ref())
+            .map_err(|e| {
+                GenericError::internal(format!("Error removing graph {}: {}", device_node, e))
+            })
+    })?;
+
+    Ok(())
+}
+
+pub fn contains_quad(quad: &Quad) -> GenericResult<bool> {
+    RDF_DB.with(|store| {
+        store
+            .borrow()
+            .contains(quad)
+            .map_err(|e| GenericError::internal(format!("Error reading quad {}: {}", quad, e)))
+    })
+}
+
+/// The HTTP header nodes that are not required by any device
+pub fn get_orphaned_header_nodes() -> GenericResult<Vec<NamedNode>> {
+    let header_class = HttpNode::from("RequestHeader");
+    let requires_header = OmniaNode::from("requiresHeader");
+
+    RDF_DB.with(|store| {
+        let rdf_db = store.borrow();
+        let mut orphaned_header_nodes = vec![];
+
+        for quad in rdf_db.quads_for_pattern(
+            None,
+            Some(vocab::rdf::TYPE),
+            Some(header_class.as_ref().into()),
+            None,
+        ) {
+            let quad =
+                quad.map_err(|e| GenericError::internal(format!("Error reading headers: {}", e)))?;
+            if let Subject::NamedNode(header_node) = quad.subject {
+                let is_required = rdf_db
+                    .quads_for_pattern(
+                        None,
+                        Some(requires_header.as_ref()),
+                        Some(header_node.as_ref().into()),
+                        None,
+                    )
+                    .next()
+                    .is_some();
+
+                if !is_required {
+                    orphaned_header_nodes.push(header_node);
+                }
+            }
+        }
+
+        Ok(orphaned_header_nodes)
+    })
+}
+
+/// The URLs of the devices declared in the RDF database
+pub fn get_rdf_device_urls() -> GenericResult<BTreeSet<String>> {
+    let device_class = SarefNode::from("Device");
+
+    RDF_DB.with(|store| {
+        let mut device_urls = BTreeSet::new();
+
+        for quad in store.borrow().quads_for_pattern(
+            None,
+            Some(vocab::rdf::TYPE),
+            Some(device_class.as_ref().into()),
+            None,
+        ) {
+            let quad =
+                quad.map_err(|e| GenericError::internal(format!("Error reading devices: {}", e)))?;
+            if let Subject::NamedNode(device_node) = quad.subject {
+                device_urls.insert(device_node.into_string());
+            }
+        }
+
+        Ok(device_urls)
+    })
+}
+
+/// The results of a SPARQL query split in pages that fit in a single response
+pub struct SparqlQueryPages {
+    /// the pages with the results that follow the ones of the previous page: in the SPARQL Query Results JSON format for the solutions and the booleans,
+    /// or in the graph format for the triples of the `CONSTRUCT` and `DESCRIBE` queries, whose pages are documents that can be concatenated
+    pub pages: Vec<Vec<u8>>,
+    /// if the results don't fit in a single response, the limit that stopped the first page
+    pub exceeded_limit: Option<GenericError>,
+    /// the number of solutions, or of triples, in the first page
+    pub first_page_rows: u64,
+    /// the format of the triples, if the query returns a graph
+    pub graph_format: Option<GraphFormat>,
+}
+
+impl SparqlQueryPages {
+    pub fn media_type(&self) -> &'static str {
+        match self.graph_format {
+            Some(graph_format) => graph_format.media_type(),
+            None => QueryResultsFormat::Json.media_type(),
+        }
+    }
+}
+
+/// Parses the query and sets its dataset, which overrides the one of its `FROM` and `FROM NAMED` clauses.
+/// If the query doesn't parse as it is, it's parsed again with the prefixes of the [get_prefix_map] declared before it.
+/// The `SELECT` queries skip the first `offset` solutions and, without `LIMIT`, get the `implicit_limit`, if any
+fn parse_sparql_query(
+    query: &str,
+    dataset: &SparqlDataset,
+    offset: usize,
+    implicit_limit: Option<usize>,
+) -> GenericResult<Query> {
+    let mut parsed_query = match spargebra::Query::parse(query, None) {
+        Ok(parsed_query) => parsed_query,
+        Err(_) => {
+            let prefixed_query =
+                format!("{}{}", get_prefix_declarations(&get_prefix_map()?), query);
+            spargebra::Query::parse(&prefixed_query, None).map_err(|e| {
+                GenericError::invalid_argument(format!(
+                    "Error parsing SPARQL query: {} (query: {})",
+                    e, query
+                ))
+            })?
+        }
+    };
+    if let spargebra::Query::Select { pattern, .. } = &mut parsed_query {
+        set_page_slice(pattern, offset, implicit_limit);
+    }
+    let mut parsed_query = Query::from(parsed_query);
+
+    if !dataset.is_empty() {
+        let parse_graph = |graph: &String| {
+            NamedNode::new(graph).map_err(|e| {
+                GenericError::invalid_argument(format!("Invalid graph {}: {}", graph, e))
+            })
+        };
+        let default_graph = dataset
+            .default_graph_uris
+            .iter()
+            .map(|graph| parse_graph(graph).map(GraphName::from))
+            .collect::<GenericResult<Vec<GraphName>>>()?;
+        // the named graphs that are not listed are not available, even if none is listed
+        let named_graphs = dataset
+            .named_graph_uris
+            .iter()
+            .map(|graph| parse_graph(graph).map(NamedOrBlankNode::from))
+            .collect::<GenericResult<Vec<NamedOrBlankNode>>>()?;
+
+        parsed_query.dataset_mut().set_default_graph(default_graph);
+        parsed_query
+            .dataset_mut()
+            .set_available_named_graphs(named_graphs);
+    }
+
+    Ok(parsed_query)
+}
+
+/// Skips the first `offset` solutions of the pattern of a `SELECT` query, after the ones skipped by its `OFFSET`,
+/// and limits them to `limit`, unless the query already has a `LIMIT`, which is reduced by the skipped solutions
+fn set_page_slice(pattern: &mut GraphPattern, offset: usize, limit: Option<usize>) {
+    match pattern {
+        GraphPattern::Slice { start, length, .. } => {
+            *start = start.saturating_add(offset);
+            *length = match *length {
+                Some(length) => Some(length.saturating_sub(offset)),
+                None => limit,
+            };
+        }
+        _ if offset == 0 && limit.is_none() => (),
+        _ => {
+            *pattern = GraphPattern::Slice {
+                inner: Box::new(std::mem::take(pattern)),
+                start: offset,
+                length: limit,
+            }
+        }
+    }
+}
+
+/// Executes the query on the dataset within the [SparqlQueryLimits], splitting its solutions, or the triples of its graph, in pages that don't exceed the rows or the response size.
+/// Without `all_pages`, only the first page is returned and the query is evaluated only as far as needed to know whether more results follow.
+/// With `compact_iris`, the IRIs of the solutions are returned in compact form when they belong to a namespace of the [get_prefix_map].
+/// The graphs of the `CONSTRUCT` and `DESCRIBE` queries are serialized in the `graph_format`
+pub fn execute_sparql_query(
+    query: &str,
+    dataset: &SparqlDataset,
+    compact_iris: bool,
+    graph_format: GraphFormat,
+    all_pages: bool,
+) -> GenericResult<SparqlQueryPages> {
+    execute_sparql_query_from(query, dataset, compact_iris, graph_format, 0, all_pages)
 }
 
-pub fn execute_sparql_query(query: String) -> GenericResult<Vec<u8>> {
+/// Executes the query like [execute_sparql_query], skipping the first `offset` solutions, or triples of its graph, which were returned by the previous pages
+pub fn execute_sparql_query_from(
+    query: &str,
+    dataset: &SparqlDataset,
+    compact_iris: bool,
+    graph_format: GraphFormat,
+    offset: usize,
+    all_pages: bool,
+) -> GenericResult<SparqlQueryPages> {
+    // only the instructions of the query count, since it can run in a call that did other work before, like certifying the devices of an environment
+    let initial_instructions = get_instruction_counter();
+    let limits = STATE.with(|state| state.borrow().sparql_query_limits);
+    // a single page needs one solution more than it can contain to know whether more solutions follow
+    let implicit_limit = (!all_pages).then(|| (limits.max_rows as usize).saturating_add(1));
+    let parsed_query = parse_sparql_query(query, dataset, offset, implicit_limit)?;
+    let prefix_map = compact_iris.then(get_prefix_map).transpose()?;
+
     RDF_DB.with(|store| {
         let rdf_db = store.borrow();
 
-        if let QueryResults::Solutions(solutions) = rdf_db
-            .query(&query)
-            .map_err(|e| format!("Error executing SPARQL query: {:?} (query: {})", e, query))?
-        {
-            let json_serializer = QueryResultsSerializer::from_format(QueryResultsFormat::Json);
+        let mut solutions = match rdf_db.query(parsed_query).map_err(|e| {
+            GenericError::invalid_argument(format!(
+                "Error executing SPARQL query: {:?} (query: {})",
+                e, query
+            ))
+        })? {
+            QueryResults::Solutions(solutions) => solutions,
+            QueryResults::Boolean(value) => {
+                return Ok(SparqlQueryPages {
+                    pages: vec![serialize_boolean(value)?],
+                    exceeded_limit: None,
+                    first_page_rows: 1,
+                    graph_format: None,
+                })
+            }
+            QueryResults::Graph(triples) => {
+                return get_triples_pages(
+                    triples,
+                    graph_format,
+                    &limits,
+                    initial_instructions,
+                    offset,
+                    all_pages,
+                )
+            }
+        };
+        let variables = solutions.variables().to_vec();
+
+        let mut pages: Vec<Vec<u8>> = vec![];
+        let mut exceeded_limit = None;
+        let mut first_page_rows = 0;
+        // the solutions that are evaluated but not yet in a page
+        let mut pending: Vec<QuerySolution> = vec![];
+        let mut is_evaluated = false;
+        loop {
+            // one solution more than the page can contain tells whether more solutions follow.
+            // Solutions are evaluated lazily, so the work done is checked before getting each of them
+            while !is_evaluated && pending.len() as u64 <= limits.max_rows {
+                if get_instruction_counter() - initial_instructions > limits.max_instructions {
+                    return Err(GenericError::LimitExceeded {
+                        limit: String::from("max_instructions"),
+                        max: limits.max_instructions,
+                    });
+                }
+                match solutions.next() {
+                    Some(solution) => pending.push(solution.map_err(|e| {
+                        GenericError::internal(format!("Error getting solution: {:?}", e))
+                    })?),
+                    None => is_evaluated = true,
+                }
+            }
 
-            let mut solutions_writer = json_serializer
-                .solutions_writer(Vec::new(), solutions.variables().to_vec())
-                .map_err(|e| format!("Error serializing SPARQL query variables: {:?}", e))?;
+            let mut rows = pending.len().min(limits.max_rows as usize);
+            let mut page_limit = (rows < pending.len()).then(|| GenericError::LimitExceeded {
+                limit: String::from("max_rows"),
+                max: limits.max_rows,
+            });
+            let results = loop {
+                let results = serialize_solutions(
+                    variables.clone(),
+                    &pending[..rows],
+                    prefix_map.as_deref(),
+                )?;
+                if results.len() as u64 <= limits.max_response_bytes {
+                    break results;
+                }
 
-            for solution in solutions {
-                solutions_writer
-                    .write(&solution.map_err(|e| format!("Error getting solution: {:?}", e))?)
-                    .map_err(|e| format!("Error serializing SPARQL query results: {:?}", e))?;
+                let max_response_bytes_exceeded = GenericError::LimitExceeded {
+                    limit: String::from("max_response_bytes"),
+                    max: limits.max_response_bytes,
+                };
+                // a single solution that doesn't fit cannot be returned in any page
+                if rows <= 1 {
+                    return Err(max_response_bytes_exceeded);
+                }
+                page_limit = Some(max_response_bytes_exceeded);
+                // the rows are scaled down to the ones expected to fit, removing at least one
+                rows = (rows - 1)
+                    .min((rows as u64 * limits.max_response_bytes / results.len() as u64) as usize)
+                    .max(1);
+            };
+            pages.push(results);
+            pending.drain(..rows);
+
+            if pages.len() == 1 {
+                exceeded_limit = page_limit;
+                first_page_rows = rows as u64;
+            }
+            if (is_evaluated && pending.is_empty()) || !all_pages {
+                return Ok(SparqlQueryPages {
+                    pages,
+                    exceeded_limit,
+                    first_page_rows,
+                    graph_format: None,
+                });
             }
+        }
+    })
+}
 
-            return solutions_writer
-                .finish()
-                .map_err(|e| format!("Error serializing SPARQL query results: {:?}", e));
+/// Splits the triples of the graph in pages of whole statements that don't exceed the rows or the response size.
+/// The first `offset` triples are skipped, since the template of the query can build any number of them from each solution
+fn get_triples_pages(
+    triples: QueryTripleIter,
+    graph_format: GraphFormat,
+    limits: &SparqlQueryLimits,
+    initial_instructions: u64,
+    offset: usize,
+    all_pages: bool,
+) -> GenericResult<SparqlQueryPages> {
+    let mut pages: Vec<Vec<u8>> = vec![];
+    let mut exceeded_limit = None;
+    let mut first_page_rows = 0;
+    let mut page: Vec<u8> = vec![];
+    let mut rows = 0;
+    for (i, triple) in triples.enumerate() {
+        if get_instruction_counter() - initial_instructions > limits.max_instructions {
+            return Err(GenericError::LimitExceeded {
+                limit: String::from("max_instructions"),
+                max: limits.max_instructions,
+            });
+        }
+        let triple =
+            triple.map_err(|e| GenericError::internal(format!("Error getting triple: {:?}", e)))?;
+        if i < offset {
+            continue;
         }
+        let statement = serialize_triple(&triple, graph_format)?;
 
-        Err("Error executing SPARQL query".to_string())
+        let page_limit = if rows == limits.max_rows {
+            Some(GenericError::LimitExceeded {
+                limit: String::from("max_rows"),
+                max: limits.max_rows,
+            })
+        } else if (page.len() + statement.len()) as u64 > limits.max_response_bytes {
+            let max_response_bytes_exceeded = GenericError::LimitExceeded {
+                limit: String::from("max_response_bytes"),
+                max: limits.max_response_bytes,
+            };
+            // a single triple that doesn't fit cannot be returned in any page
+            if page.is_empty() {
+                return Err(max_response_bytes_exceeded);
+            }
+            Some(max_response_bytes_exceeded)
+        } else {
+            None
+        };
+        if let Some(page_limit) = page_limit {
+            pages.push(std::mem::take(&mut page));
+            if pages.len() == 1 {
+                exceeded_limit = Some(page_limit);
+                first_page_rows = rows;
+            }
+            rows = 0;
+            if !all_pages {
+                break;
+            }
+        }
+
+        page.extend_from_slice(&statement);
+        rows += 1;
+    }
+    if pages.is_empty() {
+        first_page_rows = rows;
+    }
+    if pages.is_empty() || all_pages {
+        pages.push(page);
+    }
+
+    Ok(SparqlQueryPages {
+        pages,
+        exceeded_limit,
+        first_page_rows,
+        graph_format: Some(graph_format),
+    })
+}
+
+/// Serializes the triple as a statement by itself, so that the statements can be split in pages
+fn serialize_triple(triple: &Triple, graph_format: GraphFormat) -> GenericResult<Vec<u8>> {
+    let serialization_error =
+        |e| GenericError::internal(format!("Error serializing triple {}: {:?}", triple, e));
+
+    let mut statement = vec![];
+    let mut triple_writer = GraphSerializer::from_format(graph_format)
+        .triple_writer(&mut statement)
+        .map_err(serialization_error)?;
+    triple_writer.write(triple).map_err(serialization_error)?;
+    triple_writer.finish().map_err(serialization_error)?;
+
+    Ok(statement)
+}
+
+/// Serializes the result of an `ASK` query in the SPARQL Query Results JSON format
+fn serialize_boolean(value: bool) -> GenericResult<Vec<u8>> {
+    QueryResultsSerializer::from_format(QueryResultsFormat::Json)
+        .write_boolean_result(Vec::new(), value)
+        .map_err(|e| {
+            GenericError::internal(format!("Error serializing SPARQL query result: {:?}", e))
+        })
+}
+
+/// Serializes the solutions in the SPARQL Query Results JSON format, compacting their IRIs with the prefix map, if any
+fn serialize_solutions(
+    variables: Vec<Variable>,
+    solutions: &[QuerySolution],
+    prefix_map: Option<&[(String, String)]>,
+) -> GenericResult<Vec<u8>> {
+    let json_serializer = QueryResultsSerializer::from_format(QueryResultsFormat::Json);
+
+    let mut solutions_writer = json_serializer
+        .solutions_writer(Vec::new(), variables)
+        .map_err(|e| {
+            GenericError::internal(format!("Error serializing SPARQL query variables: {:?}", e))
+        })?;
+
+    for solution in solutions {
+        let written = match prefix_map {
+            Some(prefix_map) => {
+                let compacted_solution: Vec<(&Variable, Term)> = solution
+                    .iter()
+                    .map(|(variable, term)| (variable, compact_term(prefix_map, term)))
+                    .collect();
+                solutions_writer.write(
+                    compacted_solution
+                        .iter()
+                        .map(|(variable, term)| (*variable, term)),
+                )
+            }
+            None => solutions_writer.write(solution),
+        };
+        written.map_err(|e| {
+            GenericError::internal(format!("Error serializing SPARQL query results: {:?}", e))
+        })?;
+    }
+
+    solutions_writer.finish().map_err(|e| {
+        GenericError::internal(format!("Error serializing SPARQL query results: {:?}", e))
     })
 }
 
+/// The quads of the named graph, or of the whole dataset, in N-Quads, split in chunks of whole lines that don't exceed the response size.
+/// Without `all_chunks`, only the first chunk is returned along with whether more chunks follow it
+pub fn dump_quads(
+    graph: Option<&NamedNode>,
+    all_chunks: bool,
+) -> GenericResult<(Vec<Vec<u8>>, bool)> {
+    let limits = STATE.with(|state| state.borrow().sparql_query_limits);
+
+    RDF_DB.with(|store| {
+        let rdf_db = store.borrow();
+        let quads = match graph {
+            Some(graph) => rdf_db.quads_for_pattern(None, None, None, Some(graph.as_ref().into())),
+            None => rdf_db.iter(),
+        };
+
+        let mut chunks: Vec<Vec<u8>> = vec![];
+        let mut chunk: Vec<u8> = vec![];
+        for quad in quads {
+            if get_instruction_counter() > limits.max_instructions {
+                return Err(GenericError::LimitExceeded {
+                    limit: String::from("max_instructions"),
+                    max: limits.max_instructions,
+                });
+            }
+            let quad =
+                quad.map_err(|e| GenericError::internal(format!("Error reading quads: {}", e)))?;
+
+            let line = format!("{} .\n", quad);
+            if (chunk.len() + line.len()) as u64 > limits.max_response_bytes {
+                if chunk.is_empty() {
+                    return Err(GenericError::LimitExceeded {
+                        limit: String::from("max_response_bytes"),
+                        max: limits.max_response_bytes,
+                    });
+                }
+                chunks.push(std::mem::take(&mut chunk));
+                if !all_chunks {
+                    return Ok((chunks, true));
+                }
+            }
+            chunk.extend_from_slice(line.as_bytes());
+        }
+        chunks.push(chunk);
+
+        Ok((chunks, false))
+    })
+}
+
+/// Executes the query for the candid methods, whose results must fit in a single response
+fn execute_sparql_query_in_single_page(query: String) -> GenericResult<Vec<u8>> {
+    let query_pages = execute_sparql_query(
+        &query,
+        &SparqlDataset::default(),
+        false,
+        GraphFormat::Turtle,
+        false,
+    )?;
+
+    match query_pages.exceeded_limit {
+        Some(exceeded_limit) => Err(exceeded_limit),
+        None => Ok(query_pages.pages.concat()),
+    }
+}
+
 #[query(name = "executeRdfDbQuery")]
 #[candid_method(query, rename = "executeRdfDbQuery")]
-/// The result is returned in JSON in the form of a Vec<u8> that can be parsed
+/// The result is returned in the form of a Vec<u8> that can be parsed: in the SPARQL Query Results JSON format, or in Turtle for the `CONSTRUCT` and `DESCRIBE` queries.
+/// If the solutions don't fit in a single response, the exceeded limit is returned: use `LIMIT` and `OFFSET` or the HTTP endpoint, which returns them in pages
 fn execute_rdf_db_query(input_query: String) -> GenericResult<Vec<u8>> {
-    execute_sparql_query(input_query)
+    execute_sparql_query_in_single_page(input_query)
 }
 
 #[update(name = "executeRdfDbQueryAsUpdate")]
 #[candid_method(update, rename = "executeRdfDbQueryAsUpdate")]
 /// Same as `executeRdfDbQuery` but for inter-canister calls
 fn execute_rdf_db_query_as_update(input_query: String) -> GenericResult<Vec<u8>> {
-    execute_sparql_query(input_query)
+    execute_sparql_query_in_single_page(input_query)
+}
+
+#[query(name = "getSparqlQueryLimits")]
+#[candid_method(query, rename = "getSparqlQueryLimits")]
+fn get_sparql_query_limits() -> SparqlQueryLimits {
+    STATE.with(|state| state.borrow().sparql_query_limits)
+}
+
+#[update(name = "setSparqlQueryLimits")]
+#[candid_method(update, rename = "setSparqlQueryLimits")]
+/// Only controllers can change the limits of the SPARQL queries
+fn set_sparql_query_limits(limits: SparqlQueryLimits) -> GenericResult<()> {
+    caller_is_controller()?;
+
+    if limits.max_rows == 0 || limits.max_response_bytes == 0 || limits.max_instructions == 0 {
+        return Err(GenericError::invalid_argument(
+            "SPARQL query limits must be greater than 0",
+        ));
+    }
+    if limits.max_response_bytes > SPARQL_QUERY_MAX_RESPONSE_BYTES_LIMIT {
+        return Err(GenericError::invalid_argument(format!(
+            "Responses cannot be larger than {} bytes, got: {}",
+            SPARQL_QUERY_MAX_RESPONSE_BYTES_LIMIT, limits.max_response_bytes
+        )));
+    }
+
+    STATE.with(|state| state.borrow_mut().sparql_query_limits = limits);
+    // the limits change how the results are paginated
+    certify_environment_devices_responses();
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::Value;
+
+    use super::*;
+
+    const QUERY: &str = "SELECT ?device WHERE { ?device a <https://saref.etsi.org/core/Device> }";
+
+    fn insert_devices(count: usize) {
+        let quads: Vec<Quad> = (0..count)
+            .map(|i| {
+                Quad::new(
+                    NamedNode::new(format!("https://proxy.example.com/device-{:02}", i)).unwrap(),
+                    vocab::rdf::TYPE,
+                    SarefNode::from("Device"),
+                    GraphName::DefaultGraph,
+                )
+            })
+            .collect();
+        insert_quads(&quads).unwrap();
+    }
+
+    fn set_limits(max_rows: u64, max_response_bytes: u64) {
+        STATE.with(|state| {
+            let limits = &mut state.borrow_mut().sparql_query_limits;
+            limits.max_rows = max_rows;
+            limits.max_response_bytes = max_response_bytes;
+        });
+    }
+
+    fn get_devices(results: &[u8]) -> Vec<String> {
+        let results: Value = serde_json::from_slice(results).unwrap();
+        results["results"]["bindings"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|binding| binding["device"]["value"].as_str().unwrap().to_string())
+            .collect()
+    }
+
+    /// The devices of each page of the query
+    fn get_pages() -> Vec<Vec<String>> {
+        execute_sparql_query(
+            QUERY,
+            &SparqlDataset::default(),
+            false,
+            GraphFormat::Turtle,
+            true,
+        )
+        .unwrap()
+        .pages
+        .iter()
+        .map(|page| get_devices(page))
+        .collect()
+    }
+
+    /// The devices returned by the query in a single page
+    fn get_all_devices() -> Vec<String> {
+        let query_pages = execute_sparql_query(
+            QUERY,
+            &SparqlDataset::default(),
+            false,
+            GraphFormat::Turtle,
+            false,
+        )
+        .unwrap();
+        assert!(query_pages.exceeded_limit.is_none());
+        get_devices(&query_pages.pages[0])
+    }
+
+    #[test]
+    fn test_sparql_query_max_rows() {
+        insert_devices(5);
+        let all_devices = get_all_devices();
+        assert_eq!(all_devices.len(), 5);
+        set_limits(2, 1_000_000);
+
+        let first_page = execute_sparql_query(
+            QUERY,
+            &SparqlDataset::default(),
+            false,
+            GraphFormat::Turtle,
+            false,
+        )
+        .unwrap();
+        assert_eq!(first_page.pages.len(), 1);
+        assert_eq!(
+            first_page.exceeded_limit,
+            Some(GenericError::LimitExceeded {
+                limit: String::from("max_rows"),
+                max: 2,
+            })
+        );
+
+        let pages = get_pages();
+        assert_eq!(
+            pages.iter().map(Vec::len).collect::<Vec<_>>(),
+            vec![2, 2, 1]
+        );
+        assert_eq!(pages.concat(), all_devices);
+    }
+
+    #[test]
+    fn test_sparql_query_offset() {
+        insert_devices(5);
+        let all_devices = get_all_devices();
+        set_limits(2, 1_000_000);
+        let execute_query = |query: &str, offset: usize| {
+            execute_sparql_query_from(
+                query,
+                &SparqlDataset::default(),
+                false,
+                GraphFormat::NTriples,
+                offset,
+                false,
+            )
+            .unwrap()
+        };
+
+        // each page starts after the results of the previous ones, like the pages requested with the continuation tokens
+        let mut devices = vec![];
+        let mut offset = 0;
+        loop {
+            let query_pages = execute_query(QUERY, offset);
+            devices.extend(get_devices(&query_pages.pages[0]));
+            offset += query_pages.first_page_rows as usize;
+            if query_pages.exceeded_limit.is_none() {
+                break;
+            }
+        }
+        assert_eq!(devices, all_devices);
+
+        // the offset follows the one of the query and reduces its limit
+        let query_pages = execute_query(&format!("{} ORDER BY ?device OFFSET 1 LIMIT 3", QUERY), 1);
+        assert_eq!(
+            get_devices(&query_pages.pages[0]),
+            vec![
+                "https://proxy.example.com/device-02",
+                "https://proxy.example.com/device-03"
+            ]
+        );
+        assert!(query_pages.exceeded_limit.is_none());
+
+        // the triples of a graph are skipped one by one
+        let graph_query = "CONSTRUCT { ?device a saref:Device } WHERE { ?device a saref:Device }";
+        let mut triples = vec![];
+        let mut offset = 0;
+        loop {
+            let query_pages = execute_query(graph_query, offset);
+            assert!(query_pages.first_page_rows <= 2);
+            triples.extend(query_pages.pages[0].clone());
+            offset += query_pages.first_page_rows as usize;
+            if query_pages.exceeded_limit.is_none() {
+                break;
+            }
+        }
+        assert_eq!(offset, 5);
+        assert_eq!(
+            triples,
+            execute_sparql_query(
+                graph_query,
+                &SparqlDataset::default(),
+                false,
+                GraphFormat::NTriples,
+                true,
+            )
+            .unwrap()
+            .pages
+            .concat()
+        );
+    }
+
+    #[test]
+    fn test_sparql_query_max_response_bytes() {
+        insert_devices(10);
+        let all_devices = get_all_devices();
+        let all_results_len = execute_sparql_query(
+            QUERY,
+            &SparqlDataset::default(),
+            false,
+            GraphFormat::Turtle,
+            false,
+        )
+        .unwrap()
+        .pages[0]
+            .len();
+        let max_response_bytes = all_results_len as u64 / 3;
+        set_limits(100, max_response_bytes);
+
+        let query_pages = execute_sparql_query(
+            QUERY,
+            &SparqlDataset::default(),
+            false,
+            GraphFormat::Turtle,
+            true,
+        )
+        .unwrap();
+        assert!(query_pages.pages.len() > 1);
+        assert!(query_pages
+            .pages
+            .iter()
+            .all(|page| page.len() as u64 <= max_response_bytes));
+        assert!(matches!(
+            query_pages.exceeded_limit,
+            Some(GenericError::LimitExceeded { limit, .. }) if limit == "max_response_bytes"
+        ));
+        assert_eq!(get_pages().concat(), all_devices);
+
+        // a single solution that doesn't fit cannot be paginated
+        set_limits(100, 10);
+        assert_eq!(
+            execute_sparql_query(
+                QUERY,
+                &SparqlDataset::default(),
+                false,
+                GraphFormat::Turtle,
+                false
+            )
+            .err(),
+            Some(GenericError::LimitExceeded {
+                limit: String::from("max_response_bytes"),
+                max: 10,
+            })
+        );
+    }
+
+    #[test]
+    fn test_sparql_query_single_page() {
+        insert_devices(3);
+        set_limits(3, 1_000_000);
+        assert_eq!(
+            get_devices(&execute_sparql_query_in_single_page(QUERY.to_string()).unwrap()).len(),
+            3
+        );
+
+        set_limits(2, 1_000_000);
+        assert!(matches!(
+            execute_sparql_query_in_single_page(QUERY.to_string()),
+            Err(GenericError::LimitExceeded { limit, .. }) if limit == "max_rows"
+        ));
+    }
+
+    #[test]
+    fn test_sparql_query_dataset() {
+        let graphs: Vec<NamedNode> = (0..2)
+            .map(|i| NamedNode::new(format!("https://proxy.example.com/device-{:02}", i)).unwrap())
+            .collect();
+        let quads: Vec<Quad> = graphs
+            .iter()
+            .map(|graph| {
+                Quad::new(
+                    graph.clone(),
+                    vocab::rdf::TYPE,
+                    SarefNode::from("Device"),
+                    GraphName::NamedNode(graph.clone()),
+                )
+            })
+            .collect();
+        insert_quads(&quads).unwrap();
+        let get_query_devices = |query: &str, dataset: &SparqlDataset| {
+            get_devices(
+                &execute_sparql_query(query, dataset, false, GraphFormat::Turtle, false)
+                    .unwrap()
+                    .pages[0],
+            )
+        };
+
+        // the default graph of the database doesn't contain the quads of the named graphs
+        assert!(get_query_devices(QUERY, &SparqlDataset::default()).is_empty());
+        assert_eq!(
+            get_query_devices(
+                QUERY,
+                &SparqlDataset {
+                    default_graph_uris: vec![graphs[1].as_str().to_string()],
+                    named_graph_uris: vec![],
+                }
+            ),
+            vec![graphs[1].as_str()]
+        );
+
+        let graph_query =
+            "SELECT ?device WHERE { GRAPH ?g { ?device a <https://saref.etsi.org/core/Device> } }";
+        assert_eq!(
+            get_query_devices(graph_query, &SparqlDataset::default()).len(),
+            2
+        );
+        assert_eq!(
+            get_query_devices(
+                graph_query,
+                &SparqlDataset {
+                    default_graph_uris: vec![],
+                    named_graph_uris: vec![graphs[0].as_str().to_string()],
+                }
+            ),
+            vec![graphs[0].as_str()]
+        );
+
+        assert!(matches!(
+            execute_sparql_query(
+                QUERY,
+                &SparqlDataset {
+                    default_graph_uris: vec![String::from("not an IRI")],
+                    named_graph_uris: vec![],
+                },
+                false,
+                GraphFormat::Turtle,
+                false,
+            ),
+            Err(GenericError::InvalidArgument { .. })
+        ));
+    }
+
+    #[test]
+    fn test_sparql_query_prefixes() {
+        insert_devices(2);
+        let query = "SELECT ?device WHERE { ?device a saref:Device }";
+
+        let results = execute_sparql_query(
+            query,
+            &SparqlDataset::default(),
+            false,
+            GraphFormat::Turtle,
+            false,
+        )
+        .unwrap()
+        .pages
+        .concat();
+        assert_eq!(get_devices(&results).len(), 2);
+
+        // the prefixes declared by the query override the injected ones
+        let redeclared_query = format!("PREFIX saref: <https://example.com/saref#>\n{}", query);
+        assert!(get_devices(
+            &execute_sparql_query(
+                &redeclared_query,
+                &SparqlDataset::default(),
+                false,
+                GraphFormat::Turtle,
+                false
+            )
+            .unwrap()
+            .pages[0]
+        )
+        .is_empty());
+
+        assert!(matches!(
+            execute_sparql_query(
+                "SELECT ?device WHERE { ?device a unknown:Device }",
+                &SparqlDataset::default(),
+                false,
+                GraphFormat::Turtle,
+                false,
+            ),
+            Err(GenericError::InvalidArgument { .. })
+        ));
+    }
+
+    #[test]
+    fn test_sparql_query_compact_iris() {
+        insert_devices(1);
+        let query = "SELECT ?device ?class WHERE { ?device a ?class }";
+
+        let results: Value = serde_json::from_slice(
+            &execute_sparql_query(
+                query,
+                &SparqlDataset::default(),
+                true,
+                GraphFormat::Turtle,
+                false,
+            )
+            .unwrap()
+            .pages[0],
+        )
+        .unwrap();
+        let binding = &results["results"]["bindings"][0];
+        assert_eq!(binding["class"]["type"], "uri");
+        assert_eq!(binding["class"]["value"], "saref:Device");
+        // the IRIs that don't belong to a known namespace are left as they are
+        assert_eq!(
+            binding["device"]["value"],
+            "https://proxy.example.com/device-00"
+        );
+
+        let results = execute_sparql_query(
+            query,
+            &SparqlDataset::default(),
+            false,
+            GraphFormat::Turtle,
+            false,
+        )
+        .unwrap()
+        .pages
+        .concat();
+        assert!(std::str::from_utf8(&results)
+            .unwrap()
+            .contains("https://saref.etsi.org/core/Device"));
+    }
+
+    #[test]
+    fn test_sparql_query_solution_modifiers() {
+        insert_devices(5);
+        let execute_query = |query: &str| {
+            execute_sparql_query(
+                query,
+                &SparqlDataset::default(),
+                false,
+                GraphFormat::Turtle,
+                false,
+            )
+            .unwrap()
+        };
+
+        let query_pages = execute_query(&format!("{} ORDER BY DESC(?device)", QUERY));
+        assert_eq!(
+            get_devices(&query_pages.pages[0])
+                .first()
+                .map(String::as_str),
+            Some("https://proxy.example.com/device-04")
+        );
+
+        let query_pages =
+            execute_query("SELECT (COUNT(?device) AS ?count) WHERE { ?device a saref:Device }");
+        let results: Value = serde_json::from_slice(&query_pages.pages[0]).unwrap();
+        assert_eq!(results["results"]["bindings"][0]["count"]["value"], "5");
+
+        // the implicit limit keeps the offset of the query
+        set_limits(2, 1_000_000);
+        let query_pages = execute_query(&format!("{} ORDER BY ?device OFFSET 2", QUERY));
+        assert_eq!(
+            get_devices(&query_pages.pages[0]),
+            vec![
+                "https://proxy.example.com/device-02",
+                "https://proxy.example.com/device-03"
+            ]
+        );
+        assert_eq!(
+            query_pages.exceeded_limit,
+            Some(GenericError::LimitExceeded {
+                limit: String::from("max_rows"),
+                max: 2,
+            })
+        );
+        let query_pages = execute_query(&format!("{} ORDER BY ?device OFFSET 3", QUERY));
+        assert_eq!(get_devices(&query_pages.pages[0]).len(), 2);
+        assert!(query_pages.exceeded_limit.is_none());
+    }
+
+    #[test]
+    fn test_sparql_query_forms() {
+        insert_devices(5);
+        let execute_query = |query: &str, graph_format: GraphFormat, all_pages: bool| {
+            execute_sparql_query(
+                query,
+                &SparqlDataset::default(),
+                false,
+                graph_format,
+                all_pages,
+            )
+            .unwrap()
+        };
+
+        let ask_pages = execute_query("ASK { ?device a saref:Device }", GraphFormat::Turtle, false);
+        assert_eq!(ask_pages.media_type(), "application/sparql-results+json");
+        let result: Value = serde_json::from_slice(&ask_pages.pages[0]).unwrap();
+        assert_eq!(result["boolean"], Value::Bool(true));
+
+        let query = "CONSTRUCT { ?device a saref:Device } WHERE { ?device a saref:Device }";
+        for graph_format in [GraphFormat::Turtle, GraphFormat::NTriples] {
+            let graph_pages = execute_query(query, graph_format, false);
+            assert_eq!(graph_pages.media_type(), graph_format.media_type());
+            assert!(graph_pages.exceeded_limit.is_none());
+            assert_eq!(
+                std::str::from_utf8(&graph_pages.pages[0])
+                    .unwrap()
+                    .lines()
+                    .count(),
+                5
+            );
+        }
+
+        // the pages of the graph are split between whole triples
+        set_limits(2, 1_000_000);
+        let graph_pages = execute_query(query, GraphFormat::NTriples, true);
+        assert_eq!(graph_pages.pages.len(), 3);
+        assert!(graph_pages.exceeded_limit.is_some());
+        let triples = graph_pages.pages.concat();
+        assert_eq!(
+            std::str::from_utf8(&triples)
+                .unwrap()
+                .lines()
+                .filter(|line| line.ends_with(" ."))
+                .count(),
+            5
+        );
+    }
+
+    #[test]
+    fn test_dump_quads() {
+        insert_devices(5);
+        let device_node = NamedNode::new("https://proxy.example.com/device-00").unwrap();
+        insert_quads(&[Quad::new(
+            device_node.clone(),
+            vocab::rdf::TYPE,
+            TdNode::from("Thing"),
+            GraphName::NamedNode(device_node.clone()),
+        )])
+        .unwrap();
+
+        let (chunks, has_next_chunk) = dump_quads(None, false).unwrap();
+        assert!(!has_next_chunk);
+        let dump = chunks.concat();
+        let lines: Vec<&str> = std::str::from_utf8(&dump).unwrap().lines().collect();
+        assert_eq!(lines.len(), 6);
+
+        // each chunk contains whole lines
+        set_limits(100, lines[0].len() as u64 * 2 + 2);
+        let (first_chunk, has_next_chunk) = dump_quads(None, false).unwrap();
+        assert!(has_next_chunk);
+        let (chunks, _) = dump_quads(None, true).unwrap();
+        assert!(chunks.len() > 1);
+        assert_eq!(chunks[0], first_chunk[0]);
+        assert!(chunks.iter().all(|chunk| chunk.ends_with(b"\n")));
+        assert_eq!(chunks.concat(), dump);
+
+        set_limits(100, 1_000_000);
+        assert_eq!(
+            dump_quads(Some(&device_node), false).unwrap(),
+            (
+                vec![format!(
+                    "<{}> <{}> <{}> <{}> .\n",
+                    device_node.as_str(),
+                    vocab::rdf::TYPE.as_str(),
+                    TdNode::from("Thing").as_str(),
+                    device_node.as_str()
+                )
+                .into_bytes()],
+                false
+            )
+        );
+    }
 }
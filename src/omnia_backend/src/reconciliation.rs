@@ -0,0 +1,540 @@
+use std::{collections::BTreeSet, time::Duration};
+
+use candid::candid_method;
+use ic_cdk::print;
+use ic_cdk_macros::update;
+use omnia_types::{
+    consistency::{
+        ConsistencyReport, ConsistencyReportResult, DeviceReconciliationReport,
+        DeviceReconciliationReportResult,
+    },
+    device::{RegisteredDeviceIndex, RegisteredDeviceValue},
+    environment::EnvironmentUID,
+    errors::{GenericError, GenericResult},
+};
+use omnia_utils::constants::DEVICE_RECONCILIATION_INTERVAL_SECONDS;
+
+use crate::{
+    database_client::{collect_pages, get_database_client, CanisterCaller, DatabaseClient},
+    rdf::{
+        contains_quad, get_device_environment_quad, get_device_node, get_environment_quad,
+        get_orphaned_header_nodes, get_rdf_device_urls, insert_quads, remove_device_quads,
+        remove_node_quads,
+    },
+    utils::caller_is_controller,
+    STATE,
+};
+
+/// Must be held while registering a device in the database, because until the device is described in the RDF database
+/// it exists only in the database and the reconciliation must not remove it.
+/// The registration is finished when the guard is dropped, even if the registration traps after awaiting the database
+pub struct DeviceRegistrationGuard;
+
+impl DeviceRegistrationGuard {
+    pub fn start() -> Self {
+        STATE.with(|state| {
+            let mut state = state.borrow_mut();
+            state.device_registrations_in_progress += 1;
+            state.device_registrations_started += 1;
+        });
+        Self
+    }
+}
+
+impl Drop for DeviceRegistrationGuard {
+    fn drop(&mut self) {
+        STATE.with(|state| state.borrow_mut().device_registrations_in_progress -= 1);
+    }
+}
+
+/// Returns the number of device registrations started so far, or `None` if some of them are still in progress
+fn get_settled_device_registrations() -> Option<u64> {
+    STATE.with(|state| {
+        let state = state.borrow();
+        match state.device_registrations_in_progress {
+            0 => Some(state.device_registrations_started),
+            _ => None,
+        }
+    })
+}
+
+/// Prevents concurrent reconciliations, which could unregister the same devices twice
+struct ReconciliationGuard;
+
+impl ReconciliationGuard {
+    /// Returns `None` if a reconciliation is already in progress
+    fn acquire() -> Option<Self> {
+        STATE.with(|state| {
+            let mut state = state.borrow_mut();
+            if state.reconciliation_in_progress {
+                return None;
+            }
+            state.reconciliation_in_progress = true;
+            Some(Self)
+        })
+    }
+}
+
+impl Drop for ReconciliationGuard {
+    fn drop(&mut self) {
+        STATE.with(|state| state.borrow_mut().reconciliation_in_progress = false);
+    }
+}
+
+/// The repairs remove and rebuild data from the database, so they wait until its migrations have rewritten all the entries
+async fn check_migrations_completed<C: CanisterCaller>(
+    database: &DatabaseClient<C>,
+) -> GenericResult<()> {
+    match database.migrations_completed().await? {
+        true => Ok(()),
+        false => Err(GenericError::internal(
+            "Database migrations are in progress, retry the repair later",
+        )),
+    }
+}
+
+/// The devices are listed environment by environment, so that the database scans only the index entries of each environment
+async fn collect_registered_devices<C: CanisterCaller>(
+    database: &DatabaseClient<C>,
+    environment_uids: &[EnvironmentUID],
+) -> GenericResult<Vec<(RegisteredDeviceIndex, RegisteredDeviceValue)>> {
+    let mut registered_devices = vec![];
+    for environment_uid in environment_uids {
+        registered_devices.extend(
+            collect_pages(|page_request| {
+                database.get_registered_devices(page_request, environment_uid.clone())
+            })
+            .await?,
+        );
+    }
+    Ok(registered_devices)
+}
+
+/// Finds the devices that exist only in the database or only in the RDF database and, in repair mode, removes them.
+///
+/// Returns `None` if device registrations were in progress, because the two databases may legitimately differ until they finish.
+pub async fn reconcile_devices_with<C: CanisterCaller>(
+    database: &DatabaseClient<C>,
+    repair: bool,
+) -> GenericResult<Option<DeviceReconciliationReport>> {
+    let registrations_before = match get_settled_device_registrations() {
+        Some(registrations) => registrations,
+        None => return Ok(None),
+    };
+
+    if repair {
+        check_migrations_completed(database).await?;
+    }
+
+    let environment_uids =
+        collect_pages(|page_request| database.get_environment_uids(page_request)).await?;
+    let registered_devices = collect_registered_devices(database, &environment_uids).await?;
+
+    // a registration that started while waiting for the database may be missing in one of the two snapshots
+    if get_settled_device_registrations() != Some(registrations_before) {
+        return Ok(None);
+    }
+
+    let rdf_device_urls = get_rdf_device_urls()?;
+
+    let mut report = DeviceReconciliationReport {
+        repaired: repair,
+        ..Default::default()
+    };
+
+    // the RDF database is local, so it's repaired before awaiting any other call
+    for device_url in rdf_device_urls.iter() {
+        if registered_devices
+            .iter()
+            .all(|(_, registered_device_value)| &registered_device_value.device_url != device_url)
+        {
+            if repair {
+                // header nodes are shared among devices, so they are left in the graph
+                remove_device_quads(&get_device_node(device_url)?)?;
+            }
+            report.devices_only_in_rdf.push(device_url.clone());
+        }
+    }
+
+    for (registered_device_index, registered_device_value) in registered_devices {
+        if rdf_device_urls.contains(&registered_device_value.device_url) {
+            continue;
+        }
+
+        if repair {
+            // the device doesn't have its affordances anymore, so it must be registered again by its gateway
+            database
+                .unregister_device_on_gateway(
+                    registered_device_value.gateway_principal_id,
+                    registered_device_index.device_uid.clone(),
+                )
+                .await?;
+        }
+        report
+            .devices_only_in_database
+            .push(registered_device_index.device_uid);
+    }
+
+    Ok(Some(report))
+}
+
+/// The periodic reconciliation only reports the inconsistent devices, which are removed by the controllers
+async fn run_device_reconciliation() {
+    let _guard = match ReconciliationGuard::acquire() {
+        Some(guard) => guard,
+        None => {
+            print("Device reconciliation is already in progress, skipping...");
+            return;
+        }
+    };
+
+    match reconcile_devices_with(&get_database_client(), false).await {
+        Ok(Some(report)) => print(format!("Device reconciliation completed: {:?}", report)),
+        Ok(None) => print("Device registrations in progress, reconciliation postponed"),
+        Err(e) => print(format!("Error reconciling devices: {}", e)),
+    }
+}
+
+pub fn start_device_reconciliation_timer() {
+    ic_cdk_timers::set_timer_interval(
+        Duration::from_secs(DEVICE_RECONCILIATION_INTERVAL_SECONDS),
+        || ic_cdk::spawn(run_device_reconciliation()),
+    );
+}
+
+#[update(name = "reconcileDevices")]
+#[candid_method(update, rename = "reconcileDevices")]
+/// Only controllers can reconcile the devices.
+/// In repair mode, the devices that exist only in the database are unregistered and the ones that exist only in the RDF database are removed
+async fn reconcile_devices(repair: bool) -> DeviceReconciliationReportResult {
+    caller_is_controller()?;
+
+    let _guard = ReconciliationGuard::acquire().ok_or_else(|| {
+        GenericError::internal("Device reconciliation is already in progress, retry later")
+    })?;
+
+    reconcile_devices_with(&get_database_client(), repair)
+        .await?
+        .ok_or_else(|| {
+            GenericError::internal(
+                "Device registrations are in progress, retry the reconciliation later",
+            )
+        })
+}
+
+#[update(name = "checkConsistency")]
+#[candid_method(update, rename = "checkConsistency")]
+/// Only controllers can check the consistency between the database and the RDF database.
+/// In repair mode, the zones of the inconsistent environments and the relations to their devices described in the RDF database are rebuilt from the database,
+/// and orphaned header nodes are removed. The devices that are not described in the RDF database are left to `reconcileDevices`
+async fn check_consistency(repair: bool) -> ConsistencyReportResult {
+    caller_is_controller()?;
+
+    // the repair must not describe again the devices that a reconciliation is removing
+    let _guard = match repair {
+        true => Some(ReconciliationGuard::acquire().ok_or_else(|| {
+            GenericError::internal("Device reconciliation is in progress, retry the repair later")
+        })?),
+        false => None,
+    };
+
+    check_consistency_with(&get_database_client(), repair).await
+}
+
+pub async fn check_consistency_with<C: CanisterCaller>(
+    database: &DatabaseClient<C>,
+    repair: bool,
+) -> ConsistencyReportResult {
+    let registrations_in_progress_error =
+        || GenericError::internal("Device registrations are in progress, retry the repair later");
+
+    // devices being registered are not described yet and must not be described by the repair
+    let registrations_before = get_settled_device_registrations();
+    if repair && registrations_before.is_none() {
+        return Err(registrations_in_progress_error());
+    }
+
+    if repair {
+        check_migrations_completed(database).await?;
+    }
+
+    let environment_uids =
+        collect_pages(|page_request| database.get_environment_uids(page_request)).await?;
+    let registered_devices = collect_registered_devices(database, &environment_uids).await?;
+
+    // a registration that started while waiting for the database may be missing in the listed devices
+    if repair && get_settled_device_registrations() != registrations_before {
+        return Err(registrations_in_progress_error());
+    }
+
+    let mut report = ConsistencyReport::default();
+
+    for env_uid in environment_uids.iter() {
+        if !contains_quad(&get_environment_quad(env_uid))? {
+            report.environments_without_zone.push(env_uid.clone());
+        }
+    }
+
+    for (registered_device_index, registered_device_value) in registered_devices.iter() {
+        let device_node = get_device_node(&registered_device_value.device_url)?;
+        if !contains_quad(&get_device_environment_quad(
+            &registered_device_value.env_uid,
+            device_node,
+        ))? {
+            report
+                .devices_without_environment
+                .push(registered_device_index.device_uid.clone());
+        }
+    }
+
+    report.orphaned_header_nodes = get_orphaned_header_nodes()?
+        .into_iter()
+        .map(|header_node| header_node.into_string())
+        .collect();
+
+    if !repair {
+        return Ok(report);
+    }
+
+    let mut environments_to_repair: BTreeSet<_> =
+        report.environments_without_zone.iter().cloned().collect();
+    environments_to_repair.extend(
+        registered_devices
+            .iter()
+            .filter(|(registered_device_index, _)| {
+                report
+                    .devices_without_environment
+                    .contains(&registered_device_index.device_uid)
+            })
+            .map(|(_, registered_device_value)| registered_device_value.env_uid.clone()),
+    );
+
+    // affordances are stored only in the RDF database, so the devices that are not described there can't be restored:
+    // they're left to the reconciliation, which unregisters them so that their gateways register them again
+    let rdf_device_urls = get_rdf_device_urls()?;
+    for env_uid in environments_to_repair {
+        let mut quads = vec![get_environment_quad(&env_uid)];
+        for (_, registered_device_value) in
+            registered_devices
+                .iter()
+                .filter(|(_, registered_device_value)| {
+                    registered_device_value.env_uid == env_uid
+                        && rdf_device_urls.contains(&registered_device_value.device_url)
+                })
+        {
+            quads.push(get_device_environment_quad(
+                &env_uid,
+                get_device_node(&registered_device_value.device_url)?,
+            ));
+        }
+
+        insert_quads(&quads)?;
+        report.repaired_environments.push(env_uid);
+    }
+
+    // headers may be required again by the repaired devices
+    for header_node in get_orphaned_header_nodes()? {
+        remove_node_quads(&header_node)?;
+    }
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use omnia_types::pagination::Page;
+
+    use super::*;
+    use crate::database_client::tests::{block_on, called_methods, MockCaller};
+
+    #[test]
+    fn test_reconciliation_postponed_during_registrations() {
+        let database = MockCaller::default().into_client();
+
+        let registration = DeviceRegistrationGuard::start();
+        assert_eq!(block_on(reconcile_devices_with(&database, true)), Ok(None));
+        drop(registration);
+
+        assert!(called_methods(&database).is_empty());
+        assert!(get_settled_device_registrations().is_some());
+    }
+
+    #[test]
+    fn test_device_registration_guard_finishes_registration_on_unwind() {
+        // a trap after awaiting the database drops the future of the registration, and its guard with it
+        let result = std::panic::catch_unwind(|| {
+            let _registration = DeviceRegistrationGuard::start();
+            panic!("registration trapped");
+        });
+
+        assert!(result.is_err());
+        assert!(get_settled_device_registrations().is_some());
+    }
+
+    #[test]
+    fn test_reconciliation_guard() {
+        let guard = ReconciliationGuard::acquire().unwrap();
+        assert!(ReconciliationGuard::acquire().is_none());
+
+        drop(guard);
+        assert!(ReconciliationGuard::acquire().is_some());
+    }
+
+    fn get_environment_uids_page() -> Page<EnvironmentUID> {
+        Page {
+            items: vec![String::from("environment")],
+            next_cursor: None,
+        }
+    }
+
+    fn get_registered_devices_page() -> Page<(RegisteredDeviceIndex, RegisteredDeviceValue)> {
+        Page {
+            items: vec![(
+                RegisteredDeviceIndex {
+                    device_uid: String::from("device"),
+                },
+                RegisteredDeviceValue {
+                    gateway_principal_id: String::from("gateway"),
+                    env_uid: String::from("environment"),
+                    device_url: String::from("https://gateway.example.com/device"),
+                    required_headers: None,
+                },
+            )],
+            next_cursor: None,
+        }
+    }
+
+    #[test]
+    fn test_reconciliation_reports_devices_missing_in_rdf() {
+        let database = MockCaller::default()
+            .reply(get_environment_uids_page())
+            .reply(get_registered_devices_page())
+            .into_client();
+
+        assert_eq!(
+            block_on(reconcile_devices_with(&database, false)),
+            Ok(Some(DeviceReconciliationReport {
+                devices_only_in_database: vec![String::from("device")],
+                devices_only_in_rdf: vec![],
+                repaired: false,
+            }))
+        );
+        // without repair mode the device is not unregistered
+        assert_eq!(
+            called_methods(&database),
+            vec!["get_environment_uids", "get_registered_devices"]
+        );
+    }
+
+    #[test]
+    fn test_reconciliation_unregisters_devices_missing_in_rdf() {
+        let database = MockCaller::default()
+            .reply(true)
+            .reply(get_environment_uids_page())
+            .reply(get_registered_devices_page())
+            .reply::<GenericResult<()>>(Ok(()))
+            .into_client();
+
+        assert_eq!(
+            block_on(reconcile_devices_with(&database, true)),
+            Ok(Some(DeviceReconciliationReport {
+                devices_only_in_database: vec![String::from("device")],
+                devices_only_in_rdf: vec![],
+                repaired: true,
+            }))
+        );
+        assert_eq!(
+            called_methods(&database),
+            vec![
+                "migrations_completed",
+                "get_environment_uids",
+                "get_registered_devices",
+                "unregister_device_on_gateway"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_check_consistency_repair_leaves_devices_without_rdf() {
+        let database = MockCaller::default()
+            .reply(true)
+            .reply(get_environment_uids_page())
+            .reply(get_registered_devices_page())
+            .into_client();
+
+        assert_eq!(
+            block_on(check_consistency_with(&database, true)),
+            Ok(ConsistencyReport {
+                environments_without_zone: vec![String::from("environment")],
+                devices_without_environment: vec![String::from("device")],
+                repaired_environments: vec![String::from("environment")],
+                ..Default::default()
+            })
+        );
+        assert!(contains_quad(&get_environment_quad(&String::from("environment"))).unwrap());
+        // the device has no description to relate to the environment, so the reconciliation still finds it
+        assert!(get_rdf_device_urls().unwrap().is_empty());
+        assert_eq!(
+            block_on(reconcile_devices_with(
+                &MockCaller::default()
+                    .reply(get_environment_uids_page())
+                    .reply(get_registered_devices_page())
+                    .into_client(),
+                false
+            ))
+            .unwrap()
+            .unwrap()
+            .devices_only_in_database,
+            vec![String::from("device")]
+        );
+    }
+
+    #[test]
+    fn test_repairs_postponed_during_migrations() {
+        let database = MockCaller::default()
+            .reply(false)
+            .reply(false)
+            .into_client();
+
+        assert!(block_on(reconcile_devices_with(&database, true)).is_err());
+        assert!(block_on(check_consistency_with(&database, true)).is_err());
+        // nothing is listed, and so nothing is removed or rebuilt
+        assert_eq!(
+            called_methods(&database),
+            vec!["migrations_completed", "migrations_completed"]
+        );
+    }
+
+    #[test]
+    fn test_check_consistency_repair_postponed_during_registrations() {
+        let database = MockCaller::default().into_client();
+
+        let registration = DeviceRegistrationGuard::start();
+        assert!(block_on(check_consistency_with(&database, true)).is_err());
+        drop(registration);
+
+        assert!(called_methods(&database).is_empty());
+    }
+
+    #[test]
+    fn test_check_consistency_environment_without_zone() {
+        let database = MockCaller::default()
+            .reply(Page {
+                items: vec![String::from("environment")],
+                next_cursor: None,
+            })
+            .reply(Page::<(RegisteredDeviceIndex, RegisteredDeviceValue)> {
+                items: vec![],
+                next_cursor: None,
+            })
+            .into_client();
+
+        assert_eq!(
+            block_on(check_consistency_with(&database, false)),
+            Ok(ConsistencyReport {
+                environments_without_zone: vec![String::from("environment")],
+                ..Default::default()
+            })
+        );
+    }
+}
@@ -0,0 +1,145 @@
+use std::collections::BTreeMap;
+
+use omnia_types::errors::{GenericError, GenericResult};
+use omnia_utils::constants::{CACHED_RESULTS_EXPIRATION_SECONDS, CACHED_RESULTS_MAX_BYTES};
+
+struct CachedResults {
+    pages: Vec<Vec<u8>>,
+    /// media type of the body split in the pages
+    media_type: &'static str,
+    /// timestamp (in nanoseconds) after which the pages cannot be read anymore
+    expires_at: u64,
+}
+
+/// The pages of the SPARQL query results and of the dumps that don't fit in a single response, evaluated at once by an update call.
+/// Query calls cannot change the state, so they can only read the pages, which expire after [CACHED_RESULTS_EXPIRATION_SECONDS]
+#[derive(Default)]
+pub struct ResultsCache {
+    results: BTreeMap<u64, CachedResults>,
+    next_id: u64,
+    /// total size (in bytes) of the cached pages
+    size: u64,
+}
+
+impl ResultsCache {
+    /// Caches the pages, removing the expired ones first, and returns their id and their expiration.
+    /// Returns None if they don't fit in [CACHED_RESULTS_MAX_BYTES]
+    pub fn insert(
+        &mut self,
+        pages: Vec<Vec<u8>>,
+        media_type: &'static str,
+        now: u64,
+    ) -> Option<(u64, u64)> {
+        self.remove_expired(now);
+
+        let size: u64 = pages.iter().map(|page| page.len() as u64).sum();
+        if self.size + size > CACHED_RESULTS_MAX_BYTES {
+            return None;
+        }
+
+        let id = self.next_id;
+        let expires_at = now + CACHED_RESULTS_EXPIRATION_SECONDS * 1_000_000_000;
+        self.next_id += 1;
+        self.size += size;
+        self.results.insert(
+            id,
+            CachedResults {
+                pages,
+                media_type,
+                expires_at,
+            },
+        );
+
+        Some((id, expires_at))
+    }
+
+    /// The page of the cached results, their media type and whether more pages follow it
+    pub fn get_page(
+        &self,
+        id: u64,
+        page: u64,
+        now: u64,
+    ) -> GenericResult<(&[u8], &'static str, bool)> {
+        let results = self
+            .results
+            .get(&id)
+            .filter(|results| results.expires_at >= now)
+            .ok_or_else(|| {
+                GenericError::invalid_argument("The results have expired, run the query again")
+            })?;
+
+        match results.pages.get(page as usize) {
+            Some(cached_page) => Ok((
+                cached_page,
+                results.media_type,
+                (page as usize) + 1 < results.pages.len(),
+            )),
+            None => Err(GenericError::invalid_argument(format!(
+                "The results have {} pages, got page: {}",
+                results.pages.len(),
+                page
+            ))),
+        }
+    }
+
+    fn remove_expired(&mut self, now: u64) {
+        let mut removed_size = 0;
+        self.results.retain(|_, results| {
+            let is_expired = results.expires_at < now;
+            if is_expired {
+                removed_size += results
+                    .pages
+                    .iter()
+                    .map(|page| page.len() as u64)
+                    .sum::<u64>();
+            }
+            !is_expired
+        });
+        self.size -= removed_size;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_results_cache() {
+        let mut cache = ResultsCache::default();
+        let (id, expires_at) = cache
+            .insert(
+                vec![b"first".to_vec(), b"second".to_vec()],
+                "text/turtle",
+                0,
+            )
+            .unwrap();
+
+        assert_eq!(
+            cache.get_page(id, 0, 0).unwrap(),
+            (&b"first"[..], "text/turtle", true)
+        );
+        assert_eq!(
+            cache.get_page(id, 1, expires_at).unwrap(),
+            (&b"second"[..], "text/turtle", false)
+        );
+        assert!(cache.get_page(id, 2, 0).is_err());
+        assert!(cache.get_page(id + 1, 0, 0).is_err());
+        assert!(cache.get_page(id, 0, expires_at + 1).is_err());
+
+        // the expired pages are removed when other pages are cached
+        let (other_id, _) = cache
+            .insert(vec![vec![]], "text/turtle", expires_at + 1)
+            .unwrap();
+        assert_ne!(other_id, id);
+        assert!(cache.results.get(&id).is_none());
+        assert_eq!(cache.size, 0);
+
+        assert!(cache
+            .insert(
+                vec![vec![0; CACHED_RESULTS_MAX_BYTES as usize + 1]],
+                "text/turtle",
+                0
+            )
+            .is_none());
+    }
+}
@@ -0,0 +1,385 @@
+use std::collections::{btree_map::Entry, BTreeMap};
+
+use ic_oxigraph::model::{vocab, GraphName, Literal, NamedNode, Quad, Term};
+use omnia_types::errors::GenericResult;
+
+use crate::{
+    prefixes::get_prefix_map,
+    rdf::{TdNode, OMNIA_PREFIX},
+    DATASET_STATISTICS, RDF_DB,
+};
+
+/// The predicates that relate the devices to the classes of their affordances
+const AFFORDANCE_PROPERTIES: [&str; 3] = [
+    "hasPropertyAffordance",
+    "hasActionAffordance",
+    "hasEventAffordance",
+];
+
+/// IRI of the SPARQL endpoint, relative to the host of the canister
+const SPARQL_ENDPOINT_IRI: &str = "/sparql/query";
+
+/// Counts of the quads of the RDF database, published in the [VoID](https://www.w3.org/TR/void/) description of the dataset
+#[derive(Default)]
+pub struct DatasetStatistics {
+    /// number of quads in the whole dataset, including the graphs of the devices
+    pub quads: u64,
+    /// number of triples in the default graph, where the devices are described
+    pub default_graph_triples: u64,
+    /// number of instances of each class in the default graph
+    pub class_entities: BTreeMap<String, u64>,
+    /// number of triples of each property in the default graph
+    pub property_triples: BTreeMap<String, u64>,
+    /// number of devices with each affordance class, by affordance property
+    pub affordance_devices: BTreeMap<(String, String), u64>,
+}
+
+/// Increments the count of the key, or decrements it removing the keys that are not counted anymore
+fn update_count<K: Ord>(counts: &mut BTreeMap<K, u64>, key: K, is_inserted: bool) {
+    match counts.entry(key) {
+        Entry::Occupied(mut entry) => match is_inserted {
+            true => *entry.get_mut() += 1,
+            false if *entry.get() > 1 => *entry.get_mut() -= 1,
+            false => {
+                entry.remove();
+            }
+        },
+        Entry::Vacant(entry) => {
+            if is_inserted {
+                entry.insert(1);
+            }
+        }
+    }
+}
+
+impl DatasetStatistics {
+    /// Counts the quad that has been inserted in the dataset, or uncounts it if it has been removed
+    fn count_quad(&mut self, quad: &Quad, is_inserted: bool) {
+        let update_total = |total: &mut u64| match is_inserted {
+            true => *total += 1,
+            false => *total = total.saturating_sub(1),
+        };
+
+        update_total(&mut self.quads);
+        if quad.graph_name != GraphName::DefaultGraph {
+            return;
+        }
+
+        update_total(&mut self.default_graph_triples);
+        update_count(
+            &mut self.property_triples,
+            quad.predicate.as_str().to_string(),
+            is_inserted,
+        );
+        if let Term::NamedNode(object) = &quad.object {
+            if quad.predicate == vocab::rdf::TYPE {
+                update_count(
+                    &mut self.class_entities,
+                    object.as_str().to_string(),
+                    is_inserted,
+                );
+            } else if AFFORDANCE_PROPERTIES
+                .iter()
+                .any(|property| quad.predicate == TdNode::from(property))
+            {
+                update_count(
+                    &mut self.affordance_devices,
+                    (
+                        quad.predicate.as_str().to_string(),
+                        object.as_str().to_string(),
+                    ),
+                    is_inserted,
+                );
+            }
+        }
+    }
+}
+
+pub fn count_inserted_quad(quad: &Quad) {
+    DATASET_STATISTICS.with(|statistics| statistics.borrow_mut().count_quad(quad, true));
+}
+
+pub fn count_removed_quad(quad: &Quad) {
+    DATASET_STATISTICS.with(|statistics| statistics.borrow_mut().count_quad(quad, false));
+}
+
+/// Counts all the quads of the RDF database, which must be called whenever the database is loaded
+pub fn init_dataset_statistics() {
+    RDF_DB.with(|store| {
+        DATASET_STATISTICS.with(|statistics| {
+            let mut statistics = statistics.borrow_mut();
+            *statistics = DatasetStatistics::default();
+
+            for quad in store.borrow().iter() {
+                let quad = quad.expect("failed to read RDF dataset");
+                statistics.count_quad(&quad, true);
+            }
+        })
+    });
+}
+
+fn get_iri(iri: &str) -> String {
+    NamedNode::new_unchecked(iri).to_string()
+}
+
+/// The [SPARQL 1.1 Service Description](https://www.w3.org/TR/sparql11-service-description/) of the endpoint in Turtle,
+/// with the [VoID](https://www.w3.org/TR/void/) statistics of its dataset and the prefixes that the queries can omit
+pub fn get_service_description() -> GenericResult<String> {
+    let prefix_declarations: Vec<String> = get_prefix_map()?
+        .into_iter()
+        .map(|(prefix, namespace)| {
+            format!(
+                "[ sh:prefix {} ; sh:namespace {} ]",
+                Literal::new_simple_literal(prefix),
+                Literal::new_typed_literal(namespace, vocab::xsd::ANY_URI)
+            )
+        })
+        .collect();
+
+    let (quads, default_graph) = DATASET_STATISTICS.with(|statistics| {
+        let statistics = statistics.borrow();
+
+        let class_partitions: Vec<String> = statistics
+            .class_entities
+            .iter()
+            .map(|(class, entities)| {
+                format!(
+                    "[ void:class {} ; void:entities {} ]",
+                    get_iri(class),
+                    entities
+                )
+            })
+            .collect();
+        let property_partitions: Vec<String> = statistics
+            .property_triples
+            .iter()
+            .map(|(property, triples)| {
+                let affordance_partitions: Vec<String> = statistics
+                    .affordance_devices
+                    .iter()
+                    .filter(|((affordance_property, _), _)| affordance_property == property)
+                    .map(|((_, affordance), devices)| {
+                        format!(
+                            "[ omnia:affordance {} ; void:entities {} ]",
+                            get_iri(affordance),
+                            devices
+                        )
+                    })
+                    .collect();
+
+                match affordance_partitions.is_empty() {
+                    true => format!(
+                        "[ void:property {} ; void:triples {} ]",
+                        get_iri(property),
+                        triples
+                    ),
+                    false => format!(
+                        "[ void:property {} ; void:triples {} ;\n        omnia:affordancePartition {} ]",
+                        get_iri(property),
+                        triples,
+                        affordance_partitions.join(" ,\n            ")
+                    ),
+                }
+            })
+            .collect();
+
+        let mut default_graph = vec![
+            String::from("a sd:Graph, void:Dataset"),
+            format!("void:triples {}", statistics.default_graph_triples),
+        ];
+        if !class_partitions.is_empty() {
+            default_graph.push(format!(
+                "void:classPartition {}",
+                class_partitions.join(" ,\n        ")
+            ));
+        }
+        if !property_partitions.is_empty() {
+            default_graph.push(format!(
+                "void:propertyPartition {}",
+                property_partitions.join(" ,\n        ")
+            ));
+        }
+
+        (statistics.quads, default_graph.join(" ;\n    "))
+    });
+
+    let service = [
+        String::from("a sd:Service"),
+        format!("sd:endpoint <{}>", SPARQL_ENDPOINT_IRI),
+        String::from("sd:supportedLanguage sd:SPARQL11Query"),
+        String::from("sd:resultFormat formats:SPARQL_Results_JSON"),
+        format!("sd:defaultDataset <{}#dataset>", SPARQL_ENDPOINT_IRI),
+        // the namespaces of the queries are always declared
+        format!("sh:declare {}", prefix_declarations.join(" ,\n        ")),
+    ];
+
+    Ok(format!(
+        "@prefix sd: <http://www.w3.org/ns/sparql-service-description#> .
+@prefix void: <http://rdfs.org/ns/void#> .
+@prefix formats: <http://www.w3.org/ns/formats/> .
+@prefix sh: <http://www.w3.org/ns/shacl#> .
+@prefix omnia: <{omnia}> .
+
+<{endpoint}> {service} .
+
+<{endpoint}#dataset> a sd:Dataset, void:Dataset ;
+    void:sparqlEndpoint <{endpoint}> ;
+    void:triples {quads} ;
+    sd:defaultGraph <{endpoint}#default-graph> .
+
+<{endpoint}#default-graph> {default_graph} .
+",
+        omnia = OMNIA_PREFIX,
+        endpoint = SPARQL_ENDPOINT_IRI,
+        service = service.join(" ;\n    "),
+        quads = quads,
+        default_graph = default_graph,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use ic_oxigraph::{io::GraphFormat, model::GraphNameRef, sparql::QueryResults, store::Store};
+    use omnia_types::{device::RegisteredDeviceValue, environment::EnvironmentUID};
+
+    use super::*;
+    use crate::rdf::{
+        get_device_quads, get_environment_quad, insert_quads, remove_device_quads, replace_quads,
+        DeviceAffordanceNodes, SarefNode,
+    };
+
+    fn get_registered_device_value(device_uid: &str) -> RegisteredDeviceValue {
+        RegisteredDeviceValue {
+            gateway_principal_id: String::from("2vxsx-fae"),
+            env_uid: EnvironmentUID::from("9a2e6f3c-3a1b-4c43-9a7c-5f3c0b6e1d2a"),
+            device_url: format!("https://proxy.example.com/{}", device_uid),
+            required_headers: None,
+        }
+    }
+
+    fn insert_device(device_uid: &str, properties: &[&str]) -> Vec<Quad> {
+        let quads = get_device_quads(
+            &get_registered_device_value(device_uid),
+            DeviceAffordanceNodes {
+                properties: properties
+                    .iter()
+                    .map(|property| SarefNode::from(property))
+                    .collect(),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        insert_quads(&quads).unwrap();
+        quads
+    }
+
+    /// The statistics counted incrementally, after checking that they're the same as the ones counted from scratch
+    fn get_statistics() -> BTreeMap<(String, String), u64> {
+        let statistics = DATASET_STATISTICS.with(|statistics| {
+            let statistics = statistics.borrow();
+            (statistics.quads, statistics.affordance_devices.clone())
+        });
+        init_dataset_statistics();
+        let recounted_statistics = DATASET_STATISTICS.with(|statistics| {
+            let statistics = statistics.borrow();
+            (statistics.quads, statistics.affordance_devices.clone())
+        });
+        assert_eq!(statistics, recounted_statistics);
+
+        statistics.1
+    }
+
+    fn get_affordance_key(affordance: &str) -> (String, String) {
+        (
+            TdNode::from("hasPropertyAffordance").into_string(),
+            SarefNode::from(affordance).into_string(),
+        )
+    }
+
+    #[test]
+    fn test_dataset_statistics() {
+        insert_quads(&[get_environment_quad(&EnvironmentUID::from(
+            "9a2e6f3c-3a1b-4c43-9a7c-5f3c0b6e1d2a",
+        ))])
+        .unwrap();
+        let device_quads = insert_device("device-1", &["OnOffState"]);
+        insert_device("device-2", &["OnOffState", "Temperature"]);
+
+        let statistics = get_statistics();
+        assert_eq!(statistics.get(&get_affordance_key("OnOffState")), Some(&2));
+        assert_eq!(statistics.get(&get_affordance_key("Temperature")), Some(&1));
+        DATASET_STATISTICS.with(|statistics| {
+            let statistics = statistics.borrow();
+            assert_eq!(
+                statistics
+                    .class_entities
+                    .get(SarefNode::from("Device").as_str()),
+                Some(&2)
+            );
+            assert_eq!(statistics.quads, statistics.default_graph_triples);
+        });
+
+        let updated_device_quads = get_device_quads(
+            &get_registered_device_value("device-1"),
+            DeviceAffordanceNodes {
+                properties: vec![SarefNode::from("Temperature")],
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        replace_quads(&device_quads, &updated_device_quads).unwrap();
+        let statistics = get_statistics();
+        assert_eq!(statistics.get(&get_affordance_key("OnOffState")), Some(&1));
+        assert_eq!(statistics.get(&get_affordance_key("Temperature")), Some(&2));
+
+        remove_device_quads(&NamedNode::new("https://proxy.example.com/device-2").unwrap())
+            .unwrap();
+        let statistics = get_statistics();
+        assert_eq!(statistics.get(&get_affordance_key("OnOffState")), None);
+        assert_eq!(statistics.get(&get_affordance_key("Temperature")), Some(&1));
+    }
+
+    #[test]
+    fn test_service_description() {
+        insert_device("device-1", &["OnOffState"]);
+
+        let service_description = get_service_description().unwrap();
+
+        // the document is valid Turtle, whose relative IRIs are resolved against the URL of the canister
+        let store = Store::new().unwrap();
+        store
+            .load_graph(
+                service_description.as_bytes(),
+                GraphFormat::Turtle,
+                GraphNameRef::DefaultGraph,
+                Some("https://canister.example.com/sparql/query"),
+            )
+            .unwrap();
+        let results = store
+            .query(
+                "PREFIX void: <http://rdfs.org/ns/void#>
+                PREFIX omnia: <http://rdf.omnia-iot.com#>
+                SELECT ?entities WHERE {
+                    ?graph void:propertyPartition ?partition .
+                    ?partition omnia:affordancePartition ?affordancePartition .
+                    ?affordancePartition omnia:affordance <https://saref.etsi.org/core/OnOffState> ;
+                        void:entities ?entities .
+                }",
+            )
+            .unwrap();
+        let solutions = match results {
+            QueryResults::Solutions(solutions) => solutions
+                .map(|solution| solution.unwrap().get("entities").unwrap().to_string())
+                .collect::<Vec<String>>(),
+            _ => panic!("Unexpected query results"),
+        };
+        assert_eq!(
+            solutions,
+            vec![String::from(
+                "\"1\"^^<http://www.w3.org/2001/XMLSchema#integer>"
+            )]
+        );
+        assert!(service_description.contains("sh:prefix \"saref\""));
+        assert!(service_description.contains("sh:prefix \"bot\""));
+    }
+}
@@ -0,0 +1,292 @@
+use std::time::Duration;
+
+use candid::{candid_method, Encode, Func};
+use ic_cdk::{
+    api::{id, management_canister::main::raw_rand, time, trap},
+    print,
+};
+use ic_cdk_macros::query;
+use omnia_types::{
+    errors::{GenericError, GenericResult},
+    http::{StreamingCallbackHttpResponse, StreamingCallbackToken, StreamingStrategy},
+};
+use sha2::{Digest, Sha256};
+
+use crate::{RESULTS_CACHE, STATE};
+
+const STREAMING_CALLBACK_METHOD: &str = "http_request_streaming_callback";
+
+/// The SPARQL Query Results JSON format ends with the closing of the bindings array and of the objects that contain it
+const SPARQL_RESULTS_TAIL: &[u8] = b"]}}";
+const SPARQL_RESULTS_BINDINGS_START: &[u8] = b"\"bindings\":[";
+
+/// Generates the key that signs the streaming callback tokens. `raw_rand` cannot be called in `init` and `post_upgrade`,
+/// so it's called in a timer: until then, the bodies that don't fit in a single response cannot be streamed
+pub fn init_streaming_secret() {
+    ic_cdk_timers::set_timer(Duration::ZERO, || {
+        ic_cdk::spawn(async {
+            match raw_rand().await {
+                Ok((random_bytes,)) => {
+                    STATE.with(|state| state.borrow_mut().streaming_secret = Some(random_bytes))
+                }
+                Err((code, message)) => print(format!(
+                    "Error generating the streaming secret: {:?} {}",
+                    code, message
+                )),
+            }
+        })
+    });
+}
+
+/// `None` if the streaming secret hasn't been generated yet
+pub fn get_streaming_secret() -> Option<Vec<u8>> {
+    STATE.with(|state| state.borrow().streaming_secret.clone())
+}
+
+/// Keeps the chunks of a body that follow the one already in the response and returns the strategy to stream them.
+/// The chunks are streamed as they are when the body is evaluated, even if the dataset changes during the download.
+/// Returns `None` if the chunks don't fit in the [crate::results_cache::ResultsCache]
+pub fn stream_chunks(
+    secret: &[u8],
+    chunks: Vec<Vec<u8>>,
+    media_type: &'static str,
+) -> Option<StreamingStrategy> {
+    let (results_id, expires_at) = RESULTS_CACHE.with(|results_cache| {
+        results_cache
+            .borrow_mut()
+            .insert(chunks, media_type, time())
+    })?;
+
+    Some(StreamingStrategy::Callback {
+        callback: Func {
+            principal: id(),
+            method: String::from(STREAMING_CALLBACK_METHOD),
+        },
+        token: sign_token(secret, results_id, 0, expires_at),
+    })
+}
+
+/// HMAC-SHA256, see RFC 2104
+fn hmac_sha256(key: &[u8], message: &[u8]) -> Vec<u8> {
+    const BLOCK_SIZE: usize = 64;
+
+    let mut block = [0u8; BLOCK_SIZE];
+    if key.len() > BLOCK_SIZE {
+        block[..32].copy_from_slice(&Sha256::digest(key));
+    } else {
+        block[..key.len()].copy_from_slice(key);
+    }
+
+    let inner_hash = Sha256::new()
+        .chain_update(block.map(|byte| byte ^ 0x36))
+        .chain_update(message)
+        .finalize();
+    Sha256::new()
+        .chain_update(block.map(|byte| byte ^ 0x5c))
+        .chain_update(inner_hash)
+        .finalize()
+        .to_vec()
+}
+
+fn get_token_signature(secret: &[u8], results_id: u64, chunk: u64, expires_at: u64) -> Vec<u8> {
+    hmac_sha256(secret, &Encode!(&results_id, &chunk, &expires_at).unwrap())
+}
+
+fn sign_token(
+    secret: &[u8],
+    results_id: u64,
+    chunk: u64,
+    expires_at: u64,
+) -> StreamingCallbackToken {
+    StreamingCallbackToken {
+        results_id,
+        chunk,
+        expires_at,
+        signature: get_token_signature(secret, results_id, chunk, expires_at),
+    }
+}
+
+fn verify_token(secret: &[u8], token: &StreamingCallbackToken, now: u64) -> GenericResult<()> {
+    let signature = get_token_signature(secret, token.results_id, token.chunk, token.expires_at);
+    // the comparison takes the same time wherever the signatures differ
+    let is_valid = signature.len() == token.signature.len()
+        && signature
+            .iter()
+            .zip(token.signature.iter())
+            .fold(0, |difference, (a, b)| difference | (a ^ b))
+            == 0;
+    if !is_valid {
+        return Err(GenericError::invalid_argument(
+            "Invalid streaming callback token",
+        ));
+    }
+
+    if token.expires_at < now {
+        return Err(GenericError::invalid_argument(
+            "Streaming callback token has expired",
+        ));
+    }
+
+    Ok(())
+}
+
+/// Splits the SPARQL query results in the head, which opens the bindings array, the bindings and the tail
+fn split_sparql_results(results: &[u8]) -> GenericResult<(&[u8], &[u8], &[u8])> {
+    let bindings_start = results
+        .windows(SPARQL_RESULTS_BINDINGS_START.len())
+        .position(|window| window == SPARQL_RESULTS_BINDINGS_START)
+        .map(|position| position + SPARQL_RESULTS_BINDINGS_START.len());
+
+    match bindings_start {
+        Some(bindings_start) if results[bindings_start..].ends_with(SPARQL_RESULTS_TAIL) => {
+            let (head, rest) = results.split_at(bindings_start);
+            let (bindings, tail) = rest.split_at(rest.len() - SPARQL_RESULTS_TAIL.len());
+            Ok((head, bindings, tail))
+        }
+        _ => Err(GenericError::internal(
+            "Unexpected format of the SPARQL query results",
+        )),
+    }
+}
+
+/// Splits the pages of the SPARQL query results in the chunks of a single body: the first one opens the bindings array, which is closed by the last one
+pub fn get_sparql_results_chunks(pages: Vec<Vec<u8>>) -> GenericResult<Vec<Vec<u8>>> {
+    let last_page = pages.len().saturating_sub(1);
+
+    pages
+        .iter()
+        .enumerate()
+        .map(|(i, page)| {
+            let (head, bindings, tail) = split_sparql_results(page)?;
+
+            let mut chunk = vec![];
+            match i {
+                0 => chunk.extend_from_slice(head),
+                // the bindings of this chunk follow the ones of the previous chunks, so they're separated by a comma
+                _ if !bindings.is_empty() => chunk.push(b','),
+                _ => (),
+            }
+            chunk.extend_from_slice(bindings);
+            if i == last_page {
+                chunk.extend_from_slice(tail);
+            }
+
+            Ok(chunk)
+        })
+        .collect()
+}
+
+#[query]
+#[candid_method(query)]
+/// Called by the boundary node to download the chunks of the bodies that don't fit in a single response
+fn http_request_streaming_callback(token: StreamingCallbackToken) -> StreamingCallbackHttpResponse {
+    let secret = get_streaming_secret().unwrap_or_else(|| trap("Streaming is not available"));
+
+    if let Err(e) = verify_token(&secret, &token, time()) {
+        trap(&e.to_string());
+    }
+
+    let chunk = RESULTS_CACHE.with(|results_cache| {
+        results_cache
+            .borrow()
+            .get_page(token.results_id, token.chunk, time())
+            .map(|(chunk, _, has_next_chunk)| (chunk.to_vec(), has_next_chunk))
+    });
+    match chunk {
+        // the whole body must be downloaded before the chunks expire
+        Ok((body, has_next_chunk)) => StreamingCallbackHttpResponse {
+            body,
+            token: has_next_chunk
+                .then(|| sign_token(&secret, token.results_id, token.chunk + 1, token.expires_at)),
+        },
+        Err(e) => trap(&e.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ic_oxigraph::io::GraphFormat;
+    use ic_oxigraph::model::{vocab, GraphName, NamedNode, Quad};
+    use omnia_types::sparql::SparqlDataset;
+
+    use super::*;
+    use crate::rdf::{execute_sparql_query, insert_quads, SarefNode};
+
+    const SECRET: &[u8] = b"streaming secret";
+
+    #[test]
+    fn test_hmac_sha256() {
+        // test case 2 of RFC 4231
+        assert_eq!(
+            hex::encode(hmac_sha256(b"Jefe", b"what do ya want for nothing?")),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[test]
+    fn test_verify_token() {
+        let token = sign_token(SECRET, 3, 10, 1_000);
+
+        assert_eq!(verify_token(SECRET, &token, 1_000), Ok(()));
+        assert!(verify_token(SECRET, &token, 1_001).is_err());
+        assert!(verify_token(b"another secret", &token, 0).is_err());
+        assert!(verify_token(
+            SECRET,
+            &StreamingCallbackToken {
+                chunk: 0,
+                ..token.clone()
+            },
+            0
+        )
+        .is_err());
+        assert!(verify_token(
+            SECRET,
+            &StreamingCallbackToken {
+                results_id: 4,
+                ..token
+            },
+            0
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_sparql_results_chunks() {
+        let query =
+            String::from("SELECT ?device WHERE { ?device a <https://saref.etsi.org/core/Device> }");
+        let dataset = SparqlDataset::default();
+        let empty_results = execute_sparql_query(&query, &dataset, true, GraphFormat::Turtle, true)
+            .unwrap()
+            .pages;
+        assert_eq!(
+            get_sparql_results_chunks(empty_results.clone()).unwrap(),
+            empty_results
+        );
+
+        let quads: Vec<Quad> = (0..5)
+            .map(|i| {
+                Quad::new(
+                    NamedNode::new(format!("https://proxy.example.com/device-{}", i)).unwrap(),
+                    vocab::rdf::TYPE,
+                    SarefNode::from("Device"),
+                    GraphName::DefaultGraph,
+                )
+            })
+            .collect();
+        insert_quads(&quads).unwrap();
+        let all_results = execute_sparql_query(&query, &dataset, true, GraphFormat::Turtle, false)
+            .unwrap()
+            .pages
+            .concat();
+
+        STATE.with(|state| state.borrow_mut().sparql_query_limits.max_rows = 2);
+        let pages = execute_sparql_query(&query, &dataset, true, GraphFormat::Turtle, true)
+            .unwrap()
+            .pages;
+        assert_eq!(pages.len(), 3);
+        let chunks = get_sparql_results_chunks(pages).unwrap();
+
+        assert_eq!(chunks.len(), 3);
+        assert_eq!(chunks.concat(), all_results);
+    }
+}
@@ -0,0 +1,1289 @@
+use candid::candid_method;
+use ic_cdk_macros::query;
+use ic_oxigraph::model::{vocab, GraphName, Literal, NamedNode, Quad, Term};
+use omnia_types::{
+    device::{DeviceUid, DeviceUrl},
+    errors::{GenericError, GenericResult},
+    vocabulary::Vocabulary,
+};
+use omnia_utils::constants::THING_DESCRIPTION_MAX_BYTES;
+use serde::{de, Deserialize, Deserializer};
+use serde_json::{Map, Value};
+use std::collections::BTreeMap;
+
+use crate::{
+    rdf::{DeviceAffordanceNodes, HctlNode, JsonSchemaNode, OmniaNode, TdNode, WotSecNode},
+    vocabulary::{get_vocabularies, validate_term},
+    RDF_DB,
+};
+
+/// The contexts of the TD 1.1 and TD 1.0 specifications, one of which must be the first context of the Thing Description
+const TD_CONTEXTS: [&str; 2] = [
+    "https://www.w3.org/2022/wot/td/v1.1",
+    "https://www.w3.org/2019/wot/td/v1",
+];
+
+/// The terms of the TD context that can be used in the `@type` without a prefix
+const TD_CLASS_TERMS: [&str; 4] = [
+    "Thing",
+    "PropertyAffordance",
+    "ActionAffordance",
+    "EventAffordance",
+];
+
+/// The security schemes of the TD specification and their classes
+const SECURITY_SCHEMES: [(&str, &str); 9] = [
+    ("nosec", "NoSecurityScheme"),
+    ("auto", "AutoSecurityScheme"),
+    ("combo", "ComboSecurityScheme"),
+    ("basic", "BasicSecurityScheme"),
+    ("digest", "DigestSecurityScheme"),
+    ("bearer", "BearerSecurityScheme"),
+    ("psk", "PSKSecurityScheme"),
+    ("oauth2", "OAuth2SecurityScheme"),
+    ("apikey", "APIKeySecurityScheme"),
+];
+
+/// The operations allowed in the forms of each kind of affordance and their names in the TD vocabulary
+const PROPERTY_OPERATIONS: [(&str, &str); 4] = [
+    ("readproperty", "readProperty"),
+    ("writeproperty", "writeProperty"),
+    ("observeproperty", "observeProperty"),
+    ("unobserveproperty", "unobserveProperty"),
+];
+const ACTION_OPERATIONS: [(&str, &str); 3] = [
+    ("invokeaction", "invokeAction"),
+    ("queryaction", "queryAction"),
+    ("cancelaction", "cancelAction"),
+];
+const EVENT_OPERATIONS: [(&str, &str); 2] = [
+    ("subscribeevent", "subscribeEvent"),
+    ("unsubscribeevent", "unsubscribeEvent"),
+];
+
+/// The subprotocols that gateways offer to subscribe to the events of the devices and their names in the Omnia vocabulary
+const SUBSCRIPTION_PROTOCOLS: [(&str, &str); 3] = [
+    ("sse", "ServerSentEvents"),
+    ("websocket", "WebSocket"),
+    ("longpoll", "LongPoll"),
+];
+
+const DEFAULT_CONTENT_TYPE: &str = "application/json";
+
+const DEFAULT_EVENT_SUBPROTOCOL: &str = "longpoll";
+
+/// http://www.w3.org/1999/02/22-rdf-syntax-ns#JSON
+const RDF_JSON_DATATYPE: &str = "http://www.w3.org/1999/02/22-rdf-syntax-ns#JSON";
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum OneOrMany<T> {
+    // arrays are tried first, because a single JSON value may be an array as well
+    Many(Vec<T>),
+    One(T),
+}
+
+impl<T> Default for OneOrMany<T> {
+    fn default() -> Self {
+        Self::Many(vec![])
+    }
+}
+
+impl<T> OneOrMany<T> {
+    fn as_slice(&self) -> &[T] {
+        match self {
+            Self::One(item) => std::slice::from_ref(item),
+            Self::Many(items) => items,
+        }
+    }
+}
+
+/// The subset of the TD 1.1 information model that is validated and described in the RDF database
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ThingModel {
+    #[serde(rename = "@context")]
+    context: OneOrMany<Value>,
+    #[serde(rename = "@type", default)]
+    types: OneOrMany<String>,
+    title: String,
+    description: Option<String>,
+    security: OneOrMany<String>,
+    security_definitions: BTreeMap<String, SecurityScheme>,
+    #[serde(default)]
+    properties: BTreeMap<String, PropertyAffordance>,
+    #[serde(default)]
+    actions: BTreeMap<String, ActionAffordance>,
+    #[serde(default)]
+    events: BTreeMap<String, EventAffordance>,
+}
+
+#[derive(Deserialize)]
+struct SecurityScheme {
+    scheme: String,
+    description: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct InteractionAffordance {
+    #[serde(rename = "@type", default)]
+    types: OneOrMany<String>,
+    title: Option<String>,
+    description: Option<String>,
+    forms: Vec<Form>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Form {
+    href: String,
+    content_type: Option<String>,
+    subprotocol: Option<String>,
+    #[serde(default)]
+    op: OneOrMany<String>,
+}
+
+impl Form {
+    /// Events without a subprotocol are delivered with long polling
+    fn get_subprotocol(&self) -> &str {
+        self.subprotocol
+            .as_deref()
+            .unwrap_or(DEFAULT_EVENT_SUBPROTOCOL)
+    }
+}
+
+/// A property affordance is both an interaction affordance and a data schema
+struct PropertyAffordance {
+    interaction: InteractionAffordance,
+    schema: DataSchema,
+    observable: bool,
+}
+
+impl<'de> Deserialize<'de> for PropertyAffordance {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = Value::deserialize(deserializer)?;
+
+        Ok(Self {
+            interaction: InteractionAffordance::deserialize(&value).map_err(de::Error::custom)?,
+            schema: DataSchema::deserialize(&value).map_err(de::Error::custom)?,
+            observable: match value.get("observable") {
+                Some(observable) => bool::deserialize(observable).map_err(de::Error::custom)?,
+                None => false,
+            },
+        })
+    }
+}
+
+#[derive(Deserialize)]
+struct ActionAffordance {
+    #[serde(flatten)]
+    interaction: InteractionAffordance,
+    input: Option<DataSchema>,
+    output: Option<DataSchema>,
+    #[serde(default)]
+    safe: bool,
+    #[serde(default)]
+    idempotent: bool,
+}
+
+#[derive(Deserialize)]
+struct EventAffordance {
+    #[serde(flatten)]
+    interaction: InteractionAffordance,
+    subscription: Option<DataSchema>,
+    data: Option<DataSchema>,
+    cancellation: Option<DataSchema>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct DataSchema {
+    #[serde(rename = "@type", default)]
+    types: OneOrMany<String>,
+    title: Option<String>,
+    description: Option<String>,
+    #[serde(rename = "type")]
+    data_type: Option<DataType>,
+    unit: Option<String>,
+    #[serde(default)]
+    read_only: bool,
+    #[serde(default)]
+    write_only: bool,
+    minimum: Option<f64>,
+    maximum: Option<f64>,
+    #[serde(default)]
+    properties: BTreeMap<String, DataSchema>,
+    #[serde(default)]
+    items: OneOrMany<DataSchema>,
+}
+
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+enum DataType {
+    Object,
+    Array,
+    String,
+    Number,
+    Integer,
+    Boolean,
+    Null,
+}
+
+impl DataType {
+    fn schema_class(&self) -> &'static str {
+        match self {
+            Self::Object => "ObjectSchema",
+            Self::Array => "ArraySchema",
+            Self::String => "StringSchema",
+            Self::Number => "NumberSchema",
+            Self::Integer => "IntegerSchema",
+            Self::Boolean => "BooleanSchema",
+            Self::Null => "NullSchema",
+        }
+    }
+}
+
+/// A Thing Description (TD) in JSON-LD, validated against the [TD 1.1](https://www.w3.org/TR/wot-thing-description11/) information model.
+///
+/// The forms of the affordances must be relative to the device, because the device is reachable only through its public URL,
+/// which is assigned when the device is registered. That URL becomes the `id` and the `base` of the TD served to applications.
+pub struct ThingDescription {
+    document: Map<String, Value>,
+    model: ThingModel,
+    prefixes: BTreeMap<String, String>,
+    vocabularies: Vec<Vocabulary>,
+}
+
+impl ThingDescription {
+    pub fn parse(thing_description: &str) -> GenericResult<Self> {
+        if thing_description.len() > THING_DESCRIPTION_MAX_BYTES {
+            return Err(GenericError::invalid_argument(format!(
+                "Thing Description is larger than {} bytes",
+                THING_DESCRIPTION_MAX_BYTES
+            )));
+        }
+
+        let document: Map<String, Value> = serde_json::from_str(thing_description)
+            .map_err(|e| invalid_thing_description(e.to_string()))?;
+        let model: ThingModel = serde_json::from_value(Value::Object(document.clone()))
+            .map_err(|e| invalid_thing_description(e.to_string()))?;
+
+        let contexts = model.context.as_slice();
+        match contexts.first() {
+            Some(Value::String(context)) if TD_CONTEXTS.contains(&context.as_str()) => (),
+            _ => {
+                return Err(invalid_thing_description(format!(
+                    "the first @context must be one of {:?}",
+                    TD_CONTEXTS
+                )))
+            }
+        }
+
+        // the prefixes of the vocabularies can be used without declaring them in the context
+        let vocabularies = get_vocabularies()?;
+        let mut prefixes: BTreeMap<String, String> = vocabularies
+            .iter()
+            .map(|vocabulary| (vocabulary.prefix.clone(), vocabulary.namespace.clone()))
+            .collect();
+        for context in contexts.iter().skip(1) {
+            if let Value::Object(definitions) = context {
+                prefixes.extend(definitions.iter().filter_map(|(prefix, iri)| {
+                    match (prefix.starts_with('@'), iri) {
+                        (false, Value::String(iri)) => Some((prefix.clone(), iri.clone())),
+                        _ => None,
+                    }
+                }));
+            }
+        }
+
+        let thing_description = Self {
+            document,
+            model,
+            prefixes,
+            vocabularies,
+        };
+        thing_description.validate()?;
+
+        Ok(thing_description)
+    }
+
+    fn validate(&self) -> GenericResult<()> {
+        if self.model.security.as_slice().is_empty() {
+            return Err(invalid_thing_description(
+                "at least one security scheme must be applied",
+            ));
+        }
+        for security in self.model.security.as_slice() {
+            if !self.model.security_definitions.contains_key(security) {
+                return Err(invalid_thing_description(format!(
+                    "security scheme {:?} is not defined in securityDefinitions",
+                    security
+                )));
+            }
+        }
+        for (name, security_scheme) in self.model.security_definitions.iter() {
+            get_security_scheme_class(&security_scheme.scheme).ok_or_else(|| {
+                invalid_thing_description(format!(
+                    "unknown scheme {:?} of security definition {:?}",
+                    security_scheme.scheme, name
+                ))
+            })?;
+        }
+
+        for term in self.model.types.as_slice() {
+            self.expand_term(term)?;
+        }
+        for (name, property) in self.model.properties.iter() {
+            self.validate_affordance(name, &property.interaction, &PROPERTY_OPERATIONS)?;
+            self.validate_data_schema(&property.schema)?;
+        }
+        for (name, action) in self.model.actions.iter() {
+            self.validate_affordance(name, &action.interaction, &ACTION_OPERATIONS)?;
+            for schema in [&action.input, &action.output].into_iter().flatten() {
+                self.validate_data_schema(schema)?;
+            }
+        }
+        for (name, event) in self.model.events.iter() {
+            self.validate_affordance(name, &event.interaction, &EVENT_OPERATIONS)?;
+            for form in event.interaction.forms.iter() {
+                get_subscription_protocol_name(form.get_subprotocol()).ok_or_else(|| {
+                    invalid_thing_description(format!(
+                        "subprotocol {:?} of event {:?} is not one of {:?}",
+                        form.get_subprotocol(),
+                        name,
+                        SUBSCRIPTION_PROTOCOLS.map(|(subprotocol, _)| subprotocol)
+                    ))
+                })?;
+            }
+            for schema in [&event.subscription, &event.data, &event.cancellation]
+                .into_iter()
+                .flatten()
+            {
+                self.validate_data_schema(schema)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn validate_affordance(
+        &self,
+        name: &str,
+        interaction: &InteractionAffordance,
+        allowed_operations: &[(&str, &str)],
+    ) -> GenericResult<()> {
+        if name.is_empty() {
+            return Err(invalid_thing_description(
+                "affordance names must not be empty",
+            ));
+        }
+        if interaction.forms.is_empty() {
+            return Err(invalid_thing_description(format!(
+                "affordance {:?} must have at least one form",
+                name
+            )));
+        }
+        for term in interaction.types.as_slice() {
+            self.expand_term(term)?;
+        }
+
+        for form in interaction.forms.iter() {
+            validate_href(&form.href)?;
+            for op in form.op.as_slice() {
+                get_operation_name(op, allowed_operations).ok_or_else(|| {
+                    invalid_thing_description(format!(
+                        "operation {:?} is not allowed in the forms of affordance {:?}",
+                        op, name
+                    ))
+                })?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn validate_data_schema(&self, schema: &DataSchema) -> GenericResult<()> {
+        for term in schema.types.as_slice() {
+            self.expand_term(term)?;
+        }
+        for nested_schema in schema
+            .properties
+            .values()
+            .chain(schema.items.as_slice().iter())
+        {
+            self.validate_data_schema(nested_schema)?;
+        }
+
+        Ok(())
+    }
+
+    /// Expands a term of a `@type`, which must be a compact IRI with a known prefix (like `saref:OnOffState`),
+    /// an absolute IRI or one of the classes of the TD vocabulary
+    fn expand_term(&self, term: &str) -> GenericResult<NamedNode> {
+        if TD_CLASS_TERMS.contains(&term) {
+            return Ok(TdNode::from(term));
+        }
+
+        let iri = match term.split_once(':') {
+            Some((_, name)) if name.starts_with("//") => term.to_string(),
+            Some((prefix, name)) => match self.prefixes.get(prefix) {
+                Some(prefix_iri) => format!("{}{}", prefix_iri, name),
+                None => {
+                    return Err(invalid_thing_description(format!(
+                        "prefix of term {:?} is not defined in the @context",
+                        term
+                    )))
+                }
+            },
+            None => {
+                return Err(invalid_thing_description(format!(
+                    "term {:?} must be a compact IRI, like saref:OnOffState, or an absolute IRI",
+                    term
+                )))
+            }
+        };
+
+        let node = NamedNode::new(iri).map_err(|e| {
+            invalid_thing_description(format!("invalid IRI of term {:?}: {}", term, e))
+        })?;
+        // unknown terms, like typos, would describe devices with concepts that don't exist
+        validate_term(&self.vocabularies, &node)?;
+
+        Ok(node)
+    }
+
+    /// The classes of the affordances that come from SAREF, its extensions or the custom vocabularies,
+    /// leaving out the ones of the WoT vocabularies
+    fn get_class_nodes(&self, interactions: Vec<&InteractionAffordance>) -> Vec<NamedNode> {
+        interactions
+            .into_iter()
+            .flat_map(|interaction| interaction.types.as_slice())
+            .filter_map(|term| self.expand_term(term).ok())
+            .filter(|node| {
+                self.vocabularies.iter().any(|vocabulary| {
+                    (vocabulary.is_custom || vocabulary.terms.is_some())
+                        && node.as_str().starts_with(&vocabulary.namespace)
+                })
+            })
+            .collect()
+    }
+
+    /// The classes of the affordances, which are related to the device in the default graph,
+    /// and the protocols offered to subscribe to its events
+    pub fn get_affordance_nodes(&self) -> DeviceAffordanceNodes {
+        let mut subscription_protocols: Vec<NamedNode> = self
+            .model
+            .events
+            .values()
+            .flat_map(|event| event.interaction.forms.iter())
+            .map(|form| {
+                OmniaNode::from(get_subscription_protocol_name(form.get_subprotocol()).unwrap())
+            })
+            .collect();
+        subscription_protocols.sort();
+        subscription_protocols.dedup();
+
+        DeviceAffordanceNodes {
+            properties: self.get_class_nodes(
+                self.model
+                    .properties
+                    .values()
+                    .map(|property| &property.interaction)
+                    .collect(),
+            ),
+            actions: self.get_class_nodes(
+                self.model
+                    .actions
+                    .values()
+                    .map(|action| &action.interaction)
+                    .collect(),
+            ),
+            events: self.get_class_nodes(
+                self.model
+                    .events
+                    .values()
+                    .map(|event| &event.interaction)
+                    .collect(),
+            ),
+            subscription_protocols,
+        }
+    }
+
+    /// The TD document served to applications, whose forms are resolved against the device URL
+    pub fn get_document(&self, device_url: &DeviceUrl) -> Value {
+        let mut document = self.document.clone();
+        document.insert(String::from("id"), Value::String(device_url.clone()));
+        document.insert(String::from("base"), Value::String(get_base(device_url)));
+        Value::Object(document)
+    }
+
+    /// The quads that describe the Thing Description in the graph of the device
+    pub fn get_quads(
+        &self,
+        device_uid: &DeviceUid,
+        device_node: &NamedNode,
+    ) -> GenericResult<Vec<Quad>> {
+        let device_url = device_node.as_str().to_string();
+        let mut graph = DeviceGraph::new(device_node);
+
+        graph.add(
+            device_node,
+            vocab::rdf::TYPE.into_owned(),
+            TdNode::from("Thing"),
+        );
+        for term in self.model.types.as_slice() {
+            graph.add(
+                device_node,
+                vocab::rdf::TYPE.into_owned(),
+                self.expand_term(term)?,
+            );
+        }
+        graph.add(
+            device_node,
+            TdNode::from("title"),
+            Literal::new_simple_literal(&self.model.title),
+        );
+        if let Some(description) = &self.model.description {
+            graph.add(
+                device_node,
+                TdNode::from("description"),
+                Literal::new_simple_literal(description),
+            );
+        }
+        graph.add(
+            device_node,
+            OmniaNode::from("hasUid"),
+            Literal::new_simple_literal(device_uid),
+        );
+        graph.add(
+            device_node,
+            OmniaNode::from("thingDescription"),
+            Literal::new_typed_literal(
+                self.get_document(&device_url).to_string(),
+                NamedNode::new_unchecked(RDF_JSON_DATATYPE),
+            ),
+        );
+
+        for (name, security_scheme) in self.model.security_definitions.iter() {
+            let security_node = new_node(format!(
+                "{}#securityDefinitions/{}",
+                device_url,
+                percent_encode(name)
+            ))?;
+            graph.add(
+                device_node,
+                TdNode::from("definesSecurityScheme"),
+                security_node.clone(),
+            );
+            graph.add(
+                &security_node,
+                vocab::rdf::TYPE.into_owned(),
+                WotSecNode::from(get_security_scheme_class(&security_scheme.scheme).unwrap()),
+            );
+            if let Some(description) = &security_scheme.description {
+                graph.add(
+                    &security_node,
+                    TdNode::from("description"),
+                    Literal::new_simple_literal(description),
+                );
+            }
+            if self.model.security.as_slice().contains(name) {
+                graph.add(
+                    device_node,
+                    TdNode::from("hasSecurityConfiguration"),
+                    security_node,
+                );
+            }
+        }
+
+        for (name, property) in self.model.properties.iter() {
+            let property_node = new_node(format!(
+                "{}#properties/{}",
+                device_url,
+                percent_encode(name)
+            ))?;
+            graph.add(
+                device_node,
+                TdNode::from("hasPropertyAffordance"),
+                property_node.clone(),
+            );
+
+            let mut default_operations = vec![];
+            if !property.schema.write_only {
+                default_operations.push("readproperty");
+            }
+            if !property.schema.read_only {
+                default_operations.push("writeproperty");
+            }
+            if property.observable {
+                default_operations.extend(["observeproperty", "unobserveproperty"]);
+            }
+
+            self.add_affordance(
+                &mut graph,
+                &property_node,
+                "PropertyAffordance",
+                name,
+                &property.interaction,
+                &PROPERTY_OPERATIONS,
+                &default_operations,
+                &device_url,
+            )?;
+            self.add_data_schema(&mut graph, &property_node, &property.schema, false)?;
+            graph.add(
+                &property_node,
+                TdNode::from("isObservable"),
+                Literal::from(property.observable),
+            );
+        }
+
+        for (name, action) in self.model.actions.iter() {
+            let action_node = new_node(format!("{}#actions/{}", device_url, percent_encode(name)))?;
+            graph.add(
+                device_node,
+                TdNode::from("hasActionAffordance"),
+                action_node.clone(),
+            );
+
+            self.add_affordance(
+                &mut graph,
+                &action_node,
+                "ActionAffordance",
+                name,
+                &action.interaction,
+                &ACTION_OPERATIONS,
+                &["invokeaction"],
+                &device_url,
+            )?;
+            for (predicate, suffix, schema) in [
+                ("hasInputSchema", "input", &action.input),
+                ("hasOutputSchema", "output", &action.output),
+            ] {
+                if let Some(schema) = schema {
+                    let schema_node = new_node(format!("{}/{}", action_node.as_str(), suffix))?;
+                    graph.add(&action_node, TdNode::from(predicate), schema_node.clone());
+                    self.add_data_schema(&mut graph, &schema_node, schema, true)?;
+                }
+            }
+            graph.add(
+                &action_node,
+                TdNode::from("isSafe"),
+                Literal::from(action.safe),
+            );
+            graph.add(
+                &action_node,
+                TdNode::from("isIdempotent"),
+                Literal::from(action.idempotent),
+            );
+        }
+
+        for (name, event) in self.model.events.iter() {
+            let event_node = new_node(format!("{}#events/{}", device_url, percent_encode(name)))?;
+            graph.add(
+                device_node,
+                TdNode::from("hasEventAffordance"),
+                event_node.clone(),
+            );
+
+            self.add_affordance(
+                &mut graph,
+                &event_node,
+                "EventAffordance",
+                name,
+                &event.interaction,
+                &EVENT_OPERATIONS,
+                &["subscribeevent", "unsubscribeevent"],
+                &device_url,
+            )?;
+            for (predicate, suffix, schema) in [
+                ("hasSubscriptionSchema", "subscription", &event.subscription),
+                ("hasNotificationSchema", "data", &event.data),
+                ("hasCancellationSchema", "cancellation", &event.cancellation),
+            ] {
+                if let Some(schema) = schema {
+                    let schema_node = new_node(format!("{}/{}", event_node.as_str(), suffix))?;
+                    graph.add(&event_node, TdNode::from(predicate), schema_node.clone());
+                    self.add_data_schema(&mut graph, &schema_node, schema, true)?;
+                }
+            }
+        }
+
+        Ok(graph.quads)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn add_affordance(
+        &self,
+        graph: &mut DeviceGraph,
+        affordance_node: &NamedNode,
+        affordance_class: &str,
+        name: &str,
+        interaction: &InteractionAffordance,
+        allowed_operations: &[(&str, &str)],
+        default_operations: &[&str],
+        device_url: &DeviceUrl,
+    ) -> GenericResult<()> {
+        graph.add(
+            affordance_node,
+            vocab::rdf::TYPE.into_owned(),
+            TdNode::from(affordance_class),
+        );
+        for term in interaction.types.as_slice() {
+            graph.add(
+                affordance_node,
+                vocab::rdf::TYPE.into_owned(),
+                self.expand_term(term)?,
+            );
+        }
+        graph.add(
+            affordance_node,
+            TdNode::from("name"),
+            Literal::new_simple_literal(name),
+        );
+        if let Some(title) = &interaction.title {
+            graph.add(
+                affordance_node,
+                TdNode::from("title"),
+                Literal::new_simple_literal(title),
+            );
+        }
+        if let Some(description) = &interaction.description {
+            graph.add(
+                affordance_node,
+                TdNode::from("description"),
+                Literal::new_simple_literal(description),
+            );
+        }
+
+        for (i, form) in interaction.forms.iter().enumerate() {
+            let form_node = new_node(format!("{}/forms/{}", affordance_node.as_str(), i))?;
+            graph.add(affordance_node, TdNode::from("hasForm"), form_node.clone());
+            graph.add(
+                &form_node,
+                vocab::rdf::TYPE.into_owned(),
+                HctlNode::from("Form"),
+            );
+            graph.add(
+                &form_node,
+                HctlNode::from("hasTarget"),
+                Literal::new_typed_literal(
+                    format!("{}{}", get_base(device_url), form.href),
+                    vocab::xsd::ANY_URI,
+                ),
+            );
+            graph.add(
+                &form_node,
+                HctlNode::from("forContentType"),
+                Literal::new_simple_literal(
+                    form.content_type.as_deref().unwrap_or(DEFAULT_CONTENT_TYPE),
+                ),
+            );
+            // events always have a subprotocol, because they're delivered with long polling by default
+            if form.subprotocol.is_some() || affordance_class == "EventAffordance" {
+                graph.add(
+                    &form_node,
+                    HctlNode::from("forSubProtocol"),
+                    Literal::new_simple_literal(form.get_subprotocol()),
+                );
+            }
+
+            let operations: Vec<&str> = match form.op.as_slice() {
+                [] => default_operations.to_vec(),
+                operations => operations.iter().map(String::as_str).collect(),
+            };
+            for op in operations {
+                graph.add(
+                    &form_node,
+                    TdNode::from("hasOperationType"),
+                    TdNode::from(get_operation_name(op, allowed_operations).unwrap()),
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Describes the data schema on the node. The title and the description of the schema of a property
+    /// are the ones of the property itself, so they're described only for the other schemas
+    fn add_data_schema(
+        &self,
+        graph: &mut DeviceGraph,
+        schema_node: &NamedNode,
+        schema: &DataSchema,
+        with_metadata: bool,
+    ) -> GenericResult<()> {
+        if with_metadata {
+            for term in schema.types.as_slice() {
+                graph.add(
+                    schema_node,
+                    vocab::rdf::TYPE.into_owned(),
+                    self.expand_term(term)?,
+                );
+            }
+            if let Some(title) = &schema.title {
+                graph.add(
+                    schema_node,
+                    TdNode::from("title"),
+                    Literal::new_simple_literal(title),
+                );
+            }
+            if let Some(description) = &schema.description {
+                graph.add(
+                    schema_node,
+                    TdNode::from("description"),
+                    Literal::new_simple_literal(description),
+                );
+            }
+        }
+
+        if let Some(data_type) = schema.data_type {
+            graph.add(
+                schema_node,
+                vocab::rdf::TYPE.into_owned(),
+                JsonSchemaNode::from(data_type.schema_class()),
+            );
+        }
+        if let Some(unit) = &schema.unit {
+            graph.add(
+                schema_node,
+                JsonSchemaNode::from("unit"),
+                Literal::new_simple_literal(unit),
+            );
+        }
+        graph.add(
+            schema_node,
+            JsonSchemaNode::from("readOnly"),
+            Literal::from(schema.read_only),
+        );
+        graph.add(
+            schema_node,
+            JsonSchemaNode::from("writeOnly"),
+            Literal::from(schema.write_only),
+        );
+        if let Some(minimum) = schema.minimum {
+            graph.add(
+                schema_node,
+                JsonSchemaNode::from("minimum"),
+                Literal::from(minimum),
+            );
+        }
+        if let Some(maximum) = schema.maximum {
+            graph.add(
+                schema_node,
+                JsonSchemaNode::from("maximum"),
+                Literal::from(maximum),
+            );
+        }
+
+        for (name, property_schema) in schema.properties.iter() {
+            let property_node = new_node(format!(
+                "{}/properties/{}",
+                schema_node.as_str(),
+                percent_encode(name)
+            ))?;
+            graph.add(
+                schema_node,
+                JsonSchemaNode::from("properties"),
+                property_node.clone(),
+            );
+            graph.add(
+                &property_node,
+                JsonSchemaNode::from("propertyName"),
+                Literal::new_simple_literal(name),
+            );
+            self.add_data_schema(graph, &property_node, property_schema, true)?;
+        }
+        for (i, items_schema) in schema.items.as_slice().iter().enumerate() {
+            let items_node = new_node(format!("{}/items/{}", schema_node.as_str(), i))?;
+            graph.add(
+                schema_node,
+                JsonSchemaNode::from("items"),
+                items_node.clone(),
+            );
+            self.add_data_schema(graph, &items_node, items_schema, true)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Collects the quads of the graph of a device
+struct DeviceGraph {
+    graph_name: GraphName,
+    quads: Vec<Quad>,
+}
+
+impl DeviceGraph {
+    fn new(device_node: &NamedNode) -> Self {
+        Self {
+            graph_name: GraphName::NamedNode(device_node.clone()),
+            quads: vec![],
+        }
+    }
+
+    fn add(&mut self, subject: &NamedNode, predicate: NamedNode, object: impl Into<Term>) {
+        self.quads.push(Quad::new(
+            subject.clone(),
+            predicate,
+            object,
+            self.graph_name.clone(),
+        ));
+    }
+}
+
+fn invalid_thing_description(reason: impl Into<String>) -> GenericError {
+    GenericError::invalid_argument(format!("Invalid Thing Description: {}", reason.into()))
+}
+
+fn new_node(iri: String) -> GenericResult<NamedNode> {
+    NamedNode::new(&iri)
+        .map_err(|e| GenericError::internal(format!("Error creating node {:?}: {}", iri, e)))
+}
+
+fn get_security_scheme_class(scheme: &str) -> Option<&'static str> {
+    SECURITY_SCHEMES
+        .iter()
+        .find(|(name, _)| *name == scheme)
+        .map(|(_, class)| *class)
+}
+
+fn get_subscription_protocol_name(subprotocol: &str) -> Option<&'static str> {
+    SUBSCRIPTION_PROTOCOLS
+        .iter()
+        .find(|(name, _)| *name == subprotocol)
+        .map(|(_, protocol_name)| *protocol_name)
+}
+
+fn get_operation_name<'a>(op: &str, allowed_operations: &[(&str, &'a str)]) -> Option<&'a str> {
+    allowed_operations
+        .iter()
+        .find(|(name, _)| *name == op)
+        .map(|(_, operation_name)| *operation_name)
+}
+
+/// The base of the forms of the device, which are relative paths
+fn get_base(device_url: &DeviceUrl) -> String {
+    format!("{}/", device_url)
+}
+
+/// Forms must be relative paths without `..` segments, so that they always resolve under the device URL
+fn validate_href(href: &str) -> GenericResult<()> {
+    let path = href.split(['?', '#']).next().unwrap_or_default();
+
+    if href.starts_with('/')
+        || path.split('/').next().unwrap_or_default().contains(':')
+        || path.split('/').any(|segment| segment == "..")
+        || href.chars().any(char::is_whitespace)
+    {
+        return Err(invalid_thing_description(format!(
+            "href {:?} must be a path relative to the device",
+            href
+        )));
+    }
+
+    Ok(())
+}
+
+/// Encodes the name to be used in an IRI, keeping only the unreserved characters
+fn percent_encode(name: &str) -> String {
+    name.bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (byte as char).to_string()
+            }
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+#[query(name = "getThingDescription")]
+#[candid_method(query, rename = "getThingDescription")]
+/// Returns the Thing Description of the device in JSON-LD, whose forms are relative to the public URL of the device
+fn get_thing_description(device_uid: DeviceUid) -> GenericResult<String> {
+    RDF_DB.with(|store| {
+        let rdf_db = store.borrow();
+        let uid_literal = Literal::new_simple_literal(&device_uid);
+        let has_uid = OmniaNode::from("hasUid");
+        let thing_description = OmniaNode::from("thingDescription");
+
+        let uid_quad = rdf_db
+            .quads_for_pattern(
+                None,
+                Some(has_uid.as_ref()),
+                Some(uid_literal.as_ref().into()),
+                None,
+            )
+            .next()
+            .transpose()
+            .map_err(|e| GenericError::internal(format!("Error reading device: {}", e)))?
+            .ok_or_else(|| GenericError::NotFound {
+                entity: String::from("ThingDescription"),
+                id: device_uid.clone(),
+            })?;
+
+        let thing_description_quad = rdf_db
+            .quads_for_pattern(
+                Some(uid_quad.subject.as_ref()),
+                Some(thing_description.as_ref()),
+                None,
+                Some(uid_quad.graph_name.as_ref()),
+            )
+            .next()
+            .transpose()
+            .map_err(|e| {
+                GenericError::internal(format!("Error reading Thing Description: {}", e))
+            })?;
+
+        match thing_description_quad.map(|quad| quad.object) {
+            Some(Term::Literal(literal)) => Ok(literal.value().to_string()),
+            _ => Err(GenericError::internal(format!(
+                "Thing Description of device {:?} is missing",
+                device_uid
+            ))),
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use ic_oxigraph::model::Subject;
+
+    use super::*;
+    use crate::{rdf::SAREF_PREFIX, vocabulary::insert_vocabulary};
+
+    const DEVICE_URL: &str = "https://proxy.omnia-iot.com/device";
+
+    fn get_thing_description() -> Value {
+        serde_json::json!({
+            "@context": [
+                "https://www.w3.org/2022/wot/td/v1.1",
+                { "ex": "https://example.com/vocab#" }
+            ],
+            "@type": "saref:Switch",
+            "title": "Lamp",
+            "securityDefinitions": { "nosec_sc": { "scheme": "nosec" } },
+            "security": "nosec_sc",
+            "properties": {
+                "status": {
+                    "@type": "saref:OnOffState",
+                    "type": "boolean",
+                    "readOnly": true,
+                    "observable": true,
+                    "forms": [{ "href": "properties/status" }]
+                }
+            },
+            "actions": {
+                "toggle": {
+                    "@type": ["saref:ToggleCommand", "ex:Toggle"],
+                    "input": { "type": "object", "properties": { "delay": { "type": "integer" } } },
+                    "forms": [{ "href": "actions/toggle{?delay}", "op": "invokeaction" }]
+                }
+            },
+            "events": {
+                "overheating": {
+                    "@type": "saref:Temperature",
+                    "data": { "type": "string" },
+                    "forms": [
+                        { "href": "events/overheating", "subprotocol": "sse" },
+                        { "href": "events/overheating/poll" }
+                    ]
+                }
+            }
+        })
+    }
+
+    fn parse(thing_description: &Value) -> GenericResult<ThingDescription> {
+        // the example vocabulary is declared in the context as well
+        insert_vocabulary(
+            String::from("example"),
+            String::from("https://example.com/vocab#"),
+            Some(vec![String::from("Toggle")]),
+        )
+        .unwrap();
+
+        ThingDescription::parse(&thing_description.to_string())
+    }
+
+    fn contains(quads: &[Quad], subject: &str, predicate: NamedNode, object: Term) -> bool {
+        quads.iter().any(|quad| {
+            quad.subject == Subject::NamedNode(NamedNode::new_unchecked(subject))
+                && quad.predicate == predicate
+                && quad.object == object
+                && quad.graph_name == GraphName::NamedNode(NamedNode::new_unchecked(DEVICE_URL))
+        })
+    }
+
+    #[test]
+    fn test_parse_thing_description() {
+        let thing_description = parse(&get_thing_description()).unwrap();
+
+        let affordance_nodes = thing_description.get_affordance_nodes();
+        assert_eq!(
+            affordance_nodes.properties,
+            vec![NamedNode::new_unchecked(format!(
+                "{}OnOffState",
+                SAREF_PREFIX
+            ))]
+        );
+        assert_eq!(
+            affordance_nodes.actions,
+            vec![NamedNode::new_unchecked(format!(
+                "{}ToggleCommand",
+                SAREF_PREFIX
+            ))]
+        );
+        assert_eq!(
+            affordance_nodes.events,
+            vec![NamedNode::new_unchecked(format!(
+                "{}Temperature",
+                SAREF_PREFIX
+            ))]
+        );
+        assert_eq!(
+            affordance_nodes.subscription_protocols,
+            vec![
+                OmniaNode::from("LongPoll"),
+                OmniaNode::from("ServerSentEvents")
+            ]
+        );
+
+        let document = thing_description.get_document(&DEVICE_URL.to_string());
+        assert_eq!(document["id"], DEVICE_URL);
+        assert_eq!(document["base"], format!("{}/", DEVICE_URL));
+        assert_eq!(document["title"], "Lamp");
+    }
+
+    #[test]
+    fn test_invalid_thing_descriptions() {
+        let invalid_thing_descriptions = [
+            ("/@context/0", Value::from("https://www.w3.org/ns/td")),
+            ("/security", Value::from("basic_sc")),
+            ("/securityDefinitions/nosec_sc/scheme", Value::from("none")),
+            ("/properties/status/type", Value::from("text")),
+            ("/properties/status/forms", serde_json::json!([])),
+            ("/properties/status/forms/0/href", Value::from("../status")),
+            (
+                "/properties/status/forms/0/href",
+                Value::from("http://192.168.1.2/status"),
+            ),
+            (
+                "/properties/status/forms",
+                serde_json::json!([{ "href": "properties/status", "op": "invokeaction" }]),
+            ),
+            ("/actions/toggle/@type", Value::from("Toggle")),
+            ("/actions/toggle/@type", Value::from("unknown:Toggle")),
+            ("/actions/toggle/@type", Value::from("saref:ToggleComand")),
+            ("/actions/toggle/@type", Value::from("ex:Switch")),
+            (
+                "/events/overheating/forms/0/subprotocol",
+                Value::from("mqtt"),
+            ),
+        ];
+
+        for (pointer, value) in invalid_thing_descriptions {
+            let mut thing_description = get_thing_description();
+            *thing_description.pointer_mut(pointer).unwrap() = value;
+
+            assert!(
+                matches!(
+                    parse(&thing_description),
+                    Err(GenericError::InvalidArgument { .. })
+                ),
+                "{} should be invalid",
+                pointer
+            );
+        }
+
+        let mut thing_description = get_thing_description();
+        thing_description.as_object_mut().unwrap().remove("title");
+        assert!(parse(&thing_description).is_err());
+    }
+
+    #[test]
+    fn test_thing_description_quads() {
+        let device_node = NamedNode::new_unchecked(DEVICE_URL);
+        let quads = parse(&get_thing_description())
+            .unwrap()
+            .get_quads(&String::from("device"), &device_node)
+            .unwrap();
+
+        let property = format!("{}#properties/status", DEVICE_URL);
+        let property_form = format!("{}/forms/0", property);
+        let action_form = format!("{}#actions/toggle/forms/0", DEVICE_URL);
+
+        assert!(contains(
+            &quads,
+            DEVICE_URL,
+            TdNode::from("hasPropertyAffordance"),
+            NamedNode::new_unchecked(&property).into()
+        ));
+        assert!(contains(
+            &quads,
+            &property,
+            vocab::rdf::TYPE.into_owned(),
+            NamedNode::new_unchecked(format!("{}OnOffState", SAREF_PREFIX)).into()
+        ));
+        assert!(contains(
+            &quads,
+            &property_form,
+            HctlNode::from("hasTarget"),
+            Literal::new_typed_literal(
+                format!("{}/properties/status", DEVICE_URL),
+                vocab::xsd::ANY_URI
+            )
+            .into()
+        ));
+        // default operations of a read only observable property
+        for operation in ["readProperty", "observeProperty", "unobserveProperty"] {
+            assert!(contains(
+                &quads,
+                &property_form,
+                TdNode::from("hasOperationType"),
+                TdNode::from(operation).into()
+            ));
+        }
+        assert!(!contains(
+            &quads,
+            &property_form,
+            TdNode::from("hasOperationType"),
+            TdNode::from("writeProperty").into()
+        ));
+        assert!(contains(
+            &quads,
+            &action_form,
+            HctlNode::from("hasTarget"),
+            Literal::new_typed_literal(
+                format!("{}/actions/toggle{{?delay}}", DEVICE_URL),
+                vocab::xsd::ANY_URI
+            )
+            .into()
+        ));
+        assert!(contains(
+            &quads,
+            &format!("{}#actions/toggle/input/properties/delay", DEVICE_URL),
+            vocab::rdf::TYPE.into_owned(),
+            JsonSchemaNode::from("IntegerSchema").into()
+        ));
+        assert!(contains(
+            &quads,
+            &format!("{}#events/overheating", DEVICE_URL),
+            TdNode::from("hasNotificationSchema"),
+            NamedNode::new_unchecked(format!("{}#events/overheating/data", DEVICE_URL)).into()
+        ));
+        for (form, subprotocol) in [("0", "sse"), ("1", "longpoll")] {
+            assert!(contains(
+                &quads,
+                &format!("{}#events/overheating/forms/{}", DEVICE_URL, form),
+                HctlNode::from("forSubProtocol"),
+                Literal::new_simple_literal(subprotocol).into()
+            ));
+        }
+        assert!(contains(
+            &quads,
+            DEVICE_URL,
+            OmniaNode::from("hasUid"),
+            Literal::new_simple_literal("device").into()
+        ));
+    }
+}
@@ -1,34 +1,23 @@
 use candid::candid_method;
-use ic_cdk::{
-    api::{call::call, caller},
-    print,
-};
+use ic_cdk::{api::caller, print};
 use omnia_types::{
     environment::EnvironmentInfoResult, http::IpChallengeNonce,
     virtual_persona::VirtualPersonaValueResult,
 };
 
-use crate::utils::get_database_principal;
+use crate::database_client::get_database_client;
 
 #[ic_cdk_macros::update(name = "getProfile")]
 #[candid_method(update, rename = "getProfile")]
 async fn get_profile(nonce: IpChallengeNonce) -> VirtualPersonaValueResult {
     let virtual_persona_principal = caller();
 
-    match call(
-        get_database_principal(),
-        "get_virtual_persona",
-        (nonce, virtual_persona_principal.to_string()),
-    )
-    .await
-    .unwrap()
-    {
-        (Ok(virtual_persona),) => {
-            print(format!("User profile: {:?}", virtual_persona));
-            Ok(virtual_persona)
-        }
-        (Err(e),) => Err(e),
-    }
+    let virtual_persona = get_database_client()
+        .get_virtual_persona(nonce, virtual_persona_principal.to_string())
+        .await?;
+
+    print(format!("User profile: {:?}", virtual_persona));
+    Ok(virtual_persona)
 }
 
 #[ic_cdk_macros::update(name = "setEnvironment")]
@@ -36,13 +25,9 @@ async fn get_profile(nonce: IpChallengeNonce) -> VirtualPersonaValueResult {
 async fn set_environment(nonce: IpChallengeNonce) -> EnvironmentInfoResult {
     let virtual_persona_principal = caller();
 
-    let (environment_info,): (EnvironmentInfoResult,) = call(
-        get_database_principal(),
-        "set_user_in_environment",
-        (virtual_persona_principal.to_string(), nonce),
-    )
-    .await
-    .unwrap();
+    let environment_info = get_database_client()
+        .set_user_in_environment(virtual_persona_principal.to_string(), nonce)
+        .await;
 
     print(format!("User in environment: {:?}", environment_info));
 
@@ -54,13 +39,9 @@ async fn set_environment(nonce: IpChallengeNonce) -> EnvironmentInfoResult {
 async fn reset_environment(nonce: IpChallengeNonce) -> EnvironmentInfoResult {
     let virtual_persona_principal = caller();
 
-    let (environment_info,): (EnvironmentInfoResult,) = call(
-        get_database_principal(),
-        "reset_user_from_environment",
-        (virtual_persona_principal.to_string(), nonce),
-    )
-    .await
-    .unwrap();
+    let environment_info = get_database_client()
+        .reset_user_from_environment(virtual_persona_principal.to_string(), nonce)
+        .await;
 
     print(format!("User not in environment: {:?}", environment_info));
 
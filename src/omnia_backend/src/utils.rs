@@ -1,18 +1,33 @@
 use candid::Principal;
 use ic_cdk::{
-    api::management_canister::{
-        ecdsa::{ecdsa_public_key, EcdsaPublicKeyArgument, EcdsaPublicKeyResponse},
-        provisional::CanisterId,
+    api::{
+        caller, is_controller,
+        management_canister::{
+            ecdsa::{ecdsa_public_key, EcdsaPublicKeyArgument, EcdsaPublicKeyResponse},
+            provisional::CanisterId,
+        },
     },
     print,
 };
 use ic_ledger_types::{query_archived_blocks, query_blocks, Block, BlockIndex, GetBlocksArgs};
 use k256::ecdsa::signature::Verifier;
 use omnia_core_sdk::signature::get_ecdsa_key_id;
-use omnia_types::errors::GenericResult;
+use omnia_types::errors::{GenericError, GenericResult};
 
 use crate::STATE;
 
+/// Only controllers can call the administrative methods
+pub fn caller_is_controller() -> GenericResult<()> {
+    let caller = caller();
+    if !is_controller(&caller) {
+        return Err(GenericError::Unauthorized {
+            caller,
+            reason: String::from("Only controllers can call this method"),
+        });
+    }
+    Ok(())
+}
+
 pub fn get_backend_principal() -> Principal {
     STATE
         .with(|state| state.borrow().backend_principal)
@@ -70,6 +85,30 @@ pub fn update_ledger_principal(ledger_canister_principal_id: String) {
     });
 }
 
+/// The number of instructions executed in the current message, always 0 outside of a canister
+pub fn get_instruction_counter() -> u64 {
+    #[cfg(target_arch = "wasm32")]
+    {
+        ic_cdk::api::instruction_counter()
+    }
+    #[cfg(not(target_arch = "wasm32"))]
+    {
+        0
+    }
+}
+
+/// Sets the data certified by the Internet Computer, which is ignored outside of a canister
+pub fn set_certified_data(data: &[u8]) {
+    #[cfg(target_arch = "wasm32")]
+    {
+        ic_cdk::api::set_certified_data(data)
+    }
+    #[cfg(not(target_arch = "wasm32"))]
+    {
+        let _ = data;
+    }
+}
+
 pub async fn query_ledger_block(block_index: BlockIndex) -> GenericResult<Option<Block>> {
     let ledger_principal = get_ledger_principal();
 
@@ -78,22 +117,24 @@ pub async fn query_ledger_block(block_index: BlockIndex) -> GenericResult<Option
         length: 1,
     };
 
-    if let Ok(blocks_result) = query_blocks(ledger_principal, args.clone()).await {
-        if !blocks_result.blocks.is_empty() && blocks_result.first_block_index == block_index {
-            return Ok(blocks_result.blocks.into_iter().next());
-        }
+    let blocks_result = query_blocks(ledger_principal, args.clone())
+        .await
+        .map_err(|e| {
+            GenericError::inter_canister_call_failed(ledger_principal, "query_blocks", e)
+        })?;
 
-        if let Some(func) = blocks_result.archived_blocks.into_iter().find_map(|b| {
-            (b.start <= block_index && (block_index - b.start) < b.length).then_some(b.callback)
-        }) {
-            if let Ok(Ok(archived_blocks)) = query_archived_blocks(&func, args).await {
-                return Ok(archived_blocks.blocks.into_iter().next());
-            }
-        }
-        return Ok(None);
+    if !blocks_result.blocks.is_empty() && blocks_result.first_block_index == block_index {
+        return Ok(blocks_result.blocks.into_iter().next());
     }
 
-    Err(String::from("Query block failed"))
+    if let Some(func) = blocks_result.archived_blocks.into_iter().find_map(|b| {
+        (b.start <= block_index && (block_index - b.start) < b.length).then_some(b.callback)
+    }) {
+        if let Ok(Ok(archived_blocks)) = query_archived_blocks(&func, args).await {
+            return Ok(archived_blocks.blocks.into_iter().next());
+        }
+    }
+    Ok(None)
 }
 
 pub async fn is_valid_signature(
@@ -101,38 +142,33 @@ pub async fn is_valid_signature(
     message: String,
     canister_id: CanisterId,
 ) -> GenericResult<bool> {
-    let public_key_hex = hex::encode(
-        get_canister_public_key(canister_id)
-            .await
-            .map_err(|e| format!("failed to get canister public key: {:?}", e))?
-            .public_key,
-    );
+    let public_key_hex = hex::encode(get_canister_public_key(canister_id).await?.public_key);
     let signature_bytes = hex::decode(&signature_hex).map_err(|e| {
-        format!(
+        GenericError::invalid_argument(format!(
             "failed to hex-decode signature: {:?} (signature_hex: {:?})",
             e, signature_hex
-        )
+        ))
     })?;
     let pubkey_bytes = hex::decode(&public_key_hex).map_err(|e| {
-        format!(
+        GenericError::internal(format!(
             "failed to hex-decode public key: {:?} (public_key_hex: {:?})",
             e, public_key_hex
-        )
+        ))
     })?;
     let message_bytes = message.as_bytes();
 
     let signature = k256::ecdsa::Signature::try_from(signature_bytes.as_slice()).map_err(|e| {
-        format!(
+        GenericError::invalid_argument(format!(
             "failed to deserialize signature bytes into signature: {:?}",
             e
-        )
+        ))
     })?;
     match k256::ecdsa::VerifyingKey::from_sec1_bytes(&pubkey_bytes)
         .map_err(|e| {
-            format!(
+            GenericError::internal(format!(
                 "failed to deserialize sec1 encoding into public key: {:?}",
                 e
-            )
+            ))
         })?
         .verify(message_bytes, &signature)
     {
@@ -156,9 +192,13 @@ pub async fn get_canister_public_key(
         key_id: get_ecdsa_key_id(),
     };
 
-    let (res,) = ecdsa_public_key(request)
-        .await
-        .map_err(|e| format!("ecdsa_public_key failed {:?}", e))?;
+    let (res,) = ecdsa_public_key(request).await.map_err(|e| {
+        GenericError::inter_canister_call_failed(
+            Principal::management_canister(),
+            "ecdsa_public_key",
+            e,
+        )
+    })?;
 
     Ok(res)
 }
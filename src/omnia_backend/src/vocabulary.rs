@@ -0,0 +1,611 @@
+use candid::candid_method;
+use ic_cdk_macros::{query, update};
+use ic_oxigraph::model::{vocab, GraphName, Literal, NamedNode, Quad, Subject, Term};
+use omnia_types::{
+    errors::{GenericError, GenericResult},
+    vocabulary::{VocabulariesResult, Vocabulary},
+};
+
+use crate::{
+    certification::CertificationBatch,
+    prefixes::is_query_prefix,
+    rdf::{
+        insert_quads, remove_quad, OmniaNode, HCTL_PREFIX, JSON_SCHEMA_PREFIX, S4BLDG_PREFIX,
+        S4ENER_PREFIX, SAREF_PREFIX, TD_PREFIX, WOTSEC_PREFIX,
+    },
+    utils::caller_is_controller,
+    RDF_DB,
+};
+
+/// Classes and properties of [SAREF core](https://saref.etsi.org/core/v3.1.1/)
+const SAREF_TERMS: &[&str] = &[
+    "ActuatingFunction",
+    "Actuator",
+    "Appliance",
+    "CloseCommand",
+    "CloseState",
+    "Command",
+    "Commodity",
+    "Device",
+    "DoorSwitch",
+    "Electricity",
+    "Energy",
+    "EnergyMeter",
+    "EventFunction",
+    "FeatureOfInterest",
+    "Function",
+    "Gas",
+    "GetCommand",
+    "GetCurrentMeterValueCommand",
+    "GetMeterDataCommand",
+    "GetMeterHistoryCommand",
+    "GetSensingDataCommand",
+    "HVAC",
+    "Humidity",
+    "Light",
+    "LightSwitch",
+    "LightingDevice",
+    "Measurement",
+    "Meter",
+    "MeteringFunction",
+    "Motion",
+    "MultiLevelState",
+    "NotifyCommand",
+    "Occupancy",
+    "OffCommand",
+    "OffState",
+    "OnCommand",
+    "OnOffFunction",
+    "OnOffState",
+    "OnState",
+    "OpenCloseFunction",
+    "OpenCloseState",
+    "OpenCommand",
+    "OpenState",
+    "PauseCommand",
+    "Power",
+    "Pressure",
+    "Price",
+    "Profile",
+    "Property",
+    "PropertyValue",
+    "Sensor",
+    "SensingFunction",
+    "Service",
+    "SetAbsoluteLevelCommand",
+    "SetLevelCommand",
+    "SetRelativeLevelCommand",
+    "SmokeSensor",
+    "StartCommand",
+    "StartState",
+    "StartStopFunction",
+    "StartStopState",
+    "State",
+    "StepDownCommand",
+    "StepUpCommand",
+    "StopCommand",
+    "StopState",
+    "Switch",
+    "SwitchOnService",
+    "Task",
+    "Temperature",
+    "TemperatureSensor",
+    "Time",
+    "ToggleCommand",
+    "UnitOfMeasure",
+    "Water",
+];
+
+/// Classes of [SAREF4BLDG](https://saref.etsi.org/saref4bldg/v1.1.2/)
+const S4BLDG_TERMS: &[&str] = &[
+    "AirTerminal",
+    "Alarm",
+    "AudioVisualAppliance",
+    "Boiler",
+    "Building",
+    "BuildingDevice",
+    "BuildingObject",
+    "BuildingSpace",
+    "Burner",
+    "Chiller",
+    "Coil",
+    "CommunicationsAppliance",
+    "Compressor",
+    "Condenser",
+    "Controller",
+    "CooledBeam",
+    "CoolingTower",
+    "Damper",
+    "DistributionControlDevice",
+    "DistributionDevice",
+    "DistributionFlowDevice",
+    "Duct",
+    "ElectricAppliance",
+    "ElectricDistributionBoard",
+    "ElectricFlowStorageDevice",
+    "ElectricGenerator",
+    "ElectricMotor",
+    "ElectricTimeControl",
+    "EnergyConversionDevice",
+    "Engine",
+    "EvaporativeCooler",
+    "Evaporator",
+    "Fan",
+    "Filter",
+    "FireSuppressionTerminal",
+    "FlowController",
+    "FlowInstrument",
+    "FlowMeter",
+    "FlowMovingDevice",
+    "FlowSegment",
+    "FlowStorageDevice",
+    "FlowTerminal",
+    "FlowTreatmentDevice",
+    "HeatExchanger",
+    "Humidifier",
+    "Lamp",
+    "LightFixture",
+    "MedicalDevice",
+    "Motor",
+    "Outlet",
+    "PhysicalObject",
+    "Pipe",
+    "ProtectiveDevice",
+    "ProtectiveDeviceTrippingUnit",
+    "Pump",
+    "SanitaryTerminal",
+    "Sensor",
+    "ShadingDevice",
+    "SolarDevice",
+    "SpaceHeater",
+    "StackTerminal",
+    "SwitchingDevice",
+    "Tank",
+    "Transformer",
+    "TubeBundle",
+    "UnitaryControlElement",
+    "UnitaryEquipment",
+    "Valve",
+    "WasteTerminal",
+];
+
+/// Classes of [SAREF4ENER](https://saref.etsi.org/saref4ener/v1.1.2/)
+const S4ENER_TERMS: &[&str] = &[
+    "Alternative",
+    "AlternativesGroup",
+    "Energy",
+    "EnergyExpected",
+    "EnergyMax",
+    "EnergyMin",
+    "Power",
+    "PowerExpected",
+    "PowerMax",
+    "PowerMin",
+    "PowerProfile",
+    "PowerSequence",
+    "PowerSequenceState",
+    "Slot",
+    "SlotPowerValue",
+];
+
+/// The vocabularies built in the backend. The terms of the WoT vocabularies are not listed,
+/// because they're used by the TD model itself
+fn get_built_in_vocabularies() -> Vec<Vocabulary> {
+    [
+        ("saref", SAREF_PREFIX, Some(SAREF_TERMS)),
+        ("s4bldg", S4BLDG_PREFIX, Some(S4BLDG_TERMS)),
+        ("s4ener", S4ENER_PREFIX, Some(S4ENER_TERMS)),
+        ("td", TD_PREFIX, None),
+        ("hctl", HCTL_PREFIX, None),
+        ("jsonschema", JSON_SCHEMA_PREFIX, None),
+        ("wotsec", WOTSEC_PREFIX, None),
+    ]
+    .into_iter()
+    .map(|(prefix, namespace, terms)| Vocabulary {
+        prefix: prefix.to_string(),
+        namespace: namespace.to_string(),
+        terms: terms.map(|terms| terms.iter().map(|term| term.to_string()).collect()),
+        is_custom: false,
+    })
+    .collect()
+}
+
+/// The custom vocabularies are stored in their own graph, so that they don't show up in the queries of the default graph
+fn get_vocabularies_graph() -> GraphName {
+    GraphName::NamedNode(OmniaNode::from("vocabularies"))
+}
+
+/// The built-in vocabularies followed by the custom ones
+pub fn get_vocabularies() -> GenericResult<Vec<Vocabulary>> {
+    let mut vocabularies = get_built_in_vocabularies();
+
+    RDF_DB.with(|store| {
+        let rdf_db = store.borrow();
+        let graph = get_vocabularies_graph();
+        let vocabulary_class = OmniaNode::from("Vocabulary");
+        let has_prefix = OmniaNode::from("hasPrefix");
+        let defines_term = OmniaNode::from("definesTerm");
+        let read_error = |e| GenericError::internal(format!("Error reading vocabularies: {:?}", e));
+
+        for quad in rdf_db.quads_for_pattern(
+            None,
+            Some(vocab::rdf::TYPE),
+            Some(vocabulary_class.as_ref().into()),
+            Some(graph.as_ref()),
+        ) {
+            let namespace = match quad.map_err(read_error)?.subject {
+                Subject::NamedNode(namespace) => namespace,
+                _ => continue,
+            };
+
+            let mut prefix = None;
+            let mut terms = vec![];
+            for quad in rdf_db.quads_for_pattern(
+                Some(namespace.as_ref().into()),
+                None,
+                None,
+                Some(graph.as_ref()),
+            ) {
+                let quad = quad.map_err(read_error)?;
+                if let Term::Literal(literal) = quad.object {
+                    if quad.predicate == has_prefix {
+                        prefix = Some(literal.value().to_string());
+                    } else if quad.predicate == defines_term {
+                        terms.push(literal.value().to_string());
+                    }
+                }
+            }
+            terms.sort();
+
+            if let Some(prefix) = prefix {
+                vocabularies.push(Vocabulary {
+                    prefix,
+                    namespace: namespace.into_string(),
+                    // vocabularies registered without terms accept any term of their namespace
+                    terms: (!terms.is_empty()).then_some(terms),
+                    is_custom: true,
+                });
+            }
+        }
+
+        Ok(vocabularies)
+    })
+}
+
+/// Checks that the term belongs to one of the vocabularies and, if the vocabulary lists its terms, that it's one of them
+pub fn validate_term(vocabularies: &[Vocabulary], term: &NamedNode) -> GenericResult<()> {
+    let vocabulary = vocabularies
+        .iter()
+        .filter(|vocabulary| term.as_str().starts_with(&vocabulary.namespace))
+        .max_by_key(|vocabulary| vocabulary.namespace.len())
+        .ok_or_else(|| {
+            GenericError::invalid_argument(format!(
+                "Term {} doesn't belong to a known vocabulary, controllers can register it with registerVocabulary",
+                term
+            ))
+        })?;
+
+    let name = &term.as_str()[vocabulary.namespace.len()..];
+    match &vocabulary.terms {
+        Some(terms) if !terms.iter().any(|known_term| known_term == name) => {
+            let suggestion = terms
+                .iter()
+                .map(|known_term| (get_edit_distance(name, known_term), known_term))
+                .filter(|(distance, _)| *distance <= 2)
+                .min()
+                .map(|(_, known_term)| {
+                    format!(", did you mean {}:{}?", vocabulary.prefix, known_term)
+                })
+                .unwrap_or_default();
+
+            Err(GenericError::invalid_argument(format!(
+                "Term {}:{} is not defined in the vocabulary{}",
+                vocabulary.prefix, name, suggestion
+            )))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Levenshtein distance between the two names
+fn get_edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut distances: Vec<usize> = (0..=b.len()).collect();
+
+    for (i, a_char) in a.chars().enumerate() {
+        let mut previous_diagonal = distances[0];
+        distances[0] = i + 1;
+        for (j, b_char) in b.iter().enumerate() {
+            let substitution = previous_diagonal + usize::from(a_char != *b_char);
+            previous_diagonal = distances[j + 1];
+            distances[j + 1] = substitution.min(distances[j] + 1).min(distances[j + 1] + 1);
+        }
+    }
+
+    distances[b.len()]
+}
+
+fn is_valid_prefix(prefix: &str) -> bool {
+    let mut chars = prefix.chars();
+    matches!(chars.next(), Some(first) if first.is_ascii_alphabetic())
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+/// Registers the vocabulary, replacing the custom vocabulary with the same prefix or namespace, if any
+pub fn insert_vocabulary(
+    prefix: String,
+    namespace: String,
+    terms: Option<Vec<String>>,
+) -> GenericResult<()> {
+    if !is_valid_prefix(&prefix) {
+        return Err(GenericError::invalid_argument(format!(
+            "Invalid prefix {:?}, it must start with a letter and contain only letters, digits, '_' and '-'",
+            prefix
+        )));
+    }
+    if is_query_prefix(&prefix) {
+        return Err(GenericError::invalid_argument(format!(
+            "Prefix {:?} is reserved for the SPARQL queries",
+            prefix
+        )));
+    }
+    if !namespace.ends_with('/') && !namespace.ends_with('#') {
+        return Err(GenericError::invalid_argument(format!(
+            "Invalid namespace {:?}, it must end with '/' or '#'",
+            namespace
+        )));
+    }
+    let namespace_node = NamedNode::new(&namespace).map_err(|e| {
+        GenericError::invalid_argument(format!("Invalid namespace {:?}: {}", namespace, e))
+    })?;
+    if let Some(built_in_vocabulary) = get_built_in_vocabularies().iter().find(|vocabulary| {
+        vocabulary.prefix == prefix
+            || vocabulary.namespace.starts_with(&namespace)
+            || namespace.starts_with(&vocabulary.namespace)
+    }) {
+        return Err(GenericError::invalid_argument(format!(
+            "Vocabulary {:?} is built in and cannot be replaced",
+            built_in_vocabulary.prefix
+        )));
+    }
+    if let Some(terms) = &terms {
+        if terms.is_empty() {
+            return Err(GenericError::invalid_argument(
+                "Terms must not be empty, omit them to accept any term of the namespace",
+            ));
+        }
+        if let Some(term) = terms.iter().find(|term| {
+            term.is_empty() || NamedNode::new(format!("{}{}", namespace, term)).is_err()
+        }) {
+            return Err(GenericError::invalid_argument(format!(
+                "Invalid term {:?}",
+                term
+            )));
+        }
+    }
+
+    remove_custom_vocabularies(|vocabulary| {
+        vocabulary.prefix == prefix || vocabulary.namespace == namespace
+    })?;
+
+    let graph = get_vocabularies_graph();
+    let mut quads = vec![
+        Quad::new(
+            namespace_node.clone(),
+            vocab::rdf::TYPE,
+            OmniaNode::from("Vocabulary"),
+            graph.clone(),
+        ),
+        Quad::new(
+            namespace_node.clone(),
+            OmniaNode::from("hasPrefix"),
+            Literal::new_simple_literal(&prefix),
+            graph.clone(),
+        ),
+    ];
+    quads.extend(terms.unwrap_or_default().into_iter().map(|term| {
+        Quad::new(
+            namespace_node.clone(),
+            OmniaNode::from("definesTerm"),
+            Literal::new_simple_literal(term),
+            graph.clone(),
+        )
+    }));
+
+    insert_quads(&quads)
+}
+
+fn remove_custom_vocabularies(filter: impl Fn(&Vocabulary) -> bool) -> GenericResult<()> {
+    let graph = get_vocabularies_graph();
+    let mut certification_batch = CertificationBatch::default();
+
+    for vocabulary in get_vocabularies()?
+        .into_iter()
+        .filter(|vocabulary| vocabulary.is_custom && filter(vocabulary))
+    {
+        let namespace_node = NamedNode::new_unchecked(vocabulary.namespace);
+
+        RDF_DB.with(|store| {
+            let rdf_db = store.borrow();
+            let quads = rdf_db
+                .quads_for_pattern(
+                    Some(namespace_node.as_ref().into()),
+                    None,
+                    None,
+                    Some(graph.as_ref()),
+                )
+                .collect::<Result<Vec<Quad>, _>>()
+                .map_err(|e| {
+                    GenericError::internal(format!("Error reading vocabularies: {:?}", e))
+                })?;
+
+            for quad in quads.iter() {
+                remove_quad(&rdf_db, quad, &mut certification_batch).map_err(|e| {
+                    GenericError::internal(format!("Error removing quad {}: {}", quad, e))
+                })?;
+            }
+
+            Ok(())
+        })?;
+    }
+
+    Ok(())
+}
+
+#[update(name = "registerVocabulary")]
+#[candid_method(update, rename = "registerVocabulary")]
+/// Only controllers can register custom vocabularies, whose terms can then be used in the Thing Descriptions.
+/// If no terms are provided, any term of the namespace is accepted.
+/// A custom vocabulary with the same prefix or namespace is replaced, while the built-in ones cannot be replaced
+fn register_vocabulary(
+    prefix: String,
+    namespace: String,
+    terms: Option<Vec<String>>,
+) -> GenericResult<()> {
+    caller_is_controller()?;
+
+    insert_vocabulary(prefix, namespace, terms)
+}
+
+#[update(name = "unregisterVocabulary")]
+#[candid_method(update, rename = "unregisterVocabulary")]
+/// Only controllers can unregister custom vocabularies. The devices already described with their terms are not changed
+fn unregister_vocabulary(prefix: String) -> GenericResult<()> {
+    caller_is_controller()?;
+
+    if !get_vocabularies()?
+        .iter()
+        .any(|vocabulary| vocabulary.is_custom && vocabulary.prefix == prefix)
+    {
+        return Err(GenericError::NotFound {
+            entity: String::from("Vocabulary"),
+            id: prefix,
+        });
+    }
+
+    remove_custom_vocabularies(|vocabulary| vocabulary.prefix == prefix)
+}
+
+#[query(name = "getVocabularies")]
+#[candid_method(query, rename = "getVocabularies")]
+/// Returns the vocabularies whose terms can be used in the Thing Descriptions
+fn get_vocabularies_query() -> VocabulariesResult {
+    get_vocabularies()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn saref(name: &str) -> NamedNode {
+        NamedNode::new_unchecked(format!("{}{}", SAREF_PREFIX, name))
+    }
+
+    #[test]
+    fn test_validate_term() {
+        let vocabularies = get_vocabularies().unwrap();
+
+        assert_eq!(validate_term(&vocabularies, &saref("OnCommand")), Ok(()));
+        assert_eq!(
+            validate_term(
+                &vocabularies,
+                &NamedNode::new_unchecked(format!("{}Lamp", S4BLDG_PREFIX))
+            ),
+            Ok(())
+        );
+        assert_eq!(
+            validate_term(
+                &vocabularies,
+                &NamedNode::new_unchecked(format!("{}PropertyAffordance", TD_PREFIX))
+            ),
+            Ok(())
+        );
+
+        match validate_term(&vocabularies, &saref("OnComand")) {
+            Err(GenericError::InvalidArgument { reason }) => {
+                assert!(
+                    reason.ends_with("did you mean saref:OnCommand?"),
+                    "{}",
+                    reason
+                )
+            }
+            result => panic!("unexpected result {:?}", result),
+        }
+        assert!(validate_term(
+            &vocabularies,
+            &NamedNode::new_unchecked("https://example.com/vocab#Toggle")
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_custom_vocabularies() {
+        let toggle = NamedNode::new_unchecked("https://example.com/vocab#Toggle");
+
+        insert_vocabulary(
+            String::from("ex"),
+            String::from("https://example.com/vocab#"),
+            Some(vec![String::from("Toggle")]),
+        )
+        .unwrap();
+        let vocabularies = get_vocabularies().unwrap();
+        assert_eq!(
+            vocabularies.last(),
+            Some(&Vocabulary {
+                prefix: String::from("ex"),
+                namespace: String::from("https://example.com/vocab#"),
+                terms: Some(vec![String::from("Toggle")]),
+                is_custom: true,
+            })
+        );
+        assert_eq!(validate_term(&vocabularies, &toggle), Ok(()));
+        assert!(validate_term(
+            &vocabularies,
+            &NamedNode::new_unchecked("https://example.com/vocab#Switch")
+        )
+        .is_err());
+
+        // registering the same prefix again replaces the vocabulary
+        insert_vocabulary(
+            String::from("ex"),
+            String::from("https://example.com/vocab#"),
+            None,
+        )
+        .unwrap();
+        let vocabularies = get_vocabularies().unwrap();
+        assert_eq!(vocabularies.iter().filter(|v| v.is_custom).count(), 1);
+        assert_eq!(
+            validate_term(
+                &vocabularies,
+                &NamedNode::new_unchecked("https://example.com/vocab#Switch")
+            ),
+            Ok(())
+        );
+
+        remove_custom_vocabularies(|vocabulary| vocabulary.prefix == "ex").unwrap();
+        assert!(validate_term(&get_vocabularies().unwrap(), &toggle).is_err());
+    }
+
+    #[test]
+    fn test_built_in_vocabularies_cannot_be_replaced() {
+        for (prefix, namespace) in [
+            ("saref", "https://example.com/saref#"),
+            ("ex", SAREF_PREFIX),
+            ("ex", "https://saref.etsi.org/core/extension/"),
+            // the prefixes of the SPARQL queries are reserved as well
+            ("bot", "https://example.com/bot#"),
+        ] {
+            assert!(matches!(
+                insert_vocabulary(prefix.to_string(), namespace.to_string(), None),
+                Err(GenericError::InvalidArgument { .. })
+            ));
+        }
+    }
+
+    #[test]
+    fn test_edit_distance() {
+        assert_eq!(get_edit_distance("OnComand", "OnCommand"), 1);
+        assert_eq!(get_edit_distance("OnState", "OffState"), 2);
+        assert_eq!(get_edit_distance("", "Lamp"), 4);
+    }
+}
@@ -1,9 +1,17 @@
 use std::{borrow::Cow, cmp::Ordering};
 
-use crate::{errors::GenericResult, MAX_STABLE_BTREE_MAP_SIZE};
+use crate::{
+    device::{DeviceUid, DeviceUrl},
+    errors::GenericResult,
+    gateway::GatewayPrincipalId,
+    index::{Indexes, SecondaryIndex},
+    pagination::PageCursor,
+    versioning::{decode_versioned, encode_versioned, Versioned},
+    Memory,
+};
 use candid::{CandidType, Decode, Encode, Principal};
 use ic_cdk::api::management_canister::provisional::CanisterId;
-use ic_stable_structures::{BoundedStorable, Storable};
+use ic_stable_structures::{storable::Bound, Storable};
 use omnia_core_sdk::access_key::{AccessKeyUID, UniqueAccessKey};
 use serde::{Deserialize, Serialize};
 
@@ -25,6 +33,8 @@ impl PartialOrd for AccessKeyIndex {
 }
 
 impl Storable for AccessKeyIndex {
+    const BOUND: Bound = Bound::Unbounded;
+
     fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
         Cow::Owned(Encode!(self).unwrap())
     }
@@ -34,11 +44,6 @@ impl Storable for AccessKeyIndex {
     }
 }
 
-impl BoundedStorable for AccessKeyIndex {
-    const MAX_SIZE: u32 = MAX_STABLE_BTREE_MAP_SIZE;
-    const IS_FIXED_SIZE: bool = false;
-}
-
 #[derive(Debug, Clone, CandidType, Deserialize, Serialize)]
 pub struct AccessKeyValue {
     pub key: AccessKeyUID,
@@ -46,6 +51,9 @@ pub struct AccessKeyValue {
     pub transaction_hash: TransactionHash,
     pub counter: u32,
     pub used_nonces: Vec<u128>,
+    /// timestamp (in nanoseconds) of the creation of the access key,
+    /// None for access keys created before the creation time was recorded
+    pub created_at: Option<u64>,
 }
 
 impl Default for AccessKeyValue {
@@ -56,18 +64,25 @@ impl Default for AccessKeyValue {
             transaction_hash: [0; 32],
             counter: 0,
             used_nonces: vec![],
+            created_at: None,
         }
     }
 }
 
 impl AccessKeyValue {
-    pub fn new(key: AccessKeyUID, owner: Principal, transaction_hash: TransactionHash) -> Self {
+    pub fn new(
+        key: AccessKeyUID,
+        owner: Principal,
+        transaction_hash: TransactionHash,
+        created_at: u64,
+    ) -> Self {
         Self {
             key,
             owner,
             transaction_hash,
             counter: 0,
             used_nonces: vec![],
+            created_at: Some(created_at),
         }
     }
 
@@ -90,19 +105,18 @@ impl AccessKeyValue {
 }
 
 impl Storable for AccessKeyValue {
+    const BOUND: Bound = Bound::Unbounded;
+
     fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
-        Cow::Owned(Encode!(self).unwrap())
+        encode_versioned(self)
     }
 
     fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
-        Decode!(bytes.as_ref(), Self).unwrap()
+        decode_versioned(&bytes)
     }
 }
 
-impl BoundedStorable for AccessKeyValue {
-    const MAX_SIZE: u32 = MAX_STABLE_BTREE_MAP_SIZE;
-    const IS_FIXED_SIZE: bool = false;
-}
+impl Versioned for AccessKeyValue {}
 
 /// Use [get_transaction_hash][omnia_utils::ic::get_transaction_hash] to generate the transaction hash
 pub type TransactionHash = [u8; 32];
@@ -112,13 +126,139 @@ pub struct AccessKeyCreationArgs {
     pub owner: Principal,
     pub transaction_hash: TransactionHash,
 }
-pub type AccessKeyCreationResult = GenericResult<AccessKeyValue>;
+/// The secret is returned only once, when the Access Key is created
+pub type AccessKeyCreationResult = GenericResult<(AccessKeyValue, AccessKeySecret)>;
+
+/// The secret used as key in the [UniqueAccessKey] signed by the requester.
+/// Use [generate_access_key_secret][omnia_utils::access_key::generate_access_key_secret] to generate it
+pub type AccessKeySecret = String;
+/// Use [hash_access_key_secret][omnia_utils::access_key::hash_access_key_secret] to generate the secret hash
+pub type AccessKeySecretHash = [u8; 32];
+
+#[derive(Clone, Debug, CandidType, Serialize, Deserialize, PartialEq, Eq)]
+pub struct AccessKeySecretIndex {
+    pub secret_hash: AccessKeySecretHash,
+}
+
+impl Ord for AccessKeySecretIndex {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.secret_hash.cmp(&other.secret_hash)
+    }
+}
+
+impl PartialOrd for AccessKeySecretIndex {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Storable for AccessKeySecretIndex {
+    const BOUND: Bound = Bound::Unbounded;
+
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+#[derive(Debug, Clone, CandidType, Deserialize, Serialize)]
+pub struct AccessKeySecretValue {
+    /// The public identifier of the Access Key
+    pub access_key_uid: AccessKeyUID,
+}
+
+impl Storable for AccessKeySecretValue {
+    const BOUND: Bound = Bound::Unbounded;
+
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        encode_versioned(self)
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_versioned(&bytes)
+    }
+}
+
+impl Versioned for AccessKeySecretValue {}
+
+#[derive(Clone, Debug, CandidType, Serialize, Deserialize, PartialEq, Eq)]
+pub struct AccessKeyOwnerIndex {
+    pub owner: Principal,
+}
+
+impl Ord for AccessKeyOwnerIndex {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.owner.cmp(&other.owner)
+    }
+}
+
+impl PartialOrd for AccessKeyOwnerIndex {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Storable for AccessKeyOwnerIndex {
+    const BOUND: Bound = Bound::Unbounded;
+
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+/// Secondary indexes of the valid access keys
+pub struct AccessKeyIndexes {
+    pub by_owner: SecondaryIndex<AccessKeyOwnerIndex, AccessKeyIndex, AccessKeyValue>,
+}
+
+impl AccessKeyIndexes {
+    pub fn init(by_owner_memory: Memory) -> Self {
+        Self {
+            by_owner: SecondaryIndex::init(by_owner_memory, |_, access_key_value| {
+                AccessKeyOwnerIndex {
+                    owner: access_key_value.owner,
+                }
+            }),
+        }
+    }
+}
+
+impl Indexes<AccessKeyIndex, AccessKeyValue> for AccessKeyIndexes {
+    fn insert(&mut self, index: &AccessKeyIndex, value: &AccessKeyValue) {
+        self.by_owner.insert(index, value);
+    }
+
+    fn remove(&mut self, index: &AccessKeyIndex, value: &AccessKeyValue) {
+        self.by_owner.remove(index, value);
+    }
+}
+
+/// Summary of an access key listed for its owner, without the nonces used to spend it
+#[derive(Clone, Debug, CandidType, Serialize, Deserialize)]
+pub struct OwnedAccessKey {
+    pub access_key_uid: AccessKeyUID,
+    /// timestamp (in nanoseconds) of the creation of the access key,
+    /// None for access keys created before the creation time was recorded
+    pub created_at: Option<u64>,
+    /// number of requests spent so far
+    pub counter: u32,
+    pub remaining_requests: u32,
+}
 
 #[derive(Clone, Debug, CandidType, Serialize, Deserialize)]
 pub struct SignedRequest {
     signature_hex: String,
     unique_access_key: UniqueAccessKey,
     requester_canister_id: CanisterId,
+    /// UID of the device (registered on the reporting gateway) that served the request
+    device_uid: DeviceUid,
 }
 
 impl SignedRequest {
@@ -133,6 +273,17 @@ impl SignedRequest {
     pub fn get_requester_principal_id(&self) -> CanisterId {
         self.requester_canister_id
     }
+
+    pub fn get_device_uid(&self) -> DeviceUid {
+        self.device_uid.clone()
+    }
+}
+
+/// A request with a valid signature, reported by a gateway and ready to be spent
+#[derive(Clone, Debug, CandidType, Serialize, Deserialize)]
+pub struct ServedRequest {
+    pub unique_access_key: UniqueAccessKey,
+    pub device_uid: DeviceUid,
 }
 
 #[derive(Clone, Debug, CandidType, Serialize, Deserialize)]
@@ -140,6 +291,8 @@ pub enum RejectedAccessKeyReason {
     InvalidSignature,
     InvalidNonce,
     InvalidAccessKey,
+    /// The device does not exist or is not registered on the reporting gateway
+    InvalidDevice,
     RequestsLimitReached,
     NonceAlreadyUsed,
     SignatureVerificationError(String),
@@ -150,3 +303,157 @@ pub struct RejectedAccessKey {
     pub key: AccessKeyUID,
     pub reason: RejectedAccessKeyReason,
 }
+
+#[derive(Clone, Debug, Default, CandidType, Serialize, Deserialize, PartialEq, Eq)]
+pub struct AccessKeyUsageIndex {
+    pub access_key_uid: AccessKeyUID,
+    /// timestamp (in nanoseconds) of the spend
+    pub timestamp: u64,
+    pub nonce: u128,
+}
+
+impl Ord for AccessKeyUsageIndex {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.access_key_uid
+            .cmp(&other.access_key_uid)
+            .then(self.timestamp.cmp(&other.timestamp))
+            .then(self.nonce.cmp(&other.nonce))
+    }
+}
+
+impl PartialOrd for AccessKeyUsageIndex {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Storable for AccessKeyUsageIndex {
+    const BOUND: Bound = Bound::Unbounded;
+
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+/// Where an access key has been spent
+#[derive(Clone, Debug, Default, CandidType, Serialize, Deserialize)]
+pub struct AccessKeyUsageValue {
+    pub gateway_principal_id: GatewayPrincipalId,
+    pub device_uid: DeviceUid,
+    pub device_url: DeviceUrl,
+}
+
+impl Storable for AccessKeyUsageValue {
+    const BOUND: Bound = Bound::Unbounded;
+
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        encode_versioned(self)
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_versioned(&bytes)
+    }
+}
+
+impl Versioned for AccessKeyUsageValue {}
+
+const NANOSECONDS_IN_A_DAY: u64 = 24 * 60 * 60 * 1_000_000_000;
+
+/// Aggregates of the usage history of an access key, updated at every spend so that they're not computed on the whole history
+#[derive(Clone, Debug, Default, CandidType, Serialize, Deserialize)]
+pub struct AccessKeyUsageStatsValue {
+    /// total number of spends
+    pub total_requests: u64,
+    /// number of spends per day, ordered by the starting timestamp (in nanoseconds) of the day
+    pub requests_per_day: Vec<(u64, u64)>,
+    /// number of spends per device, ordered by device UID
+    pub requests_per_device: Vec<(DeviceUid, u64)>,
+}
+
+impl AccessKeyUsageStatsValue {
+    /// Counts a spend of the access key at the given timestamp (in nanoseconds) on the given device.
+    /// Once `max_devices` devices are counted, the spends on other devices are counted only in the totals
+    pub fn add_spend(&mut self, timestamp: u64, device_uid: &DeviceUid, max_devices: usize) {
+        self.total_requests += 1;
+
+        let day = timestamp - timestamp % NANOSECONDS_IN_A_DAY;
+        match self
+            .requests_per_day
+            .binary_search_by_key(&day, |(day, _)| *day)
+        {
+            Ok(position) => self.requests_per_day[position].1 += 1,
+            Err(position) => self.requests_per_day.insert(position, (day, 1)),
+        }
+
+        match self
+            .requests_per_device
+            .binary_search_by(|(counted_device_uid, _)| counted_device_uid.cmp(device_uid))
+        {
+            Ok(position) => self.requests_per_device[position].1 += 1,
+            Err(position) if self.requests_per_device.len() < max_devices => self
+                .requests_per_device
+                .insert(position, (device_uid.clone(), 1)),
+            Err(_) => (),
+        }
+    }
+}
+
+impl Storable for AccessKeyUsageStatsValue {
+    const BOUND: Bound = Bound::Unbounded;
+
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        encode_versioned(self)
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_versioned(&bytes)
+    }
+}
+
+impl Versioned for AccessKeyUsageStatsValue {}
+
+#[derive(Clone, Debug, CandidType, Serialize, Deserialize)]
+pub struct AccessKeyUsage {
+    /// spends of the access key, ordered by timestamp (paginated)
+    pub history: Vec<(AccessKeyUsageIndex, AccessKeyUsageValue)>,
+    /// cursor to pass to get the next page of the history, `None` if there are no more spends
+    pub next_cursor: Option<PageCursor>,
+    /// total number of spends
+    pub total_requests: u64,
+    /// number of spends per day, where the day is identified by its starting timestamp (in nanoseconds)
+    pub requests_per_day: Vec<(u64, u64)>,
+    /// number of spends per device, for a limited number of devices
+    pub requests_per_device: Vec<(DeviceUid, u64)>,
+}
+
+pub type AccessKeyUsageResult = GenericResult<AccessKeyUsage>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_access_key_usage_stats() {
+        let mut stats = AccessKeyUsageStatsValue::default();
+        let device1 = String::from("device1");
+        let device2 = String::from("device2");
+        let device3 = String::from("device3");
+
+        stats.add_spend(NANOSECONDS_IN_A_DAY + 1, &device2, 2);
+        stats.add_spend(1, &device1, 2);
+        stats.add_spend(2, &device2, 2);
+        // the third device is counted only in the totals
+        stats.add_spend(NANOSECONDS_IN_A_DAY + 2, &device3, 2);
+
+        assert_eq!(stats.total_requests, 4);
+        assert_eq!(
+            stats.requests_per_day,
+            vec![(0, 2), (NANOSECONDS_IN_A_DAY, 2)]
+        );
+        assert_eq!(stats.requests_per_device, vec![(device1, 1), (device2, 2)]);
+    }
+}
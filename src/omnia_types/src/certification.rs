@@ -0,0 +1,18 @@
+use candid::{CandidType, Deserialize};
+use serde::Serialize;
+
+use crate::errors::GenericResult;
+
+/// Quads of the RDF database along with the proof that they're in the certified state of the backend
+#[derive(Clone, Debug, CandidType, Serialize, Deserialize, PartialEq, Eq)]
+pub struct CertifiedQuads {
+    /// the quads in N-Quads, one per line without the line terminator
+    pub quads: Vec<String>,
+    /// certificate signed by the Internet Computer, whose certified data is the root hash of the tree
+    pub certificate: Vec<u8>,
+    /// CBOR-encoded hash tree that reveals either the SHA-256 hashes of the quads under the `quads` label,
+    /// or the hash of all the quads of an environment under the `environments` label
+    pub tree: Vec<u8>,
+}
+
+pub type CertifiedQuadsResult = GenericResult<CertifiedQuads>;
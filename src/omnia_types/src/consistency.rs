@@ -0,0 +1,34 @@
+use candid::{CandidType, Deserialize};
+use serde::Serialize;
+
+use crate::device::{DeviceUid, DeviceUrl};
+use crate::environment::EnvironmentUID;
+use crate::errors::GenericResult;
+
+/// The facts of the database that are missing or stale in the RDF database
+#[derive(Clone, Debug, Default, CandidType, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ConsistencyReport {
+    /// environments without the `bot:Zone` node
+    pub environments_without_zone: Vec<EnvironmentUID>,
+    /// devices that are not a `bot:hasElement` of their environment
+    pub devices_without_environment: Vec<DeviceUid>,
+    /// HTTP header nodes that are not required by any device
+    pub orphaned_header_nodes: Vec<String>,
+    /// environments whose RDF has been rebuilt from the database, only in repair mode
+    pub repaired_environments: Vec<EnvironmentUID>,
+}
+
+pub type ConsistencyReportResult = GenericResult<ConsistencyReport>;
+
+/// The devices that exist only in the database or only in the RDF database
+#[derive(Clone, Debug, Default, CandidType, Serialize, Deserialize, PartialEq, Eq)]
+pub struct DeviceReconciliationReport {
+    /// devices registered in the database but not described in the RDF database
+    pub devices_only_in_database: Vec<DeviceUid>,
+    /// URLs of the devices described in the RDF database but not registered in the database
+    pub devices_only_in_rdf: Vec<DeviceUrl>,
+    /// whether the devices have been unregistered from the database and removed from the RDF database
+    pub repaired: bool,
+}
+
+pub type DeviceReconciliationReportResult = GenericResult<DeviceReconciliationReport>;
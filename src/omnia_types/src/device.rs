@@ -1,20 +1,23 @@
 use std::{borrow::Cow, cmp::Ordering};
 
 use candid::{CandidType, Decode, Deserialize, Encode};
-use ic_stable_structures::{BoundedStorable, Storable};
+use ic_stable_structures::{storable::Bound, Storable};
 use serde::Serialize;
 
 use crate::{
-    environment::EnvironmentUID, errors::GenericResult, gateway::GatewayPrincipalId,
-    MAX_STABLE_BTREE_MAP_SIZE,
+    environment::{EnvironmentIndex, EnvironmentUID},
+    errors::GenericResult,
+    gateway::{GatewayPrincipalId, RegisteredGatewayIndex},
+    index::{Indexes, SecondaryIndex},
+    pagination::Page,
+    versioning::{decode_versioned, encode_versioned, Versioned},
+    Memory,
 };
 
 pub type DeviceUid = String;
 // TODO: change it to a URL type, so that we can validate it properly
 pub type DeviceUrl = String;
 
-pub type RegisteredDevicesUids = Vec<DeviceUid>;
-
 #[derive(Clone, Debug, Default, CandidType, Serialize, Deserialize, PartialEq, Eq)]
 pub struct RegisteredDeviceIndex {
     pub device_uid: DeviceUid,
@@ -33,6 +36,8 @@ impl PartialOrd for RegisteredDeviceIndex {
 }
 
 impl Storable for RegisteredDeviceIndex {
+    const BOUND: Bound = Bound::Unbounded;
+
     fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
         Cow::Owned(Encode!(self).unwrap())
     }
@@ -42,11 +47,6 @@ impl Storable for RegisteredDeviceIndex {
     }
 }
 
-impl BoundedStorable for RegisteredDeviceIndex {
-    const MAX_SIZE: u32 = MAX_STABLE_BTREE_MAP_SIZE;
-    const IS_FIXED_SIZE: bool = false;
-}
-
 #[derive(Clone, Debug, Default, CandidType, Serialize, Deserialize)]
 pub struct RegisteredDeviceValue {
     pub gateway_principal_id: GatewayPrincipalId,
@@ -58,29 +58,59 @@ pub struct RegisteredDeviceValue {
 }
 
 impl Storable for RegisteredDeviceValue {
+    const BOUND: Bound = Bound::Unbounded;
+
     fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
-        Cow::Owned(Encode!(self).unwrap())
+        encode_versioned(self)
     }
 
     fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
-        Decode!(bytes.as_ref(), Self).unwrap()
+        decode_versioned(&bytes)
     }
 }
 
-impl BoundedStorable for RegisteredDeviceValue {
-    const MAX_SIZE: u32 = MAX_STABLE_BTREE_MAP_SIZE;
-    const IS_FIXED_SIZE: bool = false;
+impl Versioned for RegisteredDeviceValue {}
+
+/// Secondary indexes of the registered devices
+pub struct RegisteredDeviceIndexes {
+    pub by_gateway:
+        SecondaryIndex<RegisteredGatewayIndex, RegisteredDeviceIndex, RegisteredDeviceValue>,
+    pub by_environment:
+        SecondaryIndex<EnvironmentIndex, RegisteredDeviceIndex, RegisteredDeviceValue>,
+}
+
+impl RegisteredDeviceIndexes {
+    pub fn init(by_gateway_memory: Memory, by_environment_memory: Memory) -> Self {
+        Self {
+            by_gateway: SecondaryIndex::init(by_gateway_memory, |_, registered_device_value| {
+                RegisteredGatewayIndex {
+                    principal_id: registered_device_value.gateway_principal_id.clone(),
+                }
+            }),
+            by_environment: SecondaryIndex::init(
+                by_environment_memory,
+                |_, registered_device_value| EnvironmentIndex {
+                    environment_uid: registered_device_value.env_uid.clone(),
+                },
+            ),
+        }
+    }
+}
+
+impl Indexes<RegisteredDeviceIndex, RegisteredDeviceValue> for RegisteredDeviceIndexes {
+    fn insert(&mut self, index: &RegisteredDeviceIndex, value: &RegisteredDeviceValue) {
+        self.by_gateway.insert(index, value);
+        self.by_environment.insert(index, value);
+    }
+
+    fn remove(&mut self, index: &RegisteredDeviceIndex, value: &RegisteredDeviceValue) {
+        self.by_gateway.remove(index, value);
+        self.by_environment.remove(index, value);
+    }
 }
 
 pub type RegisteredDeviceResult = GenericResult<(RegisteredDeviceIndex, RegisteredDeviceValue)>;
 
 pub type RegisteredDeviceOption = Option<RegisteredDeviceValue>;
 
-pub type RegisteredDevicesUidsResult = GenericResult<RegisteredDevicesUids>;
-
-#[derive(Clone, Debug, Default, CandidType, Serialize, Deserialize)]
-/// The device affordances read from the Thing Description
-pub struct DeviceAffordances {
-    pub properties: Vec<String>,
-    pub actions: Vec<String>,
-}
+pub type RegisteredDevicesUidsResult = GenericResult<Page<DeviceUid>>;
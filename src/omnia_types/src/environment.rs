@@ -1,5 +1,5 @@
 use candid::{CandidType, Decode, Deserialize, Encode};
-use ic_stable_structures::{BoundedStorable, Storable};
+use ic_stable_structures::{storable::Bound, Storable};
 use serde::Serialize;
 use std::borrow::Cow;
 use std::cmp::Ordering;
@@ -8,8 +8,8 @@ use std::collections::BTreeMap;
 use crate::errors::GenericResult;
 use crate::gateway::GatewayPrincipalId;
 use crate::http::Ip;
+use crate::versioning::{decode_versioned, encode_versioned, Versioned};
 use crate::virtual_persona::VirtualPersonaPrincipalId;
-use crate::MAX_STABLE_BTREE_MAP_SIZE;
 
 pub type EnvironmentUID = String;
 
@@ -31,6 +31,8 @@ impl PartialOrd for EnvironmentIndex {
 }
 
 impl Storable for EnvironmentIndex {
+    const BOUND: Bound = Bound::Unbounded;
+
     fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
         Cow::Owned(Encode!(self).unwrap())
     }
@@ -40,33 +42,63 @@ impl Storable for EnvironmentIndex {
     }
 }
 
-impl BoundedStorable for EnvironmentIndex {
-    const MAX_SIZE: u32 = 1000;
-    const IS_FIXED_SIZE: bool = false;
-}
-
 #[derive(Clone, Debug, Default, CandidType, Serialize, Deserialize)]
 pub struct EnvironmentValue {
     pub env_name: String,
     pub env_ip: Option<Ip>,
     pub env_users_principals_ids: BTreeMap<VirtualPersonaPrincipalId, ()>, // TODO: VirtualPersonaInfo
-    pub env_gateways_principals_ids: BTreeMap<GatewayPrincipalId, ()>,     // TODO: GatewayInfo
     pub env_manager_principal_id: VirtualPersonaPrincipalId,
 }
 
 impl Storable for EnvironmentValue {
+    const BOUND: Bound = Bound::Unbounded;
+
     fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
-        Cow::Owned(Encode!(self).unwrap())
+        encode_versioned(self)
     }
 
     fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
-        Decode!(bytes.as_ref(), Self).unwrap()
+        decode_versioned(&bytes)
     }
 }
 
-impl BoundedStorable for EnvironmentValue {
-    const MAX_SIZE: u32 = 1000;
-    const IS_FIXED_SIZE: bool = false;
+impl Versioned for EnvironmentValue {
+    /// version 1 removed the principal IDs of the registered gateways, which are indexed separately
+    const VERSION: u16 = 1;
+
+    fn decode_previous_version(version: u16, payload: &[u8]) -> Result<Self, String> {
+        match version {
+            0 => Decode!(payload, EnvironmentValueV0)
+                .map(Self::from)
+                .map_err(|e| e.to_string()),
+            _ => Err(format!(
+                "No previous version {} of EnvironmentValue",
+                version
+            )),
+        }
+    }
+}
+
+/// [EnvironmentValue] stored with version 0
+#[derive(CandidType, Deserialize)]
+struct EnvironmentValueV0 {
+    env_name: String,
+    env_ip: Option<Ip>,
+    env_users_principals_ids: BTreeMap<VirtualPersonaPrincipalId, ()>,
+    #[allow(dead_code)]
+    env_gateways_principals_ids: BTreeMap<GatewayPrincipalId, ()>,
+    env_manager_principal_id: VirtualPersonaPrincipalId,
+}
+
+impl From<EnvironmentValueV0> for EnvironmentValue {
+    fn from(environment_value: EnvironmentValueV0) -> Self {
+        Self {
+            env_name: environment_value.env_name,
+            env_ip: environment_value.env_ip,
+            env_users_principals_ids: environment_value.env_users_principals_ids,
+            env_manager_principal_id: environment_value.env_manager_principal_id,
+        }
+    }
 }
 
 #[derive(Debug, CandidType, Deserialize)]
@@ -105,26 +137,8 @@ impl PartialOrd for EnvironmentUidIndex {
 }
 
 impl Storable for EnvironmentUidIndex {
-    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
-        Cow::Owned(Encode!(self).unwrap())
-    }
+    const BOUND: Bound = Bound::Unbounded;
 
-    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
-        Decode!(bytes.as_ref(), Self).unwrap()
-    }
-}
-
-impl BoundedStorable for EnvironmentUidIndex {
-    const MAX_SIZE: u32 = MAX_STABLE_BTREE_MAP_SIZE;
-    const IS_FIXED_SIZE: bool = false;
-}
-
-#[derive(Clone, Debug, Default, CandidType, Serialize, Deserialize)]
-pub struct EnvironmentUidValue {
-    pub env_uid: EnvironmentUID,
-}
-
-impl Storable for EnvironmentUidValue {
     fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
         Cow::Owned(Encode!(self).unwrap())
     }
@@ -134,7 +148,36 @@ impl Storable for EnvironmentUidValue {
     }
 }
 
-impl BoundedStorable for EnvironmentUidValue {
-    const MAX_SIZE: u32 = MAX_STABLE_BTREE_MAP_SIZE;
-    const IS_FIXED_SIZE: bool = false;
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_legacy_environment_value() {
+        // EnvironmentValue as stored before the versioned envelopes were introduced
+        #[derive(CandidType)]
+        struct LegacyEnvironmentValue {
+            env_name: String,
+            env_ip: Option<Ip>,
+            env_users_principals_ids: BTreeMap<VirtualPersonaPrincipalId, ()>,
+            env_gateways_principals_ids: BTreeMap<GatewayPrincipalId, ()>,
+            env_manager_principal_id: VirtualPersonaPrincipalId,
+        }
+        let bytes = Encode!(&LegacyEnvironmentValue {
+            env_name: String::from("environment"),
+            env_ip: None,
+            env_users_principals_ids: BTreeMap::from([(String::from("user"), ())]),
+            env_gateways_principals_ids: BTreeMap::from([(String::from("gateway"), ())]),
+            env_manager_principal_id: String::from("manager"),
+        })
+        .unwrap();
+
+        let environment_value = EnvironmentValue::from_bytes(Cow::Owned(bytes));
+        assert_eq!(environment_value.env_name, "environment");
+        assert_eq!(environment_value.env_ip, None);
+        assert!(environment_value
+            .env_users_principals_ids
+            .contains_key("user"));
+        assert_eq!(environment_value.env_manager_principal_id, "manager");
+    }
 }
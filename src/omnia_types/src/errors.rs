@@ -1,3 +1,178 @@
-pub type GenericError = String;
+use std::fmt;
 
-pub type GenericResult<T> =  Result<T, GenericError>;
\ No newline at end of file
+use candid::{CandidType, Principal};
+use ic_cdk::api::call::RejectionCode;
+use serde::{Deserialize, Serialize};
+
+/// Mirrors [RejectionCode], so that it can be returned to the callers
+#[derive(Debug, Clone, Copy, CandidType, Deserialize, Serialize, PartialEq, Eq)]
+pub enum CallRejectionCode {
+    NoError,
+    SysFatal,
+    SysTransient,
+    DestinationInvalid,
+    CanisterReject,
+    CanisterError,
+    Unknown,
+}
+
+impl From<RejectionCode> for CallRejectionCode {
+    fn from(code: RejectionCode) -> Self {
+        match code {
+            RejectionCode::NoError => Self::NoError,
+            RejectionCode::SysFatal => Self::SysFatal,
+            RejectionCode::SysTransient => Self::SysTransient,
+            RejectionCode::DestinationInvalid => Self::DestinationInvalid,
+            RejectionCode::CanisterReject => Self::CanisterReject,
+            RejectionCode::CanisterError => Self::CanisterError,
+            RejectionCode::Unknown => Self::Unknown,
+        }
+    }
+}
+
+#[derive(Debug, Clone, CandidType, Deserialize, Serialize, PartialEq, Eq)]
+pub enum GenericError {
+    /// The entity with the given id does not exist
+    NotFound { entity: String, id: String },
+    /// An entity with the same id already exists
+    AlreadyExists { entity: String, id: String },
+    /// The caller is not allowed to perform the operation
+    Unauthorized { caller: Principal, reason: String },
+    /// The IP challenge with the given nonce has expired or has already been used
+    ChallengeExpired { nonce: String },
+    /// The caller is not in the network of the environment
+    NetworkMismatch { ip: String, reason: String },
+    /// The payment in the ledger block does not match the expected one
+    PaymentInvalid { block_index: u64, reason: String },
+    /// The call to another canister was rejected
+    InterCanisterCallFailed {
+        canister_id: Principal,
+        method: String,
+        rejection_code: CallRejectionCode,
+        message: String,
+    },
+    /// The arguments of the call are not valid
+    InvalidArgument { reason: String },
+    /// The operation would exceed one of the configured limits
+    LimitExceeded { limit: String, max: u64 },
+    /// Any other error, that the caller cannot recover from
+    Internal { reason: String },
+}
+
+impl GenericError {
+    /// Builds a [GenericError::NotFound] from the index of the entity, whose type name is used as entity name
+    pub fn not_found<I: fmt::Debug>(index: &I) -> Self {
+        Self::NotFound {
+            entity: entity_name::<I>(),
+            id: format!("{:?}", index),
+        }
+    }
+
+    /// Builds a [GenericError::AlreadyExists] from the index of the entity, whose type name is used as entity name
+    pub fn already_exists<I: fmt::Debug>(index: &I) -> Self {
+        Self::AlreadyExists {
+            entity: entity_name::<I>(),
+            id: format!("{:?}", index),
+        }
+    }
+
+    pub fn invalid_argument(reason: impl Into<String>) -> Self {
+        Self::InvalidArgument {
+            reason: reason.into(),
+        }
+    }
+
+    pub fn internal(reason: impl Into<String>) -> Self {
+        Self::Internal {
+            reason: reason.into(),
+        }
+    }
+
+    pub fn inter_canister_call_failed(
+        canister_id: Principal,
+        method: &str,
+        (rejection_code, message): (RejectionCode, String),
+    ) -> Self {
+        Self::InterCanisterCallFailed {
+            canister_id,
+            method: method.to_string(),
+            rejection_code: rejection_code.into(),
+            message,
+        }
+    }
+}
+
+/// The name of the type, without the module path and the `Index` suffix
+fn entity_name<I>() -> String {
+    let type_name = std::any::type_name::<I>();
+    let type_name = type_name.rsplit("::").next().unwrap_or(type_name);
+    type_name
+        .strip_suffix("Index")
+        .unwrap_or(type_name)
+        .to_string()
+}
+
+impl fmt::Display for GenericError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NotFound { entity, id } => write!(f, "{} {} does not exist", entity, id),
+            Self::AlreadyExists { entity, id } => write!(f, "{} {} already exists", entity, id),
+            Self::Unauthorized { caller, reason } => {
+                write!(f, "Caller {} is not authorized: {}", caller, reason)
+            }
+            Self::ChallengeExpired { nonce } => {
+                write!(f, "IP challenge with nonce {:?} has expired", nonce)
+            }
+            Self::NetworkMismatch { ip, reason } => {
+                write!(f, "Network mismatch for IP {}: {}", ip, reason)
+            }
+            Self::PaymentInvalid {
+                block_index,
+                reason,
+            } => write!(f, "Invalid payment in block {}: {}", block_index, reason),
+            Self::InterCanisterCallFailed {
+                canister_id,
+                method,
+                rejection_code,
+                message,
+            } => write!(
+                f,
+                "Call to {}.{} failed: {:?} {}",
+                canister_id, method, rejection_code, message
+            ),
+            Self::InvalidArgument { reason } => write!(f, "Invalid argument: {}", reason),
+            Self::LimitExceeded { limit, max } => {
+                write!(f, "Limit exceeded: {} is {}", limit, max)
+            }
+            Self::Internal { reason } => write!(f, "Internal error: {}", reason),
+        }
+    }
+}
+
+pub type GenericResult<T> = Result<T, GenericError>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug)]
+    struct AccessKeyIndex {
+        #[allow(dead_code)]
+        access_key_uid: String,
+    }
+
+    #[test]
+    fn test_not_found() {
+        let index = AccessKeyIndex {
+            access_key_uid: String::from("key"),
+        };
+
+        assert_eq!(
+            GenericError::not_found(&index),
+            GenericError::NotFound {
+                entity: String::from("AccessKey"),
+                id: String::from("AccessKeyIndex { access_key_uid: \"key\" }"),
+            }
+        );
+    }
+}
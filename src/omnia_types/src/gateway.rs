@@ -1,14 +1,17 @@
 use candid::{CandidType, Decode, Deserialize, Encode};
-use ic_stable_structures::{BoundedStorable, Storable};
+use ic_stable_structures::{storable::Bound, Storable};
 use serde::Serialize;
 use std::{borrow::Cow, cmp::Ordering, collections::BTreeMap};
 
 use crate::{
     device::DeviceUid,
-    environment::EnvironmentUID,
+    environment::{EnvironmentIndex, EnvironmentUID, EnvironmentUidIndex},
     errors::GenericResult,
     http::{Ip, ProxiedGatewayUID},
-    MAX_STABLE_BTREE_MAP_SIZE,
+    index::{Indexes, SecondaryIndex},
+    pagination::Page,
+    versioning::{decode_versioned, encode_versioned, Versioned},
+    Memory,
 };
 
 pub type GatewayUID = String;
@@ -35,6 +38,8 @@ impl PartialOrd for InitializedGatewayIndex {
 }
 
 impl Storable for InitializedGatewayIndex {
+    const BOUND: Bound = Bound::Unbounded;
+
     fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
         Cow::Owned(Encode!(self).unwrap())
     }
@@ -44,11 +49,6 @@ impl Storable for InitializedGatewayIndex {
     }
 }
 
-impl BoundedStorable for InitializedGatewayIndex {
-    const MAX_SIZE: u32 = MAX_STABLE_BTREE_MAP_SIZE;
-    const IS_FIXED_SIZE: bool = false;
-}
-
 #[derive(Clone, Debug, Default, CandidType, Serialize, Deserialize)]
 pub struct InitializedGatewayValue {
     pub principal_id: GatewayPrincipalId,
@@ -56,21 +56,20 @@ pub struct InitializedGatewayValue {
 }
 
 impl Storable for InitializedGatewayValue {
+    const BOUND: Bound = Bound::Unbounded;
+
     fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
-        Cow::Owned(Encode!(self).unwrap())
+        encode_versioned(self)
     }
 
     fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
-        Decode!(bytes.as_ref(), Self).unwrap()
+        decode_versioned(&bytes)
     }
 }
 
-impl BoundedStorable for InitializedGatewayValue {
-    const MAX_SIZE: u32 = MAX_STABLE_BTREE_MAP_SIZE;
-    const IS_FIXED_SIZE: bool = false;
-}
+impl Versioned for InitializedGatewayValue {}
 
-#[derive(Debug, CandidType, Deserialize)]
+#[derive(Clone, Debug, CandidType, Deserialize)]
 pub struct GatewayRegistrationInput {
     pub env_uid: EnvironmentUID,
     pub gateway_name: String,
@@ -94,6 +93,8 @@ impl PartialOrd for RegisteredGatewayIndex {
 }
 
 impl Storable for RegisteredGatewayIndex {
+    const BOUND: Bound = Bound::Unbounded;
+
     fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
         Cow::Owned(Encode!(self).unwrap())
     }
@@ -103,11 +104,6 @@ impl Storable for RegisteredGatewayIndex {
     }
 }
 
-impl BoundedStorable for RegisteredGatewayIndex {
-    const MAX_SIZE: u32 = MAX_STABLE_BTREE_MAP_SIZE;
-    const IS_FIXED_SIZE: bool = false;
-}
-
 #[derive(Debug, Clone, CandidType, Default, Deserialize, Serialize)]
 pub struct RegisteredGatewayValue {
     pub gateway_name: String,
@@ -117,13 +113,126 @@ pub struct RegisteredGatewayValue {
     /// TODO: avoid storing it, becuase it can be derived from the gateway_ip
     pub gateway_url: GatewayUrl,
     pub proxied_gateway_uid: Option<ProxiedGatewayUID>,
+    // TODO: add a is_proxied field to avoid having to check if proxied_gateway_uid is None and improve readability
+    // not sure if this is a good idea, because it would be another field stored in the DB
     pub env_uid: EnvironmentUID,
-    pub gat_registered_device_uids: BTreeMap<DeviceUid, ()>, // TODO: DeviceInfo
-                                                             // TODO: add a is_proxied field to avoid having to check if proxied_gateway_uid is None and improve readability
-                                                             // not sure if this is a good idea, because it would be another field stored in the DB
 }
 
 impl Storable for RegisteredGatewayValue {
+    const BOUND: Bound = Bound::Unbounded;
+
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        encode_versioned(self)
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_versioned(&bytes)
+    }
+}
+
+impl Versioned for RegisteredGatewayValue {
+    /// version 1 removed the UIDs of the registered devices, which are indexed separately
+    const VERSION: u16 = 1;
+
+    fn decode_previous_version(version: u16, payload: &[u8]) -> Result<Self, String> {
+        match version {
+            0 => Decode!(payload, RegisteredGatewayValueV0)
+                .map(Self::from)
+                .map_err(|e| e.to_string()),
+            _ => Err(format!(
+                "No previous version {} of RegisteredGatewayValue",
+                version
+            )),
+        }
+    }
+}
+
+/// [RegisteredGatewayValue] stored with version 0
+#[derive(CandidType, Deserialize)]
+struct RegisteredGatewayValueV0 {
+    gateway_name: String,
+    gateway_ip: Ip,
+    gateway_url: GatewayUrl,
+    proxied_gateway_uid: Option<ProxiedGatewayUID>,
+    env_uid: EnvironmentUID,
+    #[allow(dead_code)]
+    gat_registered_device_uids: BTreeMap<DeviceUid, ()>,
+}
+
+impl From<RegisteredGatewayValueV0> for RegisteredGatewayValue {
+    fn from(registered_gateway_value: RegisteredGatewayValueV0) -> Self {
+        Self {
+            gateway_name: registered_gateway_value.gateway_name,
+            gateway_ip: registered_gateway_value.gateway_ip,
+            gateway_url: registered_gateway_value.gateway_url,
+            proxied_gateway_uid: registered_gateway_value.proxied_gateway_uid,
+            env_uid: registered_gateway_value.env_uid,
+        }
+    }
+}
+
+/// Secondary indexes of the registered gateways
+pub struct RegisteredGatewayIndexes {
+    pub by_environment:
+        SecondaryIndex<EnvironmentIndex, RegisteredGatewayIndex, RegisteredGatewayValue>,
+    /// the gateways of a network are registered in the same environment,
+    /// so the environment of a user is found by the IP of the gateways
+    pub by_ip: SecondaryIndex<EnvironmentUidIndex, RegisteredGatewayIndex, RegisteredGatewayValue>,
+}
+
+impl RegisteredGatewayIndexes {
+    pub fn init(by_environment_memory: Memory, by_ip_memory: Memory) -> Self {
+        Self {
+            by_environment: SecondaryIndex::init(
+                by_environment_memory,
+                |_, registered_gateway_value| EnvironmentIndex {
+                    environment_uid: registered_gateway_value.env_uid.clone(),
+                },
+            ),
+            by_ip: SecondaryIndex::init(by_ip_memory, |_, registered_gateway_value| {
+                EnvironmentUidIndex {
+                    ip: registered_gateway_value.gateway_ip.clone(),
+                }
+            }),
+        }
+    }
+}
+
+impl Indexes<RegisteredGatewayIndex, RegisteredGatewayValue> for RegisteredGatewayIndexes {
+    fn insert(&mut self, index: &RegisteredGatewayIndex, value: &RegisteredGatewayValue) {
+        self.by_environment.insert(index, value);
+        self.by_ip.insert(index, value);
+    }
+
+    fn remove(&mut self, index: &RegisteredGatewayIndex, value: &RegisteredGatewayValue) {
+        self.by_environment.remove(index, value);
+        self.by_ip.remove(index, value);
+    }
+}
+
+pub type RegisteredGatewayResult = GenericResult<RegisteredGatewayValue>;
+pub type MultipleRegisteredGatewayResult = GenericResult<Page<RegisteredGatewayValue>>;
+
+#[derive(Clone, Debug, Default, CandidType, Serialize, Deserialize, PartialEq, Eq)]
+pub struct GatewayLedgerIndex {
+    pub gateway_principal_id: GatewayPrincipalId,
+}
+
+impl Ord for GatewayLedgerIndex {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.gateway_principal_id.cmp(&other.gateway_principal_id)
+    }
+}
+
+impl PartialOrd for GatewayLedgerIndex {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Storable for GatewayLedgerIndex {
+    const BOUND: Bound = Bound::Unbounded;
+
     fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
         Cow::Owned(Encode!(self).unwrap())
     }
@@ -133,10 +242,205 @@ impl Storable for RegisteredGatewayValue {
     }
 }
 
-impl BoundedStorable for RegisteredGatewayValue {
-    const MAX_SIZE: u32 = MAX_STABLE_BTREE_MAP_SIZE;
-    const IS_FIXED_SIZE: bool = false;
+/// Keeps track of the requests served by a gateway, as reported through `reportSignedRequests`
+#[derive(Clone, Debug, Default, CandidType, Serialize, Deserialize)]
+pub struct GatewayLedgerValue {
+    /// total number of requests served by the gateway
+    pub served_requests: u64,
+    /// number of served requests that have already been paid to the gateway operator
+    pub paid_requests: u64,
+    /// timestamp (in nanoseconds) of the last reported request
+    pub last_served_at: u64,
+    /// payout whose transfer has been started but not recorded yet
+    pub pending_payout: Option<PendingGatewayPayout>,
 }
 
-pub type RegisteredGatewayResult = GenericResult<RegisteredGatewayValue>;
-pub type MultipleRegisteredGatewayResult = GenericResult<Vec<RegisteredGatewayValue>>;
+impl GatewayLedgerValue {
+    pub fn get_unpaid_requests(&self) -> u64 {
+        self.served_requests - self.paid_requests
+    }
+}
+
+impl Storable for GatewayLedgerValue {
+    const BOUND: Bound = Bound::Unbounded;
+
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        encode_versioned(self)
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_versioned(&bytes)
+    }
+}
+
+impl Versioned for GatewayLedgerValue {}
+
+/// Payout reserved in the gateway ledger before its transfer, so that the same requests are not paid twice.
+///
+/// The memo and the creation time of the transfer are stored with the payout,
+/// so that the ledger rejects a retry of a transfer that has already been executed as a duplicate
+#[derive(Clone, Debug, Default, CandidType, Serialize, Deserialize, PartialEq, Eq)]
+pub struct PendingGatewayPayout {
+    /// number of served requests covered by the payout
+    pub paid_requests: u64,
+    /// amount to transfer to the gateway operator, in e8s
+    pub amount_e8s: u64,
+    /// memo of the transfer, which is the number of requests paid before the payout
+    pub memo: u64,
+    /// creation time (in nanoseconds) of the transfer
+    pub created_at_time: u64,
+}
+
+/// Outcome of the transfer of a pending payout, checked on the ledger by the controllers
+/// when the transfer is too old to be retried
+#[derive(Clone, Debug, CandidType, Serialize, Deserialize, PartialEq, Eq)]
+pub enum PendingGatewayPayoutResolution {
+    /// the transfer has been executed in the ledger block with the given index
+    Transferred(u64),
+    /// the transfer has not been executed, so the requests can be paid by the next payouts
+    NotTransferred,
+}
+
+#[derive(Clone, Debug, Default, CandidType, Serialize, Deserialize, PartialEq, Eq)]
+pub struct GatewayPayoutIndex {
+    pub gateway_principal_id: GatewayPrincipalId,
+    /// index of the ledger block that contains the payout transfer
+    pub block_index: u64,
+}
+
+impl Ord for GatewayPayoutIndex {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.gateway_principal_id
+            .cmp(&other.gateway_principal_id)
+            .then(self.block_index.cmp(&other.block_index))
+    }
+}
+
+impl PartialOrd for GatewayPayoutIndex {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Storable for GatewayPayoutIndex {
+    const BOUND: Bound = Bound::Unbounded;
+
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+#[derive(Clone, Debug, Default, CandidType, Serialize, Deserialize)]
+pub struct GatewayPayoutValue {
+    /// number of served requests covered by the payout
+    pub paid_requests: u64,
+    /// amount transferred to the gateway operator, in e8s
+    pub amount_e8s: u64,
+    /// timestamp (in nanoseconds) of the payout
+    pub timestamp: u64,
+}
+
+impl Storable for GatewayPayoutValue {
+    const BOUND: Bound = Bound::Unbounded;
+
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        encode_versioned(self)
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_versioned(&bytes)
+    }
+}
+
+impl Versioned for GatewayPayoutValue {}
+
+pub type GatewayPayoutsResult = GenericResult<Page<(GatewayPayoutIndex, GatewayPayoutValue)>>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::versioning::open_envelope;
+
+    /// [RegisteredGatewayValue] as stored before the versioned envelopes were introduced
+    fn get_legacy_registered_gateway_bytes() -> Vec<u8> {
+        #[derive(CandidType)]
+        struct LegacyRegisteredGatewayValue {
+            gateway_name: String,
+            gateway_ip: Ip,
+            gateway_url: GatewayUrl,
+            proxied_gateway_uid: Option<ProxiedGatewayUID>,
+            env_uid: EnvironmentUID,
+            gat_registered_device_uids: BTreeMap<DeviceUid, ()>,
+        }
+
+        Encode!(&LegacyRegisteredGatewayValue {
+            gateway_name: String::from("gateway"),
+            gateway_ip: String::from("127.0.0.1"),
+            gateway_url: String::from("https://gateway.example.com"),
+            proxied_gateway_uid: Some(String::from("peer")),
+            env_uid: String::from("env"),
+            gat_registered_device_uids: BTreeMap::from([(String::from("device"), ())]),
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_decode_legacy_registered_gateway_value() {
+        let registered_gateway_value =
+            RegisteredGatewayValue::from_bytes(Cow::Owned(get_legacy_registered_gateway_bytes()));
+
+        assert_eq!(registered_gateway_value.gateway_name, "gateway");
+        assert_eq!(registered_gateway_value.gateway_ip, "127.0.0.1");
+        assert_eq!(
+            registered_gateway_value.gateway_url,
+            "https://gateway.example.com"
+        );
+        assert_eq!(
+            registered_gateway_value.proxied_gateway_uid,
+            Some(String::from("peer"))
+        );
+        assert_eq!(registered_gateway_value.env_uid, "env");
+    }
+
+    #[test]
+    fn test_migrate_legacy_registered_gateway_value() {
+        let registered_gateway_value =
+            RegisteredGatewayValue::from_bytes(Cow::Owned(get_legacy_registered_gateway_bytes()));
+
+        // rewriting the record stores it with the current version
+        let bytes = registered_gateway_value.to_bytes();
+        assert_eq!(open_envelope(&bytes).0, RegisteredGatewayValue::VERSION);
+        assert_eq!(
+            RegisteredGatewayValue::from_bytes(bytes).env_uid,
+            registered_gateway_value.env_uid
+        );
+    }
+
+    #[test]
+    fn test_decode_unversioned_gateway_ledger_value() {
+        /// [GatewayLedgerValue] as stored before the pending payouts were introduced
+        #[derive(CandidType)]
+        struct LegacyGatewayLedgerValue {
+            served_requests: u64,
+            paid_requests: u64,
+            last_served_at: u64,
+        }
+
+        let bytes = Encode!(&LegacyGatewayLedgerValue {
+            served_requests: 10,
+            paid_requests: 5,
+            last_served_at: 1,
+        })
+        .unwrap();
+
+        let decoded_gateway_ledger_value = GatewayLedgerValue::from_bytes(Cow::Owned(bytes));
+        assert_eq!(decoded_gateway_ledger_value.served_requests, 10);
+        assert_eq!(decoded_gateway_ledger_value.paid_requests, 5);
+        assert_eq!(decoded_gateway_ledger_value.last_served_at, 1);
+        assert_eq!(decoded_gateway_ledger_value.pending_payout, None);
+    }
+}
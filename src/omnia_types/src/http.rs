@@ -1,10 +1,13 @@
 use std::{borrow::Cow, cmp::Ordering};
 
-use candid::{CandidType, Decode, Encode};
-use ic_stable_structures::{BoundedStorable, Storable};
+use candid::{CandidType, Decode, Encode, Func};
+use ic_stable_structures::{storable::Bound, Storable};
 use serde::{Deserialize, Serialize};
 
-use crate::{errors::GenericResult, MAX_STABLE_BTREE_MAP_SIZE};
+use crate::{
+    errors::GenericResult,
+    versioning::{decode_versioned, encode_versioned, Versioned},
+};
 
 pub const CONTENT_TYPE_HEADER_KEY: &str = "content-type";
 
@@ -12,6 +15,27 @@ pub const ACCESS_CONTROL_ALLOW_ORIGIN_HEADER_KEY: &str = "Access-Control-Allow-O
 
 pub const CONNECTION_HEADER_KEY: &str = "Connection";
 
+pub const ACCESS_CONTROL_EXPOSE_HEADERS_HEADER_KEY: &str = "Access-Control-Expose-Headers";
+
+pub const ACCESS_CONTROL_ALLOW_METHODS_HEADER_KEY: &str = "Access-Control-Allow-Methods";
+
+pub const ACCESS_CONTROL_ALLOW_HEADERS_HEADER_KEY: &str = "Access-Control-Allow-Headers";
+
+pub const ACCESS_CONTROL_MAX_AGE_HEADER_KEY: &str = "Access-Control-Max-Age";
+
+pub const ALLOW_HEADER_KEY: &str = "Allow";
+
+pub const ACCEPT_HEADER_KEY: &str = "Accept";
+
+/// Carries the token to get the next page of the solutions of a SPARQL query, which can be passed in the `continuation` parameter of the URL
+pub const SPARQL_CONTINUATION_HEADER_KEY: &str = "X-Sparql-Continuation";
+
+/// Carries the certificate and the witness of a certified response, verified by the HTTP gateways
+pub const IC_CERTIFICATE_HEADER_KEY: &str = "IC-Certificate";
+
+/// Carries the expression that tells the HTTP gateways which parts of the request and of the response are certified
+pub const IC_CERTIFICATE_EXPRESSION_HEADER_KEY: &str = "IC-CertificateExpression";
+
 pub type HttpHeader = (String, String);
 pub type IpChallengeNonce = String;
 pub type Ip = String;
@@ -36,10 +60,39 @@ pub struct HttpResponse {
     pub status_code: u16,
     pub headers: Vec<HttpHeader>,
     pub body: Vec<u8>,
-    pub streaming_strategy: Option<String>,
+    pub streaming_strategy: Option<StreamingStrategy>,
     pub upgrade: Option<bool>,
 }
 
+/// Lets the boundary node download the rest of the body by calling the callback query method with the token
+#[derive(CandidType, Deserialize, Debug, PartialEq, Clone)]
+pub enum StreamingStrategy {
+    Callback {
+        callback: Func,
+        token: StreamingCallbackToken,
+    },
+}
+
+/// Points to the next chunk of a body that didn't fit in a single response, whose chunks are kept by the canister
+#[derive(CandidType, Deserialize, Debug, PartialEq, Eq, Clone)]
+pub struct StreamingCallbackToken {
+    /// id of the chunks of the body kept by the canister
+    pub results_id: u64,
+    /// index of the next chunk of the body
+    pub chunk: u64,
+    /// timestamp (in nanoseconds) after which the token cannot be used anymore, when the chunks are not kept anymore
+    pub expires_at: u64,
+    /// signature of the other fields by the canister, so that tokens cannot be forged
+    pub signature: Vec<u8>,
+}
+
+#[derive(CandidType, Deserialize, Debug, PartialEq, Clone)]
+pub struct StreamingCallbackHttpResponse {
+    pub body: Vec<u8>,
+    /// `None` if this is the last chunk of the body
+    pub token: Option<StreamingCallbackToken>,
+}
+
 #[derive(Clone, Default, CandidType, Serialize, Deserialize, Debug)]
 pub struct IpChallengeValue {
     pub requester_ip: Ip,
@@ -52,19 +105,18 @@ pub struct IpChallengeValue {
 }
 
 impl Storable for IpChallengeValue {
+    const BOUND: Bound = Bound::Unbounded;
+
     fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
-        Cow::Owned(Encode!(self).unwrap())
+        encode_versioned(self)
     }
 
     fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
-        Decode!(bytes.as_ref(), Self).unwrap()
+        decode_versioned(&bytes)
     }
 }
 
-impl BoundedStorable for IpChallengeValue {
-    const MAX_SIZE: u32 = MAX_STABLE_BTREE_MAP_SIZE;
-    const IS_FIXED_SIZE: bool = false;
-}
+impl Versioned for IpChallengeValue {}
 
 #[derive(Clone, Debug, Default, CandidType, Serialize, Deserialize, PartialEq, Eq)]
 pub struct IpChallengeIndex {
@@ -84,6 +136,8 @@ impl PartialOrd for IpChallengeIndex {
 }
 
 impl Storable for IpChallengeIndex {
+    const BOUND: Bound = Bound::Unbounded;
+
     fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
         Cow::Owned(Encode!(self).unwrap())
     }
@@ -93,9 +147,4 @@ impl Storable for IpChallengeIndex {
     }
 }
 
-impl BoundedStorable for IpChallengeIndex {
-    const MAX_SIZE: u32 = MAX_STABLE_BTREE_MAP_SIZE;
-    const IS_FIXED_SIZE: bool = false;
-}
-
 pub type IpChallengeValueResult = GenericResult<IpChallengeValue>;
@@ -0,0 +1,279 @@
+use ic_stable_structures::{storable::Bound as StorableBound, StableBTreeMap, Storable};
+use std::borrow::Cow;
+use std::fmt::Debug;
+use std::ops::Bound;
+
+use crate::Memory;
+
+/// Secondary indexes of a [CrudMap](crate::CrudMap), kept in sync with the primary map on every write
+pub trait Indexes<I, V> {
+    fn insert(&mut self, index: &I, value: &V);
+
+    fn remove(&mut self, index: &I, value: &V);
+}
+
+/// Maps without secondary indexes
+impl<I, V> Indexes<I, V> for () {
+    fn insert(&mut self, _index: &I, _value: &V) {}
+
+    fn remove(&mut self, _index: &I, _value: &V) {}
+}
+
+/// Entry of a secondary index, ordered by key first so that the entries with the same key are contiguous.
+/// The entry without index is the first one of its key and is only used to start the scans
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct IndexEntry<K, I> {
+    key: K,
+    index: Option<I>,
+}
+
+impl<K: Storable, I: Storable> Storable for IndexEntry<K, I> {
+    const BOUND: StorableBound = StorableBound::Unbounded;
+
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let key_bytes = self.key.to_bytes();
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&(key_bytes.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(&key_bytes);
+        if let Some(index) = &self.index {
+            bytes.push(1);
+            bytes.extend_from_slice(&index.to_bytes());
+        }
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        let key_len = u32::from_le_bytes(bytes[..4].try_into().unwrap()) as usize;
+        let (key_bytes, index_bytes) = bytes[4..].split_at(key_len);
+        Self {
+            key: K::from_bytes(Cow::Borrowed(key_bytes)),
+            index: match index_bytes.split_first() {
+                Some((1, index_bytes)) => Some(I::from_bytes(Cow::Borrowed(index_bytes))),
+                _ => None,
+            },
+        }
+    }
+}
+
+/// Value of the index entries, which carry all their information in the key
+#[derive(Clone, Debug)]
+pub struct IndexEntryValue;
+
+impl Storable for IndexEntryValue {
+    const BOUND: StorableBound = StorableBound::Bounded {
+        max_size: 0,
+        is_fixed_size: false,
+    };
+
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Borrowed(&[])
+    }
+
+    fn from_bytes(_bytes: Cow<[u8]>) -> Self {
+        Self
+    }
+}
+
+/// Index of the entries of a [CrudMap](crate::CrudMap) by a key derived from each entry, stored in its own memory
+pub struct SecondaryIndex<K: Ord + Storable + Clone, I: Ord + Storable + Clone, V> {
+    entries: StableBTreeMap<IndexEntry<K, I>, IndexEntryValue, Memory>,
+    key_of: fn(&I, &V) -> K,
+}
+
+impl<K, I, V> SecondaryIndex<K, I, V>
+where
+    K: Ord + Debug + Storable + Clone,
+    I: Ord + Debug + Storable + Clone,
+{
+    pub fn init(memory: Memory, key_of: fn(&I, &V) -> K) -> Self {
+        Self {
+            entries: StableBTreeMap::init(memory),
+            key_of,
+        }
+    }
+
+    pub fn len(&self) -> u64 {
+        self.entries.len()
+    }
+
+    /// Whether the entry has the key, even if it's not indexed yet
+    pub fn has_key(&self, key: &K, index: &I, value: &V) -> bool {
+        &(self.key_of)(index, value) == key
+    }
+
+    /// Iterates over the indexes of the entries with the key, ordered by index and starting right after the cursor
+    pub fn scan<'a>(&'a self, key: &'a K, cursor: Option<I>) -> impl Iterator<Item = I> + 'a {
+        let start = IndexEntry {
+            key: key.clone(),
+            index: cursor.clone(),
+        };
+        let start = match cursor {
+            Some(_) => Bound::Excluded(start),
+            None => Bound::Included(start),
+        };
+
+        self.entries
+            .range((start, Bound::Unbounded))
+            .take_while(move |(entry, _)| &entry.key == key)
+            .filter_map(|(entry, _)| entry.index)
+    }
+}
+
+impl<K, I, V> Indexes<I, V> for SecondaryIndex<K, I, V>
+where
+    K: Ord + Debug + Storable + Clone,
+    I: Ord + Debug + Storable + Clone,
+{
+    fn insert(&mut self, index: &I, value: &V) {
+        let entry = IndexEntry {
+            key: (self.key_of)(index, value),
+            index: Some(index.clone()),
+        };
+        self.entries.insert(entry, IndexEntryValue);
+    }
+
+    fn remove(&mut self, index: &I, value: &V) {
+        let entry = IndexEntry {
+            key: (self.key_of)(index, value),
+            index: Some(index.clone()),
+        };
+        self.entries.remove(&entry);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::CrudMap;
+    use ic_stable_structures::memory_manager::{MemoryId, MemoryManager};
+    use ic_stable_structures::DefaultMemoryImpl;
+
+    /// indexes the numbers by their last digit
+    fn init_map(
+        memory_manager: &MemoryManager<DefaultMemoryImpl>,
+    ) -> CrudMap<u64, u64, SecondaryIndex<u64, u64, u64>> {
+        CrudMap::with_indexes(
+            memory_manager.get(MemoryId::new(0)),
+            SecondaryIndex::init(memory_manager.get(MemoryId::new(1)), |_, value| value % 10),
+        )
+    }
+
+    #[test]
+    fn test_index_entry_storable() {
+        let entry: IndexEntry<u64, u64> = IndexEntry {
+            key: 1,
+            index: Some(2),
+        };
+        assert_eq!(IndexEntry::from_bytes(entry.to_bytes()), entry);
+
+        let entry: IndexEntry<u64, u64> = IndexEntry {
+            key: 1,
+            index: None,
+        };
+        assert_eq!(IndexEntry::from_bytes(entry.to_bytes()), entry);
+    }
+
+    #[test]
+    fn test_index_follows_writes() {
+        let memory_manager = MemoryManager::init(DefaultMemoryImpl::default());
+        let mut map = init_map(&memory_manager);
+        map.create(1, 11).unwrap();
+        map.create(2, 21).unwrap();
+        map.create(3, 12).unwrap();
+
+        assert_eq!(map.indexes().scan(&1, None).collect::<Vec<_>>(), vec![1, 2]);
+        assert_eq!(map.indexes().scan(&2, None).collect::<Vec<_>>(), vec![3]);
+
+        map.update(2, 22).unwrap();
+        map.delete(&3).unwrap();
+        map.upsert(4, 31);
+
+        assert_eq!(map.indexes().scan(&1, None).collect::<Vec<_>>(), vec![1, 4]);
+        assert_eq!(map.indexes().scan(&2, None).collect::<Vec<_>>(), vec![2]);
+        assert_eq!(map.indexes().len(), map.len());
+
+        assert_eq!(map.compare_and_update(4, &0, 32), Ok(false));
+        assert_eq!(map.indexes().scan(&1, None).collect::<Vec<_>>(), vec![1, 4]);
+        assert_eq!(map.compare_and_update(4, &31, 32), Ok(true));
+        assert_eq!(map.indexes().scan(&1, None).collect::<Vec<_>>(), vec![1]);
+    }
+
+    #[test]
+    fn test_scan_with_cursor() {
+        let memory_manager = MemoryManager::init(DefaultMemoryImpl::default());
+        let mut map = init_map(&memory_manager);
+        for index in 0..30 {
+            map.create(index, index).unwrap();
+        }
+
+        assert_eq!(
+            map.indexes().scan(&3, Some(3)).collect::<Vec<_>>(),
+            vec![13, 23]
+        );
+
+        let (entries, next_cursor) =
+            map.get_indexed_page(map.indexes().scan(&3, None), 2, |_, _| true);
+        assert_eq!(entries, vec![(3, 3), (13, 13)]);
+        assert_eq!(next_cursor, Some(13));
+
+        let (entries, next_cursor) =
+            map.get_indexed_page(map.indexes().scan(&3, next_cursor), 2, |_, _| true);
+        assert_eq!(entries, vec![(23, 23)]);
+        assert_eq!(next_cursor, None);
+    }
+
+    #[test]
+    fn test_secondary_page_scans_the_map_until_indexed() {
+        let memory_manager = MemoryManager::init(DefaultMemoryImpl::default());
+        {
+            let mut map: CrudMap<u64, u64> = CrudMap::default(memory_manager.get(MemoryId::new(0)));
+            for index in 0..30 {
+                map.create(index, index).unwrap();
+            }
+        }
+        let mut map = init_map(&memory_manager);
+
+        // the entries stored before the index was declared are found by scanning the map, in the same order
+        let (entries, next_cursor) =
+            map.get_secondary_page(map.indexes(), &3, None, 2, false, |_, _| true);
+        assert_eq!(entries, vec![(3, 3), (13, 13)]);
+        assert_eq!(next_cursor, Some(13));
+        assert!(map
+            .get_secondary_page(map.indexes(), &3, None, 2, true, |_, _| true)
+            .0
+            .is_empty());
+
+        assert_eq!(map.index_batch(None, 30), None);
+        let (entries, next_cursor) =
+            map.get_secondary_page(map.indexes(), &3, next_cursor, 2, true, |_, _| true);
+        assert_eq!(entries, vec![(23, 23)]);
+        assert_eq!(next_cursor, None);
+    }
+
+    #[test]
+    fn test_index_is_built_in_batches_for_existing_entries() {
+        let memory_manager = MemoryManager::init(DefaultMemoryImpl::default());
+        {
+            let mut map: CrudMap<u64, u64> = CrudMap::default(memory_manager.get(MemoryId::new(0)));
+            map.create(1, 11).unwrap();
+            map.create(2, 12).unwrap();
+            map.create(3, 21).unwrap();
+        }
+
+        // declaring the index doesn't index the existing entries
+        let mut map = init_map(&memory_manager);
+        assert_eq!(map.indexes().len(), 0);
+
+        let next_cursor = map.index_batch(None, 2);
+        assert_eq!(next_cursor, Some(2));
+        assert_eq!(map.indexes().scan(&1, None).collect::<Vec<_>>(), vec![1]);
+        assert_eq!(map.indexes().scan(&2, None).collect::<Vec<_>>(), vec![2]);
+
+        assert_eq!(map.index_batch(next_cursor, 2), None);
+        assert_eq!(map.indexes().scan(&1, None).collect::<Vec<_>>(), vec![1, 3]);
+
+        // indexing the entries again has no effect
+        assert_eq!(map.index_batch(None, 10), None);
+        assert_eq!(map.indexes().len(), map.len());
+    }
+}
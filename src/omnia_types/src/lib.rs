@@ -1,58 +1,99 @@
-use access_key::{AccessKeyIndex, AccessKeyValue, TransactionHash};
-use device::DeviceUid;
-use environment::{
-    EnvironmentIndex, EnvironmentUID, EnvironmentUidIndex, EnvironmentUidValue, EnvironmentValue,
+use access_key::{
+    AccessKeyIndex, AccessKeyIndexes, AccessKeyUsageIndex, AccessKeyUsageValue, AccessKeyValue,
+    TransactionHash,
 };
-use errors::GenericResult;
+use environment::{EnvironmentIndex, EnvironmentUID, EnvironmentUidIndex, EnvironmentValue};
+use errors::{GenericError, GenericResult};
 use gateway::{
-    GatewayPrincipalId, InitializedGatewayIndex, InitializedGatewayValue, RegisteredGatewayIndex,
-    RegisteredGatewayValue,
+    GatewayLedgerIndex, GatewayLedgerValue, InitializedGatewayIndex, InitializedGatewayValue,
+    PendingGatewayPayout, RegisteredGatewayIndex, RegisteredGatewayIndexes, RegisteredGatewayValue,
 };
 use http::{IpChallengeIndex, IpChallengeNonce, IpChallengeValue};
 use ic_stable_structures::StableBTreeMap;
-use ic_stable_structures::{memory_manager::VirtualMemory, BoundedStorable, DefaultMemoryImpl};
+use ic_stable_structures::{memory_manager::VirtualMemory, DefaultMemoryImpl, Storable};
+use index::{Indexes, SecondaryIndex};
+use omnia_core_sdk::access_key::AccessKeyUID;
 use std::fmt::Debug;
+use std::ops::{Bound, RangeBounds};
+use versioning::{open_envelope, Versioned};
 use virtual_persona::{VirtualPersonaIndex, VirtualPersonaPrincipalId, VirtualPersonaValue};
 
 pub mod access_key;
+pub mod certification;
+pub mod consistency;
 pub mod device;
 pub mod environment;
 pub mod errors;
 pub mod gateway;
 pub mod http;
+pub mod index;
+pub mod pagination;
+pub mod snapshot;
+pub mod sparql;
 pub mod updates;
+pub mod versioning;
 pub mod virtual_persona;
-
-pub const MAX_STABLE_BTREE_MAP_SIZE: u32 = 1000;
+pub mod vocabulary;
 
 pub type Memory = VirtualMemory<DefaultMemoryImpl>;
 
-pub struct CrudMap<I: Ord + Debug + BoundedStorable + Clone, V: BoundedStorable + Clone> {
+/// Stable map with optional [secondary indexes](index::Indexes), which are written in the same call as the map,
+/// so that a trap reverts both
+pub struct CrudMap<I: Ord + Debug + Storable + Clone, V: Storable + Clone, X: Indexes<I, V> = ()> {
     map: StableBTreeMap<I, V, Memory>,
+    /// memory of the map, to read the values as stored, see [CrudMap::rewrite_batch]
+    memory: Memory,
+    indexes: X,
 }
 
-impl<I: Ord + Debug + BoundedStorable + Clone, V: BoundedStorable + Clone> CrudMap<I, V> {
+impl<I: Ord + Debug + Storable + Clone, V: Storable + Clone> CrudMap<I, V> {
     pub fn default(memory: Memory) -> Self {
         Self {
-            map: StableBTreeMap::init(memory),
+            map: StableBTreeMap::init(memory.clone()),
+            memory,
+            indexes: (),
         }
     }
 }
 
-impl<I: Ord + Debug + BoundedStorable + Clone, V: BoundedStorable + Clone> CrudMap<I, V> {
+impl<I: Ord + Debug + Storable + Clone, V: Storable + Clone, X: Indexes<I, V>> CrudMap<I, V, X> {
+    /// Declares the secondary indexes of the map. The entries stored before the indexes were declared
+    /// are not indexed until they're indexed in batches, see [CrudMap::index_batch]
+    pub fn with_indexes(memory: Memory, indexes: X) -> Self {
+        Self {
+            map: StableBTreeMap::init(memory.clone()),
+            memory,
+            indexes,
+        }
+    }
+
+    pub fn indexes(&self) -> &X {
+        &self.indexes
+    }
+
+    /// Writes the entry in the map and in the indexes, returning the previous value if any
+    fn write(&mut self, index: I, value: V) -> Option<V> {
+        let previous_value = self.map.get(&index);
+        if let Some(previous_value) = &previous_value {
+            self.indexes.remove(&index, previous_value);
+        }
+        self.indexes.insert(&index, &value);
+        self.map.insert(index, value);
+        previous_value
+    }
+}
+
+impl<I: Ord + Debug + Storable + Clone, V: Storable + Clone, X: Indexes<I, V>> CrudMap<I, V, X> {
     pub fn create(&mut self, index: I, value: V) -> GenericResult<()> {
         match self.map.contains_key(&index) {
             false => {
-                self.map.insert(index, value);
+                self.write(index, value);
                 Ok(())
             }
             true => {
-                let err = format!(
-                    "Entry with index {:?} already exists, use UPDATE method instead",
-                    index
-                );
+                let err = GenericError::already_exists(&index);
 
-                println!("{}", err);
+                println!("{}, use UPDATE method instead", err);
                 Err(err)
             }
         }
@@ -62,7 +103,7 @@ impl<I: Ord + Debug + BoundedStorable + Clone, V: BoundedStorable + Clone> CrudM
         match self.map.get(index) {
             Some(value) => Ok(value),
             None => {
-                let err = format!("Entry with index {:?} does not exist", index);
+                let err = GenericError::not_found(index);
 
                 println!("{}", err);
                 Err(err)
@@ -73,16 +114,12 @@ impl<I: Ord + Debug + BoundedStorable + Clone, V: BoundedStorable + Clone> CrudM
     pub fn update(&mut self, index: I, value: V) -> GenericResult<V> {
         match self.map.contains_key(&index) {
             true => Ok(self
-                .map
-                .insert(index, value)
+                .write(index, value)
                 .expect("should contain previous value")),
             false => {
-                let err = format!(
-                    "Entry with index {:?} does not exist, use CREATE method instead",
-                    index
-                );
+                let err = GenericError::not_found(&index);
 
-                println!("{}", err);
+                println!("{}, use CREATE method instead", err);
                 Err(err)
             }
         }
@@ -90,9 +127,12 @@ impl<I: Ord + Debug + BoundedStorable + Clone, V: BoundedStorable + Clone> CrudM
 
     pub fn delete(&mut self, index: &I) -> GenericResult<V> {
         match self.map.remove(index) {
-            Some(deleted_value) => Ok(deleted_value),
+            Some(deleted_value) => {
+                self.indexes.remove(index, &deleted_value);
+                Ok(deleted_value)
+            }
             None => {
-                let err = format!("Entry with index {:?} does not exist", index);
+                let err = GenericError::not_found(index);
 
                 println!("{}", err);
                 Err(err)
@@ -101,13 +141,219 @@ impl<I: Ord + Debug + BoundedStorable + Clone, V: BoundedStorable + Clone> CrudM
     }
 }
 
+impl<I: Ord + Debug + Storable + Clone, V: Storable + Clone, X: Indexes<I, V>> CrudMap<I, V, X> {
+    pub fn len(&self) -> u64 {
+        self.map.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    pub fn contains(&self, index: &I) -> bool {
+        self.map.contains_key(index)
+    }
+
+    /// Iterates over all the entries, ordered by index
+    pub fn iter(&self) -> impl Iterator<Item = (I, V)> + '_ {
+        self.map.iter()
+    }
+
+    /// Iterates over the entries whose index is in the range, ordered by index
+    pub fn range(&self, range: impl RangeBounds<I>) -> impl Iterator<Item = (I, V)> + '_ {
+        self.map.range(range)
+    }
+
+    /// Iterates over the entries whose index has the prefix checked by `has_prefix`, ordered by index.
+    /// `first` must not be greater than any index with the prefix (e.g. the prefix followed by empty fields),
+    /// the scan stops at the first index without the prefix
+    pub fn prefix<'a>(
+        &'a self,
+        first: I,
+        has_prefix: impl Fn(&I) -> bool + 'a,
+    ) -> impl Iterator<Item = (I, V)> + 'a {
+        self.map
+            .range(first..)
+            .take_while(move |(index, _)| has_prefix(index))
+    }
+
+    /// Creates the entry or replaces its value, returning the previous value if any
+    pub fn upsert(&mut self, index: I, value: V) -> Option<V> {
+        self.write(index, value)
+    }
+
+    /// Replaces the value only if the current one is equal to `expected`.
+    /// Returns whether the value has been replaced
+    pub fn compare_and_update(&mut self, index: I, expected: &V, value: V) -> GenericResult<bool>
+    where
+        V: PartialEq,
+    {
+        let current_value = self.read(&index)?;
+        if &current_value != expected {
+            return Ok(false);
+        }
+        self.write(index, value);
+        Ok(true)
+    }
+}
+
+impl<I: Ord + Debug + Storable + Clone, V: Storable + Clone, X: Indexes<I, V>> CrudMap<I, V, X> {
+    /// Scans the entries in the range, starting right after the cursor, and returns up to `limit` of them that satisfy the filter.
+    /// If more entries may follow, the index of the last returned one is returned as well, to be used as the next cursor
+    pub fn get_page(
+        &self,
+        range: (Bound<I>, Bound<I>),
+        cursor: Option<I>,
+        limit: usize,
+        filter: impl Fn(&I, &V) -> bool,
+    ) -> (Vec<(I, V)>, Option<I>) {
+        let start = match cursor {
+            Some(cursor) => Bound::Excluded(cursor),
+            None => range.0,
+        };
+
+        Self::collect_page(
+            self.range((start, range.1))
+                .filter(|(index, value)| filter(index, value)),
+            limit,
+        )
+    }
+
+    /// Adds up to `limit` entries after the cursor to the indexes, which can be done again with no effect.
+    /// If more entries may follow, the index of the last indexed one is returned, to be used as the next cursor
+    pub fn index_batch(&mut self, cursor: Option<I>, limit: usize) -> Option<I> {
+        let (entries, next_cursor) = self.get_page(
+            (Bound::Unbounded, Bound::Unbounded),
+            cursor,
+            limit,
+            |_, _| true,
+        );
+        for (index, value) in entries {
+            self.indexes.insert(&index, &value);
+        }
+        next_cursor
+    }
+
+    /// Same as [CrudMap::get_page], but scans only the entries whose index has the prefix, see [CrudMap::prefix]
+    pub fn get_prefix_page(
+        &self,
+        first: I,
+        has_prefix: impl Fn(&I) -> bool,
+        cursor: Option<I>,
+        limit: usize,
+    ) -> (Vec<(I, V)>, Option<I>) {
+        let start = match cursor {
+            Some(cursor) => Bound::Excluded(cursor),
+            None => Bound::Included(first),
+        };
+
+        Self::collect_page(
+            self.range((start, Bound::Unbounded))
+                .take_while(|(index, _)| has_prefix(index)),
+            limit,
+        )
+    }
+
+    /// Same as [CrudMap::get_page], but scans the entries whose index is returned by a secondary index,
+    /// see [SecondaryIndex::scan](index::SecondaryIndex::scan)
+    pub fn get_indexed_page(
+        &self,
+        indexes: impl Iterator<Item = I>,
+        limit: usize,
+        filter: impl Fn(&I, &V) -> bool,
+    ) -> (Vec<(I, V)>, Option<I>) {
+        Self::collect_page(
+            indexes
+                .filter_map(|index| self.map.get(&index).map(|value| (index, value)))
+                .filter(|(index, value)| filter(index, value)),
+            limit,
+        )
+    }
+
+    /// Same as [CrudMap::get_indexed_page] on the entries with the key of the secondary index.
+    /// If the secondary index may still miss the entries stored before it was declared (`is_indexed` is false),
+    /// the whole map is scanned instead, in the same order and with the same cursors
+    pub fn get_secondary_page<K: Ord + Debug + Storable + Clone>(
+        &self,
+        secondary_index: &SecondaryIndex<K, I, V>,
+        key: &K,
+        cursor: Option<I>,
+        limit: usize,
+        is_indexed: bool,
+        filter: impl Fn(&I, &V) -> bool,
+    ) -> (Vec<(I, V)>, Option<I>) {
+        match is_indexed {
+            true => self.get_indexed_page(secondary_index.scan(key, cursor), limit, filter),
+            false => self.get_page(
+                (Bound::Unbounded, Bound::Unbounded),
+                cursor,
+                limit,
+                |index, value| secondary_index.has_key(key, index, value) && filter(index, value),
+            ),
+        }
+    }
+
+    fn collect_page(
+        entries: impl Iterator<Item = (I, V)>,
+        limit: usize,
+    ) -> (Vec<(I, V)>, Option<I>) {
+        // one more entry is read to know if there is a next page
+        let mut entries: Vec<(I, V)> = entries.take(limit + 1).collect();
+
+        let next_cursor = match entries.len() > limit {
+            true => {
+                entries.truncate(limit);
+                entries.last().map(|(index, _)| index.clone())
+            }
+            false => None,
+        };
+
+        (entries, next_cursor)
+    }
+}
+
+impl<I: Ord + Debug + Storable + Clone, V: Versioned + Storable + Clone, X: Indexes<I, V>>
+    CrudMap<I, V, X>
+{
+    /// Scans up to `limit` entries after the cursor and writes again the ones stored with a previous version of their schema,
+    /// so that they're stored with the current version. If more entries may follow, the index of the last scanned one
+    /// is returned, to be used as the next cursor
+    pub fn rewrite_batch(&mut self, cursor: Option<I>, limit: usize) -> Option<I> {
+        // the values are read as stored, to get the version of their schema from their envelope
+        let stored_map: CrudMap<I, Vec<u8>> = CrudMap::default(self.memory.clone());
+        let (stored_entries, next_cursor) = stored_map.get_page(
+            (Bound::Unbounded, Bound::Unbounded),
+            cursor,
+            limit,
+            |_, _| true,
+        );
+        let outdated_indexes: Vec<I> = stored_entries
+            .into_iter()
+            .filter(|(_, bytes)| open_envelope(bytes).0 < V::VERSION)
+            .map(|(index, _)| index)
+            .collect();
+
+        // the values don't change, so the indexes don't have to be updated
+        for index in outdated_indexes {
+            let value = self.map.get(&index).expect("stored entry should exist");
+            self.map.insert(index, value);
+        }
+        next_cursor
+    }
+}
+
 impl CrudMap<IpChallengeIndex, IpChallengeValue> {
     pub fn validate_ip_challenge_by_nonce(
         &mut self,
         nonce: IpChallengeNonce,
     ) -> GenericResult<IpChallengeValue> {
-        let ip_challenge_index = IpChallengeIndex { nonce };
-        let ip_challenge_value = self.delete(&ip_challenge_index)?;
+        let ip_challenge_index = IpChallengeIndex {
+            nonce: nonce.clone(),
+        };
+        // challenges are deleted once validated, so a missing challenge has expired or was already used
+        let ip_challenge_value = self
+            .delete(&ip_challenge_index)
+            .map_err(|_| GenericError::ChallengeExpired { nonce })?;
         Ok(ip_challenge_value)
     }
 }
@@ -122,29 +368,30 @@ impl CrudMap<InitializedGatewayIndex, InitializedGatewayValue> {
     }
 }
 
-impl CrudMap<EnvironmentUidIndex, EnvironmentUidValue> {
+impl CrudMap<RegisteredGatewayIndex, RegisteredGatewayValue, RegisteredGatewayIndexes> {
+    /// Returns the environment of the gateways registered with the IP.
+    /// The gateways are scanned if the index may still miss the ones registered before it was declared (`is_indexed` is false)
     pub fn get_environment_uid_by_ip(
         &self,
         environment_uid_index: EnvironmentUidIndex,
+        is_indexed: bool,
     ) -> GenericResult<EnvironmentUID> {
-        let environment_uid_value = self.read(&environment_uid_index)?;
-        Ok(environment_uid_value.env_uid)
+        self.get_secondary_page(
+            &self.indexes.by_ip,
+            &environment_uid_index,
+            None,
+            1,
+            is_indexed,
+            |_, _| true,
+        )
+        .0
+        .pop()
+        .map(|(_, registered_gateway_value)| registered_gateway_value.env_uid)
+        .ok_or_else(|| GenericError::not_found(&environment_uid_index))
     }
 }
 
 impl CrudMap<EnvironmentIndex, EnvironmentValue> {
-    pub fn insert_gateway_principal_id_in_env(
-        &mut self,
-        environment_index: EnvironmentIndex,
-        gateway_principal_id: GatewayPrincipalId,
-    ) -> GenericResult<EnvironmentValue> {
-        let mut updatable_environment_value = self.read(&environment_index)?;
-        updatable_environment_value
-            .env_gateways_principals_ids
-            .insert(gateway_principal_id, ());
-        self.update(environment_index, updatable_environment_value)
-    }
-
     pub fn insert_user_principal_id_in_env(
         &mut self,
         environment_index: EnvironmentIndex,
@@ -212,26 +459,583 @@ impl CrudMap<VirtualPersonaIndex, VirtualPersonaValue> {
     }
 }
 
-impl CrudMap<RegisteredGatewayIndex, RegisteredGatewayValue> {
-    pub fn insert_device_uid_in_gateway(
+impl CrudMap<AccessKeyIndex, AccessKeyValue, AccessKeyIndexes> {
+    pub fn transaction_hash_exists(&self, transaction_hash: TransactionHash) -> bool {
+        self.iter()
+            .any(|(_, value)| value.transaction_hash == transaction_hash)
+    }
+
+    pub fn get_access_keys(&self) -> Vec<(AccessKeyIndex, AccessKeyValue)> {
+        self.iter().collect()
+    }
+}
+
+impl CrudMap<AccessKeyUsageIndex, AccessKeyUsageValue> {
+    pub fn get_access_key_usages(
+        &self,
+        access_key_uid: &AccessKeyUID,
+    ) -> Vec<(AccessKeyUsageIndex, AccessKeyUsageValue)> {
+        self.prefix(
+            AccessKeyUsageIndex {
+                access_key_uid: access_key_uid.clone(),
+                ..Default::default()
+            },
+            |index| &index.access_key_uid == access_key_uid,
+        )
+        .collect()
+    }
+}
+
+impl CrudMap<GatewayLedgerIndex, GatewayLedgerValue> {
+    /// Adds the served requests to the gateway ledger, creating the ledger if it doesn't exist yet
+    pub fn insert_served_requests_in_ledger(
+        &mut self,
+        gateway_ledger_index: GatewayLedgerIndex,
+        served_requests: u64,
+        timestamp: u64,
+    ) -> GenericResult<GatewayLedgerValue> {
+        let mut updatable_gateway_ledger_value =
+            self.map.get(&gateway_ledger_index).unwrap_or_default();
+        updatable_gateway_ledger_value.served_requests += served_requests;
+        updatable_gateway_ledger_value.last_served_at = timestamp;
+        self.upsert(gateway_ledger_index, updatable_gateway_ledger_value.clone());
+
+        Ok(updatable_gateway_ledger_value)
+    }
+
+    /// Reserves the requests of the payout in the gateway ledger, before the payout is transferred
+    pub fn insert_pending_payout_in_ledger(
+        &mut self,
+        gateway_ledger_index: GatewayLedgerIndex,
+        pending_payout: PendingGatewayPayout,
+    ) -> GenericResult<GatewayLedgerValue> {
+        let mut updatable_gateway_ledger_value = self.read(&gateway_ledger_index)?;
+        if let Some(existing_payout) = &updatable_gateway_ledger_value.pending_payout {
+            let err = GenericError::invalid_argument(format!(
+                "Gateway with index {:?} already has the pending payout {:?}",
+                gateway_ledger_index, existing_payout
+            ));
+
+            println!("{}", err);
+            return Err(err);
+        }
+        if pending_payout.paid_requests > updatable_gateway_ledger_value.get_unpaid_requests() {
+            let err = GenericError::invalid_argument(format!(
+                "Cannot pay {} requests, gateway with index {:?} has only {} unpaid requests",
+                pending_payout.paid_requests,
+                gateway_ledger_index,
+                updatable_gateway_ledger_value.get_unpaid_requests()
+            ));
+
+            println!("{}", err);
+            return Err(err);
+        }
+        updatable_gateway_ledger_value.pending_payout = Some(pending_payout);
+        self.update(gateway_ledger_index, updatable_gateway_ledger_value.clone())?;
+
+        Ok(updatable_gateway_ledger_value)
+    }
+
+    /// Marks the requests of the pending payout as paid, once the payout has been transferred
+    pub fn insert_paid_requests_in_ledger(
         &mut self,
-        registered_gateway_index: RegisteredGatewayIndex,
-        device_uid: DeviceUid,
-    ) -> GenericResult<RegisteredGatewayValue> {
-        let mut updatable_registered_gateway_value = self.read(&registered_gateway_index)?;
-        updatable_registered_gateway_value
-            .gat_registered_device_uids
-            .insert(device_uid, ());
-        self.update(registered_gateway_index, updatable_registered_gateway_value)
+        gateway_ledger_index: GatewayLedgerIndex,
+        paid_requests: u64,
+    ) -> GenericResult<GatewayLedgerValue> {
+        let mut updatable_gateway_ledger_value = self.read(&gateway_ledger_index)?;
+        match &updatable_gateway_ledger_value.pending_payout {
+            Some(pending_payout) if pending_payout.paid_requests == paid_requests => (),
+            pending_payout => {
+                let err = GenericError::invalid_argument(format!(
+                    "Cannot pay {} requests, gateway with index {:?} has the pending payout {:?}",
+                    paid_requests, gateway_ledger_index, pending_payout
+                ));
+
+                println!("{}", err);
+                return Err(err);
+            }
+        }
+        updatable_gateway_ledger_value.paid_requests += paid_requests;
+        updatable_gateway_ledger_value.pending_payout = None;
+        self.update(gateway_ledger_index, updatable_gateway_ledger_value.clone())?;
+
+        Ok(updatable_gateway_ledger_value)
+    }
+
+    /// Releases the requests of the pending payout, once the ledger has rejected its transfer
+    pub fn remove_pending_payout_from_ledger(
+        &mut self,
+        gateway_ledger_index: GatewayLedgerIndex,
+        pending_payout: &PendingGatewayPayout,
+    ) -> GenericResult<GatewayLedgerValue> {
+        let mut updatable_gateway_ledger_value = self.read(&gateway_ledger_index)?;
+        if updatable_gateway_ledger_value.pending_payout.as_ref() != Some(pending_payout) {
+            let err = GenericError::invalid_argument(format!(
+                "Gateway with index {:?} doesn't have the pending payout {:?}",
+                gateway_ledger_index, pending_payout
+            ));
+
+            println!("{}", err);
+            return Err(err);
+        }
+        updatable_gateway_ledger_value.pending_payout = None;
+        self.update(gateway_ledger_index, updatable_gateway_ledger_value.clone())?;
+
+        Ok(updatable_gateway_ledger_value)
     }
 }
 
-impl CrudMap<AccessKeyIndex, AccessKeyValue> {
-    pub fn transaction_hash_exists(&self, transaction_hash: TransactionHash) -> bool {
-        self.map
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candid::{CandidType, Decode, Deserialize, Encode};
+    use ic_stable_structures::{
+        memory_manager::{MemoryId, MemoryManager},
+        storable::Bound as StorableBound,
+    };
+    use std::borrow::Cow;
+    use versioning::{decode_versioned, encode_versioned};
+
+    fn init_map<I: Ord + Debug + Storable + Clone, V: Storable + Clone>() -> CrudMap<I, V> {
+        let memory_manager = MemoryManager::init(DefaultMemoryImpl::default());
+        CrudMap::default(memory_manager.get(MemoryId::new(0)))
+    }
+
+    fn init_filled_map() -> CrudMap<u64, u64> {
+        let mut map = init_map();
+        for index in 0..10 {
+            map.create(index, index * 10).unwrap();
+        }
+        map
+    }
+
+    fn access_key_usage_index(access_key_uid: &str, timestamp: u64) -> AccessKeyUsageIndex {
+        AccessKeyUsageIndex {
+            access_key_uid: String::from(access_key_uid),
+            timestamp,
+            nonce: 0,
+        }
+    }
+
+    #[test]
+    fn test_len() {
+        let mut map = init_map::<u64, u64>();
+        assert!(map.is_empty());
+
+        map.create(1, 10).unwrap();
+        map.create(2, 20).unwrap();
+        map.delete(&1).unwrap();
+
+        assert_eq!(map.len(), 1);
+        assert!(!map.is_empty());
+        assert!(map.contains(&2));
+        assert!(!map.contains(&1));
+    }
+
+    #[test]
+    fn test_iter() {
+        let mut map = init_map::<u64, u64>();
+        for index in [3, 1, 2] {
+            map.create(index, index * 10).unwrap();
+        }
+
+        assert_eq!(
+            map.iter().collect::<Vec<_>>(),
+            vec![(1, 10), (2, 20), (3, 30)]
+        );
+    }
+
+    #[test]
+    fn test_range() {
+        let map = init_filled_map();
+
+        assert_eq!(
+            map.range(3..6).map(|(index, _)| index).collect::<Vec<_>>(),
+            vec![3, 4, 5]
+        );
+        assert_eq!(
+            map.range(8..).map(|(index, _)| index).collect::<Vec<_>>(),
+            vec![8, 9]
+        );
+        assert_eq!(
+            map.range((Bound::Excluded(7), Bound::Included(9)))
+                .map(|(index, _)| index)
+                .collect::<Vec<_>>(),
+            vec![8, 9]
+        );
+        assert_eq!(map.range(20..).count(), 0);
+    }
+
+    #[test]
+    fn test_prefix() {
+        let mut map = init_map::<AccessKeyUsageIndex, AccessKeyUsageValue>();
+        for (access_key_uid, timestamp) in [("a", 1), ("b", 2), ("b", 1), ("c", 1)] {
+            map.create(
+                access_key_usage_index(access_key_uid, timestamp),
+                AccessKeyUsageValue::default(),
+            )
+            .unwrap();
+        }
+
+        let indexes: Vec<AccessKeyUsageIndex> = map
+            .prefix(access_key_usage_index("b", 0), |index| {
+                index.access_key_uid == "b"
+            })
+            .map(|(index, _)| index)
+            .collect();
+        assert_eq!(
+            indexes,
+            vec![
+                access_key_usage_index("b", 1),
+                access_key_usage_index("b", 2)
+            ]
+        );
+
+        assert_eq!(map.get_access_key_usages(&String::from("c")).len(), 1);
+        assert!(map.get_access_key_usages(&String::from("d")).is_empty());
+    }
+
+    #[test]
+    fn test_upsert() {
+        let mut map = init_map::<u64, u64>();
+
+        assert_eq!(map.upsert(1, 10), None);
+        assert_eq!(map.upsert(1, 11), Some(10));
+        assert_eq!(map.read(&1), Ok(11));
+        assert_eq!(map.len(), 1);
+    }
+
+    #[test]
+    fn test_compare_and_update() {
+        let mut map = init_map::<u64, u64>();
+        map.create(1, 10).unwrap();
+
+        assert_eq!(map.compare_and_update(1, &20, 30), Ok(false));
+        assert_eq!(map.read(&1), Ok(10));
+
+        assert_eq!(map.compare_and_update(1, &10, 30), Ok(true));
+        assert_eq!(map.read(&1), Ok(30));
+
+        assert!(matches!(
+            map.compare_and_update(2, &10, 30),
+            Err(GenericError::NotFound { .. })
+        ));
+        assert!(!map.contains(&2));
+    }
+
+    #[derive(Clone, Debug, CandidType, Deserialize, PartialEq)]
+    struct RecordV0 {
+        name: String,
+    }
+
+    impl Versioned for RecordV0 {}
+
+    impl Storable for RecordV0 {
+        const BOUND: StorableBound = StorableBound::Unbounded;
+
+        fn to_bytes(&self) -> Cow<[u8]> {
+            encode_versioned(self)
+        }
+
+        fn from_bytes(bytes: Cow<[u8]>) -> Self {
+            decode_versioned(&bytes)
+        }
+    }
+
+    #[derive(Clone, Debug, CandidType, Deserialize, PartialEq)]
+    struct Record {
+        name: String,
+        renamed: bool,
+    }
+
+    impl Versioned for Record {
+        const VERSION: u16 = 1;
+
+        fn decode_previous_version(_version: u16, payload: &[u8]) -> Result<Self, String> {
+            let record = Decode!(payload, RecordV0).map_err(|e| e.to_string())?;
+            Ok(Self {
+                name: record.name,
+                renamed: false,
+            })
+        }
+    }
+
+    impl Storable for Record {
+        const BOUND: StorableBound = StorableBound::Unbounded;
+
+        fn to_bytes(&self) -> Cow<[u8]> {
+            encode_versioned(self)
+        }
+
+        fn from_bytes(bytes: Cow<[u8]>) -> Self {
+            decode_versioned(&bytes)
+        }
+    }
+
+    fn get_stored_versions(memory: Memory) -> Vec<u16> {
+        let stored_map: CrudMap<u64, Vec<u8>> = CrudMap::default(memory);
+        stored_map
             .iter()
-            .find(|(_, value)| value.transaction_hash == transaction_hash)
-            .map(|_| true)
-            .unwrap_or(false)
+            .map(|(_, bytes)| open_envelope(&bytes).0)
+            .collect()
+    }
+
+    #[test]
+    fn test_rewrite_batch() {
+        let memory_manager = MemoryManager::init(DefaultMemoryImpl::default());
+        let mut map_v0: CrudMap<u64, RecordV0> =
+            CrudMap::default(memory_manager.get(MemoryId::new(0)));
+        for index in 0..10 {
+            map_v0
+                .create(
+                    index,
+                    RecordV0 {
+                        name: index.to_string(),
+                    },
+                )
+                .unwrap();
+        }
+        drop(map_v0);
+
+        let mut map: CrudMap<u64, Record> = CrudMap::default(memory_manager.get(MemoryId::new(0)));
+        map.update(
+            3,
+            Record {
+                name: String::from("3"),
+                renamed: true,
+            },
+        )
+        .unwrap();
+
+        let next_cursor = map.rewrite_batch(None, 6);
+        assert_eq!(next_cursor, Some(5));
+        assert_eq!(map.rewrite_batch(next_cursor, 6), None);
+        assert_eq!(
+            get_stored_versions(memory_manager.get(MemoryId::new(0))),
+            vec![1; 10]
+        );
+        assert_eq!(
+            map.iter().collect::<Vec<_>>(),
+            (0..10)
+                .map(|index| (
+                    index,
+                    Record {
+                        name: index.to_string(),
+                        renamed: index == 3,
+                    }
+                ))
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_rewrite_batch_skips_current_records() {
+        let memory_manager = MemoryManager::init(DefaultMemoryImpl::default());
+        // stored before the envelopes were introduced, which is still the current version of the schema
+        let legacy_bytes = Encode!(&RecordV0 {
+            name: String::from("legacy"),
+        })
+        .unwrap();
+        let mut stored_map: CrudMap<u64, Vec<u8>> =
+            CrudMap::default(memory_manager.get(MemoryId::new(0)));
+        stored_map.create(0, legacy_bytes.clone()).unwrap();
+        drop(stored_map);
+
+        let mut map: CrudMap<u64, RecordV0> =
+            CrudMap::default(memory_manager.get(MemoryId::new(0)));
+        assert_eq!(map.rewrite_batch(None, 10), None);
+
+        let stored_map: CrudMap<u64, Vec<u8>> =
+            CrudMap::default(memory_manager.get(MemoryId::new(0)));
+        assert_eq!(stored_map.read(&0), Ok(legacy_bytes));
+    }
+
+    #[test]
+    fn test_record_larger_than_legacy_bound() {
+        // the layout of ic-stable-structures 0.5 stored at most 1000 bytes per record, envelope included
+        let mut map = init_map::<u64, RecordV0>();
+        let record = RecordV0 {
+            name: "a".repeat(1000),
+        };
+
+        map.create(0, record.clone()).unwrap();
+        assert_eq!(map.read(&0), Ok(record));
+    }
+
+    #[test]
+    fn test_get_page() {
+        let map = init_filled_map();
+
+        let (entries, next_cursor) =
+            map.get_page((Bound::Unbounded, Bound::Unbounded), None, 4, |index, _| {
+                index % 2 == 0
+            });
+        assert_eq!(entries, vec![(0, 0), (2, 20), (4, 40), (6, 60)]);
+        assert_eq!(next_cursor, Some(6));
+
+        let (entries, next_cursor) = map.get_page(
+            (Bound::Unbounded, Bound::Unbounded),
+            next_cursor,
+            4,
+            |index, _| index % 2 == 0,
+        );
+        assert_eq!(entries, vec![(8, 80)]);
+        assert_eq!(next_cursor, None);
+    }
+
+    #[test]
+    fn test_get_prefix_page() {
+        let mut map = init_map::<AccessKeyUsageIndex, AccessKeyUsageValue>();
+        for (access_key_uid, timestamp) in [("a", 1), ("b", 1), ("b", 2), ("b", 3), ("c", 1)] {
+            map.create(
+                access_key_usage_index(access_key_uid, timestamp),
+                AccessKeyUsageValue::default(),
+            )
+            .unwrap();
+        }
+        let has_prefix = |index: &AccessKeyUsageIndex| index.access_key_uid == "b";
+
+        let (entries, next_cursor) =
+            map.get_prefix_page(access_key_usage_index("b", 0), has_prefix, None, 2);
+        assert_eq!(entries.len(), 2);
+        assert_eq!(next_cursor, Some(access_key_usage_index("b", 2)));
+
+        let (entries, next_cursor) =
+            map.get_prefix_page(access_key_usage_index("b", 0), has_prefix, next_cursor, 2);
+        assert_eq!(
+            entries
+                .into_iter()
+                .map(|(index, _)| index)
+                .collect::<Vec<_>>(),
+            vec![access_key_usage_index("b", 3)]
+        );
+        assert_eq!(next_cursor, None);
+    }
+
+    #[test]
+    fn test_gateway_ledger_payout() {
+        let mut map = init_map::<GatewayLedgerIndex, GatewayLedgerValue>();
+        let gateway_ledger_index = GatewayLedgerIndex {
+            gateway_principal_id: String::from("gateway"),
+        };
+        map.insert_served_requests_in_ledger(gateway_ledger_index.clone(), 10, 1)
+            .unwrap();
+        let pending_payout = PendingGatewayPayout {
+            paid_requests: 10,
+            amount_e8s: 100,
+            memo: 0,
+            created_at_time: 2,
+        };
+
+        // the requests cannot be paid before the payout is reserved
+        assert!(map
+            .insert_paid_requests_in_ledger(gateway_ledger_index.clone(), 10)
+            .is_err());
+        assert!(map
+            .insert_pending_payout_in_ledger(
+                gateway_ledger_index.clone(),
+                PendingGatewayPayout {
+                    paid_requests: 11,
+                    ..pending_payout.clone()
+                }
+            )
+            .is_err());
+
+        map.insert_pending_payout_in_ledger(gateway_ledger_index.clone(), pending_payout.clone())
+            .unwrap();
+        // the reserved requests cannot be reserved again by another payout
+        assert!(map
+            .insert_pending_payout_in_ledger(gateway_ledger_index.clone(), pending_payout.clone())
+            .is_err());
+
+        let gateway_ledger_value = map
+            .insert_paid_requests_in_ledger(gateway_ledger_index.clone(), 10)
+            .unwrap();
+        assert_eq!(gateway_ledger_value.paid_requests, 10);
+        assert_eq!(gateway_ledger_value.pending_payout, None);
+        // recording the payout again doesn't pay the requests twice
+        assert!(map
+            .insert_paid_requests_in_ledger(gateway_ledger_index, 10)
+            .is_err());
+    }
+
+    #[test]
+    fn test_remove_pending_payout_from_ledger() {
+        let mut map = init_map::<GatewayLedgerIndex, GatewayLedgerValue>();
+        let gateway_ledger_index = GatewayLedgerIndex {
+            gateway_principal_id: String::from("gateway"),
+        };
+        map.insert_served_requests_in_ledger(gateway_ledger_index.clone(), 10, 1)
+            .unwrap();
+        let pending_payout = PendingGatewayPayout {
+            paid_requests: 10,
+            amount_e8s: 100,
+            memo: 0,
+            created_at_time: 2,
+        };
+        map.insert_pending_payout_in_ledger(gateway_ledger_index.clone(), pending_payout.clone())
+            .unwrap();
+
+        assert!(map
+            .remove_pending_payout_from_ledger(
+                gateway_ledger_index.clone(),
+                &PendingGatewayPayout {
+                    created_at_time: 3,
+                    ..pending_payout.clone()
+                }
+            )
+            .is_err());
+
+        let gateway_ledger_value = map
+            .remove_pending_payout_from_ledger(gateway_ledger_index, &pending_payout)
+            .unwrap();
+        assert_eq!(gateway_ledger_value.paid_requests, 0);
+        assert_eq!(gateway_ledger_value.get_unpaid_requests(), 10);
+        assert_eq!(gateway_ledger_value.pending_payout, None);
+    }
+
+    #[test]
+    fn test_get_environment_uid_by_ip() {
+        let memory_manager = MemoryManager::init(DefaultMemoryImpl::default());
+        let mut map = CrudMap::with_indexes(
+            memory_manager.get(MemoryId::new(0)),
+            RegisteredGatewayIndexes::init(
+                memory_manager.get(MemoryId::new(1)),
+                memory_manager.get(MemoryId::new(2)),
+            ),
+        );
+        let environment_uid_index = EnvironmentUidIndex {
+            ip: String::from("10.10.10.10"),
+        };
+        let registered_gateway_index = RegisteredGatewayIndex {
+            principal_id: String::from("gateway"),
+        };
+        let registered_gateway_value = RegisteredGatewayValue {
+            gateway_name: String::from("gateway"),
+            gateway_ip: environment_uid_index.ip.clone(),
+            gateway_url: String::from("https://10.10.10.10"),
+            proxied_gateway_uid: None,
+            env_uid: String::from("environment"),
+        };
+
+        assert!(matches!(
+            map.get_environment_uid_by_ip(environment_uid_index.clone(), true),
+            Err(GenericError::NotFound { entity, .. }) if entity == "EnvironmentUid"
+        ));
+
+        map.create(registered_gateway_index.clone(), registered_gateway_value)
+            .unwrap();
+        for is_indexed in [true, false] {
+            assert_eq!(
+                map.get_environment_uid_by_ip(environment_uid_index.clone(), is_indexed),
+                Ok(String::from("environment"))
+            );
+        }
+
+        // the environment follows the gateways of the network
+        map.delete(&registered_gateway_index).unwrap();
+        assert!(map
+            .get_environment_uid_by_ip(environment_uid_index, true)
+            .is_err());
     }
 }
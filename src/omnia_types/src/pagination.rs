@@ -0,0 +1,20 @@
+use candid::{CandidType, Deserialize};
+use serde::Serialize;
+
+/// Identifies the last item of a page, the next page starts right after it
+pub type PageCursor = String;
+
+#[derive(Clone, Debug, Default, CandidType, Serialize, Deserialize)]
+pub struct PageRequest {
+    /// The `next_cursor` of the previous page, `None` to get the first page
+    pub cursor: Option<PageCursor>,
+    /// The maximum number of items in the page, capped by the canister
+    pub limit: Option<u64>,
+}
+
+#[derive(Clone, Debug, CandidType, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    /// `None` if there are no more items
+    pub next_cursor: Option<PageCursor>,
+}
@@ -0,0 +1,189 @@
+use candid::{CandidType, Deserialize};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+use crate::errors::{GenericError, GenericResult};
+
+/// Version of the snapshot format, to be increased on every change of [SnapshotChunk] or of its checksum
+pub const SNAPSHOT_FORMAT_VERSION: u16 = 2;
+
+/// Position of the next chunk to export
+#[derive(Clone, Debug, CandidType, Serialize, Deserialize, PartialEq, Eq)]
+pub struct SnapshotCursor {
+    /// name of the map to export
+    pub map: String,
+    /// encoded index of the last exported entry of the map, None to start from the first entry
+    pub index: Option<Vec<u8>>,
+    /// checksum of the chunk that returned the cursor, empty to start from the first chunk
+    pub previous_checksum: Vec<u8>,
+}
+
+/// Entries of a stored map, exported as they're encoded in stable memory
+#[derive(Clone, Debug, CandidType, Serialize, Deserialize, PartialEq, Eq)]
+pub struct SnapshotChunk {
+    pub format_version: u16,
+    pub map: String,
+    /// encoded index of the last entry of the previous chunk of the same map, None if the chunk starts from the first entry
+    pub start_index: Option<Vec<u8>>,
+    /// encoded indexes and values
+    pub entries: Vec<(Vec<u8>, Vec<u8>)>,
+    /// SHA-256 of the checksum of the previous chunk, of the format version, of the position of the chunk,
+    /// of the entries and of the position of the next chunk, so that the chunks can only be imported in the order they've been exported
+    pub checksum: Vec<u8>,
+    /// None if this is the last chunk of the snapshot
+    pub next_cursor: Option<SnapshotCursor>,
+}
+
+impl SnapshotChunk {
+    /// The chunk exported from the cursor, whose checksum is set as previous checksum of the next cursor
+    pub fn new(
+        cursor: SnapshotCursor,
+        entries: Vec<(Vec<u8>, Vec<u8>)>,
+        next_cursor: Option<SnapshotCursor>,
+    ) -> Self {
+        let mut chunk = Self {
+            format_version: SNAPSHOT_FORMAT_VERSION,
+            map: cursor.map,
+            start_index: cursor.index,
+            entries,
+            checksum: vec![],
+            next_cursor,
+        };
+        chunk.checksum = chunk.compute_checksum(&cursor.previous_checksum);
+        if let Some(next_cursor) = chunk.next_cursor.as_mut() {
+            next_cursor.previous_checksum = chunk.checksum.clone();
+        }
+        chunk
+    }
+
+    /// Checks that the chunk has been exported with the current format right after the chunk with the previous checksum,
+    /// and hasn't been altered
+    pub fn verify(&self, previous_checksum: &[u8]) -> GenericResult<()> {
+        if self.format_version != SNAPSHOT_FORMAT_VERSION {
+            return Err(GenericError::invalid_argument(format!(
+                "Unsupported snapshot format version {}, expected {}",
+                self.format_version, SNAPSHOT_FORMAT_VERSION
+            )));
+        }
+        if self.compute_checksum(previous_checksum) != self.checksum {
+            return Err(GenericError::invalid_argument(format!(
+                "Invalid checksum of the snapshot chunk of map {}",
+                self.map
+            )));
+        }
+        Ok(())
+    }
+
+    fn compute_checksum(&self, previous_checksum: &[u8]) -> Vec<u8> {
+        let mut hasher = Sha256::new();
+        // lengths are hashed as well, so that bytes can't be moved between adjacent fields
+        hash_bytes(&mut hasher, previous_checksum);
+        hasher.update(self.format_version.to_le_bytes());
+        hash_position(&mut hasher, &self.map, &self.start_index);
+        for (index, value) in self.entries.iter() {
+            hash_bytes(&mut hasher, index);
+            hash_bytes(&mut hasher, value);
+        }
+        // the checksum of the chunk is returned in the next cursor, so only its position is hashed
+        match &self.next_cursor {
+            Some(next_cursor) => {
+                hasher.update([1]);
+                hash_position(&mut hasher, &next_cursor.map, &next_cursor.index);
+            }
+            None => hasher.update([0]),
+        }
+        hasher.finalize().to_vec()
+    }
+}
+
+fn hash_bytes(hasher: &mut Sha256, bytes: &[u8]) {
+    hasher.update((bytes.len() as u64).to_le_bytes());
+    hasher.update(bytes);
+}
+
+fn hash_position(hasher: &mut Sha256, map: &str, index: &Option<Vec<u8>>) {
+    hash_bytes(hasher, map.as_bytes());
+    match index {
+        Some(index) => {
+            hasher.update([1]);
+            hash_bytes(hasher, index);
+        }
+        None => hasher.update([0]),
+    }
+}
+
+pub type SnapshotChunkResult = GenericResult<SnapshotChunk>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get_first_cursor() -> SnapshotCursor {
+        SnapshotCursor {
+            map: String::from("environments"),
+            index: None,
+            previous_checksum: vec![],
+        }
+    }
+
+    fn get_chunk() -> SnapshotChunk {
+        SnapshotChunk::new(
+            get_first_cursor(),
+            vec![(vec![1, 2], vec![3]), (vec![4], vec![5, 6])],
+            Some(SnapshotCursor {
+                map: String::from("environments"),
+                index: Some(vec![4]),
+                previous_checksum: vec![],
+            }),
+        )
+    }
+
+    #[test]
+    fn test_verify() {
+        let chunk = get_chunk();
+        assert_eq!(chunk.verify(&[]), Ok(()));
+
+        // the next chunk is chained to the checksum of the chunk that returned its cursor
+        let next_cursor = chunk.next_cursor.clone().unwrap();
+        assert_eq!(next_cursor.previous_checksum, chunk.checksum);
+        let next_chunk = SnapshotChunk::new(next_cursor, vec![(vec![7], vec![8])], None);
+        assert_eq!(next_chunk.verify(&chunk.checksum), Ok(()));
+        assert!(next_chunk.verify(&[]).is_err());
+    }
+
+    #[test]
+    fn test_verify_altered_chunk() {
+        let mut chunk = get_chunk();
+        chunk.entries[1].1 = vec![5, 7];
+        assert!(chunk.verify(&[]).is_err());
+
+        // moving a byte between an index and its value changes the checksum
+        let mut chunk = get_chunk();
+        chunk.entries[0] = (vec![1], vec![2, 3]);
+        assert!(chunk.verify(&[]).is_err());
+
+        let mut chunk = get_chunk();
+        chunk.map = String::from("registered_devices");
+        assert!(chunk.verify(&[]).is_err());
+
+        let mut chunk = get_chunk();
+        chunk.start_index = Some(vec![0]);
+        assert!(chunk.verify(&[]).is_err());
+
+        // a chunk can't be turned into the last one of the snapshot
+        let mut chunk = get_chunk();
+        chunk.next_cursor = None;
+        assert!(chunk.verify(&[]).is_err());
+
+        let mut chunk = get_chunk();
+        chunk.next_cursor.as_mut().unwrap().index = Some(vec![1, 2]);
+        assert!(chunk.verify(&[]).is_err());
+    }
+
+    #[test]
+    fn test_verify_unsupported_version() {
+        let mut chunk = get_chunk();
+        chunk.format_version = SNAPSHOT_FORMAT_VERSION + 1;
+        assert!(chunk.verify(&[]).is_err());
+    }
+}
@@ -0,0 +1,170 @@
+use candid::{CandidType, Deserialize};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+use crate::errors::{GenericError, GenericResult};
+
+/// Caps on the work done by a single SPARQL query and on the size of the response with its results
+#[derive(Clone, Copy, Debug, Default, CandidType, Serialize, Deserialize, PartialEq, Eq)]
+pub struct SparqlQueryLimits {
+    /// maximum number of solutions returned in a single response
+    pub max_rows: u64,
+    /// maximum size (in bytes) of the serialized solutions returned in a single response
+    pub max_response_bytes: u64,
+    /// maximum number of instructions executed to evaluate the query, which bounds its complexity
+    pub max_instructions: u64,
+}
+
+/// The RDF dataset set by the `default-graph-uri` and `named-graph-uri` parameters of the [SPARQL 1.1 Protocol](https://www.w3.org/TR/sparql11-protocol/#dataset).
+/// If empty, the query is evaluated on the dataset of its `FROM` and `FROM NAMED` clauses, or on the whole database
+#[derive(Clone, Debug, Default, CandidType, Serialize, Deserialize, PartialEq, Eq)]
+pub struct SparqlDataset {
+    /// IRIs of the graphs merged in the default graph
+    pub default_graph_uris: Vec<String>,
+    /// IRIs of the named graphs that the query can match with `GRAPH`
+    pub named_graph_uris: Vec<String>,
+}
+
+impl SparqlDataset {
+    pub fn is_empty(&self) -> bool {
+        self.default_graph_uris.is_empty() && self.named_graph_uris.is_empty()
+    }
+}
+
+/// Number of bytes of the query hash in the continuation tokens
+const QUERY_HASH_BYTES: usize = 8;
+
+/// Points to the next page of the results of a query that didn't fit in the previous responses, so that the query can be evaluated again from there.
+/// It's bound to the query and its dataset, so that it cannot be used to get the results of another one
+#[derive(Debug, PartialEq, Eq)]
+pub struct SparqlContinuationToken {
+    /// number of solutions, or of triples of the graph, returned by the previous pages
+    pub offset: u64,
+    query_hash: [u8; QUERY_HASH_BYTES],
+}
+
+impl SparqlContinuationToken {
+    pub fn new(query: &str, dataset: &SparqlDataset, offset: u64) -> Self {
+        Self {
+            offset,
+            query_hash: get_query_hash(query, dataset),
+        }
+    }
+
+    /// Encodes the token as the hex of the offset, followed by the hash of the query
+    pub fn encode(&self) -> String {
+        self.offset
+            .to_be_bytes()
+            .iter()
+            .chain(self.query_hash.iter())
+            .map(|byte| format!("{:02x}", byte))
+            .collect()
+    }
+
+    /// Decodes a token returned for the same query on the same dataset
+    pub fn decode(token: &str, query: &str, dataset: &SparqlDataset) -> GenericResult<Self> {
+        let invalid_token =
+            || GenericError::invalid_argument(format!("Invalid continuation token: {}", token));
+
+        if !token.is_ascii() || token.len() != 2 * (8 + QUERY_HASH_BYTES) {
+            return Err(invalid_token());
+        }
+        let bytes = (0..token.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&token[i..i + 2], 16))
+            .collect::<Result<Vec<u8>, _>>()
+            .map_err(|_| invalid_token())?;
+
+        let (offset, query_hash) = bytes.split_at(8);
+        if query_hash != get_query_hash(query, dataset) {
+            return Err(GenericError::invalid_argument(
+                "The continuation token was returned for another query",
+            ));
+        }
+
+        Ok(Self::new(
+            query,
+            dataset,
+            u64::from_be_bytes(offset.try_into().unwrap()),
+        ))
+    }
+}
+
+fn get_query_hash(query: &str, dataset: &SparqlDataset) -> [u8; QUERY_HASH_BYTES] {
+    let mut hasher = Sha256::new();
+    // each string is prefixed by its length and the graphs are separated by an empty string, which is not a valid IRI,
+    // so that different queries and datasets cannot be encoded in the same way
+    for value in [query]
+        .into_iter()
+        .chain(dataset.default_graph_uris.iter().map(String::as_str))
+        .chain([""])
+        .chain(dataset.named_graph_uris.iter().map(String::as_str))
+    {
+        hasher.update((value.len() as u64).to_be_bytes());
+        hasher.update(value.as_bytes());
+    }
+
+    hasher.finalize()[..QUERY_HASH_BYTES].try_into().unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const QUERY: &str = "SELECT ?s WHERE { ?s ?p ?o }";
+
+    #[test]
+    fn test_continuation_token() {
+        let dataset = SparqlDataset::default();
+        let token = SparqlContinuationToken::new(QUERY, &dataset, 1_000);
+        let encoded_token = token.encode();
+
+        assert_eq!(
+            SparqlContinuationToken::decode(&encoded_token, QUERY, &dataset),
+            Ok(token)
+        );
+        assert!(matches!(
+            SparqlContinuationToken::decode(
+                &encoded_token,
+                "SELECT ?o WHERE { ?s ?p ?o }",
+                &dataset
+            ),
+            Err(GenericError::InvalidArgument { .. })
+        ));
+        assert!(matches!(
+            SparqlContinuationToken::decode(&encoded_token[1..], QUERY, &dataset),
+            Err(GenericError::InvalidArgument { .. })
+        ));
+        assert!(matches!(
+            SparqlContinuationToken::decode(&encoded_token.replace('0', "g"), QUERY, &dataset),
+            Err(GenericError::InvalidArgument { .. })
+        ));
+    }
+
+    #[test]
+    fn test_continuation_token_dataset() {
+        let graph = String::from("https://proxy.example.com/device");
+        let default_graph_dataset = SparqlDataset {
+            default_graph_uris: vec![graph.clone()],
+            named_graph_uris: vec![],
+        };
+        let named_graph_dataset = SparqlDataset {
+            default_graph_uris: vec![],
+            named_graph_uris: vec![graph],
+        };
+        let encoded_token =
+            SparqlContinuationToken::new(QUERY, &default_graph_dataset, 10).encode();
+
+        assert!(
+            SparqlContinuationToken::decode(&encoded_token, QUERY, &default_graph_dataset).is_ok()
+        );
+        assert!(matches!(
+            SparqlContinuationToken::decode(&encoded_token, QUERY, &named_graph_dataset),
+            Err(GenericError::InvalidArgument { .. })
+        ));
+        assert!(matches!(
+            SparqlContinuationToken::decode(&encoded_token, QUERY, &SparqlDataset::default()),
+            Err(GenericError::InvalidArgument { .. })
+        ));
+    }
+}
@@ -1,14 +1,14 @@
 use std::{borrow::Cow, cmp::Ordering};
 
 use candid::{CandidType, Decode, Deserialize, Encode};
-use ic_stable_structures::{BoundedStorable, Storable};
+use ic_stable_structures::{storable::Bound, Storable};
 use serde::Serialize;
 
 use crate::{
     errors::GenericResult,
     gateway::GatewayPrincipalId,
+    versioning::{decode_versioned, encode_versioned, Versioned},
     virtual_persona::{VirtualPersonaIp, VirtualPersonaPrincipalId},
-    MAX_STABLE_BTREE_MAP_SIZE,
 };
 
 pub type PairingPayload = String;
@@ -31,6 +31,8 @@ impl PartialOrd for UpdateIndex {
 }
 
 impl Storable for UpdateIndex {
+    const BOUND: Bound = Bound::Unbounded;
+
     fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
         Cow::Owned(Encode!(self).unwrap())
     }
@@ -40,11 +42,6 @@ impl Storable for UpdateIndex {
     }
 }
 
-impl BoundedStorable for UpdateIndex {
-    const MAX_SIZE: u32 = MAX_STABLE_BTREE_MAP_SIZE;
-    const IS_FIXED_SIZE: bool = false;
-}
-
 #[derive(Clone, Debug, Default, CandidType, Serialize, Deserialize)]
 pub struct UpdateValue {
     pub virtual_persona_principal_id: VirtualPersonaPrincipalId,
@@ -54,19 +51,18 @@ pub struct UpdateValue {
 }
 
 impl Storable for UpdateValue {
+    const BOUND: Bound = Bound::Unbounded;
+
     fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
-        Cow::Owned(Encode!(self).unwrap())
+        encode_versioned(self)
     }
 
     fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
-        Decode!(bytes.as_ref(), Self).unwrap()
+        decode_versioned(&bytes)
     }
 }
 
-impl BoundedStorable for UpdateValue {
-    const MAX_SIZE: u32 = MAX_STABLE_BTREE_MAP_SIZE;
-    const IS_FIXED_SIZE: bool = false;
-}
+impl Versioned for UpdateValue {}
 
 #[derive(Clone, Debug, Default, CandidType, Serialize, Deserialize)]
 pub struct PairingInfo {
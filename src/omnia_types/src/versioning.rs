@@ -0,0 +1,184 @@
+use candid::{CandidType, Decode, Encode};
+use serde::de::DeserializeOwned;
+use std::any::type_name;
+use std::borrow::Cow;
+
+/// First byte of the versioned envelope, which is never the first byte of a Candid message (`DIDL`)
+const ENVELOPE_TAG: u8 = 0xFE;
+const ENVELOPE_HEADER_SIZE: usize = 3;
+
+/// Record stored in stable memory, whose schema can change between upgrades.
+///
+/// Records are stored in an envelope carrying the version of the schema they have been encoded with,
+/// so that the records stored with a previous version can still be decoded after a non-additive change.
+/// Records stored before the envelopes were introduced are read as version 0
+pub trait Versioned: CandidType + DeserializeOwned {
+    /// Version of the current schema, to be increased on every non-additive change
+    const VERSION: u16 = 0;
+
+    /// Decodes the payload of a record stored with a previous version of the schema,
+    /// usually by decoding the previous schema and converting it to the current one
+    fn decode_previous_version(version: u16, _payload: &[u8]) -> Result<Self, String> {
+        Err(format!(
+            "Cannot decode {} stored with version {}, current version is {}",
+            type_name::<Self>(),
+            version,
+            Self::VERSION
+        ))
+    }
+}
+
+/// Encodes the record in an envelope with the current version of its schema
+pub fn encode_versioned<T: Versioned>(record: &T) -> Cow<'static, [u8]> {
+    let payload = Encode!(record).unwrap();
+    let mut bytes = Vec::with_capacity(ENVELOPE_HEADER_SIZE + payload.len());
+    bytes.push(ENVELOPE_TAG);
+    bytes.extend_from_slice(&T::VERSION.to_le_bytes());
+    bytes.extend_from_slice(&payload);
+    Cow::Owned(bytes)
+}
+
+/// Decodes a record stored with any version of its schema
+pub fn decode_versioned<T: Versioned>(bytes: &[u8]) -> T {
+    try_decode_versioned(bytes).unwrap_or_else(|e| panic!("{}", e))
+}
+
+/// Same as [decode_versioned], but returns an error instead of trapping, for the records that don't come from stable memory
+pub fn try_decode_versioned<T: Versioned>(bytes: &[u8]) -> Result<T, String> {
+    let (version, payload) = open_envelope(bytes);
+
+    if version == T::VERSION {
+        return Decode!(payload, T).map_err(|e| {
+            format!(
+                "Cannot decode {} stored with version {}: {}",
+                type_name::<T>(),
+                version,
+                e
+            )
+        });
+    }
+    if version > T::VERSION {
+        return Err(format!(
+            "Cannot decode {} stored with version {}, which is newer than the current version {}",
+            type_name::<T>(),
+            version,
+            T::VERSION
+        ));
+    }
+    T::decode_previous_version(version, payload)
+}
+
+/// Returns the version and the Candid payload of the stored record
+pub fn open_envelope(bytes: &[u8]) -> (u16, &[u8]) {
+    match bytes.first() {
+        Some(&ENVELOPE_TAG) if bytes.len() >= ENVELOPE_HEADER_SIZE => (
+            u16::from_le_bytes([bytes[1], bytes[2]]),
+            &bytes[ENVELOPE_HEADER_SIZE..],
+        ),
+        // stored before the envelopes were introduced
+        _ => (0, bytes),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candid::Deserialize;
+    use serde::Serialize;
+
+    #[derive(Debug, CandidType, Serialize, Deserialize, PartialEq)]
+    struct RecordV0 {
+        name: String,
+        tags: Vec<String>,
+    }
+
+    impl Versioned for RecordV0 {}
+
+    #[derive(Debug, CandidType, Serialize, Deserialize, PartialEq)]
+    struct Record {
+        name: String,
+        tags_count: u64,
+    }
+
+    impl Versioned for Record {
+        const VERSION: u16 = 1;
+
+        fn decode_previous_version(version: u16, payload: &[u8]) -> Result<Self, String> {
+            match version {
+                0 => {
+                    let record = Decode!(payload, RecordV0).map_err(|e| e.to_string())?;
+                    Ok(Self {
+                        name: record.name,
+                        tags_count: record.tags.len() as u64,
+                    })
+                }
+                _ => Err(format!("No previous version {} of Record", version)),
+            }
+        }
+    }
+
+    fn get_record_v0() -> RecordV0 {
+        RecordV0 {
+            name: String::from("record"),
+            tags: vec![String::from("a"), String::from("b")],
+        }
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let record = Record {
+            name: String::from("record"),
+            tags_count: 2,
+        };
+        let bytes = encode_versioned(&record);
+
+        assert_eq!(open_envelope(&bytes).0, 1);
+        assert_eq!(decode_versioned::<Record>(&bytes), record);
+    }
+
+    #[test]
+    fn test_decode_legacy_record() {
+        let bytes = Encode!(&get_record_v0()).unwrap();
+
+        assert_eq!(open_envelope(&bytes), (0, bytes.as_slice()));
+        assert_eq!(decode_versioned::<RecordV0>(&bytes), get_record_v0());
+        assert_eq!(
+            decode_versioned::<Record>(&bytes),
+            Record {
+                name: String::from("record"),
+                tags_count: 2,
+            }
+        );
+    }
+
+    #[test]
+    fn test_decode_previous_version() {
+        let bytes = encode_versioned(&get_record_v0());
+
+        assert_eq!(
+            decode_versioned::<Record>(&bytes),
+            Record {
+                name: String::from("record"),
+                tags_count: 2,
+            }
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "newer than the current version")]
+    fn test_decode_newer_version() {
+        let bytes = encode_versioned(&Record {
+            name: String::from("record"),
+            tags_count: 2,
+        });
+
+        decode_versioned::<RecordV0>(&bytes);
+    }
+
+    #[test]
+    fn test_try_decode_invalid_record() {
+        assert!(try_decode_versioned::<Record>(&[0xFE, 1, 0, 1, 2, 3]).is_err());
+        assert!(try_decode_versioned::<Record>(&[0xFE, 2, 0]).is_err());
+        assert!(try_decode_versioned::<RecordV0>(&[]).is_err());
+    }
+}
@@ -1,10 +1,14 @@
 use std::{borrow::Cow, cmp::Ordering};
 
 use candid::{CandidType, Decode, Deserialize, Encode};
-use ic_stable_structures::{BoundedStorable, Storable};
+use ic_stable_structures::{storable::Bound, Storable};
 use serde::Serialize;
 
-use crate::{environment::EnvironmentUID, errors::GenericResult, MAX_STABLE_BTREE_MAP_SIZE};
+use crate::{
+    environment::EnvironmentUID,
+    errors::GenericResult,
+    versioning::{decode_versioned, encode_versioned, Versioned},
+};
 
 pub type VirtualPersonaPrincipalId = String;
 
@@ -28,6 +32,8 @@ impl PartialOrd for VirtualPersonaIndex {
 }
 
 impl Storable for VirtualPersonaIndex {
+    const BOUND: Bound = Bound::Unbounded;
+
     fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
         Cow::Owned(Encode!(self).unwrap())
     }
@@ -37,11 +43,6 @@ impl Storable for VirtualPersonaIndex {
     }
 }
 
-impl BoundedStorable for VirtualPersonaIndex {
-    const MAX_SIZE: u32 = MAX_STABLE_BTREE_MAP_SIZE;
-    const IS_FIXED_SIZE: bool = false;
-}
-
 #[derive(Clone, Debug, Default, CandidType, Serialize, Deserialize)]
 pub struct VirtualPersonaValue {
     pub virtual_persona_principal_id: VirtualPersonaPrincipalId,
@@ -51,18 +52,17 @@ pub struct VirtualPersonaValue {
 }
 
 impl Storable for VirtualPersonaValue {
+    const BOUND: Bound = Bound::Unbounded;
+
     fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
-        Cow::Owned(Encode!(self).unwrap())
+        encode_versioned(self)
     }
 
     fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
-        Decode!(bytes.as_ref(), Self).unwrap()
+        decode_versioned(&bytes)
     }
 }
 
-impl BoundedStorable for VirtualPersonaValue {
-    const MAX_SIZE: u32 = MAX_STABLE_BTREE_MAP_SIZE;
-    const IS_FIXED_SIZE: bool = false;
-}
+impl Versioned for VirtualPersonaValue {}
 
 pub type VirtualPersonaValueResult = GenericResult<VirtualPersonaValue>;
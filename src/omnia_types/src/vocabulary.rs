@@ -0,0 +1,22 @@
+use candid::{CandidType, Deserialize};
+use serde::Serialize;
+
+use crate::errors::GenericResult;
+
+/// A vocabulary whose terms can be used in the `@type` of the Thing Descriptions
+#[derive(Clone, Debug, CandidType, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Vocabulary {
+    /// prefix of the compact IRIs of the terms, like `saref`
+    pub prefix: String,
+    /// IRI that the terms are appended to, ending with `/` or `#`
+    pub namespace: String,
+    /// names of the terms of the vocabulary, None if any term of the namespace is accepted
+    pub terms: Option<Vec<String>>,
+    /// whether the vocabulary has been registered by a controller or is built in the backend
+    pub is_custom: bool,
+}
+
+pub type VocabulariesResult = GenericResult<Vec<Vocabulary>>;
+
+/// The prefixes of the SPARQL queries along with their namespaces
+pub type PrefixMapResult = GenericResult<Vec<(String, String)>>;
@@ -0,0 +1,105 @@
+use omnia_types::access_key::{AccessKeySecret, AccessKeySecretHash};
+use sha2::{Digest, Sha256};
+
+use crate::constants::{ACCESS_KEY_ID_PREFIX, ACCESS_KEY_SECRET_PREFIX};
+
+/// Number of random bytes in an Access Key secret (256 bits of entropy)
+pub const ACCESS_KEY_SECRET_BYTES: usize = 32;
+
+/// Number of bytes of the secret hash used in the public identifier of an Access Key
+const ACCESS_KEY_ID_BYTES: usize = 16;
+
+/// Number of bytes of the checksum appended to secrets and identifiers
+const CHECKSUM_BYTES: usize = 4;
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn get_checksum(prefix: &str, body: &str) -> String {
+    let mut state = Sha256::new();
+    state.update(prefix.as_bytes());
+    state.update(body.as_bytes());
+    to_hex(&state.finalize()[..CHECKSUM_BYTES])
+}
+
+/// Encodes the bytes as `<prefix><hex of bytes><hex of checksum>`
+fn encode_with_checksum(prefix: &str, bytes: &[u8]) -> String {
+    let body = to_hex(bytes);
+    let checksum = get_checksum(prefix, &body);
+    format!("{prefix}{body}{checksum}")
+}
+
+fn is_valid_with_checksum(prefix: &str, value: &str, bytes_len: usize) -> bool {
+    let Some(hex) = value.strip_prefix(prefix) else {
+        return false;
+    };
+
+    if hex.len() != (bytes_len + CHECKSUM_BYTES) * 2
+        || !hex
+            .chars()
+            .all(|c| c.is_ascii_digit() || ('a'..='f').contains(&c))
+    {
+        return false;
+    }
+
+    let (body, checksum) = hex.split_at(bytes_len * 2);
+    get_checksum(prefix, body) == checksum
+}
+
+/// Generates the Access Key secret from the random bytes, obtained from the `raw_rand` method of the management canister
+pub fn generate_access_key_secret(random_bytes: [u8; ACCESS_KEY_SECRET_BYTES]) -> AccessKeySecret {
+    encode_with_checksum(ACCESS_KEY_SECRET_PREFIX, &random_bytes)
+}
+
+/// Checks the format and the checksum of the secret, without looking it up in the database
+pub fn is_valid_access_key_secret(secret: &str) -> bool {
+    is_valid_with_checksum(ACCESS_KEY_SECRET_PREFIX, secret, ACCESS_KEY_SECRET_BYTES)
+}
+
+/// The hash of the secret is the only information about the secret stored in the database
+pub fn hash_access_key_secret(secret: &str) -> AccessKeySecretHash {
+    Sha256::digest(secret.as_bytes()).into()
+}
+
+/// Derives the public identifier of the Access Key from the hash of its secret
+pub fn get_access_key_id(secret_hash: &AccessKeySecretHash) -> String {
+    encode_with_checksum(ACCESS_KEY_ID_PREFIX, &secret_hash[..ACCESS_KEY_ID_BYTES])
+}
+
+/// Checks the format and the checksum of the public identifier, without looking it up in the database
+pub fn is_valid_access_key_id(access_key_id: &str) -> bool {
+    is_valid_with_checksum(ACCESS_KEY_ID_PREFIX, access_key_id, ACCESS_KEY_ID_BYTES)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_access_key_secret() {
+        let secret = generate_access_key_secret([42; ACCESS_KEY_SECRET_BYTES]);
+        assert!(secret.starts_with(ACCESS_KEY_SECRET_PREFIX));
+        assert!(is_valid_access_key_secret(&secret));
+
+        // a typo in the secret is detected by the checksum
+        let mut typo = secret.clone();
+        typo.replace_range(
+            ACCESS_KEY_SECRET_PREFIX.len()..ACCESS_KEY_SECRET_PREFIX.len() + 1,
+            "0",
+        );
+        assert!(!is_valid_access_key_secret(&typo));
+        assert!(!is_valid_access_key_secret(&secret[1..]));
+        assert!(!is_valid_access_key_secret(&secret.to_uppercase()));
+    }
+
+    #[test]
+    fn test_access_key_id() {
+        let secret = generate_access_key_secret([42; ACCESS_KEY_SECRET_BYTES]);
+        let access_key_id = get_access_key_id(&hash_access_key_secret(&secret));
+        assert!(access_key_id.starts_with(ACCESS_KEY_ID_PREFIX));
+        assert!(is_valid_access_key_id(&access_key_id));
+        assert!(!is_valid_access_key_id(&secret));
+        assert!(!access_key_id.contains(&secret[ACCESS_KEY_SECRET_PREFIX.len()..]));
+    }
+}
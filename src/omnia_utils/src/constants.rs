@@ -7,3 +7,60 @@ pub const OMNIA_PROXY_HOST: &str = "proxy.omnia-iot.com";
 
 /// The maximum number of requests that can be sent to Gateways with a single Access Key.
 pub const ACCESS_KEY_REQUESTS_LIMIT: u32 = 10;
+
+/// The default percentage of the Access Key revenue that is paid to the operators of the Gateways that served the requests.
+pub const GATEWAY_REVENUE_SHARE_PERCENTAGE: u8 = 70;
+
+/// The interval (in seconds) between two consecutive payouts to Gateway operators.
+pub const GATEWAY_PAYOUTS_INTERVAL_SECONDS: u64 = 24 * 60 * 60;
+
+/// The delay (in seconds) before the payouts that failed are retried, which must be shorter than the deduplication window of the ledger (24 hours).
+pub const GATEWAY_PAYOUTS_RETRY_SECONDS: u64 = 10 * 60;
+
+/// The maximum number of entries of the Access Key usage history that can be returned in a single page.
+pub const ACCESS_KEY_USAGE_PAGE_SIZE_LIMIT: u64 = 100;
+
+/// The maximum number of devices whose requests are counted separately in the usage of an Access Key.
+pub const ACCESS_KEY_USAGE_DEVICES_LIMIT: u64 = 100;
+
+/// The prefix of the public identifiers of the Access Keys.
+pub const ACCESS_KEY_ID_PREFIX: &str = "omnia_ak_";
+
+/// The prefix of the Access Key secrets, which are used to sign the requests to Gateways.
+pub const ACCESS_KEY_SECRET_PREFIX: &str = "omnia_sk_";
+
+/// The number of times an idempotent inter-canister call rejected with a transient error is retried.
+pub const INTER_CANISTER_CALL_MAX_RETRIES: u8 = 3;
+
+/// The interval (in seconds) between two consecutive reconciliations of the devices in the Database and in the RDF database.
+pub const DEVICE_RECONCILIATION_INTERVAL_SECONDS: u64 = 60 * 60;
+
+/// The maximum number of items that can be returned in a single page by the listing methods.
+pub const PAGE_SIZE_LIMIT: u64 = 100;
+
+/// The number of records rewritten with the current version of their schema by each migration batch of the Database.
+pub const MIGRATION_BATCH_SIZE: usize = 200;
+
+/// The maximum size (in bytes) of the entries exported in a single snapshot chunk, which must fit in a canister response.
+pub const SNAPSHOT_CHUNK_MAX_BYTES: usize = 1_000_000;
+
+/// The maximum size (in bytes) of the Thing Description of a device, which is stored in the RDF database.
+pub const THING_DESCRIPTION_MAX_BYTES: usize = 64 * 1024;
+
+/// The default maximum number of solutions of a SPARQL query returned in a single response.
+pub const SPARQL_QUERY_MAX_ROWS: u64 = 10_000;
+
+/// The default maximum size (in bytes) of the solutions of a SPARQL query returned in a single response.
+pub const SPARQL_QUERY_MAX_RESPONSE_BYTES: u64 = 1_500_000;
+
+/// The default maximum number of instructions executed to evaluate a SPARQL query, below the limit of a query call.
+pub const SPARQL_QUERY_MAX_INSTRUCTIONS: u64 = 2_000_000_000;
+
+/// The upper bound of the configurable size (in bytes) of the solutions of a SPARQL query, which leaves room for the headers in the 2 MB limit of the canister responses.
+pub const SPARQL_QUERY_MAX_RESPONSE_BYTES_LIMIT: u64 = 1_900_000;
+
+/// The time (in seconds) for which the pages of the SPARQL query results and of the dumps that don't fit in a single response are kept, within which they must be downloaded.
+pub const CACHED_RESULTS_EXPIRATION_SECONDS: u64 = 10 * 60;
+
+/// The maximum size (in bytes) of the pages of the SPARQL query results and of the dumps kept at the same time.
+pub const CACHED_RESULTS_MAX_BYTES: u64 = 100_000_000;
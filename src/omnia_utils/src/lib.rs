@@ -1,3 +1,4 @@
+pub mod access_key;
 pub mod constants;
 pub mod ic;
 pub mod net;
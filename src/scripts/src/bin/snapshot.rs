@@ -0,0 +1,185 @@
+use std::{
+    collections::BTreeMap,
+    env,
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Write},
+    path::Path,
+};
+
+use candid::{Decode, Encode};
+use omnia_types::{
+    errors::GenericResult,
+    snapshot::{SnapshotChunk, SnapshotCursor, SNAPSHOT_FORMAT_VERSION},
+};
+
+/// Snapshot files start with the magic bytes and the format version, followed by the chunks,
+/// each one prefixed by the length of its Candid encoding
+const SNAPSHOT_FILE_MAGIC: &[u8; 8] = b"OMNISNAP";
+
+const USAGE: &str = "Usage:
+  snapshot start
+      prints the argument of the first exportSnapshot call
+  snapshot append <file>
+      reads the raw exportSnapshot response from stdin, verifies it and appends it to the file,
+      then prints the argument of the next exportSnapshot call, or nothing if the snapshot is complete
+  snapshot verify <file>
+      verifies the chunks of the file and prints the number of entries of each map
+  snapshot split <file> <dir>
+      writes the argument of an importSnapshot call for each chunk of the file in the directory";
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+
+    let result = match args.iter().map(String::as_str).collect::<Vec<_>>()[1..] {
+        ["start"] => start(),
+        ["append", file] => append(Path::new(file)),
+        ["verify", file] => verify(Path::new(file)),
+        ["split", file, dir] => split(Path::new(file), Path::new(dir)),
+        _ => Err(USAGE.to_string()),
+    };
+
+    if let Err(e) = result {
+        eprintln!("{e}");
+        std::process::exit(1);
+    }
+}
+
+fn start() -> Result<(), String> {
+    let cursor: Option<SnapshotCursor> = None;
+    println!("{}", hex::encode(Encode!(&cursor).unwrap()));
+    Ok(())
+}
+
+fn append(file: &Path) -> Result<(), String> {
+    let mut response = String::new();
+    io::stdin()
+        .read_to_string(&mut response)
+        .map_err(|e| e.to_string())?;
+    let response = hex::decode(response.trim()).map_err(|e| e.to_string())?;
+    let chunk = Decode!(&response, GenericResult<SnapshotChunk>)
+        .map_err(|e| e.to_string())?
+        .map_err(|e| e.to_string())?;
+    let is_new_file = !file.exists();
+    // the chunk must follow the last chunk of the file, or be the first chunk of the snapshot
+    let previous_checksum = match is_new_file {
+        true => vec![],
+        false => read_chained_chunks(file)?
+            .pop()
+            .map(|previous_chunk| previous_chunk.checksum)
+            .unwrap_or_default(),
+    };
+    chunk
+        .verify(&previous_checksum)
+        .map_err(|e| e.to_string())?;
+
+    let mut snapshot_file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(file)
+        .map_err(|e| e.to_string())?;
+    if is_new_file {
+        snapshot_file
+            .write_all(SNAPSHOT_FILE_MAGIC)
+            .and_then(|_| snapshot_file.write_all(&SNAPSHOT_FORMAT_VERSION.to_le_bytes()))
+            .map_err(|e| e.to_string())?;
+    }
+    let encoded_chunk = Encode!(&chunk).unwrap();
+    snapshot_file
+        .write_all(&(encoded_chunk.len() as u32).to_le_bytes())
+        .and_then(|_| snapshot_file.write_all(&encoded_chunk))
+        .map_err(|e| e.to_string())?;
+
+    eprintln!(
+        "Exported {} entries of map {}",
+        chunk.entries.len(),
+        chunk.map
+    );
+    if chunk.next_cursor.is_some() {
+        println!("{}", hex::encode(Encode!(&chunk.next_cursor).unwrap()));
+    }
+    Ok(())
+}
+
+/// Reads and verifies all the chunks of the snapshot file, each one chained to the checksum of the previous one
+fn read_chained_chunks(file: &Path) -> Result<Vec<SnapshotChunk>, String> {
+    let mut bytes = vec![];
+    File::open(file)
+        .and_then(|mut snapshot_file| snapshot_file.read_to_end(&mut bytes))
+        .map_err(|e| e.to_string())?;
+
+    let header_size = SNAPSHOT_FILE_MAGIC.len() + 2;
+    if bytes.len() < header_size || &bytes[..SNAPSHOT_FILE_MAGIC.len()] != SNAPSHOT_FILE_MAGIC {
+        return Err(format!("{} is not a snapshot file", file.display()));
+    }
+    let format_version = u16::from_le_bytes([bytes[header_size - 2], bytes[header_size - 1]]);
+    if format_version != SNAPSHOT_FORMAT_VERSION {
+        return Err(format!(
+            "Unsupported snapshot format version {format_version}, expected {SNAPSHOT_FORMAT_VERSION}"
+        ));
+    }
+
+    let mut chunks: Vec<SnapshotChunk> = vec![];
+    let mut rest = &bytes[header_size..];
+    while !rest.is_empty() {
+        if rest.len() < 4 {
+            return Err(String::from("Truncated snapshot file"));
+        }
+        let (len, tail) = rest.split_at(4);
+        let len = u32::from_le_bytes(len.try_into().unwrap()) as usize;
+        if tail.len() < len {
+            return Err(String::from("Truncated snapshot file"));
+        }
+        let (encoded_chunk, tail) = tail.split_at(len);
+        let chunk = Decode!(encoded_chunk, SnapshotChunk).map_err(|e| e.to_string())?;
+        let previous_checksum = chunks
+            .last()
+            .map(|previous_chunk| previous_chunk.checksum.as_slice())
+            .unwrap_or_default();
+        chunk
+            .verify(previous_checksum)
+            .map_err(|e| format!("Chunk {}: {}", chunks.len(), e))?;
+        chunks.push(chunk);
+        rest = tail;
+    }
+    Ok(chunks)
+}
+
+/// Reads the chunks of a complete snapshot file
+fn read_chunks(file: &Path) -> Result<Vec<SnapshotChunk>, String> {
+    let chunks = read_chained_chunks(file)?;
+
+    match chunks.last() {
+        Some(chunk) if chunk.next_cursor.is_none() => Ok(chunks),
+        _ => Err(String::from(
+            "Incomplete snapshot, the last chunk is not the last of the database",
+        )),
+    }
+}
+
+fn verify(file: &Path) -> Result<(), String> {
+    let chunks = read_chunks(file)?;
+
+    let mut entries_per_map: BTreeMap<&str, usize> = BTreeMap::new();
+    for chunk in chunks.iter() {
+        *entries_per_map.entry(&chunk.map).or_default() += chunk.entries.len();
+    }
+
+    println!("Snapshot verified, {} chunks", chunks.len());
+    for (map, entries) in entries_per_map {
+        println!("  {map}: {entries} entries");
+    }
+    Ok(())
+}
+
+fn split(file: &Path, dir: &Path) -> Result<(), String> {
+    let chunks = read_chunks(file)?;
+
+    fs::create_dir_all(dir).map_err(|e| e.to_string())?;
+    for (i, chunk) in chunks.iter().enumerate() {
+        let argument = hex::encode(Encode!(chunk).unwrap());
+        fs::write(dir.join(format!("chunk-{i:05}.hex")), argument).map_err(|e| e.to_string())?;
+    }
+
+    println!("{} chunks written to {}", chunks.len(), dir.display());
+    Ok(())
+}